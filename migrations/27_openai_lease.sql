@@ -0,0 +1,6 @@
+alter table openai add column lease_id uuid;
+alter table openai add column leased_until timestamptz;
+
+-- Keys locked by the old status flag never expire. Give them a short lease so the sweeper
+-- reclaims them once any worker still holding one has had time to finish.
+update openai set leased_until = now() + interval '5 minutes' where openai_status = 1;
@@ -0,0 +1,13 @@
+-- Per-key limits; null means the key has no limit we know of.
+alter table openai add column rpm_limit integer;
+alter table openai add column tpm_limit integer;
+
+-- Usage in the current one minute window, reset lazily by the next lease after it ends.
+alter table openai add column window_started_at timestamptz;
+alter table openai add column window_requests integer not null default 0;
+alter table openai add column window_tokens integer not null default 0;
+
+-- Set when the provider answers 429; the key isn't leased again until then.
+alter table openai add column cooldown_until timestamptz;
+
+comment on column openai.is_plus is 'whether the key may serve gpt-4 class models';
@@ -0,0 +1,13 @@
+-- Per-key accounting, so burned or revoked keys show up without digging through logs.
+alter table openai alter column token_count type bigint;
+alter table openai add column request_count integer not null default 0;
+alter table openai add column error_count integer not null default 0;
+-- Reset on every successful call; a key that keeps failing is most likely revoked or out of quota.
+alter table openai add column consecutive_error_count integer not null default 0;
+alter table openai add column last_error text;
+alter table openai add column last_error_at timestamptz;
+alter table openai add column last_used_at timestamptz;
+
+-- The key that served the call the metric was recorded for; null for providers outside the pool.
+alter table metric_v2 add column openai_id uuid references openai(openai_id) on delete set null;
+create index metric_v2_openai_id_idx on metric_v2(openai_id);
@@ -0,0 +1,27 @@
+create table chat_session_v2(
+    session_id uuid primary key default uuid_generate_v4(),
+    module_id uuid not null references module_v2(module_id) on delete cascade,
+    user_id uuid not null references "user"(user_id) on delete cascade,
+    title text not null,
+    -- The session this one was forked from, if any.
+    forked_from uuid references chat_session_v2(session_id) on delete set null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index chat_session_v2_module_user_idx on chat_session_v2(module_id, user_id);
+
+select trigger_updated_at('chat_session_v2');
+
+create table chat_message_v2(
+    message_id uuid primary key default uuid_generate_v4(),
+    session_id uuid not null references chat_session_v2(session_id) on delete cascade,
+    user_input text not null,
+    ai_output text not null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index chat_message_v2_session_idx on chat_message_v2(session_id, created_at);
+
+select trigger_updated_at('chat_message_v2');
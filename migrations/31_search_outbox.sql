@@ -0,0 +1,38 @@
+-- Every change to something that's searchable in a module's Elasticsearch index is recorded
+-- here by the triggers below, and applied to the index by `retrieval::spawn_index_sync`.
+create table search_outbox(
+    outbox_id bigserial primary key,
+    module_id uuid not null,
+    document_id uuid not null,
+    created_at timestamptz not null default now()
+);
+
+-- `TG_ARGV[0]` is the name of the table's id column.
+create or replace function queue_search_sync()
+    returns trigger as
+$$
+begin
+    if TG_OP in ('UPDATE', 'DELETE') and OLD.module_id is not null then
+        insert into search_outbox (module_id, document_id)
+        values (OLD.module_id, (to_jsonb(OLD) ->> TG_ARGV[0])::uuid);
+    end if;
+    if TG_OP in ('INSERT', 'UPDATE') and NEW.module_id is not null
+        and (TG_OP = 'INSERT' or NEW.module_id is distinct from OLD.module_id) then
+        insert into search_outbox (module_id, document_id)
+        values (NEW.module_id, (to_jsonb(NEW) ->> TG_ARGV[0])::uuid);
+    end if;
+    return null;
+end;
+$$ language plpgsql;
+
+create trigger queue_search_sync
+    after insert or delete or update of content, module_id
+    on candidate_v2
+    for each row
+execute function queue_search_sync('candidate_id');
+
+create trigger queue_search_sync
+    after insert or delete or update of data_content, module_id
+    on data_v2
+    for each row
+execute function queue_search_sync('data_id');
@@ -0,0 +1,2 @@
+-- The passages retrieved for the question, and whether the answer cited them.
+alter table chat_message_v2 add column sources jsonb;
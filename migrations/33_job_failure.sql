@@ -0,0 +1,23 @@
+-- Queue messages a worker gave up on, recorded from the dead-letter queue.
+--
+-- The ids are copied out of the message, which may refer to a job or module deleted since, so
+-- they are not foreign keys.
+create table job_failure(
+    failure_id uuid primary key default uuid_generate_v4(),
+    queue_name text not null,
+    job_id uuid,
+    module_id uuid,
+    workspace_id uuid,
+    error text not null,
+    attempts integer not null,
+    payload text not null,
+    -- Set once the message has been published back to its queue.
+    redriven_at timestamptz,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index job_failure_workspace_idx on job_failure(workspace_id, created_at);
+create index job_failure_job_idx on job_failure(job_id);
+
+select trigger_updated_at('job_failure');
@@ -0,0 +1,18 @@
+-- Which lane a module job's messages are queued on; see `JobPriority`.
+alter table job_v2 add column priority integer not null default 0;
+
+-- A model call a worker is making for a workspace, so that no workspace takes up more than
+-- its share of the workers. Rows whose lease ran out belong to a worker that died, and are
+-- ignored and cleared.
+create table workspace_slot(
+    slot_id uuid primary key default uuid_generate_v4(),
+    workspace_id uuid not null,
+    job_id uuid,
+    leased_until timestamptz not null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index workspace_slot_workspace_idx on workspace_slot(workspace_id);
+
+select trigger_updated_at('workspace_slot');
@@ -0,0 +1,12 @@
+-- The messages of module jobs whose candidates were saved, by the id the message was published
+-- with, so that a message delivered again after a crash doesn't save its candidates twice.
+create table work_item(
+    work_item_id uuid primary key,
+    job_id uuid not null references job_v2(job_id) on delete cascade,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index work_item_job_idx on work_item(job_id);
+
+select trigger_updated_at('work_item');
@@ -0,0 +1,46 @@
+-- Modules wired into a DAG, where each stage works on the candidates of the stages before it.
+-- `config_data` holds the stages; see `http::v2::pipelines::StageConfig`.
+create table pipeline(
+    pipeline_id uuid primary key default uuid_generate_v4(),
+    pipeline_name text not null,
+    workspace_id uuid not null references workspace_v2(workspace_id),
+    config_data jsonb not null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index pipeline_workspace_idx on pipeline(workspace_id);
+
+select trigger_updated_at('pipeline');
+
+-- One run of a pipeline, with the stages as they were when it started.
+create table pipeline_run(
+    pipeline_run_id uuid primary key default uuid_generate_v4(),
+    pipeline_id uuid not null references pipeline(pipeline_id) on delete cascade,
+    workspace_id uuid not null references workspace_v2(workspace_id),
+    user_id uuid not null references "user"(user_id),
+    config_data jsonb not null,
+    run_status integer not null default 0,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index pipeline_run_pipeline_idx on pipeline_run(pipeline_id, created_at);
+create index pipeline_run_status_idx on pipeline_run(run_status);
+
+select trigger_updated_at('pipeline_run');
+
+-- Where each stage of a run stands, and the module job that does its work once it started.
+create table pipeline_stage(
+    pipeline_run_id uuid not null references pipeline_run(pipeline_run_id) on delete cascade,
+    stage_key text not null,
+    module_id uuid not null references module_v2(module_id),
+    job_id uuid references job_v2(job_id) on delete set null,
+    stage_status integer not null default 0,
+    error text,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz,
+    primary key (pipeline_run_id, stage_key)
+);
+
+select trigger_updated_at('pipeline_stage');
@@ -0,0 +1,12 @@
+-- Answers to module preprocess steps, by a hash of everything that went into the call, so that
+-- trying or running a module again doesn't pay for the same step twice.
+create table preprocess_cache(
+    cache_key text primary key,
+    output text not null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index preprocess_cache_created_idx on preprocess_cache(created_at);
+
+select trigger_updated_at('preprocess_cache');
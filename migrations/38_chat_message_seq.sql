@@ -0,0 +1,6 @@
+-- Messages written in one transaction, like the history a session is seeded with, share
+-- `created_at`, so they're ordered by the order they were written in too.
+alter table chat_message_v2 add column seq bigserial;
+
+drop index chat_message_v2_session_idx;
+create index chat_message_v2_session_idx on chat_message_v2(session_id, created_at, seq);
@@ -0,0 +1,12 @@
+-- Kept up to date by the workers, so that job events don't count a job's candidates and
+-- failures all over again for every message answered.
+alter table job_v2 add column completed_count integer not null default 0;
+alter table job_v2 add column failed_count integer not null default 0;
+
+update job_v2 j set
+    completed_count = (
+        select count(distinct job_status_group_id) from candidate_v2 c where c.job_id = j.job_id
+    ),
+    failed_count = (
+        select count(*) from job_failure f where f.job_id = j.job_id and f.redriven_at is null
+    );
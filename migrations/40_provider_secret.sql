@@ -0,0 +1,53 @@
+-- Keys of module providers (Azure, Anthropic, compatible servers). Module configs only refer
+-- to them by `apiKeyId`, so that they are never sent back to workspace members.
+create table provider_secret_v2(
+    secret_id uuid primary key default uuid_generate_v4(),
+    workspace_id uuid not null references workspace_v2(workspace_id) on delete cascade,
+    api_key text not null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+create index provider_secret_v2_workspace_idx on provider_secret_v2(workspace_id);
+
+select trigger_updated_at('provider_secret_v2');
+
+-- Move the keys modules already hold out of their config, for the provider they chat with and
+-- the one their retrieval embeds with.
+do
+$$
+    declare
+        path text[];
+        module record;
+        secret uuid;
+    begin
+        for path in select p from (values ('{provider}'::text[]), ('{retrieval,provider}')) as paths(p)
+            loop
+                for module in
+                    select module_id, workspace_id, config_data #>> (path || 'apiKey'::text) as api_key
+                    from module_v2
+                    where config_data #>> (path || 'apiKey'::text) is not null
+                    loop
+                        insert into provider_secret_v2 (workspace_id, api_key)
+                        values (module.workspace_id, module.api_key)
+                        returning secret_id into secret;
+                        update module_v2
+                        set config_data = jsonb_set(
+                                config_data #- (path || 'apiKey'::text),
+                                path || 'apiKeyId'::text,
+                                to_jsonb(secret::text)
+                            )
+                        where module_id = module.module_id;
+                        -- Jobs keep a copy of the config they were started with.
+                        update job_v2
+                        set config_data = jsonb_set(
+                                config_data #- (path || 'apiKey'::text),
+                                path || 'apiKeyId'::text,
+                                to_jsonb(secret::text)
+                            )
+                        where module_id = module.module_id
+                          and config_data #>> (path || 'apiKey'::text) is not null;
+                    end loop;
+            end loop;
+    end
+$$;
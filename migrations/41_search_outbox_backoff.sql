@@ -0,0 +1,7 @@
+-- Entries are claimed by pushing `available_at` out for as long as the claim may take, rather
+-- than by holding row locks while the indexes are updated. Entries of a module whose index
+-- keeps failing back off further each time, so they don't hold up everyone else's.
+alter table search_outbox add column attempts integer not null default 0;
+alter table search_outbox add column available_at timestamptz not null default now();
+
+create index search_outbox_available_idx on search_outbox(available_at, outbox_id);
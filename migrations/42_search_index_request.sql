@@ -0,0 +1,11 @@
+-- Modules whose index chat found missing or outdated. The index sync task builds them, so that
+-- chat requests never do it themselves and several of them can't rebuild the same index at
+-- once. A row is claimed by pushing `available_at` out for as long as the build may take.
+create table search_index_request(
+    module_id uuid primary key references module_v2(module_id) on delete cascade,
+    attempts integer not null default 0,
+    available_at timestamptz not null default now(),
+    created_at timestamptz not null default now()
+);
+
+create index search_index_request_available_idx on search_index_request(available_at);
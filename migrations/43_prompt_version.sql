@@ -0,0 +1,66 @@
+-- Prompts used to know only `@key/`, `@prompt/`, `@ref/` and `^^`. Those that contain what
+-- templates now read as syntax, `@{`, `{%` or `\`, keep reading it as text: they are marked
+-- as syntax version 1. Prompts without a `promptVersion` are in the current syntax.
+create function pg_temp.is_v1_prompt(prompt text) returns boolean as
+$$
+select prompt ~ '@\{|\{%|\\'
+$$ language sql immutable;
+
+-- Whether any of the prompts of a JSON array, or of the `prompt` of its objects, is marked.
+create function pg_temp.has_v1_prompt(prompts jsonb) returns boolean as
+$$
+select case
+           when jsonb_typeof(prompts) = 'array' then exists (
+               select 1
+               from jsonb_array_elements(prompts) p
+               where pg_temp.is_v1_prompt(
+                   case jsonb_typeof(p) when 'string' then p #>> '{}' else p ->> 'prompt' end
+               )
+           )
+           else false
+           end
+$$ language sql immutable;
+
+-- A module's preprocess steps are in the syntax of its prompt.
+update module_v2
+set config_data = config_data || '{"promptVersion": 1}'
+where (pg_temp.is_v1_prompt(config_data ->> 'prompt')
+    or pg_temp.has_v1_prompt(config_data -> 'preprocess'))
+  and not config_data ? 'promptVersion';
+
+update generator_v2
+set config_data = config_data || '{"promptVersion": 1}'
+where pg_temp.is_v1_prompt(config_data ->> 'prompt')
+  and not config_data ? 'promptVersion';
+
+update evaluator_v2
+set config_data = config_data || '{"promptVersion": 1}'
+where pg_temp.is_v1_prompt(config_data ->> 'prompt')
+  and not config_data ? 'promptVersion';
+
+-- Modules, generators and evaluators made from a template take its version, and that of its
+-- evaluator.
+update template_v2
+set template_data = template_data || '{"promptVersion": 1}'
+where (pg_temp.is_v1_prompt(template_data ->> 'prompt')
+    or pg_temp.has_v1_prompt(template_data -> 'preprocess'))
+  and not template_data ? 'promptVersion';
+
+update template_v2
+set template_data = jsonb_set(template_data, '{evaluator,promptVersion}', '1')
+where jsonb_typeof(template_data -> 'evaluator') = 'object'
+  and pg_temp.is_v1_prompt(template_data #>> '{evaluator,prompt}')
+  and not template_data -> 'evaluator' ? 'promptVersion';
+
+-- The prompt chains of the first generators, and of the jobs started with them.
+update generator
+set prompt_chain = prompt_chain || '{"promptVersion": 1}'
+where jsonb_typeof(prompt_chain) = 'object'
+  and pg_temp.has_v1_prompt(prompt_chain -> 'prompts')
+  and not prompt_chain ? 'promptVersion';
+
+update job
+set prompt_chain = prompt_chain || '{"promptVersion": 1}'
+where jsonb_typeof(prompt_chain) = 'object'
+  and pg_temp.has_v1_prompt(prompt_chain -> 'prompts')
+  and not prompt_chain ? 'promptVersion';
@@ -0,0 +1,4 @@
+-- Which model served the tokens of a row, and whether they were its answer rather than the
+-- prompt, so spend can be priced from the model catalog. Rows from before are left unpriced.
+alter table metric_v2 add column model text;
+alter table metric_v2 add column is_output boolean;
@@ -1,5 +1,20 @@
+/// Which parts of the backend a process runs.
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    /// The HTTP API and the queue workers, in one process.
+    All,
+    /// Only the HTTP API. Jobs are published, but worked on elsewhere.
+    Api,
+    /// Only the queue workers.
+    Worker,
+}
+
 #[derive(clap::Parser)]
 pub struct Config {
+    /// Which parts of the backend to run, so the API and the workers can be scaled separately.
+    #[clap(long, env, value_enum, default_value = "all")]
+    pub mode: Mode,
+
     #[clap(long, env)]
     pub database_url: String,
 
@@ -19,9 +34,111 @@ pub struct Config {
     #[clap(long, env)]
     pub rabbitmq_url: String,
 
+    /// How many messages of the v2 generator and evaluate queues a worker holds at once.
+    #[clap(long, env, default_value = "1")]
+    pub queue_prefetch: u16,
+
+    /// How many messages of the module queue a worker holds at once.
+    ///
+    /// Every message is one model call, so this is roughly how many calls a worker makes at
+    /// the same time.
+    #[clap(long, env, default_value = "2")]
+    pub evo_queue_prefetch: u16,
+
+    /// How many messages of the interactive module queue a worker holds at once, on top of
+    /// `evo_queue_prefetch`.
+    #[clap(long, env, default_value = "2")]
+    pub evo_interactive_queue_prefetch: u16,
+
+    /// How many batch module calls a workspace may have in flight at once, across all workers.
+    /// 0 means no limit.
+    #[clap(long, env, default_value = "4")]
+    pub workspace_concurrency: i64,
+
+    /// Module runs of up to this many inputs go on the interactive queue, unless the run asks
+    /// for the batch queue. Larger runs always go on the batch queue.
+    #[clap(long, env, default_value = "50")]
+    pub interactive_max_inputs: i32,
+
+    /// How many channels the API spreads its publishes over, all on one connection.
+    #[clap(long, env, default_value = "4")]
+    pub rabbitmq_publish_channels: usize,
+
+    /// How long to wait on shutdown for open requests and messages in flight to finish.
+    ///
+    /// Requests still open after this are dropped, and messages are put back on their queue.
+    #[clap(long, env, default_value = "30")]
+    pub shutdown_timeout_secs: u64,
+
     #[clap(long, env)]
     pub unstructured_url: String,
 
     #[clap(long, env)]
     pub es_url: String,
+
+    /// Answer every model call from a mock provider instead of a real one.
+    ///
+    /// Either `echo`, which answers with the prompt itself, or a path to a JSON file with
+    /// scripted responses, injected latency and failures. See `openai::MockConfig`.
+    /// This is meant for tests and offline development; never set it in production.
+    #[clap(long, env)]
+    pub llm_mock: Option<String>,
+
+    /// Hosts a module's provider `apiBase` may point at even though they aren't public, e.g.
+    /// a self-hosted model server, comma separated. Any other host has to resolve to public
+    /// addresses only.
+    #[clap(long, env, value_delimiter = ',')]
+    pub provider_allowed_hosts: Vec<String>,
+
+    /// How long, in seconds, a key from the `openai` table is leased for per model call.
+    ///
+    /// A worker that dies while holding a key only blocks it until the lease runs out,
+    /// after which the lease sweeper hands it back.
+    #[clap(long, env, default_value = "300")]
+    pub openai_lease_secs: u64,
+
+    /// Users allowed to call the `/v2/admin` endpoints, comma separated.
+    #[clap(long, env, value_delimiter = ',')]
+    pub admin_user_ids: Vec<uuid::Uuid>,
+
+    /// How often, in seconds, changes to candidates and data are applied to the search indexes.
+    #[clap(long, env, default_value = "5")]
+    pub search_sync_secs: u64,
+
+    /// How often, in seconds, every search index is compared with the database and repaired.
+    #[clap(long, env, default_value = "3600")]
+    pub search_reconcile_secs: u64,
+
+    /// How often, in seconds, running pipelines are checked for stages to finish or start.
+    #[clap(long, env, default_value = "5")]
+    pub pipeline_tick_secs: u64,
+
+    /// How long, in seconds, the answer to a module preprocess step is reused for the same
+    /// prompt and parameters. 0 turns the cache off.
+    #[clap(long, env, default_value = "86400")]
+    pub preprocess_cache_secs: u64,
+
+    /// How long, in milliseconds, a failed queue message waits before its first retry.
+    ///
+    /// Every further retry waits twice as long as the one before, up to `queue_retry_max_ms`,
+    /// with up to half of it random. See `queue::RetryPolicy`.
+    #[clap(long, env, default_value = "1000")]
+    pub queue_retry_base_ms: u64,
+
+    /// The longest, in milliseconds, a failed queue message waits before a retry.
+    #[clap(long, env, default_value = "300000")]
+    pub queue_retry_max_ms: u64,
+
+    /// How many times a message on `claymore_v2_queue` is tried before it is dead-lettered.
+    #[clap(long, env, default_value = "4")]
+    pub v2_queue_max_attempts: i32,
+
+    /// How many times a message on `claymore_v2_evaluate_queue` is tried before it is
+    /// dead-lettered.
+    #[clap(long, env, default_value = "4")]
+    pub v2_evaluate_queue_max_attempts: i32,
+
+    /// How many times a message on `claymore_evo_queue` is tried before it is dead-lettered.
+    #[clap(long, env, default_value = "4")]
+    pub evo_queue_max_attempts: i32,
 }
@@ -0,0 +1,57 @@
+//! Ordering things that depend on each other, like pipeline stages and preprocess steps.
+
+use std::collections::VecDeque;
+
+/// Order the nodes `0..n` so that each comes after the nodes it depends on, where an edge
+/// `(from, to)` says that `to` depends on `from`.
+///
+/// Fails with the nodes that can't be ordered, because they're on a cycle or wait on one.
+pub fn toposort(
+    n: usize,
+    edges: impl IntoIterator<Item = (usize, usize)>,
+) -> Result<Vec<usize>, Vec<usize>> {
+    let mut waiting_on = vec![0; n];
+    let mut next = vec![Vec::new(); n];
+    for (from, to) in edges {
+        waiting_on[to] += 1;
+        next[from].push(to);
+    }
+
+    let mut ready = (0..n)
+        .filter(|&i| waiting_on[i] == 0)
+        .collect::<VecDeque<_>>();
+    let mut order = Vec::with_capacity(n);
+    while let Some(i) = ready.pop_front() {
+        order.push(i);
+        for &j in &next[i] {
+            waiting_on[j] -= 1;
+            if waiting_on[j] == 0 {
+                ready.push_back(j);
+            }
+        }
+    }
+    if order.len() < n {
+        return Err((0..n).filter(|&i| waiting_on[i] > 0).collect());
+    }
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_nodes_after_what_they_depend_on() {
+        assert_eq!(toposort(4, [(2, 0), (3, 2), (3, 1)]), Ok(vec![3, 2, 1, 0]));
+        assert_eq!(toposort(0, []), Ok(vec![]));
+    }
+
+    #[test]
+    fn cycles_fail_with_the_nodes_left_waiting() {
+        assert_eq!(
+            toposort(4, [(0, 1), (1, 2), (2, 1), (2, 3)]),
+            Err(vec![1, 2, 3])
+        );
+    }
+}
@@ -85,6 +85,10 @@ pub enum Error {
     #[error("an error occurred with the OpenAI API")]
     OpenAI(#[from] OpenAIError),
 
+    /// Return `500 Internal Server Error` on an error from any `LlmProvider`.
+    #[error("an error occurred with the language model provider")]
+    Llm(#[from] crate::openai::Error),
+
     #[error("an error occurred with std::io")]
     StdIO(#[from] std::io::Error),
 
@@ -127,6 +131,7 @@ impl Error {
             Self::Sqlx(_)
             | Self::Anyhow(_)
             | Self::OpenAI(_)
+            | Self::Llm(_)
             | Self::StdIO(_)
             | Self::Pdf(_)
             | Self::ElasticSearch(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -140,7 +145,7 @@ impl Error {
             Self::NotFound => "request path not found".to_string(),
             Self::UnprocessableEntity { .. } => "error in the request body".to_string(),
             Self::Sqlx(err) => err.to_string(),
-            Self::OpenAI(_) | Self::StdIO(_) | Self::Pdf(_) => {
+            Self::OpenAI(_) | Self::Llm(_) | Self::StdIO(_) | Self::Pdf(_) => {
                 "an internal server error occurred".to_string()
             }
             Self::Anyhow(err) => err.to_string(),
@@ -149,6 +154,31 @@ impl Error {
     }
 }
 
+/// A prompt that doesn't parse or render is a problem with what the user wrote.
+impl From<crate::template::Error> for Error {
+    fn from(e: crate::template::Error) -> Self {
+        Error::unprocessable_entity([("prompt", e.to_string())])
+    }
+}
+
+/// Steps that don't fit together are a problem with the module's config; calls they make can
+/// fail like any other.
+impl From<crate::preprocess::Error> for Error {
+    fn from(e: crate::preprocess::Error) -> Self {
+        match e {
+            crate::preprocess::Error::Llm(e) => Error::Llm(e),
+            crate::preprocess::Error::Sqlx(e) => Error::Sqlx(e),
+            e => Error::unprocessable_entity([("preprocess", e.to_string())]),
+        }
+    }
+}
+
+impl From<crate::openai::ParamsError> for Error {
+    fn from(e: crate::openai::ParamsError) -> Self {
+        Error::unprocessable_entity([("modelParams", e.to_string())])
+    }
+}
+
 /// Axum allows you to return `Result` from handler functions, but the error type
 /// also must be some sort of response type.
 ///
@@ -2,15 +2,12 @@ use crate::http::extractor::AuthUser;
 use crate::http::types::Timestamptz;
 use crate::http::ApiContext;
 use crate::http::{Error, Result, ResultExt};
-use async_openai::{
-    types::{ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, Role},
-    Client,
-};
+use crate::openai::{ChatRequest, ProviderConfig};
+use crate::template::{self, Context, Template};
 use axum::extract::{Query, State};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use log::info;
-use regex::Regex;
 use serde_json::json;
 use uuid::Uuid;
 
@@ -337,161 +334,32 @@ async fn handle_try_generator(
         }
     }
     let prompts = prompt_chain["prompts"].as_array().unwrap();
-    let client = Client::new();
+    let prompt_version = template::prompt_version_of(&prompt_chain);
     let mut response = String::new();
     let mut prompt_responses: Vec<String> = Vec::new();
-    for (current_idx, prompt) in prompts.iter().enumerate() {
+    for prompt in prompts.iter() {
         info!("prompt: {} model_name: {}", prompt, &model_name);
         let prompt = prompt.as_str().unwrap();
-        let mut prompt = prompt.replace("^^", &response);
-        let regex = Regex::new(r"@(ref|prompt)/([\w+/]+)").unwrap();
-        let mut patterns = Vec::new();
-        for cap in regex.captures_iter(&prompt) {
-            let mut pattern = Vec::new();
-            pattern.push(cap[1].to_string());
-            pattern.extend(cap[2].split('/').map(|s| s.to_string()));
-            patterns.push(pattern);
-        }
-        for pattern in patterns {
-            let pattern_type = &pattern[0];
-            if pattern_type == "ref" {
-                let name = &pattern[1];
-                let character = sqlx::query!(
-                    r#"select settings from character where character_name = $1 and project_id = $2"#,
-                    name,
-                    project_id
-                )
-                .fetch_one(&ctx.db)
-                .await
-                .unwrap()
-                .settings;
-                let character = character["kv"].as_array().unwrap();
-                let result;
-                if pattern.len() == 2 {
-                    // whole
-                    result = character
-                        .iter()
-                        .map(|x| {
-                            let key = x["key"].as_str().unwrap();
-                            let value_type = x["type"].as_str().unwrap();
-                            let value;
-                            if value_type == "array" {
-                                let values = x["value"].as_array().unwrap();
-                                value = values
-                                    .iter()
-                                    .map(|x| x.as_str().unwrap())
-                                    .collect::<Vec<&str>>()
-                                    .join("; ");
-                            } else {
-                                value = x["value"].as_str().unwrap().to_string();
-                            }
-                            format!("{}: {}", key, value)
-                        })
-                        .collect::<Vec<String>>()
-                        .join(", ");
-                } else if pattern.len() == 3 {
-                    if pattern[2] == "random" {
-                        // random from `name`
-                        let idx = rand::random::<usize>() % character.len();
-                        let x = &character[idx];
-                        let value_type = x["type"].as_str().unwrap();
-                        let value;
-                        if value_type == "array" {
-                            let values = x["value"].as_array().unwrap();
-                            value = values
-                                .iter()
-                                .map(|x| x.as_str().unwrap())
-                                .collect::<Vec<&str>>()
-                                .join("; ");
-                        } else {
-                            value = x["value"].as_str().unwrap().to_string();
-                        }
-                        result = format!("{}: {}", x["key"].as_str().unwrap(), value);
-                    } else {
-                        let keys = pattern[2].split('+').collect::<Vec<&str>>();
-                        let mut temp = Vec::new();
-                        for key in keys {
-                            let x = character
-                                .iter()
-                                .find(|x| x["key"].as_str().unwrap() == key)
-                                .unwrap();
-                            let value_type = x["type"].as_str().unwrap();
-                            let value;
-                            if value_type == "array" {
-                                let values = x["value"].as_array().unwrap();
-                                value = values
-                                    .iter()
-                                    .map(|x| x.as_str().unwrap())
-                                    .collect::<Vec<&str>>()
-                                    .join("; ");
-                            } else {
-                                value = x["value"].as_str().unwrap().to_string();
-                            }
-                            temp.push(format!("{}: {}", key, value));
-                        }
-                        result = temp.join(", ");
-                    }
-                } else if pattern.len() == 4 {
-                    let key = &pattern[2];
-                    if pattern[3] == "random" {
-                        let value_type = character
-                            .iter()
-                            .find(|x| x["key"].as_str().unwrap() == key)
-                            .unwrap()["type"]
-                            .as_str()
-                            .unwrap();
-                        if value_type != "array" {
-                            result = "".to_string();
-                        } else {
-                            // random from `name` with `key`
-                            let value = character
-                                .iter()
-                                .find(|x| x["key"].as_str().unwrap() == key)
-                                .unwrap()["value"]
-                                .as_array();
-                            if let Some(value) = value {
-                                let idx = rand::random::<usize>() % value.len();
-                                result = value[idx].as_str().unwrap().to_string();
-                            } else {
-                                result = "".to_string();
-                            }
-                        }
-                    } else {
-                        result = "".to_string();
-                    }
-                } else {
-                    result = "".to_string();
-                }
-                prompt = prompt.replacen(&format!("@{}", pattern.join("/")), &result, 1);
-            } else if pattern_type == "prompt" {
-                let prompt_idx = pattern[1].parse::<usize>().unwrap();
-                if prompt_idx > current_idx {
-                    continue;
-                }
-                let prompt_response = prompt_responses[prompt_idx - 1].clone();
-                prompt = prompt.replace(&format!("@prompt/{}", prompt_idx), &prompt_response);
-            }
-        }
-        let chat_request = CreateChatCompletionRequestArgs::default()
-            .max_tokens(word_count as u16)
-            .model(&model_name)
-            .temperature(temperature as f32)
-            .messages([ChatCompletionRequestUserMessageArgs::default()
-                .content(format!(r#"{}"#, prompt))
-                .build()?
-                .into()])
-            .build()?;
-        let gpt_response = client.chat().create(chat_request).await?;
-        let output = &gpt_response
-            .choices
-            .iter()
-            .filter(|x| x.message.role == Role::Assistant)
-            .next()
-            .unwrap()
-            .message
-            .content
-            .clone()
-            .unwrap_or("".to_string());
+        let template = Template::parse_chain_version(prompt, prompt_version)?;
+        let refs = template::character_refs(&ctx.db, project_id, &template).await?;
+        let prompt = template.render(
+            &Context::new()
+                .with_answers(&prompt_responses)
+                .with_refs(refs),
+        )?;
+        let output = &ctx
+            .llm
+            .chat(
+                &ProviderConfig::OpenAI,
+                ChatRequest {
+                    model: model_name.clone(),
+                    input: prompt,
+                    max_tokens: Some(word_count as u16),
+                    temperature: Some(temperature as f32),
+                    ..Default::default()
+                },
+            )
+            .await?;
         prompt_responses.push(output.to_string());
         response = output.to_string();
     }
@@ -251,9 +251,8 @@ async fn handle_new_job(
     .fetch_one(&ctx.db)
     .await?;
 
-    let channel = queue::make_channel(&ctx.config.rabbitmq_url).await;
-    let _result = queue::publish_message(
-        &channel,
+    queue::publish_message(
+        &ctx.publisher,
         json!({
             "job_id": job.job_id,
             "model_name": job.model_name,
@@ -262,7 +261,7 @@ async fn handle_new_job(
             "word_count": job.word_count,
         }),
     )
-    .await;
+    .await?;
 
     Ok(Json(CommonResponse {
         code: 200,
@@ -429,7 +428,7 @@ async fn handle_operate_job(
             .await?;
 
             queue::publish_message(
-                &queue::make_channel(&ctx.config.rabbitmq_url).await,
+                &ctx.publisher,
                 json!({
                     "job_id": req.job.job_id,
                     "model_name": model_name,
@@ -438,7 +437,7 @@ async fn handle_operate_job(
                     "word_count": word_count,
                 }),
             )
-            .await;
+            .await?;
         }
     } else if req.job.job_operation == JobOperation::Pause {
         if current_status == JobStatus::Finished || current_status == JobStatus::Paused {
@@ -1,12 +1,16 @@
 use crate::config::Config;
+use crate::openai::Llm;
+use crate::queue::{JobEvents, Publisher};
 use anyhow::Context;
 use axum::Router;
 use serde_json::Value;
 use sqlx::PgPool;
 use std::{
+    future::Future,
     marker::PhantomData,
     net::{Ipv4Addr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 
 mod error;
@@ -48,6 +52,9 @@ use tower_http::trace::TraceLayer;
 pub(crate) struct ApiContext {
     config: Arc<Config>,
     db: PgPool,
+    llm: Llm,
+    publisher: Publisher,
+    job_events: JobEvents,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -81,18 +88,38 @@ pub(crate) struct CommonResponse {
     data: Value,
 }
 
-pub async fn serve(config: Config, db: PgPool) -> anyhow::Result<()> {
+/// Serve the API until `shutdown` completes, then stop accepting connections and wait for the
+/// open ones to finish.
+pub async fn serve(
+    config: Config,
+    db: PgPool,
+    llm: Llm,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    // Every handler publishes jobs through this one connection, rather than opening its own.
+    let publisher = Publisher::new(&config.rabbitmq_url, config.rabbitmq_publish_channels);
+    let job_events = JobEvents::listen(&db).await?;
     let api_context = ApiContext {
         config: Arc::new(config),
         db,
+        llm,
+        publisher,
+        job_events,
     };
 
+    // Pipelines move on from stage to stage in the background, as their jobs finish.
+    v2::spawn_pipeline_driver(
+        api_context.clone(),
+        Duration::from_secs(api_context.config.pipeline_tick_secs),
+    );
+
     let app = api_router(api_context);
 
     let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080));
     log::info!("Listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown)
         .await
         .context("error running HTTP server")
 }
@@ -0,0 +1,379 @@
+use crate::http::extractor::AuthUser;
+use crate::http::types::Timestamptz;
+use crate::http::ApiContext;
+use crate::http::{Error, Result};
+use crate::openai::MODELS;
+use axum::extract::State;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::http::CommonResponse;
+
+pub(crate) fn router() -> Router<ApiContext> {
+    Router::new()
+        .route(
+            "/v2/admin/openai/keys",
+            get(handle_list_openai_keys).post(handle_create_openai_key),
+        )
+        .route(
+            "/v2/admin/openai/keys/update",
+            post(handle_update_openai_key),
+        )
+        .route("/v2/admin/llm/mock-usage", get(handle_get_mock_usage))
+        .route("/v2/admin/llm/spend", get(handle_get_workspace_spend))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct KeyBody<T> {
+    key: T,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NewKeyRequest {
+    openai_key: String,
+    #[serde(default)]
+    is_plus: bool,
+    /// Requests per minute the key may serve; no limit when missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpm_limit: Option<i32>,
+    /// Tokens per minute the key may serve; no limit when missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tpm_limit: Option<i32>,
+}
+
+/// The limits replace the key's current ones, so a missing limit lifts it. `isPlus` is left
+/// alone when missing.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UpdateKeyRequest {
+    openai_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_plus: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpm_limit: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tpm_limit: Option<i32>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct OpenAIKeyFromSql {
+    openai_id: Uuid,
+    /// Only the last few characters, so the key can be told apart without leaking it.
+    key_hint: String,
+    is_plus: bool,
+    rpm_limit: Option<i32>,
+    tpm_limit: Option<i32>,
+    created_at: Timestamptz,
+    updated_at: Option<Timestamptz>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct OpenAIKeyHealthFromSql {
+    openai_id: Uuid,
+    /// Only the last few characters, so the key can be told apart without leaking it.
+    key_hint: String,
+    is_plus: bool,
+    /// `free`, `leased`, `expired` (leased, but the holder never gave it back) or `coolingDown`.
+    lease_state: String,
+    leased_until: Option<Timestamptz>,
+    cooldown_until: Option<Timestamptz>,
+    rpm_limit: Option<i32>,
+    tpm_limit: Option<i32>,
+    window_requests: i32,
+    window_tokens: i32,
+    token_count: i64,
+    /// Tokens recorded in `metric_v2` for module runs this key served.
+    attributed_token_count: i64,
+    /// What those tokens cost in US dollars, at the catalog's prices.
+    spend: f64,
+    /// Tokens among them that have no price: of models not in the catalog, or recorded before
+    /// the model was.
+    unpriced_token_count: i64,
+    request_count: i32,
+    error_count: i32,
+    consecutive_error_count: i32,
+    last_error: Option<String>,
+    last_error_at: Option<Timestamptz>,
+    last_used_at: Option<Timestamptz>,
+    created_at: Timestamptz,
+}
+
+async fn handle_list_openai_keys(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+) -> Result<Json<CommonResponse>> {
+    if !ctx.config.admin_user_ids.contains(&auth_user.user_id) {
+        return Err(Error::Forbidden);
+    }
+
+    let (names, input_prices, output_prices) = price_table();
+    let keys = sqlx::query_as!(
+        OpenAIKeyHealthFromSql,
+        // language=PostgreSQL
+        r#"select
+            openai.openai_id,
+            '...' || right(openai_key, 4) "key_hint!",
+            is_plus,
+            case
+                when cooldown_until > now() then 'coolingDown'
+                when openai_status = 0 then 'free'
+                when leased_until < now() then 'expired'
+                else 'leased'
+            end "lease_state!",
+            leased_until "leased_until: Timestamptz",
+            cooldown_until "cooldown_until: Timestamptz",
+            rpm_limit,
+            tpm_limit,
+            window_requests,
+            window_tokens,
+            openai.token_count,
+            coalesce(metric.token_count, 0) "attributed_token_count!",
+            coalesce(metric.spend, 0) "spend!",
+            coalesce(metric.unpriced_token_count, 0) "unpriced_token_count!",
+            request_count,
+            error_count,
+            consecutive_error_count,
+            last_error,
+            last_error_at "last_error_at: Timestamptz",
+            last_used_at "last_used_at: Timestamptz",
+            openai.created_at "created_at: Timestamptz"
+        from openai
+        left join (
+            select
+                m.openai_id,
+                sum(m.token_count)::bigint token_count,
+                sum(m.token_count * case when m.is_output then p.output_price else p.input_price end
+                    / 1000)::float8 spend,
+                sum(m.token_count) filter (where p.model is null or m.is_output is null)::bigint
+                    unpriced_token_count
+            from metric_v2 m
+            left join unnest($1::text[], $2::float8[], $3::float8[]) p(model, input_price, output_price)
+                on p.model = m.model
+            where m.openai_id is not null
+            group by m.openai_id
+        ) metric on metric.openai_id = openai.openai_id
+        order by consecutive_error_count desc, openai.created_at"#,
+        &names,
+        &input_prices,
+        &output_prices
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "keys": keys,
+        }),
+    }))
+}
+
+/// The catalog's prices, as arrays for `unnest($1::text[], $2::float8[], $3::float8[])`, so
+/// queries can price `metric_v2` rows by their model.
+fn price_table() -> (Vec<String>, Vec<f64>, Vec<f64>) {
+    let names = MODELS.iter().map(|model| model.name.to_string()).collect();
+    let input_prices = MODELS.iter().map(|model| model.input_price).collect();
+    let output_prices = MODELS.iter().map(|model| model.output_price).collect();
+    (names, input_prices, output_prices)
+}
+
+/// A limit of zero or less would keep the key from ever being leased; leave it out instead.
+fn check_limits(rpm_limit: Option<i32>, tpm_limit: Option<i32>) -> Result<()> {
+    if rpm_limit.is_some_and(|limit| limit < 1) {
+        return Err(Error::unprocessable_entity([(
+            "rpmLimit",
+            "must be at least 1",
+        )]));
+    }
+    if tpm_limit.is_some_and(|limit| limit < 1) {
+        return Err(Error::unprocessable_entity([(
+            "tpmLimit",
+            "must be at least 1",
+        )]));
+    }
+
+    Ok(())
+}
+
+/// Add a key to the pool, with the limits its OpenAI account has.
+async fn handle_create_openai_key(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<KeyBody<NewKeyRequest>>,
+) -> Result<Json<CommonResponse>> {
+    if !ctx.config.admin_user_ids.contains(&auth_user.user_id) {
+        return Err(Error::Forbidden);
+    }
+    let NewKeyRequest {
+        openai_key,
+        is_plus,
+        rpm_limit,
+        tpm_limit,
+    } = req.key;
+    let openai_key = openai_key.trim();
+    if openai_key.is_empty() {
+        return Err(Error::unprocessable_entity([("openaiKey", "is required")]));
+    }
+    check_limits(rpm_limit, tpm_limit)?;
+
+    let key = sqlx::query_as!(
+        OpenAIKeyFromSql,
+        // language=PostgreSQL
+        r#"insert into openai (openai_key, is_plus, rpm_limit, tpm_limit)
+        values ($1, $2, $3, $4)
+        returning
+            openai_id,
+            '...' || right(openai_key, 4) "key_hint!",
+            is_plus,
+            rpm_limit,
+            tpm_limit,
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz""#,
+        openai_key,
+        is_plus,
+        rpm_limit,
+        tpm_limit
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({ "key": key }),
+    }))
+}
+
+/// Change the limits of a key in the pool, e.g. after its OpenAI account moved up a tier.
+///
+/// A key that's leased keeps its lease; the new limits apply from its next lease on.
+async fn handle_update_openai_key(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<KeyBody<UpdateKeyRequest>>,
+) -> Result<Json<CommonResponse>> {
+    if !ctx.config.admin_user_ids.contains(&auth_user.user_id) {
+        return Err(Error::Forbidden);
+    }
+    let UpdateKeyRequest {
+        openai_id,
+        is_plus,
+        rpm_limit,
+        tpm_limit,
+    } = req.key;
+    check_limits(rpm_limit, tpm_limit)?;
+
+    let key = sqlx::query_as!(
+        OpenAIKeyFromSql,
+        // language=PostgreSQL
+        r#"update openai
+        set is_plus = coalesce($2, is_plus), rpm_limit = $3, tpm_limit = $4
+        where openai_id = $1
+        returning
+            openai_id,
+            '...' || right(openai_key, 4) "key_hint!",
+            is_plus,
+            rpm_limit,
+            tpm_limit,
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz""#,
+        openai_id,
+        is_plus,
+        rpm_limit,
+        tpm_limit
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({ "key": key }),
+    }))
+}
+
+/// The calls, tokens and spend the `--llm-mock` provider served, per model and in total, for
+/// checking what a test run would have cost. Not found unless the process runs with the mock.
+async fn handle_get_mock_usage(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+) -> Result<Json<CommonResponse>> {
+    if !ctx.config.admin_user_ids.contains(&auth_user.user_id) {
+        return Err(Error::Forbidden);
+    }
+    let usage = ctx.llm.mock_usage().ok_or(Error::NotFound)?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({ "usage": usage }),
+    }))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct WorkspaceSpendFromSql {
+    workspace_id: Uuid,
+    workspace_name: String,
+    token_count: i64,
+    /// What the tokens cost in US dollars, at the catalog's prices.
+    spend: f64,
+    /// Tokens that have no price: of models not in the catalog, or recorded before the model
+    /// was.
+    unpriced_token_count: i64,
+}
+
+/// What every workspace's module calls cost so far, most expensive first.
+async fn handle_get_workspace_spend(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+) -> Result<Json<CommonResponse>> {
+    if !ctx.config.admin_user_ids.contains(&auth_user.user_id) {
+        return Err(Error::Forbidden);
+    }
+
+    let (names, input_prices, output_prices) = price_table();
+    let workspaces = sqlx::query_as!(
+        WorkspaceSpendFromSql,
+        // language=PostgreSQL
+        r#"select
+            w.workspace_id,
+            w.workspace_name,
+            sum(m.token_count)::bigint "token_count!",
+            coalesce(
+                sum(m.token_count * case when m.is_output then p.output_price else p.input_price end
+                    / 1000),
+                0
+            )::float8 "spend!",
+            coalesce(
+                sum(m.token_count) filter (where p.model is null or m.is_output is null),
+                0
+            )::bigint "unpriced_token_count!"
+        from workspace_v2 w
+        join metric_v2 m on m.workspace_id = w.workspace_id
+        left join unnest($1::text[], $2::float8[], $3::float8[]) p(model, input_price, output_price)
+            on p.model = m.model
+        group by w.workspace_id, w.workspace_name
+        order by 4 desc, w.workspace_name"#,
+        &names,
+        &input_prices,
+        &output_prices
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "workspaces": workspaces,
+        }),
+    }))
+}
@@ -0,0 +1,382 @@
+use crate::http::extractor::AuthUser;
+use crate::http::types::Timestamptz;
+use crate::http::ApiContext;
+use crate::http::{Error, Result};
+use axum::extract::{Query, State};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::json;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::http::CommonResponse;
+
+pub(crate) fn router() -> Router<ApiContext> {
+    Router::new()
+        .route(
+            "/v2/chat/session",
+            post(handle_new_session).get(handle_session_info),
+        )
+        .route("/v2/chat/session/list", get(handle_list_session))
+        .route("/v2/chat/session/delete", post(handle_delete_session))
+        .route("/v2/chat/session/fork", post(handle_fork_session))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct SessionBody<T> {
+    session: T,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NewSessionRequest {
+    module_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SessionInfoRequest {
+    session_id: Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SessionListRequest {
+    module_id: Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SessionForkRequest {
+    session_id: Uuid,
+    /// Fork right after this message; the whole session is copied when missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<Uuid>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct SessionFromSql {
+    pub(super) session_id: Uuid,
+    pub(super) module_id: Uuid,
+    pub(super) user_id: Uuid,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forked_from: Option<Uuid>,
+    created_at: Timestamptz,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<Timestamptz>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct MessageFromSql {
+    message_id: Uuid,
+    pub(super) user_input: String,
+    pub(super) ai_output: String,
+    /// The passages retrieved for the question; see `chats::Source`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sources: Option<serde_json::Value>,
+    created_at: Timestamptz,
+}
+
+async fn handle_new_session(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<SessionBody<NewSessionRequest>>,
+) -> Result<Json<CommonResponse>> {
+    let module_id = req.session.module_id;
+    let workspace_id = sqlx::query!(
+        r#"select workspace_id from module_v2 where module_id = $1"#,
+        module_id
+    )
+    .fetch_one(&ctx.db)
+    .await?
+    .workspace_id;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    let title = req.session.title.unwrap_or_default();
+    let session = create_session(&ctx.db, module_id, auth_user.user_id, &title).await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "session": session,
+        }),
+    }))
+}
+
+async fn handle_session_info(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Query(req): Query<SessionInfoRequest>,
+) -> Result<Json<CommonResponse>> {
+    let session = load_session(&ctx.db, req.session_id, auth_user.user_id).await?;
+    let messages = load_messages(&ctx.db, session.session_id).await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "session": session,
+            "messages": messages,
+        }),
+    }))
+}
+
+async fn handle_list_session(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Query(req): Query<SessionListRequest>,
+) -> Result<Json<CommonResponse>> {
+    check_module_member(&ctx.db, req.module_id, auth_user.user_id).await?;
+
+    let sessions = sqlx::query_as!(
+        SessionFromSql,
+        // language=PostgreSQL
+        r#"select
+            session_id,
+            module_id,
+            user_id,
+            title,
+            forked_from,
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz"
+        from chat_session_v2
+        where module_id = $1 and user_id = $2
+        order by coalesce(updated_at, created_at) desc"#,
+        req.module_id,
+        auth_user.user_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "sessions": sessions,
+        }),
+    }))
+}
+
+async fn handle_delete_session(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<SessionBody<SessionInfoRequest>>,
+) -> Result<Json<CommonResponse>> {
+    let session = load_session(&ctx.db, req.session.session_id, auth_user.user_id).await?;
+    sqlx::query!(
+        r#"delete from chat_session_v2 where session_id = $1"#,
+        session.session_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({}),
+    }))
+}
+
+async fn handle_fork_session(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<SessionBody<SessionForkRequest>>,
+) -> Result<Json<CommonResponse>> {
+    let source = load_session(&ctx.db, req.session.session_id, auth_user.user_id).await?;
+
+    let mut tx = ctx.db.begin().await?;
+    let session = sqlx::query_as!(
+        SessionFromSql,
+        // language=PostgreSQL
+        r#"insert into chat_session_v2 (module_id, user_id, title, forked_from)
+        values ($1, $2, $3, $4)
+        returning
+            session_id,
+            module_id,
+            user_id,
+            title,
+            forked_from,
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz""#,
+        source.module_id,
+        auth_user.user_id,
+        source.title,
+        source.session_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // Copy the messages up to and including `message_id`, keeping their timestamps and their
+    // order so the fork reads the same as the original up to the fork point.
+    let copied = sqlx::query!(
+        r#"insert into chat_message_v2 (session_id, user_input, ai_output, sources, created_at)
+        select $1, user_input, ai_output, sources, created_at
+        from chat_message_v2
+        where session_id = $2
+            and ($3::uuid is null or (created_at, seq) <= (
+                select created_at, seq from chat_message_v2 where message_id = $3 and session_id = $2
+            ))
+        order by created_at, seq"#,
+        session.session_id,
+        source.session_id,
+        req.session.message_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if req.session.message_id.is_some() && copied.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+    tx.commit().await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "session": session,
+        }),
+    }))
+}
+
+pub(super) async fn create_session(
+    db: impl PgExecutor<'_>,
+    module_id: Uuid,
+    user_id: Uuid,
+    title: &str,
+) -> Result<SessionFromSql> {
+    let session = sqlx::query_as!(
+        SessionFromSql,
+        // language=PostgreSQL
+        r#"insert into chat_session_v2 (module_id, user_id, title)
+        values ($1, $2, $3)
+        returning
+            session_id,
+            module_id,
+            user_id,
+            title,
+            forked_from,
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz""#,
+        module_id,
+        user_id,
+        title
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(session)
+}
+
+/// Load a session, making sure it belongs to `user_id` and that they are still a member of
+/// the workspace of its module.
+pub(super) async fn load_session(
+    db: &PgPool,
+    session_id: Uuid,
+    user_id: Uuid,
+) -> Result<SessionFromSql> {
+    let session = sqlx::query_as!(
+        SessionFromSql,
+        // language=PostgreSQL
+        r#"select
+            session_id,
+            module_id,
+            user_id,
+            title,
+            forked_from,
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz"
+        from chat_session_v2
+        where session_id = $1"#,
+        session_id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or(Error::NotFound)?;
+    if session.user_id != user_id {
+        return Err(Error::Forbidden);
+    }
+    check_module_member(db, session.module_id, user_id).await?;
+
+    Ok(session)
+}
+
+/// Make sure `user_id` is a member of the workspace the module belongs to. Sessions outlive
+/// memberships, and whoever leaves a workspace loses its modules' chats with it.
+async fn check_module_member(db: &PgPool, module_id: Uuid, user_id: Uuid) -> Result<()> {
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2
+        where user_id = $2
+          and workspace_id = (select workspace_id from module_v2 where module_id = $1)"#,
+        module_id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    Ok(())
+}
+
+/// Every message of a session, oldest first.
+pub(super) async fn load_messages(db: &PgPool, session_id: Uuid) -> Result<Vec<MessageFromSql>> {
+    let messages = sqlx::query_as!(
+        MessageFromSql,
+        // language=PostgreSQL
+        r#"select
+            message_id,
+            user_input,
+            ai_output,
+            sources,
+            created_at "created_at: Timestamptz"
+        from chat_message_v2
+        where session_id = $1
+        order by created_at, seq"#,
+        session_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(messages)
+}
+
+pub(super) async fn append_message(
+    db: &mut PgConnection,
+    session_id: Uuid,
+    user_input: &str,
+    ai_output: &str,
+    sources: Option<&serde_json::Value>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"insert into chat_message_v2 (session_id, user_input, ai_output, sources) values ($1, $2, $3, $4)"#,
+        session_id,
+        user_input,
+        ai_output,
+        sources
+    )
+    .execute(&mut *db)
+    .await?;
+    // Bump the session so the most recently used one is listed first.
+    sqlx::query!(
+        r#"update chat_session_v2 set updated_at = now() where session_id = $1"#,
+        session_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
@@ -1,30 +1,45 @@
 use crate::http::extractor::AuthUser;
+use crate::http::v2::modules::CallMetric;
+use crate::http::v2::{chat_sessions, modules, sse};
 use crate::http::ApiContext;
 use crate::http::{Error, Result};
-use async_openai::types::ChatCompletionRequestMessage;
-use async_openai::{
-    types::{
-        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestUserMessageArgs,
-        CreateChatCompletionRequestArgs, Role,
-    },
-    Client,
-};
+use crate::openai;
+use crate::openai::{History, ModelParams, ProviderConfig};
+use crate::retrieval;
+use crate::retrieval::{DocumentKind, Hit, RetrievalConfig, Retriever};
 use axum::extract::State;
+use axum::response::IntoResponse;
 use axum::routing::post;
 use axum::{Json, Router};
-use elasticsearch::http::transport::Transport;
+use futures::StreamExt;
+use regex::Regex;
 use serde_json::json;
+use sqlx::PgPool;
 use uuid::Uuid;
 
 use crate::http::CommonResponse;
 
+/// The model chats call when the module's `modelParams` don't name one.
+const CHAT_MODEL: &str = "gpt-4-1106-preview";
+
+/// How many tokens of earlier turns are sent along with a question.
+///
+/// gpt-4-1106-preview takes far more, but the references and the answer need room too, and
+/// long histories mostly cost money without helping the answer.
+const HISTORY_TOKEN_BUDGET: usize = 4096;
+
+/// New sessions are titled with the start of their first question.
+const SESSION_TITLE_LENGTH: usize = 50;
+
 #[derive(serde::Serialize, serde::Deserialize)]
 struct ChatBody<T> {
     chat: T,
 }
 
 pub(crate) fn router() -> Router<ApiContext> {
-    Router::new().route("/v2/chat", post(handle_chat))
+    Router::new()
+        .route("/v2/chat", post(handle_chat))
+        .route("/v2/chat/stream", post(handle_chat_stream))
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -32,6 +47,11 @@ pub(crate) fn router() -> Router<ApiContext> {
 struct ChatRequest {
     module_id: Uuid,
     user_input: String,
+    /// Continue this session; its stored messages are used as the history.
+    ///
+    /// Without it a new session is started, seeded with `chat_history` if that is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<Uuid>,
     #[serde(skip_serializing_if = "Option::is_none")]
     chat_history: Option<Vec<ChatHistory>>,
 }
@@ -48,158 +68,343 @@ async fn handle_chat(
     ctx: State<ApiContext>,
     Json(req): Json<ChatBody<ChatRequest>>,
 ) -> Result<Json<CommonResponse>> {
-    let module_id = req.chat.module_id;
-    let workspace_id = sqlx::query!(
+    let chat = prepare_chat(&ctx, auth_user.user_id, req.chat).await?;
+    let output = ctx
+        .llm
+        .chat_with_usage(&chat.provider, chat.request.clone())
+        .await?;
+    let call = CallMetric {
+        workspace_id: chat.workspace_id,
+        module_id: chat.module_id,
+        user_id: auth_user.user_id,
+        openai_id: output.openai_id,
+        model: &chat.request.model,
+    };
+    modules::record_call(&ctx.db, &call, &chat.request.input, &output.output).await?;
+
+    let data = finish_chat(&ctx.db, chat, auth_user.user_id, output.output).await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data,
+    }))
+}
+
+/// `handle_chat`, but with the answer streamed as server-sent events; see `sse::relay`.
+///
+/// The final `done` event carries the same `{"sessionId": ..., "history": ..., "sources": ...}`
+/// the non-streaming endpoint returns.
+async fn handle_chat_stream(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<ChatBody<ChatRequest>>,
+) -> Result<impl IntoResponse> {
+    let chat = prepare_chat(&ctx, auth_user.user_id, req.chat).await?;
+    let stream = ctx
+        .llm
+        .chat_stream(&chat.provider, chat.request.clone())
+        .await?;
+    let mut metrics = StreamMetrics {
+        db: ctx.db.clone(),
+        workspace_id: chat.workspace_id,
+        module_id: chat.module_id,
+        user_id: auth_user.user_id,
+        openai_id: stream.openai_id,
+        model: chat.request.model.clone(),
+        prompt: chat.request.input.clone(),
+        output: String::new(),
+    };
+    let deltas = stream
+        .deltas
+        .map(move |delta| {
+            if let Ok(delta) = &delta {
+                metrics.output.push_str(delta);
+            }
+            delta
+        })
+        .boxed();
+    let db = ctx.db.clone();
+
+    Ok(sse::relay(deltas, move |output| async move {
+        finish_chat(&db, chat, auth_user.user_id, output).await
+    }))
+}
+
+/// Records the metrics of a streamed turn once its answer is dropped, whether it was read to
+/// the end or the client went away halfway, since what was streamed was paid for either way.
+struct StreamMetrics {
+    db: PgPool,
+    workspace_id: Uuid,
+    module_id: Uuid,
+    user_id: Uuid,
+    openai_id: Option<Uuid>,
+    model: String,
+    prompt: String,
+    output: String,
+}
+
+impl Drop for StreamMetrics {
+    fn drop(&mut self) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let db = self.db.clone();
+        let (workspace_id, module_id, user_id, openai_id) = (
+            self.workspace_id,
+            self.module_id,
+            self.user_id,
+            self.openai_id,
+        );
+        let model = std::mem::take(&mut self.model);
+        let prompt = std::mem::take(&mut self.prompt);
+        let output = std::mem::take(&mut self.output);
+        handle.spawn(async move {
+            let call = CallMetric {
+                workspace_id,
+                module_id,
+                user_id,
+                openai_id,
+                model: &model,
+            };
+            if let Err(e) = modules::record_call(&db, &call, &prompt, &output).await {
+                log::error!("failed to record metrics of a streamed chat: {:?}", e);
+            }
+        });
+    }
+}
+
+/// A chat turn, ready to be sent to the model.
+struct PreparedChat {
+    workspace_id: Uuid,
+    module_id: Uuid,
+    provider: ProviderConfig,
+    request: openai::ChatRequest,
+    session: ChatSession,
+    user_input: String,
+    chat_history: Vec<ChatHistory>,
+    /// The passages in the prompt, in the order they are numbered there.
+    hits: Vec<Hit>,
+}
+
+/// The session a chat turn goes to.
+enum ChatSession {
+    Existing(Uuid),
+    /// Created along with the turn's message, seeded with the turn's history, so a turn that
+    /// fails leaves no empty session behind.
+    New {
+        title: String,
+    },
+}
+
+/// A passage retrieved for a question, as returned to the client and stored with the message.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Source {
+    /// The number the passage was given in the prompt, and cited by in the answer.
+    index: usize,
+    /// The `candidate_id` or `data_id` the passage was made from.
+    id: Uuid,
+    kind: DocumentKind,
+    content: String,
+    score: f64,
+    cited: bool,
+}
+
+/// Check access to the module, look up references for the question and build the prompt.
+async fn prepare_chat(ctx: &ApiContext, user_id: Uuid, req: ChatRequest) -> Result<PreparedChat> {
+    let module_id = req.module_id;
+    let module = sqlx::query!(
         // language=PostgreSQL
-        r#"select workspace_id from module_v2 where module_id = $1"#,
+        r#"select workspace_id, config_data from module_v2 where module_id = $1"#,
         module_id
     )
     .fetch_one(&ctx.db)
-    .await?
-    .workspace_id;
+    .await?;
+    let workspace_id = module.workspace_id;
+    let provider = ProviderConfig::from_module_config(&module.config_data)?;
+    let params = ModelParams::from_module_config(&module.config_data, &provider, CHAT_MODEL)?;
 
     let _member_record = sqlx::query!(
         // language=PostgreSQL
         r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
         workspace_id,
-        auth_user.user_id
+        user_id
     )
     .fetch_optional(&ctx.db)
     .await?
     .ok_or_else(|| Error::Forbidden)?;
 
-    let user_input = req.chat.user_input;
-    let chat_history = req.chat.chat_history;
-    let new_history = chat_history.clone();
-    let datadrops = sqlx::query!(
-        // language=PostgreSQL
-        r#"select
-            candidate_id,
-            content
-        from candidate_v2
-        where module_id = $1"#,
-        module_id
-    )
-    .fetch_all(&ctx.db)
-    .await?;
-
-    let datadrops = datadrops
-        .iter()
-        .map(|d| d.content.as_str())
-        .collect::<Vec<&str>>();
-
-    let transport = Transport::single_node(&ctx.config.es_url).unwrap();
-    let es_client = elasticsearch::Elasticsearch::new(transport);
-    let response = es_client
-        .indices()
-        .exists(elasticsearch::indices::IndicesExistsParts::Index(&[
-            module_id.to_string().as_str(),
-        ]))
-        .send()
-        .await?;
-    if !response.status_code().is_success() {
-        let mut count = 1;
-        for datadrop in datadrops {
-            let _resp = es_client
-                .index(elasticsearch::IndexParts::IndexId(
-                    module_id.to_string().as_str(),
-                    count.to_string().as_str(),
-                ))
-                .body(json!({
-                    "content": datadrop,
-                }))
-                .send()
-                .await?;
-            count += 1;
-        }
-    }
-
-    let search_resp = es_client
-        .search(elasticsearch::SearchParts::Index(&[module_id
-            .to_string()
-            .as_str()]))
-        .size(5)
-        .body(json!({
-            "query": {
-                "match": {
-                    "content": &user_input,
-                }
+    let user_input = req.user_input;
+    let (session, chat_history) = match req.session_id {
+        Some(session_id) => {
+            let session = chat_sessions::load_session(&ctx.db, session_id, user_id).await?;
+            if session.module_id != module_id {
+                return Err(Error::unprocessable_entity([(
+                    "sessionId",
+                    "session belongs to another module",
+                )]));
             }
-        }))
-        .send()
-        .await?;
-    let search_resp = search_resp.json::<serde_json::Value>().await?;
-    log::info!("search_resp: {:?}", search_resp);
-    let refs = search_resp["hits"]["hits"]
-        .as_array()
-        .unwrap()
+            let history = chat_sessions::load_messages(&ctx.db, session_id)
+                .await?
+                .into_iter()
+                .map(|m| ChatHistory {
+                    user_input: m.user_input,
+                    ai_output: m.ai_output,
+                })
+                .collect();
+            (ChatSession::Existing(session_id), history)
+        }
+        None => {
+            let title = user_input
+                .chars()
+                .take(SESSION_TITLE_LENGTH)
+                .collect::<String>();
+            let history = req.chat_history.unwrap_or_default();
+            (ChatSession::New { title }, history)
+        }
+    };
+    let retrieval = RetrievalConfig::from_module_config(&module.config_data)?;
+    let retriever = Retriever::new(&ctx.config.es_url, ctx.llm.clone())?;
+    // Building an index can take a while, so it's left to the index sync task rather than
+    // holding up (and racing between) chat requests, which search the database meanwhile.
+    let hits = if retriever.index_ready(module_id, &retrieval).await? {
+        retriever.search(module_id, &retrieval, &user_input).await?
+    } else {
+        retrieval::request_index(&ctx.db, module_id).await?;
+        retrieval::search_unindexed(&ctx.db, module_id, &retrieval, &user_input).await?
+    };
+    log::info!("hits: {:?}", hits);
+    // Passages are numbered from 1 so the model can cite them as [1], [2], ...
+    let refs = hits
         .iter()
-        .map(|x| x["_source"]["content"].as_str().unwrap().to_string())
+        .enumerate()
+        .map(|(i, hit)| format!("[{}] {}", i + 1, hit.content))
         .collect::<Vec<String>>()
         .join("\n\n");
 
-    let mut messages;
-    if let Some(chat_history) = chat_history {
-        messages = chat_history
-            .iter()
-            .map(|h| {
-                vec![
-                    ChatCompletionRequestUserMessageArgs::default()
-                        .content(h.user_input.clone())
-                        .build()
-                        .unwrap()
-                        .into(),
-                    ChatCompletionRequestAssistantMessageArgs::default()
-                        .content(h.ai_output.clone())
-                        .build()
-                        .unwrap()
-                        .into(),
-                ]
-            })
-            .flatten()
-            .collect::<Vec<ChatCompletionRequestMessage>>();
-    } else {
-        messages = Vec::<ChatCompletionRequestMessage>::new()
+    let prompt = format!("你是一个 AI 聊天助手，你的目标是根据我提供的知识库 {} 回答我的问题。我会检验你对知识库中内容的掌握程度，是否正确地回答了我的问题。在回答时，你需要遵循以下规则：\n1. 你必须使用知识库中相关的文本，来回答我的问题，你的回答必须是完整，专业，严谨的。\n2. 如果在知识库中没有找到符合我提问的答案，请直接说不知道，不要编造虚假的内容，或者使用其他不相关的内容来回答。\n3. 知识库中的每一条都有编号，例如 [1]。在用到某一条的句子后面用方括号标注它的编号，例如 [1] 或 [2][3]。\n{}", &refs, &user_input);
+    let history = truncate_history(&chat_history, HISTORY_TOKEN_BUDGET);
+
+    log::info!("prompt: {:?}", prompt);
+
+    Ok(PreparedChat {
+        workspace_id,
+        module_id,
+        provider,
+        request: openai::ChatRequest {
+            history: Some(history),
+            ..params.request(prompt)
+        },
+        session,
+        user_input,
+        chat_history,
+        hits,
+    })
+}
+
+/// Append a finished turn to the session and the history, creating the session first if the
+/// turn starts one.
+async fn finish_chat(
+    db: &PgPool,
+    chat: PreparedChat,
+    user_id: Uuid,
+    output: String,
+) -> Result<serde_json::Value> {
+    let cited = cited_sources(&output, chat.hits.len());
+    let sources = chat
+        .hits
+        .into_iter()
+        .enumerate()
+        .map(|(i, hit)| Source {
+            index: i + 1,
+            id: hit.id,
+            kind: hit.kind,
+            content: hit.content,
+            score: hit.score,
+            cited: cited.contains(&(i + 1)),
+        })
+        .collect::<Vec<Source>>();
+    let sources = json!(sources);
+
+    let mut tx = db.begin().await?;
+    let session_id = match chat.session {
+        ChatSession::Existing(session_id) => session_id,
+        ChatSession::New { title } => {
+            let session =
+                chat_sessions::create_session(&mut *tx, chat.module_id, user_id, &title).await?;
+            for h in &chat.chat_history {
+                chat_sessions::append_message(
+                    &mut tx,
+                    session.session_id,
+                    &h.user_input,
+                    &h.ai_output,
+                    None,
+                )
+                .await?;
+            }
+            session.session_id
+        }
     };
-    messages.push(
-        ChatCompletionRequestUserMessageArgs::default()
-            .content(format!("你是一个 AI 聊天助手，你的目标是根据我提供的知识库 {} 回答我的问题。我会检验你对知识库中内容的掌握程度，是否正确地回答了我的问题。在回答时，你需要遵循以下规则：\n1. 你必须使用知识库中相关的文本，来回答我的问题，你的回答必须是完整，专业，严谨的。\n2. 如果在知识库中没有找到符合我提问的答案，请直接说不知道，不要编造虚假的内容，或者使用其他不相关的内容来回答。\n{}", &refs, &user_input))
-            .build()
-            .unwrap()
-            .into(),
-    );
-
-    log::info!("messages: {:?}", messages);
-    let client = Client::new();
-    let chat_request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(2048u16)
-        .model("gpt-4-1106-preview")
-        .temperature(0.1)
-        .messages(messages)
-        .build()?;
-    let gpt_response = client.chat().create(chat_request).await?;
-    let output = &gpt_response
-        .choices
-        .iter()
-        .filter(|x| x.message.role == Role::Assistant)
-        .next()
-        .unwrap()
-        .message
-        .content
-        .clone()
-        .unwrap_or_default();
-
-    let mut history = new_history.unwrap_or_default();
+    chat_sessions::append_message(
+        &mut tx,
+        session_id,
+        &chat.user_input,
+        &output,
+        Some(&sources),
+    )
+    .await?;
+    tx.commit().await?;
+
+    let mut history = chat.chat_history;
     history.push(ChatHistory {
-        user_input: user_input.clone(),
-        ai_output: output.clone(),
+        user_input: chat.user_input,
+        ai_output: output,
     });
 
-    Ok(Json(CommonResponse {
-        code: 200,
-        message: "success".to_string(),
-        data: json!({
-            "history": history,
-        }),
+    Ok(json!({
+        "sessionId": session_id,
+        "history": history,
+        "sources": sources,
     }))
 }
+
+/// The numbers of the passages the answer cites with `[n]`, in the order they are first cited.
+///
+/// Numbers that don't refer to a passage are ignored, since the model sometimes uses square
+/// brackets for other things.
+fn cited_sources(output: &str, count: usize) -> Vec<usize> {
+    let regex = Regex::new(r"\[(\d+)\]").unwrap();
+    let mut cited = Vec::new();
+    for captures in regex.captures_iter(output) {
+        if let Ok(index) = captures[1].parse::<usize>() {
+            if (1..=count).contains(&index) && !cited.contains(&index) {
+                cited.push(index);
+            }
+        }
+    }
+
+    cited
+}
+
+/// The most recent turns of `chat_history` that fit in `budget` tokens, oldest first.
+///
+/// Whole turns are dropped from the front, so the model never sees half a question.
+fn truncate_history(chat_history: &[ChatHistory], budget: usize) -> Vec<History> {
+    let mut used = 0;
+    let mut history = chat_history
+        .iter()
+        .rev()
+        .take_while(|h| {
+            used += openai::count_tokens(&h.user_input) + openai::count_tokens(&h.ai_output);
+            used <= budget
+        })
+        .map(|h| History {
+            user_input: h.user_input.clone(),
+            ai_output: h.ai_output.clone(),
+        })
+        .collect::<Vec<History>>();
+    history.reverse();
+
+    history
+}
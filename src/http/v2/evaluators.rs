@@ -1,6 +1,7 @@
 use crate::http::extractor::AuthUser;
 use crate::http::ApiContext;
 use crate::http::{Error, Result};
+use crate::template;
 use axum::extract::State;
 use axum::routing::post;
 use axum::{Json, Router};
@@ -33,7 +34,8 @@ async fn handle_save_evaluator(
     let evaluator_id = req.evaluator.evaluator_id;
     let evaluator = sqlx::query!(
         r#"select
-            project_id
+            project_id,
+            config_data
         from evaluator_v2 where evaluator_id = $1"#,
         evaluator_id
     )
@@ -57,7 +59,8 @@ async fn handle_save_evaluator(
     .await?
     .ok_or_else(|| Error::Forbidden)?;
 
-    let evaluator_config = req.evaluator.data;
+    let mut evaluator_config = req.evaluator.data;
+    template::keep_prompt_version(&mut evaluator_config, &evaluator.config_data);
 
     let evaluator = sqlx::query!(
         // language=PostgreSQL
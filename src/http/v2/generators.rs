@@ -2,11 +2,10 @@ use crate::http::extractor::AuthUser;
 use crate::http::types::Timestamptz;
 use crate::http::ApiContext;
 use crate::http::{Error, Result};
+use crate::openai::{ChatRequest, ProviderConfig};
 use crate::queue;
-use async_openai::{
-    types::{ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, Role},
-    Client,
-};
+use crate::queue::{EvaluateMessage, GeneratorMessage, MESSAGE_VERSION};
+use crate::template::{self, Context};
 use axum::extract::{Query, State};
 use axum::routing::{get, post};
 use axum::{Json, Router};
@@ -212,11 +211,13 @@ async fn handle_new_generator(
         }
         evaluator_config = json!({
             "prompt": evaluator_prompt,
+            "promptVersion": template::prompt_version_of(&template_data["evaluator"]),
             "keys": evaluator_keys,
             "keyConfigs": serde_json::Value::Object(evaluator_map),
         });
         generator_config = json!({
             "prompt": prompt,
+            "promptVersion": template::prompt_version_of(&template_data),
             "input": "",
             "keys": keys,
             "keyConfigs": serde_json::Value::Object(map),
@@ -468,22 +469,17 @@ async fn handle_try_generator(
     .config_data;
 
     let generator_config = generator_config.as_object().unwrap();
-    let mut prompt = generator_config["prompt"].as_str().unwrap().to_string();
     let input = req
         .generator
         .input
         .unwrap_or_else(|| generator_config["input"].as_str().unwrap().to_string());
-    let keys = generator_config["keys"].as_array().unwrap();
-    let key_configs = &generator_config["keyConfigs"];
-    for key in keys {
-        let key = key.as_str().unwrap();
-        let key_config = key_configs[key].as_object().unwrap();
-        prompt = prompt.replace(
-            &format!("@key/{}", key),
-            key_config["value"].as_str().unwrap(),
-        );
-    }
-    prompt = prompt.replace("@key/input", &input);
+    let prompt = template::render_version(
+        generator_config["prompt"].as_str().unwrap(),
+        template::prompt_version(generator_config),
+        &Context::new()
+            .with_keys(template::config_keys(generator_config))
+            .with_key("input", input),
+    )?;
     let bpe = cl100k_base().unwrap();
     let tokens = bpe.encode_with_special_tokens(&prompt);
     sqlx::query!(
@@ -497,27 +493,19 @@ async fn handle_try_generator(
     .execute(&ctx.db)
     .await?;
 
-    let client = Client::new();
-    let chat_request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(2048 as u16)
-        .model("gpt-3.5-turbo")
-        .temperature(0.1)
-        .messages([ChatCompletionRequestUserMessageArgs::default()
-            .content(format!(r#"{}"#, prompt))
-            .build()?
-            .into()])
-        .build()?;
-    let gpt_response = client.chat().create(chat_request).await?;
-    let output = &gpt_response
-        .choices
-        .iter()
-        .filter(|x| x.message.role == Role::Assistant)
-        .next()
-        .unwrap()
-        .message
-        .content
-        .clone()
-        .unwrap_or("".to_string());
+    let output = &ctx
+        .llm
+        .chat(
+            &ProviderConfig::OpenAI,
+            ChatRequest {
+                model: "gpt-3.5-turbo".to_string(),
+                input: prompt,
+                max_tokens: Some(2048),
+                temperature: Some(0.1),
+                ..Default::default()
+            },
+        )
+        .await?;
 
     let tokens = bpe.encode_with_special_tokens(&output);
     sqlx::query!(
@@ -782,7 +770,8 @@ async fn handle_save_generator(
     let generator_id = req.generator.generator_id;
     let generator = sqlx::query!(
         r#"select
-            project_id
+            project_id,
+            config_data
         from generator_v2 where generator_id = $1"#,
         generator_id
     )
@@ -806,7 +795,8 @@ async fn handle_save_generator(
     .await?
     .ok_or_else(|| Error::Forbidden)?;
 
-    let generator_config = req.generator.data;
+    let mut generator_config = req.generator.data;
+    template::keep_prompt_version(&mut generator_config, &generator.config_data);
 
     let generator = sqlx::query_as!(
         GeneratorFromSql,
@@ -903,6 +893,7 @@ async fn handle_reset_generator(
         }
         generator_config = json!({
             "prompt": prompt,
+            "promptVersion": template::prompt_version_of(&template_data),
             "input": "",
             "keys": keys,
             "keyConfigs": serde_json::Value::Object(map),
@@ -993,17 +984,18 @@ async fn handle_run_generator(
     .config_data;
 
     let generator_config = generator_config.as_object().unwrap();
-    let mut prompt = generator_config["prompt"].as_str().unwrap().to_string();
-    let keys = generator_config["keys"].as_array().unwrap();
-    let key_configs = &generator_config["keyConfigs"];
-    for key in keys {
-        let key = key.as_str().unwrap();
-        let key_config = key_configs[key].as_object().unwrap();
-        prompt = prompt.replace(
-            &format!("@key/{}", key),
-            key_config["value"].as_str().unwrap(),
-        );
-    }
+    let prompt = generator_config["prompt"].as_str().unwrap().to_string();
+    let prompt_version = template::prompt_version(generator_config);
+    let keys = template::config_keys(generator_config);
+    // Workers fill in the inputs, but a prompt that can't render is better caught now.
+    template::render_version(
+        &prompt,
+        prompt_version,
+        &Context::new()
+            .with_keys(keys.clone())
+            .with_key("input", "")
+            .with_key("reference", ""),
+    )?;
     let separtor = generator_config["separator"].as_str().unwrap_or("\n\n");
 
     let files = sqlx::query!(
@@ -1055,23 +1047,24 @@ async fn handle_run_generator(
         let body = response.json::<serde_json::Value>().await.unwrap();
         let body = body.as_array().unwrap();
         log::info!("extracted: count: {}", body.len());
+        let mut messages = Vec::new();
         for item in body {
             let input = item["text"].as_str().unwrap().to_string();
-            queue::publish_message_v2(
-                &queue::make_channel(&ctx.config.rabbitmq_url).await,
-                json!({
-                    "generator_id": generator_id,
-                    "file_id": file.file_id,
-                    "project_id": generator.project_id,
-                    "input": input,
-                    "prompt": prompt,
-                    "team_id": team_id,
-                    "user_id": auth_user.user_id,
-                    "separator": separtor,
-                }),
-            )
-            .await;
+            messages.push(GeneratorMessage {
+                version: MESSAGE_VERSION,
+                generator_id,
+                file_id: file.file_id,
+                project_id: generator.project_id,
+                input,
+                prompt: prompt.to_string(),
+                prompt_version,
+                keys: keys.clone(),
+                team_id,
+                user_id: auth_user.user_id,
+                separator: separtor.to_string(),
+            });
         }
+        queue::publish_messages_v2(&ctx.publisher, &messages).await?;
     }
 
     Ok(Json(CommonResponse {
@@ -1181,17 +1174,18 @@ async fn handle_evaluate_generator(
 
     let evaluator_config = evaluator.config_data;
     let evaluator_config = evaluator_config.as_object().unwrap();
-    let mut prompt = evaluator_config["prompt"].as_str().unwrap().to_string();
-    let keys = evaluator_config["keys"].as_array().unwrap();
-    let key_configs = &evaluator_config["keyConfigs"];
-    for key in keys {
-        let key = key.as_str().unwrap();
-        let key_config = key_configs[key].as_object().unwrap();
-        prompt = prompt.replace(
-            &format!("@key/{}", key),
-            key_config["value"].as_str().unwrap(),
-        );
-    }
+    let prompt = evaluator_config["prompt"].as_str().unwrap().to_string();
+    let prompt_version = template::prompt_version(evaluator_config);
+    let keys = template::config_keys(evaluator_config);
+    // Workers fill in the inputs, but a prompt that can't render is better caught now.
+    template::render_version(
+        &prompt,
+        prompt_version,
+        &Context::new()
+            .with_keys(keys.clone())
+            .with_key("input", "")
+            .with_key("reference", ""),
+    )?;
     let datadrops = sqlx::query!(
         r#"select
             datadrop_id,
@@ -1206,25 +1200,26 @@ async fn handle_evaluate_generator(
     .fetch_all(&ctx.db)
     .await?;
 
+    let mut messages = Vec::new();
     for datadrop in datadrops {
         let input = datadrop.datadrop_content;
         let extra_data = datadrop.extra_data.unwrap_or(json!({"text": ""}));
         let reference = extra_data["text"].as_str().unwrap();
-        queue::publish_message_v2_evaluate(
-            &queue::make_channel(&ctx.config.rabbitmq_url).await,
-            json!({
-                "generator_id": generator_id,
-                "datadrop_id": datadrop.datadrop_id,
-                "project_id": generator.project_id,
-                "input": input,
-                "prompt": prompt,
-                "team_id": team_id,
-                "user_id": auth_user.user_id,
-                "reference": reference,
-            }),
-        )
-        .await;
+        messages.push(EvaluateMessage {
+            version: MESSAGE_VERSION,
+            generator_id,
+            datadrop_id: datadrop.datadrop_id,
+            project_id: generator.project_id,
+            input,
+            prompt: prompt.to_string(),
+            prompt_version,
+            keys: keys.clone(),
+            team_id,
+            user_id: auth_user.user_id,
+            reference: reference.to_string(),
+        });
     }
+    queue::publish_messages_v2_evaluate(&ctx.publisher, &messages).await?;
 
     Ok(Json(CommonResponse {
         code: 200,
@@ -0,0 +1,380 @@
+use crate::http::extractor::AuthUser;
+use crate::http::types::Timestamptz;
+use crate::http::ApiContext;
+use crate::http::{Error, Result};
+use crate::queue;
+use crate::queue::{JobEvent, JobEventKind, JobStatusV2};
+use axum::extract::{Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::stream::{self, StreamExt};
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::http::CommonResponse;
+
+pub(crate) fn router() -> Router<ApiContext> {
+    Router::new()
+        .route("/v2/job/events", get(handle_job_events))
+        .route("/v2/job/cancel", post(handle_cancel_job))
+        .route("/v2/job/pause", post(handle_pause_job))
+        .route("/v2/job/resume", post(handle_resume_job))
+        .route("/v2/job/failure/list", get(handle_list_failure))
+        .route("/v2/job/failure/redrive", post(handle_redrive_failure))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct JobBody<T> {
+    job: T,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JobEventsRequest {
+    workspace_id: Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JobOperateRequest {
+    job_id: Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct FailureBody<T> {
+    failure: T,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FailureListRequest {
+    workspace_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job_id: Option<Uuid>,
+    /// Also list failures that were already re-driven.
+    #[serde(default)]
+    include_redriven: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FailureRedriveRequest {
+    workspace_id: Uuid,
+    failure_ids: Vec<Uuid>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FailureFromSql {
+    failure_id: Uuid,
+    queue_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module_id: Option<Uuid>,
+    error: String,
+    attempts: i32,
+    payload: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redriven_at: Option<Timestamptz>,
+    created_at: Timestamptz,
+}
+
+/// Follow the jobs of a workspace as server-sent events, instead of polling the modules.
+///
+/// Every event is a `queue::JobEvent`, named after its kind: `started`, `progress`, `failed`
+/// or `finished`. Right after connecting, a `progress` event is sent for every job still
+/// running, so the client starts from the current state.
+async fn handle_job_events(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Query(req): Query<JobEventsRequest>,
+) -> Result<impl IntoResponse> {
+    let workspace_id = req.workspace_id;
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    // Subscribe before taking the snapshot, so nothing in between is missed.
+    let receiver = ctx.job_events.subscribe();
+
+    let jobs = sqlx::query!(
+        r#"select job_id from job_v2
+        where workspace_id = $1 and job_status <> $2
+        order by created_at"#,
+        workspace_id,
+        JobStatusV2::Cancelled as i32
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+    let mut snapshot = Vec::new();
+    for job in jobs {
+        if let Some(event) = JobEvent::load(&ctx.db, JobEventKind::Progress, job.job_id).await? {
+            if event.completed_count + event.failed_count < event.target_count {
+                snapshot.push(event);
+            }
+        }
+    }
+
+    let updates = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if event.workspace_id == workspace_id => return Some((event, receiver)),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("job event subscriber fell behind by {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = stream::iter(snapshot).chain(updates).map(|event| {
+        let name = serde_json::to_value(event.kind).unwrap_or_default();
+        Ok::<_, Infallible>(
+            Event::default()
+                .event(name.as_str().unwrap_or_default())
+                .data(json!(event).to_string()),
+        )
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Stop a job for good. Messages of the job still queued are dropped by the workers, and an
+/// answer that comes back after this is thrown away rather than saved as a candidate.
+async fn handle_cancel_job(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<JobBody<JobOperateRequest>>,
+) -> Result<Json<CommonResponse>> {
+    let status = set_job_status(
+        &ctx,
+        auth_user.user_id,
+        req.job.job_id,
+        &[JobStatusV2::Active, JobStatusV2::Paused],
+        JobStatusV2::Cancelled,
+    )
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "jobStatus": status,
+        }),
+    }))
+}
+
+/// Hold back the messages of a job until it is resumed. Calls already in flight finish.
+async fn handle_pause_job(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<JobBody<JobOperateRequest>>,
+) -> Result<Json<CommonResponse>> {
+    let status = set_job_status(
+        &ctx,
+        auth_user.user_id,
+        req.job.job_id,
+        &[JobStatusV2::Active],
+        JobStatusV2::Paused,
+    )
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "jobStatus": status,
+        }),
+    }))
+}
+
+/// Let the workers pick a paused job up again. Its held back messages return within half a
+/// minute to four minutes, the longer the job was paused.
+async fn handle_resume_job(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<JobBody<JobOperateRequest>>,
+) -> Result<Json<CommonResponse>> {
+    let status = set_job_status(
+        &ctx,
+        auth_user.user_id,
+        req.job.job_id,
+        &[JobStatusV2::Paused],
+        JobStatusV2::Active,
+    )
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "jobStatus": status,
+        }),
+    }))
+}
+
+/// Move a job from one of the statuses in `from` to `to`, if the user may operate it.
+async fn set_job_status(
+    ctx: &ApiContext,
+    user_id: Uuid,
+    job_id: Uuid,
+    from: &[JobStatusV2],
+    to: JobStatusV2,
+) -> Result<JobStatusV2> {
+    let job = sqlx::query!(
+        r#"select workspace_id, job_status "job_status: JobStatusV2" from job_v2 where job_id = $1"#,
+        job_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        job.workspace_id,
+        user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    let from = from.iter().map(|s| *s as i32).collect::<Vec<i32>>();
+    // Checked again in the update, so that two operations racing can't both win.
+    let updated = sqlx::query!(
+        r#"update job_v2 set job_status = $1 where job_id = $2 and job_status = any($3)"#,
+        to as i32,
+        job_id,
+        &from
+    )
+    .execute(&ctx.db)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(Error::unprocessable_entity([(
+            "jobStatus",
+            format!("This job is {:?}", job.job_status).to_lowercase(),
+        )]));
+    }
+
+    Ok(to)
+}
+
+/// Messages the queue workers gave up on, most recent first.
+async fn handle_list_failure(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Query(req): Query<FailureListRequest>,
+) -> Result<Json<CommonResponse>> {
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        req.workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    let failures = sqlx::query_as!(
+        FailureFromSql,
+        // language=PostgreSQL
+        r#"select
+            failure_id,
+            queue_name,
+            job_id,
+            module_id,
+            error,
+            attempts,
+            payload,
+            redriven_at "redriven_at: Timestamptz",
+            created_at "created_at: Timestamptz"
+        from job_failure
+        where workspace_id = $1
+            and ($2::uuid is null or module_id = $2)
+            and ($3::uuid is null or job_id = $3)
+            and ($4 or redriven_at is null)
+        order by created_at desc"#,
+        req.workspace_id,
+        req.module_id,
+        req.job_id,
+        req.include_redriven
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "failures": failures,
+        }),
+    }))
+}
+
+/// Publish failed messages back to the queues they came from.
+///
+/// Each message starts over with a fresh attempt count. If it fails again it is recorded as a
+/// new failure.
+async fn handle_redrive_failure(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<FailureBody<FailureRedriveRequest>>,
+) -> Result<Json<CommonResponse>> {
+    let workspace_id = req.failure.workspace_id;
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    let failures = sqlx::query!(
+        r#"select failure_id, queue_name, payload from job_failure
+        where workspace_id = $1 and failure_id = any($2) and redriven_at is null"#,
+        workspace_id,
+        &req.failure.failure_ids
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    let mut redriven = Vec::new();
+    for failure in failures {
+        queue::redrive(&ctx.publisher, &failure.queue_name, &failure.payload).await?;
+        // Marked one at a time, so a failed publish halfway leaves the rest to be retried.
+        queue::mark_redriven(&ctx.db, failure.failure_id).await?;
+        redriven.push(failure.failure_id);
+    }
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "failureIds": redriven,
+        }),
+    }))
+}
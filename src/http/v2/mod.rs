@@ -8,28 +8,39 @@ use serde_json::json;
 
 use crate::http::CommonResponse;
 
+mod admin;
+mod chat_sessions;
 mod chats;
 mod databases;
 mod evaluators;
 mod files;
 mod generators;
 mod invoices;
+mod jobs;
 mod modules;
+mod pipelines;
+mod sse;
 mod templates;
 mod workspaces;
 
+pub(crate) use pipelines::spawn_pipeline_driver;
+
 pub(crate) fn router() -> Router<ApiContext> {
     Router::new()
         .route("/v2/ping", get(handle_ping))
+        .merge(admin::router())
         .merge(generators::router())
         .merge(templates::router())
         .merge(files::router())
         .merge(chats::router())
+        .merge(chat_sessions::router())
         .merge(evaluators::router())
         .merge(modules::router())
+        .merge(pipelines::router())
         .merge(workspaces::router())
         .merge(databases::router())
         .merge(invoices::router())
+        .merge(jobs::router())
 }
 
 async fn handle_ping(ctx: State<ApiContext>) -> Result<Json<CommonResponse>> {
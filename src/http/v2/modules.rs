@@ -1,22 +1,39 @@
+use crate::config::Config;
 use crate::http::extractor::AuthUser;
 use crate::http::types::Timestamptz;
+use crate::http::v2::sse;
 use crate::http::ApiContext;
 use crate::http::{Error, Result};
-use crate::openai::ChatRequest;
-use crate::{openai, queue};
+use crate::openai::{self, count_tokens, ChatRequest, ModelParams, ProviderConfig};
+use crate::preprocess::{Preprocess, Runner, StepConfig, Steps};
+use crate::queue;
+use crate::queue::{JobEventKind, JobPriority, JobStatusV2, ModuleMessage, MESSAGE_VERSION};
+use crate::retrieval::{RetrievalConfig, Retriever};
+use crate::template::{self, Context, Template};
+use anyhow::Context as _;
 use axum::body::Body;
 use axum::extract::{Query, State};
 use axum::http::header;
-use axum::response::Response;
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use serde_json::json;
+use sqlx::{PgExecutor, PgPool};
 use std::path::Path;
-use tiktoken_rs::cl100k_base;
 use uuid::Uuid;
 
 use crate::http::CommonResponse;
 
+/// How many rendered prompts a validation sends back when it isn't asked for a number.
+const VALIDATE_SAMPLE_SIZE: usize = 5;
+
+/// The most rendered prompts a validation sends back, however many it is asked for. Runs can
+/// have thousands of inputs; the per-input estimates cover the rest.
+const MAX_VALIDATE_SAMPLE_SIZE: usize = 20;
+
+/// How many inputs of each source a validation checks. Its estimates cover only these.
+const MAX_VALIDATE_INPUTS: usize = 1000;
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct ModuleBody<T> {
     module: T,
@@ -30,9 +47,12 @@ pub(crate) fn router() -> Router<ApiContext> {
         )
         .route("/v2/module/list", get(handle_list_module))
         .route("/v2/module/try", post(handle_try_module))
+        .route("/v2/module/try/stream", post(handle_try_module_stream))
         .route("/v2/module/save", post(handle_save_module))
         .route("/v2/module/reset", post(handle_reset_module))
+        .route("/v2/module/reindex", post(handle_reindex_module))
         .route("/v2/module/run", post(handle_run_module))
+        .route("/v2/module/validate", post(handle_validate_module))
         .route("/v2/module/clearFiles", post(handle_clear_files))
         .route("/v2/module/saveData", post(handle_save_data))
         .route("/v2/module/assignData", post(handle_assign_data))
@@ -96,6 +116,12 @@ struct ModuleTryRequest {
     input: Option<String>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ModuleReindexRequest {
+    module_id: Uuid,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct ModuleSaveRequest {
@@ -114,6 +140,17 @@ struct ModuleResetRequest {
 #[serde(rename_all = "camelCase")]
 struct ModuleRunRequest {
     module_id: Uuid,
+    /// Which queue the run's jobs go on; by default, small jobs are interactive. Large jobs
+    /// are always batch, whatever is asked for.
+    priority: Option<JobPriority>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ModuleValidateRequest {
+    module_id: Uuid,
+    /// How many rendered prompts to send back; 5 by default, and at most 20.
+    sample_size: Option<usize>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -191,6 +228,7 @@ async fn handle_new_module(
         let separator = template_data["separator"].as_str().unwrap_or_default();
         let key_configs = &template_data["keyConfigs"];
         let preprocess = &template_data["preprocess"];
+        let model_params = &template_data["modelParams"];
         let mut map = serde_json::Map::new();
         for key in keys {
             let key = key.as_str().unwrap();
@@ -208,11 +246,13 @@ async fn handle_new_module(
         }
         module_config = json!({
             "prompt": prompt,
+            "promptVersion": template::prompt_version_of(&template_data),
             "input": "",
             "keys": keys,
             "keyConfigs": serde_json::Value::Object(map),
             "separator": separator,
             "preprocess": preprocess,
+            "modelParams": model_params,
             "assignData": {},
         });
     } else {
@@ -223,6 +263,7 @@ async fn handle_new_module(
             "keyConfigs": {},
             "separator": "",
             "preprocess": [],
+            "modelParams": {},
             "assignData": {},
         });
     }
@@ -391,7 +432,80 @@ async fn handle_try_module(
     Json(req): Json<ModuleBody<ModuleTryRequest>>,
 ) -> Result<Json<CommonResponse>> {
     log::info!("{:?}", req);
-    let module_id = req.module.module_id;
+    let module_try = prepare_try(&ctx, auth_user.user_id, req.module).await?;
+    let prompt = module_try.request.input.clone();
+
+    let chat_output = ctx
+        .llm
+        .chat_with_usage(&module_try.provider, module_try.request.clone())
+        .await?;
+    let output = chat_output.output;
+
+    let call = CallMetric {
+        workspace_id: module_try.workspace_id,
+        module_id: module_try.module_id,
+        user_id: auth_user.user_id,
+        openai_id: chat_output.openai_id,
+        model: &module_try.request.model,
+    };
+    record_call(&ctx.db, &call, &prompt, &output).await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "response": output,
+        }),
+    }))
+}
+
+/// `handle_try_module`, but with the answer streamed as server-sent events; see `sse::relay`.
+///
+/// The final `done` event carries the same `{"response": ...}` the non-streaming endpoint
+/// returns.
+async fn handle_try_module_stream(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<ModuleBody<ModuleTryRequest>>,
+) -> Result<impl IntoResponse> {
+    log::info!("{:?}", req);
+    let module_try = prepare_try(&ctx, auth_user.user_id, req.module).await?;
+    let prompt = module_try.request.input.clone();
+
+    let stream = ctx
+        .llm
+        .chat_stream(&module_try.provider, module_try.request.clone())
+        .await?;
+    let openai_id = stream.openai_id;
+    let db = ctx.db.clone();
+
+    Ok(sse::relay(stream.deltas, move |output| async move {
+        let call = CallMetric {
+            workspace_id: module_try.workspace_id,
+            module_id: module_try.module_id,
+            user_id: auth_user.user_id,
+            openai_id,
+            model: &module_try.request.model,
+        };
+        record_call(&db, &call, &prompt, &output).await?;
+
+        Ok(json!({
+            "response": output,
+        }))
+    }))
+}
+
+/// A module try, ready to be sent to the model.
+struct ModuleTry {
+    workspace_id: Uuid,
+    module_id: Uuid,
+    provider: ProviderConfig,
+    request: ChatRequest,
+}
+
+/// Check access to the module, run its preprocess steps and fill in the main prompt.
+async fn prepare_try(ctx: &ApiContext, user_id: Uuid, req: ModuleTryRequest) -> Result<ModuleTry> {
+    let module_id = req.module_id;
     let workspace_id = sqlx::query!(
         r#"select
             workspace_id
@@ -406,117 +520,142 @@ async fn handle_try_module(
         // language=PostgreSQL
         r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
         workspace_id,
-        auth_user.user_id
+        user_id
     )
     .fetch_optional(&ctx.db)
     .await?
     .ok_or_else(|| Error::Forbidden)?;
 
-    let mut module_config = sqlx::query!(
+    let module = sqlx::query!(
         r#"select
-            config_data
+            config_data,
+            module_category
         from module_v2 where module_id = $1"#,
         module_id
     )
     .fetch_one(&ctx.db)
-    .await?
-    .config_data;
-
-    let module_config = module_config.as_object_mut().unwrap();
+    .await?;
 
-    let preprocess = module_config["preprocess"].as_array().unwrap().clone();
-    for process in preprocess {
-        let input_keys = process["inputKeys"].as_array().unwrap();
-        let mut prompt = process["prompt"].as_str().unwrap().to_string();
-        let model = process["model"].as_str().unwrap().to_string();
-        let output_key = process["outputKey"].as_str().unwrap();
-        for key in input_keys {
-            let key = key.as_str().unwrap();
-            let key_config = module_config["keyConfigs"][key].as_object().unwrap();
-            prompt = prompt.replace(
-                &format!("@key/{}", key),
-                key_config["value"].as_str().unwrap(),
-            );
-        }
-        let api_key = openai::get_available_key(&ctx.db).await?;
-        let output = openai::chat(
-            ChatRequest {
-                model: model,
-                input: prompt,
-                max_tokens: Some(2048),
-                temperature: Some(0.1),
-                history: None,
-            },
-            &api_key.openai_key,
-        )
-        .await?;
-        openai::release_key(&ctx.db, api_key).await?;
-        let output_key_config = module_config["keyConfigs"][output_key]
-            .as_object_mut()
-            .unwrap();
-        output_key_config["value"] = serde_json::Value::String(output);
-    }
+    let provider = ProviderConfig::from_module_config(&module.config_data)?;
+    let params = ModelParams::from_module_config(
+        &module.config_data,
+        &provider,
+        default_model(&module.module_category),
+    )?;
+    let module_config = module.config_data.as_object().unwrap();
 
-    let mut prompt = module_config["prompt"].as_str().unwrap().to_string();
     let input = req
-        .module
         .input
         .unwrap_or_else(|| module_config["input"].as_str().unwrap().to_string());
-    let keys = module_config["keys"].as_array().unwrap();
-    let key_configs = &module_config["keyConfigs"];
-    for key in keys {
-        let key = key.as_str().unwrap();
-        let key_config = key_configs[key].as_object().unwrap();
-        prompt = prompt.replace(
-            &format!("@key/{}", key),
-            key_config["value"].as_str().unwrap(),
-        );
-    }
-    prompt = prompt.replace("@key/input", &input);
-    let bpe = cl100k_base().unwrap();
-    let tokens = bpe.encode_with_special_tokens(&prompt);
-    sqlx::query!(
-        r#"insert into metric_v2 (workspace_id, user_id, module_id, token_count, word_count) values ($1, $2, $3, $4, $5)"#,
+    let mut keys = template::config_keys(module_config);
+    keys.insert("input".to_string(), serde_json::Value::String(input));
+    let runner = Runner {
+        db: &ctx.db,
+        llm: &ctx.llm,
+        provider: &provider,
+        cache_secs: ctx.config.preprocess_cache_secs,
+    };
+    Preprocess::from_config(module_config)?
+        .run(&runner, &mut keys, Steps::All)
+        .await?;
+    let prompt = template::render_version(
+        module_config["prompt"].as_str().unwrap(),
+        template::prompt_version(module_config),
+        &Context::new().with_keys(keys),
+    )?;
+
+    Ok(ModuleTry {
         workspace_id,
-        auth_user.user_id,
         module_id,
-        tokens.len() as i32,
-        prompt.chars().count() as i32
+        provider,
+        request: params.request(prompt),
+    })
+}
+
+/// Who a call to a module's model was made for, and what served it; see `record_call`.
+pub(super) struct CallMetric<'a> {
+    pub(super) workspace_id: Uuid,
+    pub(super) module_id: Uuid,
+    pub(super) user_id: Uuid,
+    pub(super) openai_id: Option<Uuid>,
+    pub(super) model: &'a str,
+}
+
+/// Record the `prompt` sent to a module's model and the `output` it answered with in
+/// `metric_v2`, a row each.
+///
+/// Rows are attributed to the key that served the call and to the model, so spend can be
+/// tracked per key and priced from the model catalog.
+pub(super) async fn record_call(
+    db: &PgPool,
+    call: &CallMetric<'_>,
+    prompt: &str,
+    output: &str,
+) -> Result<()> {
+    sqlx::query!(
+        r#"insert into metric_v2
+            (workspace_id, user_id, module_id, openai_id, model, token_count, word_count, is_output)
+        values ($1, $2, $3, $4, $5, $6, $7, false), ($1, $2, $3, $4, $5, $8, $9, true)"#,
+        call.workspace_id,
+        call.user_id,
+        call.module_id,
+        call.openai_id,
+        call.model,
+        count_tokens(prompt) as i32,
+        prompt.chars().count() as i32,
+        count_tokens(output) as i32,
+        output.chars().count() as i32
     )
-    .execute(&ctx.db)
+    .execute(db)
     .await?;
 
-    let api_key = openai::get_available_key(&ctx.db).await?;
-    let output = openai::chat(
-        ChatRequest {
-            model: "gpt-3.5-turbo-1106".to_string(),
-            input: prompt,
-            max_tokens: Some(2048),
-            temperature: Some(0.1),
-            history: None,
-        },
-        &api_key.openai_key,
+    Ok(())
+}
+
+/// Rebuild the module's search index from scratch.
+///
+/// Indexes are kept in sync on their own; this is for when they are known to be broken, or
+/// after the retrieval config of the module changed.
+async fn handle_reindex_module(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<ModuleBody<ModuleReindexRequest>>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let module_id = req.module.module_id;
+    let module = sqlx::query!(
+        r#"select
+            workspace_id,
+            config_data
+        from module_v2 where module_id = $1"#,
+        module_id
     )
+    .fetch_one(&ctx.db)
     .await?;
-    openai::release_key(&ctx.db, api_key).await?;
 
-    let tokens = bpe.encode_with_special_tokens(&output);
-    sqlx::query!(
-        r#"insert into metric_v2 (workspace_id, user_id, module_id, token_count, word_count) values ($1, $2, $3, $4, $5)"#,
-        workspace_id,
-        auth_user.user_id,
-        module_id,
-        tokens.len() as i32,
-        output.chars().count() as i32
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        module.workspace_id,
+        auth_user.user_id
     )
-    .execute(&ctx.db)
-    .await?;
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    let config = RetrievalConfig::from_module_config(&module.config_data)?;
+    let retriever = Retriever::new(&ctx.config.es_url, ctx.llm.clone())?;
+    let count = retriever.rebuild_index(&ctx.db, module_id, &config).await?;
 
     Ok(Json(CommonResponse {
         code: 200,
         message: "success".to_string(),
         data: json!({
-            "response": output,
+            "count": count,
         }),
     }))
 }
@@ -526,18 +665,22 @@ async fn handle_save_module(
     ctx: State<ApiContext>,
     Json(req): Json<ModuleBody<ModuleSaveRequest>>,
 ) -> Result<Json<CommonResponse>> {
-    log::info!("{:?}", req);
+    // Not the whole request: `data` may carry provider keys.
+    log::info!("save module {}", req.module.module_id);
     let module_id = req.module.module_id;
-    let data = req.module.data;
-    let workspace_id = sqlx::query!(
+    let mut data = req.module.data;
+    let module = sqlx::query!(
         r#"select
-            workspace_id
+            workspace_id,
+            module_category,
+            config_data
         from module_v2 where module_id = $1"#,
         module_id
     )
     .fetch_one(&ctx.db)
-    .await?
-    .workspace_id;
+    .await?;
+    let workspace_id = module.workspace_id;
+    template::keep_prompt_version(&mut data, &module.config_data);
 
     let _member_record = sqlx::query!(
         // language=PostgreSQL
@@ -549,6 +692,22 @@ async fn handle_save_module(
     .await?
     .ok_or_else(|| Error::Forbidden)?;
 
+    // Keys are only kept by reference, so that they aren't sent back to every member.
+    openai::store_provider_secrets(
+        &ctx.db,
+        workspace_id,
+        &ctx.config.provider_allowed_hosts,
+        &mut data,
+    )
+    .await
+    .map_err(provider_error)?;
+    // Calls with parameters the model refuses would fail every try and every input of a run.
+    let provider = ProviderConfig::from_module_config(&data).map_err(provider_error)?;
+    ModelParams::from_module_config(&data, &provider, default_model(&module.module_category))?;
+    if let Some(module_config) = data.as_object() {
+        Preprocess::from_config(module_config)?.check_models(&provider)?;
+    }
+
     let module = sqlx::query_as!(
         ModuleFromSql,
         r#"update module_v2 set config_data = $1 where module_id = $2
@@ -575,6 +734,16 @@ async fn handle_save_module(
     }))
 }
 
+/// A provider config that can't be used is a problem with the module's config.
+fn provider_error(e: openai::Error) -> Error {
+    match e {
+        openai::Error::InvalidConfig(message) => {
+            Error::unprocessable_entity([("provider", message)])
+        }
+        e => e.into(),
+    }
+}
+
 async fn handle_reset_module(
     auth_user: AuthUser,
     ctx: State<ApiContext>,
@@ -604,8 +773,23 @@ async fn handle_reset_module(
     .await?
     .ok_or_else(|| Error::Forbidden)?;
 
+    // Jobs are cancelled before their candidates are cleared out; workers check for that
+    // while saving theirs. See `execute_job_evo`. Jobs of pipeline stages are left to their
+    // run, whose later stages read their candidates.
+    let _update_jobs = sqlx::query!(
+        r#"update job_v2 set job_status = $2
+        where module_id = $1 and job_status <> $2
+          and not exists (select 1 from pipeline_stage s where s.job_id = job_v2.job_id)"#,
+        module_id,
+        JobStatusV2::Cancelled as i32
+    )
+    .execute(&ctx.db)
+    .await?;
+
     let _clean_candidate = sqlx::query!(
-        r#"delete from candidate_v2 where module_id = $1"#,
+        r#"delete from candidate_v2
+        where module_id = $1
+          and not exists (select 1 from pipeline_stage s where s.job_id = candidate_v2.job_id)"#,
         module_id
     )
     .execute(&ctx.db)
@@ -615,13 +799,6 @@ async fn handle_reset_module(
         .execute(&ctx.db)
         .await?;
 
-    let _update_jobs = sqlx::query!(
-        r#"update job_v2 set job_status = 1 where module_id = $1 and job_status = 0"#,
-        module_id
-    )
-    .execute(&ctx.db)
-    .await?;
-
     let module_config;
     if let Some(template_id) = req.module.template_id {
         let template = sqlx::query!(
@@ -645,6 +822,7 @@ async fn handle_reset_module(
         let separator = template_data["separator"].as_str().unwrap_or_default();
         let key_configs = &template_data["keyConfigs"];
         let preprocess = &template_data["preprocess"];
+        let model_params = &template_data["modelParams"];
         let mut map = serde_json::Map::new();
         for key in keys {
             let key = key.as_str().unwrap();
@@ -662,11 +840,13 @@ async fn handle_reset_module(
         }
         module_config = json!({
             "prompt": prompt,
+            "promptVersion": template::prompt_version_of(&template_data),
             "input": "",
             "keys": keys,
             "keyConfigs": serde_json::Value::Object(map),
             "separator": separator,
             "preprocess": preprocess,
+            "modelParams": model_params,
             "assignData": {},
         });
     } else {
@@ -677,6 +857,7 @@ async fn handle_reset_module(
             "keyConfigs": {},
             "separator": "",
             "preprocess": [],
+            "modelParams": {},
             "assignData": {},
         });
     }
@@ -782,200 +963,293 @@ async fn handle_list_module(
     }))
 }
 
-async fn handle_run_module(
-    auth_user: AuthUser,
-    ctx: State<ApiContext>,
-    Json(req): Json<ModuleBody<ModuleRunRequest>>,
-) -> Result<Json<CommonResponse>> {
-    log::info!("{:?}", req);
-    let module_id = req.module.module_id;
-    let module = sqlx::query!(
-        r#"select
-            config_data,
-            workspace_id,
-            template_id,
-            module_category
-        from module_v2 where module_id = $1"#,
-        module_id
-    )
-    .fetch_one(&ctx.db)
-    .await?;
-
-    let _member_record = sqlx::query!(
-        // language=PostgreSQL
-        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
-        module.workspace_id,
-        auth_user.user_id
-    )
-    .fetch_optional(&ctx.db)
-    .await?
-    .ok_or_else(|| Error::Forbidden)?;
+/// The queue a job of `target_count` inputs goes on.
+///
+/// A run may ask for the batch queue, but not for the interactive one: that queue isn't held
+/// to the workspace concurrency caps, so only jobs small enough to go there anyway do.
+fn job_priority(
+    config: &Config,
+    requested: Option<JobPriority>,
+    target_count: usize,
+) -> JobPriority {
+    if requested == Some(JobPriority::Batch)
+        || target_count > config.interactive_max_inputs.max(0) as usize
+    {
+        JobPriority::Batch
+    } else {
+        JobPriority::Interactive
+    }
+}
 
-    if _member_record.user_level > 1 {
-        return Err(Error::Forbidden);
+/// The model a module of `module_category` calls when its `modelParams` don't name one.
+pub(super) fn default_model(module_category: &str) -> &'static str {
+    if module_category == "generator" {
+        "gpt-3.5-turbo-1106"
+    } else {
+        "gpt-4-1106-preview"
     }
+}
 
-    let _clean_candidate = sqlx::query!(
-        r#"delete from candidate_v2 where module_id = $1"#,
-        module_id
-    )
-    .execute(&ctx.db)
-    .await?;
+/// One input of a run, and what its answer is compared with, if anything.
+pub(super) struct RunInput {
+    pub(super) input: String,
+    pub(super) reference: String,
+}
 
-    let _reset_files = sqlx::query!(
-        r#"update file_module set finish_process = false where module_id = $1"#,
-        module_id
-    )
-    .execute(&ctx.db)
-    .await?;
+/// Inputs from one source, which a run turns into one job.
+pub(super) struct RunBatch {
+    pub(super) file_id: Option<Uuid>,
+    pub(super) inputs: Vec<RunInput>,
+}
 
-    let _update_jobs = sqlx::query!(
-        r#"update job_v2 set job_status = 1 where module_id = $1 and job_status = 0"#,
-        module_id
-    )
-    .execute(&ctx.db)
-    .await?;
+/// A run of a module, with its shared preprocessing done and its prompt checked, whose inputs
+/// are queued as jobs.
+pub(super) struct ModuleRun {
+    module_id: Uuid,
+    workspace_id: Uuid,
+    user_id: Uuid,
+    pub(super) module_config: serde_json::Map<String, serde_json::Value>,
+    prompt: String,
+    prompt_version: u32,
+    /// The module's keys, with the answers of the preprocess steps run so far.
+    keys: serde_json::Map<String, serde_json::Value>,
+    /// The preprocess steps the workers run per input.
+    preprocess: Vec<StepConfig>,
+    separator: String,
+    params: ModelParams,
+    provider: ProviderConfig,
+}
 
-    let mut module_config = module.config_data;
+impl ModuleRun {
+    /// Run the module's preprocess steps that don't read the input, and check that its prompt
+    /// renders.
+    pub(super) async fn prepare(
+        ctx: &ApiContext,
+        module_id: Uuid,
+        workspace_id: Uuid,
+        user_id: Uuid,
+        module_category: &str,
+        config_data: serde_json::Value,
+    ) -> Result<ModuleRun> {
+        let provider = ProviderConfig::from_module_config(&config_data)?;
+        let params = ModelParams::from_module_config(
+            &config_data,
+            &provider,
+            default_model(module_category),
+        )?;
+        let module_config = config_data.as_object().cloned().unwrap();
+
+        let preprocess = Preprocess::from_config(&module_config)?;
+        preprocess.check_models(&provider)?;
+        let mut keys = template::config_keys(&module_config);
+        let runner = Runner {
+            db: &ctx.db,
+            llm: &ctx.llm,
+            provider: &provider,
+            cache_secs: ctx.config.preprocess_cache_secs,
+        };
+        preprocess.run(&runner, &mut keys, Steps::Shared).await?;
+
+        let prompt = module_config["prompt"].as_str().unwrap().to_string();
+        let prompt_version = template::prompt_version(&module_config);
+        // Workers fill in the inputs and the steps that read them, but a prompt that can't
+        // render is better caught now.
+        let mut context = Context::new()
+            .with_keys(keys.clone())
+            .with_key("input", "")
+            .with_key("reference", "");
+        for key in preprocess.output_keys(Steps::PerInput) {
+            context = context.with_key(&key, "");
+        }
+        template::render_version(&prompt, prompt_version, &context)?;
+        let separator = module_config["separator"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
 
-    let module_config = module_config.as_object_mut().unwrap();
+        Ok(ModuleRun {
+            module_id,
+            workspace_id,
+            user_id,
+            module_config,
+            prompt,
+            prompt_version,
+            keys,
+            preprocess: preprocess.configs(Steps::PerInput),
+            separator,
+            params,
+            provider,
+        })
+    }
 
-    let preprocess = module_config["preprocess"].as_array().unwrap().clone();
-    for process in preprocess {
-        let input_keys = process["inputKeys"].as_array().unwrap();
-        let mut prompt = process["prompt"].as_str().unwrap().to_string();
-        let model = process["model"].as_str().unwrap().to_string();
-        let output_key = process["outputKey"].as_str().unwrap();
-        for key in input_keys {
-            let key = key.as_str().unwrap();
-            let key_config = module_config["keyConfigs"][key].as_object().unwrap();
-            prompt = prompt.replace(
-                &format!("@key/{}", key),
-                key_config["value"].as_str().unwrap(),
-            );
-        }
-        log::info!("preprocessing: key: {}", &output_key);
-        let api_key = openai::get_available_key(&ctx.db).await?;
-        let output = openai::chat(
-            ChatRequest {
-                model: model,
-                input: prompt,
-                max_tokens: Some(2048),
-                temperature: Some(0.1),
-                history: None,
-            },
-            &api_key.openai_key,
+    /// Add a job of `target_count` inputs to the run, and return it with the queue it goes on.
+    pub(super) async fn create_job(
+        &self,
+        db: impl PgExecutor<'_>,
+        config: &Config,
+        requested: Option<JobPriority>,
+        target_count: usize,
+    ) -> Result<(Uuid, JobPriority)> {
+        let priority = job_priority(config, requested, target_count);
+        let job = sqlx::query!(
+            r#"insert into job_v2 (module_id, config_data, workspace_id, target_count, priority) values ($1, $2, $3, $4, $5) returning job_id"#,
+            self.module_id,
+            json!({}),
+            self.workspace_id,
+            target_count as i32,
+            priority as i32,
         )
+        .fetch_one(db)
         .await?;
-        openai::release_key(&ctx.db, api_key).await?;
-        log::info!("preprocessing: key: {}, finish", &output_key);
-        let output_key_config = module_config["keyConfigs"][output_key]
-            .as_object_mut()
-            .unwrap();
-        output_key_config["value"] = serde_json::Value::String(output);
+
+        Ok((job.job_id, priority))
     }
 
-    log::info!("new module_config: {:?}", &module_config);
-    let mut prompt = module_config["prompt"].as_str().unwrap().to_string();
-    let keys = module_config["keys"].as_array().unwrap();
-    let key_configs = &module_config["keyConfigs"];
-    for key in keys {
-        let key = key.as_str().unwrap();
-        let key_config = key_configs[key].as_object().unwrap();
-        prompt = prompt.replace(
-            &format!("@key/{}", key),
-            key_config["value"].as_str().unwrap(),
-        );
+    /// Queue a message for every input of `batch`, as part of `job_id`.
+    pub(super) async fn queue_job(
+        &self,
+        ctx: &ApiContext,
+        job_id: Uuid,
+        priority: JobPriority,
+        batch: RunBatch,
+    ) -> Result<()> {
+        let messages = batch
+            .inputs
+            .into_iter()
+            .map(|item| ModuleMessage {
+                version: MESSAGE_VERSION,
+                module_id: self.module_id,
+                job_id,
+                work_item_id: Some(Uuid::new_v4()),
+                workspace_id: self.workspace_id,
+                file_id: batch.file_id,
+                input: item.input,
+                prompt: self.prompt.clone(),
+                prompt_version: self.prompt_version,
+                keys: self.keys.clone(),
+                preprocess: self.preprocess.clone(),
+                user_id: self.user_id,
+                separator: self.separator.clone(),
+                reference: item.reference,
+                model_name: self.params.model.clone(),
+                params: self.params.clone(),
+                provider: self.provider.clone(),
+            })
+            .collect::<Vec<_>>();
+        queue::publish_messages_evo(&ctx.publisher, priority, &messages).await?;
+        queue::emit_job_event(&ctx.db, JobEventKind::Started, job_id, None, None).await;
+
+        Ok(())
     }
-    let separtor = module_config["separator"].as_str().unwrap_or_default();
+}
+
+/// How many of a module's inputs `run_batches` reads.
+#[derive(Clone, Copy)]
+pub(super) enum Inputs {
+    /// Everything, for a run.
+    All,
+    /// At most this many per source, for a validation. Files the unstructured service would
+    /// have to split are left out, with an empty batch in their place.
+    Sample(usize),
+}
+
+/// The inputs a run of the module works through: its assigned data, if any, then each
+/// attached file.
+///
+/// CSV files give an input and a reference per row; other files are split into chunks by the
+/// unstructured service.
+pub(super) async fn run_batches(
+    ctx: &ApiContext,
+    module_id: Uuid,
+    module_config: &serde_json::Map<String, serde_json::Value>,
+    read: Inputs,
+) -> Result<Vec<RunBatch>> {
+    let mut batches = Vec::new();
+    let limit = match read {
+        Inputs::All => None,
+        Inputs::Sample(size) => Some(size),
+    };
 
     let assign_data = &module_config["assignData"];
     let datastore_id = assign_data["datastoreId"].as_str();
     let is_raw = assign_data["isRaw"].as_bool();
     let tags = assign_data["tags"].as_str();
     if let (Some(datastore_id), Some(is_raw), Some(tags)) = (datastore_id, is_raw, tags) {
-        let datastore_id = Uuid::parse_str(datastore_id).unwrap();
+        let datastore_id = Uuid::parse_str(datastore_id).map_err(|_| {
+            Error::unprocessable_entity([("assignData", "datastoreId isn't a valid id")])
+        })?;
         let tags = tags
             .split(',')
             .map(|s| s.to_string())
             .collect::<Vec<String>>();
-        let like_conditions = tags
-            .iter()
-            .map(|tag| {
-                format!(
-                    "(tags LIKE '%{}%' OR tags LIKE '{},%' OR tags LIKE '%,{}' OR tags = '{}')",
-                    tag, tag, tag, tag
-                )
-            })
-            .collect::<Vec<_>>()
-            .join(" OR ");
+        let limit = limit.map(|limit| limit as i64);
 
-        #[derive(serde::Serialize, serde::Deserialize, sqlx::FromRow)]
         struct DataFromSql {
             data_content: String,
             extra_data: Option<serde_json::Value>,
         }
-        let assigned_data: Vec<DataFromSql>;
-        if is_raw {
-            let query = format!(
+        // Data matches if its comma separated tags contain any of the module's.
+        let assigned_data = if is_raw {
+            sqlx::query_as!(
+                DataFromSql,
                 r#"select
                     data_content,
                     extra_data
-                from data_v2 where module_id = '{}' and is_raw = true and ({})"#,
-                datastore_id, like_conditions
-            );
-            assigned_data = sqlx::query_as(&query).fetch_all(&ctx.db).await?;
+                from data_v2
+                where module_id = $1 and is_raw = true
+                  and exists (
+                      select 1 from unnest($2::text[]) tag
+                      where tags like '%' || tag || '%' or tags like tag || ',%'
+                          or tags like '%,' || tag or tags = tag
+                  )
+                limit $3"#,
+                datastore_id,
+                &tags,
+                limit
+            )
+            .fetch_all(&ctx.db)
+            .await?
         } else {
-            let query = format!(
+            sqlx::query_as!(
+                DataFromSql,
                 r#"select
                     data_content,
                     extra_data
-                from data_v2 where datastore_id = '{}' and is_raw = false and ({})"#,
-                datastore_id, like_conditions
-            );
-            assigned_data = sqlx::query_as(&query).fetch_all(&ctx.db).await?;
-        }
-        log::info!("assigned: count: {}", assigned_data.len());
-        let job = sqlx::query!(
-            r#"insert into job_v2 (module_id, config_data, workspace_id, target_count) values ($1, $2, $3, $4) returning job_id"#,
-            module_id,
-            json!({}),
-            module.workspace_id,
-            assigned_data.len() as i32
-        )
-        .fetch_one(&ctx.db)
-        .await?;
-        let model_name = if module.module_category == "generator" {
-            "gpt-3.5-turbo-1106"
-        } else {
-            "gpt-4-1106-preview"
+                from data_v2
+                where datastore_id = $1 and is_raw = false
+                  and exists (
+                      select 1 from unnest($2::text[]) tag
+                      where tags like '%' || tag || '%' or tags like tag || ',%'
+                          or tags like '%,' || tag or tags = tag
+                  )
+                limit $3"#,
+                datastore_id,
+                &tags,
+                limit
+            )
+            .fetch_all(&ctx.db)
+            .await?
         };
-
-        for data in assigned_data {
-            let input = data.data_content;
-            let mut reference = "".to_string();
-            if let Some(extra_data) = data.extra_data {
-                if let Some(reference_data) = extra_data["text"].as_str() {
-                    reference = reference_data.to_string();
+        log::info!("assigned: count: {}", assigned_data.len());
+        let inputs = assigned_data
+            .into_iter()
+            .map(|data| {
+                let reference = data
+                    .extra_data
+                    .as_ref()
+                    .and_then(|extra_data| extra_data["text"].as_str())
+                    .unwrap_or_default()
+                    .to_string();
+                RunInput {
+                    input: data.data_content,
+                    reference,
                 }
-            }
-            queue::publish_message_evo(
-                &queue::make_channel(&ctx.config.rabbitmq_url).await,
-                json!({
-                    "module_id": module_id,
-                    "job_id": job.job_id,
-                    "workspace_id": module.workspace_id,
-                    "file_id": "",
-                    "input": input,
-                    "prompt": prompt,
-                    "user_id": auth_user.user_id,
-                    "separator": separtor,
-                    "reference": reference,
-                    "model_name": model_name,
-                }),
-            )
-            .await;
-        }
+            })
+            .collect();
+        batches.push(RunBatch {
+            file_id: None,
+            inputs,
+        });
     }
 
     let files = sqlx::query!(
@@ -1005,117 +1279,372 @@ async fn handle_run_module(
                 input: String,
                 reference: String,
             }
-            let mut reader = csv::Reader::from_path(&file_path).unwrap();
-            let csv_data = reader
-                .deserialize::<CsvRecord>()
-                .map(|r| r.unwrap())
-                .collect::<Vec<CsvRecord>>();
-            let job = sqlx::query!(
-                r#"insert into job_v2 (module_id, config_data, workspace_id, target_count) values ($1, $2, $3, $4) returning job_id"#,
-                module_id,
-                json!({}),
-                module.workspace_id,
-                csv_data.len() as i32
-            )
-            .fetch_one(&ctx.db)
-            .await?;
-            let model_name = if module.module_category == "generator" {
-                "gpt-3.5-turbo-1106"
-            } else {
-                "gpt-4-1106-preview"
+            let invalid_csv = |e: csv::Error| {
+                Error::unprocessable_entity([("files", format!("{}: {}", file.file_name, e))])
             };
-            for data in csv_data {
-                let input = data.input;
-                let reference = data.reference;
-                queue::publish_message_evo(
-                    &queue::make_channel(&ctx.config.rabbitmq_url).await,
-                    json!({
-                        "module_id": module_id,
-                        "job_id": job.job_id,
-                        "workspace_id": module.workspace_id,
-                        "file_id": "",
-                        "input": input,
-                        "prompt": prompt,
-                        "user_id": auth_user.user_id,
-                        "separator": separtor,
-                        "reference": reference,
-                        "model_name": model_name,
-                    }),
-                )
-                .await;
-            }
+            let mut reader = csv::Reader::from_path(&file_path).map_err(invalid_csv)?;
+            let inputs = reader
+                .deserialize::<CsvRecord>()
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|record| {
+                    let record = record.map_err(invalid_csv)?;
+                    Ok(RunInput {
+                        input: record.input,
+                        reference: record.reference,
+                    })
+                })
+                .collect::<Result<_>>()?;
+            batches.push(RunBatch {
+                file_id: None,
+                inputs,
+            });
+        } else if limit.is_some() {
+            batches.push(RunBatch {
+                file_id: Some(file.file_id),
+                inputs: Vec::new(),
+            });
         } else {
-            let client = reqwest::Client::builder().build().unwrap();
-
-            let mut headers = reqwest::header::HeaderMap::new();
-            headers.insert("Accept", "application/json".parse().unwrap());
-
-            let form = reqwest::multipart::Form::new()
-                .part(
-                    "files",
-                    reqwest::multipart::Part::bytes(std::fs::read(file_path).unwrap())
-                        .file_name(file.file_name),
-                )
-                .text("strategy", "auto")
-                .text("chunking_strategy", "by_title")
-                .text("new_after_n_chars", "500")
-                .text("max_characters", "1000")
-                .text("combine_under_n_chars", "500");
-
-            let request = client
-                .request(
-                    reqwest::Method::POST,
-                    format!("{}/general/v0/general", &ctx.config.unstructured_url),
-                )
-                .headers(headers)
-                .multipart(form);
-
-            let response = request.send().await.unwrap();
-            let body = response.json::<serde_json::Value>().await.unwrap();
-            let body = body.as_array().unwrap();
-            log::info!("extracted: count: {}", body.len());
-            let job = sqlx::query!(
-                r#"insert into job_v2 (module_id, config_data, workspace_id, target_count) values ($1, $2, $3, $4) returning job_id"#,
-                module_id,
-                json!({}),
-                module.workspace_id,
-                body.len() as i32
+            let inputs = extract_chunks(&ctx.config, &file_path, file.file_name).await?;
+            log::info!("extracted: count: {}", inputs.len());
+            batches.push(RunBatch {
+                file_id: Some(file.file_id),
+                inputs,
+            });
+        }
+    }
+
+    Ok(batches)
+}
+
+/// Have the unstructured service split a file into chunks, one input each.
+async fn extract_chunks(
+    config: &Config,
+    file_path: &Path,
+    file_name: String,
+) -> anyhow::Result<Vec<RunInput>> {
+    let content = tokio::fs::read(file_path)
+        .await
+        .with_context(|| format!("failed to read {}", file_path.display()))?;
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "files",
+            reqwest::multipart::Part::bytes(content).file_name(file_name),
+        )
+        .text("strategy", "auto")
+        .text("chunking_strategy", "by_title")
+        .text("new_after_n_chars", "500")
+        .text("max_characters", "1000")
+        .text("combine_under_n_chars", "500");
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/general/v0/general", &config.unstructured_url))
+        .header(header::ACCEPT, "application/json")
+        .multipart(form)
+        .send()
+        .await
+        .context("failed to reach the unstructured service")?
+        .error_for_status()
+        .context("the unstructured service turned the file down")?;
+    let body = response.json::<Vec<serde_json::Value>>().await?;
+
+    Ok(body
+        .iter()
+        .filter_map(|item| item["text"].as_str())
+        .map(|text| RunInput {
+            input: text.to_string(),
+            reference: String::new(),
+        })
+        .collect())
+}
+
+async fn handle_run_module(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<ModuleBody<ModuleRunRequest>>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let module_id = req.module.module_id;
+    let module = sqlx::query!(
+        r#"select
+            config_data,
+            workspace_id,
+            template_id,
+            module_category
+        from module_v2 where module_id = $1"#,
+        module_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        module.workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    // A prompt or params that don't work shouldn't cost the module the results it has.
+    let run = ModuleRun::prepare(
+        &ctx,
+        module_id,
+        module.workspace_id,
+        auth_user.user_id,
+        &module.module_category,
+        module.config_data,
+    )
+    .await?;
+
+    // Jobs are cancelled before their candidates are cleared out; see `execute_job_evo`.
+    // Those of pipeline stages are left to their run; see `handle_reset_module`.
+    let _update_jobs = sqlx::query!(
+        r#"update job_v2 set job_status = $2
+        where module_id = $1 and job_status <> $2
+          and not exists (select 1 from pipeline_stage s where s.job_id = job_v2.job_id)"#,
+        module_id,
+        JobStatusV2::Cancelled as i32
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    let _clean_candidate = sqlx::query!(
+        r#"delete from candidate_v2
+        where module_id = $1
+          and not exists (select 1 from pipeline_stage s where s.job_id = candidate_v2.job_id)"#,
+        module_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    let _reset_files = sqlx::query!(
+        r#"update file_module set finish_process = false where module_id = $1"#,
+        module_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    for batch in run_batches(&ctx, module_id, &run.module_config, Inputs::All).await? {
+        let (job_id, priority) = run
+            .create_job(
+                &ctx.db,
+                &ctx.config,
+                req.module.priority,
+                batch.inputs.len(),
             )
-            .fetch_one(&ctx.db)
             .await?;
+        run.queue_job(&ctx, job_id, priority, batch).await?;
+    }
 
-            let model_name = if module.module_category == "generator" {
-                "gpt-3.5-turbo-1106"
-            } else {
-                "gpt-4-1106-preview"
-            };
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({}),
+    }))
+}
+
+/// Check a module's run without running it.
+///
+/// Renders the prompt against every input the run would send, and reports keys the prompt
+/// uses that the module doesn't provide, model parameters the model doesn't accept, inputs
+/// whose prompt doesn't fit the model's context window, and how many tokens and dollars each
+/// input and the whole run may take. Only a sample of the rendered prompts is sent back.
+/// Preprocess steps aren't run, so their answers count as empty, unless the module's keys
+/// give them a value. Inputs whose prompt doesn't render are left out of the estimate, and
+/// listed in `renderErrors` instead.
+async fn handle_validate_module(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<ModuleBody<ModuleValidateRequest>>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let module_id = req.module.module_id;
+    let sample_size = req
+        .module
+        .sample_size
+        .unwrap_or(VALIDATE_SAMPLE_SIZE)
+        .min(MAX_VALIDATE_SAMPLE_SIZE);
+    let module = sqlx::query!(
+        r#"select
+            config_data,
+            workspace_id,
+            module_category
+        from module_v2 where module_id = $1"#,
+        module_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
 
-            for item in body {
-                let input = item["text"].as_str().unwrap().to_string();
-                queue::publish_message_evo(
-                    &queue::make_channel(&ctx.config.rabbitmq_url).await,
-                    json!({
-                        "module_id": module_id,
-                        "job_id": job.job_id,
-                        "workspace_id": module.workspace_id,
-                        "file_id": file.file_id,
-                        "input": input,
-                        "prompt": prompt,
-                        "user_id": auth_user.user_id,
-                        "separator": separtor,
-                        "reference": "",
-                        "model_name": model_name,
-                    }),
-                )
-                .await;
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        module.workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    // Reads the module's data like a run does.
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    let module_config = module.config_data.as_object().cloned().unwrap_or_default();
+    let mut keys = template::config_keys(&module_config);
+    for key in Preprocess::from_config(&module_config)?.output_keys(Steps::All) {
+        keys.entry(key)
+            .or_insert_with(|| serde_json::Value::String(String::new()));
+    }
+    let provider = ProviderConfig::from_module_config(&module.config_data)?;
+    let params = match ModelParams::from_module_config(
+        &module.config_data,
+        &provider,
+        default_model(&module.module_category),
+    ) {
+        Ok(params) => params,
+        Err(e) => {
+            return Ok(Json(CommonResponse {
+                code: 200,
+                message: "success".to_string(),
+                data: json!({ "valid": false, "paramsError": e.to_string() }),
+            }))
+        }
+    };
+    let model = openai::model_info(&params.model);
+    let max_output = params.max_tokens as usize;
+    let system_tokens = params.system_prompt.as_deref().map_or(0, count_tokens);
+
+    let source = module_config["prompt"].as_str().unwrap_or_default();
+    let prompt_version = template::prompt_version(&module_config);
+    let template = match Template::parse_version(source, prompt_version) {
+        Ok(template) => template,
+        Err(e) => {
+            return Ok(Json(CommonResponse {
+                code: 200,
+                message: "success".to_string(),
+                data: json!({
+                    "valid": false,
+                    "templateError": e,
+                    "model": model,
+                    "params": params,
+                }),
+            }))
+        }
+    };
+    let missing_keys = template
+        .keys()
+        .into_iter()
+        .filter(|key| {
+            let is_key = |key: &str| key == "input" || key == "reference" || keys.contains_key(key);
+            template::resolve_key(key, is_key).is_none()
+        })
+        .collect::<Vec<_>>();
+
+    let mut items = Vec::new();
+    let mut samples = Vec::new();
+    let mut render_errors = Vec::new();
+    let mut input_tokens = 0;
+    let mut largest_tokens = 0;
+    let mut index = 0;
+    let batches = run_batches(
+        &ctx,
+        module_id,
+        &module_config,
+        Inputs::Sample(MAX_VALIDATE_INPUTS),
+    )
+    .await?;
+    let unchecked_files = batches
+        .iter()
+        .filter(|batch| batch.inputs.is_empty())
+        .filter_map(|batch| batch.file_id)
+        .collect::<Vec<_>>();
+    let sampled = !unchecked_files.is_empty()
+        || batches
+            .iter()
+            .any(|batch| batch.inputs.len() >= MAX_VALIDATE_INPUTS);
+    for batch in batches {
+        for item in batch.inputs {
+            let context = Context::new()
+                .with_keys(keys.clone())
+                .with_key("input", item.input.as_str())
+                .with_key("reference", item.reference.as_str());
+            match template.render(&context) {
+                Ok(prompt) => {
+                    let tokens = system_tokens + count_tokens(&prompt);
+                    let exceeds_context =
+                        model.is_some_and(|model| tokens + max_output > model.context_window);
+                    input_tokens += tokens;
+                    largest_tokens = largest_tokens.max(tokens);
+                    let cost = model.map(|model| {
+                        json!({
+                            "input": model.cost(tokens, 0),
+                            "max": model.cost(tokens, max_output),
+                        })
+                    });
+                    items.push(json!({
+                        "index": index,
+                        "fileId": batch.file_id,
+                        "tokens": tokens,
+                        "cost": cost,
+                        "exceedsContext": exceeds_context,
+                    }));
+                    if samples.len() < sample_size {
+                        samples.push(json!({
+                            "index": index,
+                            "input": item.input,
+                            "prompt": prompt,
+                            "tokens": tokens,
+                        }));
+                    }
+                }
+                Err(e) => render_errors.push(json!({ "index": index, "error": e })),
             }
+            index += 1;
         }
     }
 
+    let exceeding = items
+        .iter()
+        .filter(|item| item["exceedsContext"] == json!(true))
+        .count();
+    let max_output_tokens = items.len() * max_output;
+    let cost = model.map(|model| {
+        json!({
+            "input": model.cost(input_tokens, 0),
+            "maxOutput": model.cost(0, max_output_tokens),
+            "max": model.cost(input_tokens, max_output_tokens),
+        })
+    });
+
     Ok(Json(CommonResponse {
         code: 200,
         message: "success".to_string(),
-        data: json!({}),
+        data: json!({
+            "valid": missing_keys.is_empty() && render_errors.is_empty() && exceeding == 0,
+            "missingKeys": missing_keys,
+            "model": model,
+            "params": params,
+            "itemCount": index,
+            "sampled": sampled,
+            "uncheckedFileIds": unchecked_files,
+            "exceedingCount": exceeding,
+            "items": items,
+            "samples": samples,
+            "renderErrors": render_errors,
+            "estimate": {
+                "itemCount": items.len(),
+                "inputTokens": input_tokens,
+                "maxOutputTokens": max_output_tokens,
+                "largestItemTokens": largest_tokens,
+                "cost": cost,
+            },
+        }),
     }))
 }
 
@@ -1196,12 +1725,15 @@ async fn handle_save_data(
     .await?
     .ok_or_else(|| Error::Forbidden)?;
 
+    // Candidates of pipeline stages stay with their run, whose later stages read them.
     let candidates = sqlx::query!(
         r#"select
             content,
             module_id,
             extra_data
-        from candidate_v2 where module_id = $1"#,
+        from candidate_v2
+        where module_id = $1
+          and not exists (select 1 from pipeline_stage s where s.job_id = candidate_v2.job_id)"#,
         module_id
     )
     .fetch_all(&ctx.db)
@@ -1210,7 +1742,9 @@ async fn handle_save_data(
     let job_ids_record = sqlx::query!(
         r#"select
             distinct job_id
-        from candidate_v2 where module_id = $1"#,
+        from candidate_v2
+        where module_id = $1
+          and not exists (select 1 from pipeline_stage s where s.job_id = candidate_v2.job_id)"#,
         module_id
     )
     .fetch_all(&ctx.db)
@@ -1255,15 +1789,18 @@ async fn handle_save_data(
     }
 
     let _clean_candidate = sqlx::query!(
-        r#"delete from candidate_v2 where module_id = $1"#,
+        r#"delete from candidate_v2
+        where module_id = $1
+          and not exists (select 1 from pipeline_stage s where s.job_id = candidate_v2.job_id)"#,
         module_id
     )
     .execute(&ctx.db)
     .await?;
 
     let _update_job_status = sqlx::query!(
-        r#"update job_v2 set job_status = 1 where job_id = any($1)"#,
-        &job_ids
+        r#"update job_v2 set job_status = $2 where job_id = any($1)"#,
+        &job_ids,
+        JobStatusV2::Cancelled as i32
     )
     .execute(&ctx.db)
     .await?;
@@ -0,0 +1,1249 @@
+use crate::graph;
+use crate::http::extractor::AuthUser;
+use crate::http::types::Timestamptz;
+use crate::http::ApiContext;
+use crate::http::{Error, Result};
+use crate::queue;
+use crate::queue::JobStatusV2;
+use axum::extract::{Query, State};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use sqlx::{PgExecutor, Postgres, Transaction};
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::modules::{run_batches, Inputs, ModuleRun, RunBatch, RunInput};
+use crate::http::CommonResponse;
+
+pub(crate) fn router() -> Router<ApiContext> {
+    Router::new()
+        .route(
+            "/v2/pipeline",
+            post(handle_new_pipeline).get(handle_pipeline_info),
+        )
+        .route("/v2/pipeline/list", get(handle_list_pipeline))
+        .route("/v2/pipeline/save", post(handle_save_pipeline))
+        .route(
+            "/v2/pipeline/run",
+            post(handle_run_pipeline).get(handle_pipeline_run_info),
+        )
+        .route("/v2/pipeline/run/resume", post(handle_resume_pipeline_run))
+        .route("/v2/pipeline/run/cancel", post(handle_cancel_pipeline_run))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct PipelineBody<T> {
+    pipeline: T,
+}
+
+/// A stage of a pipeline, as kept in the `stages` of its `config_data`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StageConfig {
+    /// Names the stage within its pipeline.
+    key: String,
+    module_id: Uuid,
+    /// The stages whose candidates are this stage's inputs, each with the input it answered
+    /// as its reference. A stage after none works on its module's assigned data and files,
+    /// like a run of the module.
+    #[serde(default)]
+    after: Vec<String>,
+    /// Which candidates of the stages before it the stage takes; all of them by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filter: Option<StageFilter>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StageFilter {
+    /// Only candidates rated at least this, e.g. by an evaluator. Unrated ones are left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_rating: Option<f64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, sqlx::Type, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+#[repr(i32)]
+enum PipelineRunStatus {
+    /// Some stage is running or may still start.
+    Running = 0,
+    Succeeded = 1,
+    /// A stage failed and nothing else can go on until the run is resumed.
+    Failed = 2,
+    Cancelled = 3,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, sqlx::Type, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+#[repr(i32)]
+enum StageStatus {
+    /// Waiting for the stages before it.
+    Pending = 0,
+    /// Its job is queued.
+    Running = 1,
+    /// Every input of its job was answered, or it had no inputs.
+    Succeeded = 2,
+    /// It couldn't start, or some inputs of its job were given up on or it was cancelled.
+    Failed = 3,
+    Cancelled = 4,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PipelineNewRequest {
+    pipeline_name: String,
+    workspace_id: Uuid,
+    stages: Vec<StageConfig>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PipelineInfoRequest {
+    pipeline_id: Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PipelineListRequest {
+    workspace_id: Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PipelineSaveRequest {
+    pipeline_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pipeline_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stages: Option<Vec<StageConfig>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PipelineRunRequest {
+    pipeline_id: Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PipelineRunOperateRequest {
+    pipeline_run_id: Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PipelineFromSql {
+    pipeline_id: Uuid,
+    pipeline_name: String,
+    workspace_id: Uuid,
+    config_data: Value,
+    created_at: Timestamptz,
+    updated_at: Option<Timestamptz>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PipelineRunFromSql {
+    pipeline_run_id: Uuid,
+    run_status: PipelineRunStatus,
+    created_at: Timestamptz,
+    updated_at: Option<Timestamptz>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StageFromSql {
+    stage_key: String,
+    module_id: Uuid,
+    job_id: Option<Uuid>,
+    stage_status: StageStatus,
+    error: Option<String>,
+    target_count: Option<i32>,
+    completed_count: i32,
+    failed_count: i32,
+    updated_at: Option<Timestamptz>,
+}
+
+/// The order the stages can run in, each after the stages it depends on.
+///
+/// Fails on duplicate or unknown stage keys and on cycles.
+fn stage_order(stages: &[StageConfig]) -> Result<Vec<usize>> {
+    let invalid = |reason: String| Error::unprocessable_entity([("stages", reason)]);
+    if stages.is_empty() {
+        return Err(invalid("a pipeline needs at least one stage".to_string()));
+    }
+    let mut index = HashMap::new();
+    for (i, stage) in stages.iter().enumerate() {
+        if stage.key.is_empty() {
+            return Err(invalid("every stage needs a key".to_string()));
+        }
+        if index.insert(stage.key.as_str(), i).is_some() {
+            return Err(invalid(format!("stage `{}` is defined twice", stage.key)));
+        }
+    }
+
+    let mut edges = Vec::new();
+    for (i, stage) in stages.iter().enumerate() {
+        for after in &stage.after {
+            let Some(&j) = index.get(after.as_str()) else {
+                return Err(invalid(format!(
+                    "stage `{}` comes after `{}`, which isn't a stage",
+                    stage.key, after
+                )));
+            };
+            edges.push((j, i));
+        }
+    }
+
+    graph::toposort(stages.len(), edges).map_err(|waiting| {
+        let cycle = waiting
+            .into_iter()
+            .map(|i| format!("`{}`", stages[i].key))
+            .collect::<Vec<_>>()
+            .join(", ");
+        invalid(format!("stages {} wait on each other", cycle))
+    })
+}
+
+/// Check that the stages form a DAG of modules of `workspace_id`.
+async fn check_stages(ctx: &ApiContext, workspace_id: Uuid, stages: &[StageConfig]) -> Result<()> {
+    stage_order(stages)?;
+    let module_ids = stages.iter().map(|s| s.module_id).collect::<Vec<_>>();
+    let found = sqlx::query_scalar!(
+        r#"select module_id from module_v2 where workspace_id = $1 and module_id = any($2)"#,
+        workspace_id,
+        &module_ids
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+    if let Some(stage) = stages.iter().find(|s| !found.contains(&s.module_id)) {
+        return Err(Error::unprocessable_entity([(
+            "stages",
+            format!("stage `{}` uses a module not in this workspace", stage.key),
+        )]));
+    }
+
+    Ok(())
+}
+
+fn run_stages(config_data: &Value) -> Result<Vec<StageConfig>> {
+    Ok(serde_json::from_value(config_data["stages"].clone()).map_err(anyhow::Error::from)?)
+}
+
+async fn handle_new_pipeline(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<PipelineBody<PipelineNewRequest>>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let workspace_id = req.pipeline.workspace_id;
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    check_stages(&ctx, workspace_id, &req.pipeline.stages).await?;
+    let pipeline = sqlx::query!(
+        // language=PostgreSQL
+        r#"insert into pipeline (pipeline_name, workspace_id, config_data)
+        values ($1, $2, $3)
+        returning pipeline_id"#,
+        req.pipeline.pipeline_name,
+        workspace_id,
+        json!({ "stages": req.pipeline.stages }),
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "pipeline": {
+                "pipelineId": pipeline.pipeline_id,
+            }
+        }),
+    }))
+}
+
+/// A pipeline, with its latest runs.
+async fn handle_pipeline_info(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Query(req): Query<PipelineInfoRequest>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let pipeline = sqlx::query_as!(
+        PipelineFromSql,
+        r#"select
+            pipeline_id,
+            pipeline_name,
+            workspace_id,
+            config_data,
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz"
+        from pipeline where pipeline_id = $1"#,
+        req.pipeline_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        pipeline.workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    let runs = sqlx::query_as!(
+        PipelineRunFromSql,
+        r#"select
+            pipeline_run_id,
+            run_status "run_status: PipelineRunStatus",
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz"
+        from pipeline_run where pipeline_id = $1
+        order by created_at desc
+        limit 20"#,
+        req.pipeline_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({ "pipeline": pipeline, "runs": runs }),
+    }))
+}
+
+async fn handle_list_pipeline(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Query(req): Query<PipelineListRequest>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let workspace_id = req.workspace_id;
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    let pipelines = sqlx::query_as!(
+        PipelineFromSql,
+        r#"select
+            pipeline_id,
+            pipeline_name,
+            workspace_id,
+            config_data,
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz"
+        from pipeline where workspace_id = $1
+        order by created_at"#,
+        workspace_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({ "pipelines": pipelines }),
+    }))
+}
+
+/// Rename a pipeline or change its stages. Runs already started keep the stages they had.
+async fn handle_save_pipeline(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<PipelineBody<PipelineSaveRequest>>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let pipeline_id = req.pipeline.pipeline_id;
+    let pipeline = sqlx::query!(
+        r#"select workspace_id from pipeline where pipeline_id = $1"#,
+        pipeline_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        pipeline.workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    if let Some(stages) = &req.pipeline.stages {
+        check_stages(&ctx, pipeline.workspace_id, stages).await?;
+    }
+    sqlx::query!(
+        r#"update pipeline set
+            pipeline_name = coalesce($2, pipeline_name),
+            config_data = coalesce($3, config_data)
+        where pipeline_id = $1"#,
+        pipeline_id,
+        req.pipeline.pipeline_name,
+        req.pipeline
+            .stages
+            .map(|stages| json!({ "stages": stages })),
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({}),
+    }))
+}
+
+/// Start a run of a pipeline. Its first stages are queued straight away, and the rest as the
+/// stages before them finish; see `advance_run`.
+async fn handle_run_pipeline(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<PipelineBody<PipelineRunRequest>>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let pipeline_id = req.pipeline.pipeline_id;
+    let pipeline = sqlx::query!(
+        r#"select workspace_id, config_data from pipeline where pipeline_id = $1"#,
+        pipeline_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        pipeline.workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    let stages = run_stages(&pipeline.config_data)?;
+    check_stages(&ctx, pipeline.workspace_id, &stages).await?;
+
+    let mut tx = ctx.db.begin().await?;
+    let run = sqlx::query!(
+        r#"insert into pipeline_run (pipeline_id, workspace_id, user_id, config_data)
+        values ($1, $2, $3, $4)
+        returning pipeline_run_id"#,
+        pipeline_id,
+        pipeline.workspace_id,
+        auth_user.user_id,
+        pipeline.config_data,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    for stage in &stages {
+        sqlx::query!(
+            r#"insert into pipeline_stage (pipeline_run_id, stage_key, module_id) values ($1, $2, $3)"#,
+            run.pipeline_run_id,
+            stage.key,
+            stage.module_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    advance_run(&ctx, run.pipeline_run_id).await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "pipelineRun": {
+                "pipelineRunId": run.pipeline_run_id,
+            }
+        }),
+    }))
+}
+
+/// A run of a pipeline, with where each of its stages stands.
+async fn handle_pipeline_run_info(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Query(req): Query<PipelineRunOperateRequest>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let pipeline_run_id = req.pipeline_run_id;
+    let run = sqlx::query!(
+        r#"select
+            pipeline_id,
+            workspace_id,
+            config_data,
+            run_status "run_status: PipelineRunStatus",
+            created_at "created_at: Timestamptz",
+            updated_at "updated_at: Timestamptz"
+        from pipeline_run where pipeline_run_id = $1"#,
+        pipeline_run_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        run.workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    let mut stages = sqlx::query_as!(
+        StageFromSql,
+        r#"select
+            s.stage_key,
+            s.module_id,
+            s.job_id,
+            s.stage_status "stage_status: StageStatus",
+            s.error,
+            j.target_count "target_count?",
+            coalesce(j.completed_count, 0) "completed_count!",
+            coalesce(j.failed_count, 0) "failed_count!",
+            s.updated_at "updated_at: Timestamptz"
+        from pipeline_stage s
+        left join job_v2 j on j.job_id = s.job_id
+        where s.pipeline_run_id = $1"#,
+        pipeline_run_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+    // In the order they run in.
+    let order = run_stages(&run.config_data)?
+        .into_iter()
+        .map(|stage| stage.key)
+        .collect::<Vec<_>>();
+    stages.sort_by_key(|stage| order.iter().position(|key| *key == stage.stage_key));
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({
+            "pipelineRun": {
+                "pipelineRunId": pipeline_run_id,
+                "pipelineId": run.pipeline_id,
+                "runStatus": run.run_status,
+                "configData": run.config_data,
+                "createdAt": run.created_at,
+                "updatedAt": run.updated_at,
+                "stages": stages,
+            }
+        }),
+    }))
+}
+
+/// Pick a failed run up from its failed stages.
+///
+/// A failed stage whose job gave up on some inputs has just those inputs sent again. One that
+/// couldn't start, or whose job was cancelled, starts over with a new job.
+async fn handle_resume_pipeline_run(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<PipelineBody<PipelineRunOperateRequest>>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let pipeline_run_id = req.pipeline.pipeline_run_id;
+    let workspace_id = sqlx::query_scalar!(
+        r#"select workspace_id from pipeline_run where pipeline_run_id = $1"#,
+        pipeline_run_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    // The run is locked while its stages are rewritten, so the pipeline driver can't see the
+    // run running again before its failed stages are, and fail it all over.
+    let mut tx = ctx.db.begin().await?;
+    let run_status = sqlx::query_scalar!(
+        r#"select run_status "run_status: PipelineRunStatus" from pipeline_run
+        where pipeline_run_id = $1
+        for update"#,
+        pipeline_run_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if run_status != PipelineRunStatus::Failed {
+        return Err(Error::unprocessable_entity([(
+            "runStatus",
+            format!("This run is {:?}", run_status).to_lowercase(),
+        )]));
+    }
+
+    let mut redriven = Vec::new();
+    let failed = sqlx::query!(
+        r#"select stage_key, job_id from pipeline_stage where pipeline_run_id = $1 and stage_status = $2"#,
+        pipeline_run_id,
+        StageStatus::Failed as i32,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for stage in failed {
+        let failures = sqlx::query!(
+            r#"select failure_id, queue_name, payload from job_failure
+            where job_id = $1 and redriven_at is null"#,
+            stage.job_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        if failures.is_empty() {
+            if let Some(job_id) = stage.job_id {
+                sqlx::query!(
+                    r#"update job_v2 set job_status = $2 where job_id = $1 and job_status <> $2"#,
+                    job_id,
+                    JobStatusV2::Cancelled as i32
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            sqlx::query!(
+                r#"update pipeline_stage set stage_status = $3, error = null, job_id = null
+                where pipeline_run_id = $1 and stage_key = $2"#,
+                pipeline_run_id,
+                stage.stage_key,
+                StageStatus::Pending as i32,
+            )
+            .execute(&mut *tx)
+            .await?;
+            continue;
+        }
+
+        // Marked now, so the job doesn't look finished, and the stage failed, before they're
+        // sent again; they're only sent once the run is unlocked.
+        for failure in &failures {
+            queue::mark_redriven(&mut *tx, failure.failure_id).await?;
+        }
+        set_stage(
+            &mut *tx,
+            pipeline_run_id,
+            &stage.stage_key,
+            StageStatus::Running,
+            None,
+        )
+        .await?;
+        redriven.push((stage.stage_key, failures));
+    }
+    sqlx::query!(
+        r#"update pipeline_run set run_status = $1 where pipeline_run_id = $2"#,
+        PipelineRunStatus::Running as i32,
+        pipeline_run_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    for (stage_key, failures) in redriven {
+        for (i, failure) in failures.iter().enumerate() {
+            let sent = queue::redrive(&ctx.publisher, &failure.queue_name, &failure.payload).await;
+            if let Err(e) = sent {
+                // Those that weren't sent count again, for resuming to send them once more.
+                let mut tx = ctx.db.begin().await?;
+                sqlx::query!(
+                    r#"select pipeline_run_id from pipeline_run where pipeline_run_id = $1 for update"#,
+                    pipeline_run_id
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+                for failure in &failures[i..] {
+                    queue::reopen_failure(&mut *tx, failure.failure_id).await?;
+                }
+                set_stage(
+                    &mut *tx,
+                    pipeline_run_id,
+                    &stage_key,
+                    StageStatus::Failed,
+                    Some(e.to_string()),
+                )
+                .await?;
+                tx.commit().await?;
+                break;
+            }
+        }
+    }
+
+    advance_run(&ctx, pipeline_run_id).await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({}),
+    }))
+}
+
+/// Stop a run, cancelling the jobs of its running stages.
+async fn handle_cancel_pipeline_run(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<PipelineBody<PipelineRunOperateRequest>>,
+) -> Result<Json<CommonResponse>> {
+    log::info!("{:?}", req);
+    let pipeline_run_id = req.pipeline.pipeline_run_id;
+    let run = sqlx::query!(
+        r#"select workspace_id, run_status "run_status: PipelineRunStatus"
+        from pipeline_run where pipeline_run_id = $1"#,
+        pipeline_run_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let _member_record = sqlx::query!(
+        // language=PostgreSQL
+        r#"select user_level from workspace_member_v2 where workspace_id = $1 and user_id = $2"#,
+        run.workspace_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::Forbidden)?;
+
+    if _member_record.user_level > 1 {
+        return Err(Error::Forbidden);
+    }
+
+    let updated = sqlx::query!(
+        r#"update pipeline_run set run_status = $1 where pipeline_run_id = $2 and run_status = any($3)"#,
+        PipelineRunStatus::Cancelled as i32,
+        pipeline_run_id,
+        &[
+            PipelineRunStatus::Running as i32,
+            PipelineRunStatus::Failed as i32
+        ],
+    )
+    .execute(&ctx.db)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(Error::unprocessable_entity([(
+            "runStatus",
+            format!("This run is {:?}", run.run_status).to_lowercase(),
+        )]));
+    }
+
+    sqlx::query!(
+        r#"update job_v2 set job_status = $1
+        where job_status <> $1
+            and job_id in (select job_id from pipeline_stage where pipeline_run_id = $2 and stage_status = $3)"#,
+        JobStatusV2::Cancelled as i32,
+        pipeline_run_id,
+        StageStatus::Running as i32,
+    )
+    .execute(&ctx.db)
+    .await?;
+    sqlx::query!(
+        r#"update pipeline_stage set stage_status = $1 where pipeline_run_id = $2 and stage_status = any($3)"#,
+        StageStatus::Cancelled as i32,
+        pipeline_run_id,
+        &[StageStatus::Pending as i32, StageStatus::Running as i32],
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(Json(CommonResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: json!({}),
+    }))
+}
+
+async fn set_stage(
+    db: impl PgExecutor<'_>,
+    pipeline_run_id: Uuid,
+    stage_key: &str,
+    status: StageStatus,
+    error: Option<String>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"update pipeline_stage set stage_status = $3, error = $4 where pipeline_run_id = $1 and stage_key = $2"#,
+        pipeline_run_id,
+        stage_key,
+        status as i32,
+        error,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Move a running pipeline run along as far as it goes for now.
+///
+/// Running stages whose job is done succeed or fail, and pending stages whose stages before
+/// them all succeeded are started. The run succeeds once every stage has, and fails once a
+/// stage failed and nothing is left running or ready to start.
+async fn advance_run(ctx: &ApiContext, pipeline_run_id: Uuid) -> Result<()> {
+    loop {
+        let Some(ready) = settle_run(ctx, pipeline_run_id).await? else {
+            return Ok(());
+        };
+        for stage in &ready.stages {
+            start_stage(
+                ctx,
+                pipeline_run_id,
+                ready.workspace_id,
+                ready.user_id,
+                stage,
+            )
+            .await?;
+        }
+    }
+}
+
+/// Stages of a run that are ready to start.
+struct ReadyStages {
+    workspace_id: Uuid,
+    user_id: Uuid,
+    stages: Vec<StageConfig>,
+}
+
+/// Finish the running stages of a run whose job is done, and settle the run if it's done too.
+///
+/// Returns the stages that are ready to start, if there are any. The run is locked while this
+/// goes on, and its stages are read and written under the same lock, so API replicas
+/// advancing side by side skip runs another one is on.
+async fn settle_run(ctx: &ApiContext, pipeline_run_id: Uuid) -> Result<Option<ReadyStages>> {
+    let mut tx = ctx.db.begin().await?;
+    let run = sqlx::query!(
+        r#"select workspace_id, user_id, config_data from pipeline_run
+        where pipeline_run_id = $1 and run_status = $2
+        for update skip locked"#,
+        pipeline_run_id,
+        PipelineRunStatus::Running as i32,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(run) = run else {
+        return Ok(None);
+    };
+    let stages = run_stages(&run.config_data)?;
+    let order = stage_order(&stages)?;
+
+    let mut statuses = sqlx::query!(
+        r#"select stage_key, job_id, stage_status "stage_status: StageStatus"
+        from pipeline_stage where pipeline_run_id = $1"#,
+        pipeline_run_id
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|stage| (stage.stage_key, (stage.stage_status, stage.job_id)))
+    .collect::<HashMap<_, _>>();
+
+    for &i in &order {
+        let stage = &stages[i];
+        let Some(&(StageStatus::Running, Some(job_id))) = statuses.get(&stage.key) else {
+            continue;
+        };
+        if let Some((status, error)) = job_outcome(ctx, job_id).await? {
+            log::info!(
+                "pipeline run {}: stage {} is {:?}",
+                pipeline_run_id,
+                stage.key,
+                status
+            );
+            set_stage(&mut *tx, pipeline_run_id, &stage.key, status, error).await?;
+            statuses.insert(stage.key.clone(), (status, Some(job_id)));
+        }
+    }
+
+    let ready = order
+        .iter()
+        .map(|&i| &stages[i])
+        .filter(|stage| {
+            matches!(statuses.get(&stage.key), Some((StageStatus::Pending, _)))
+                && stage
+                    .after
+                    .iter()
+                    .all(|key| matches!(statuses.get(key), Some((StageStatus::Succeeded, _))))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let all = |status| statuses.values().all(|(s, _)| *s == status);
+    let any = |status| statuses.values().any(|(s, _)| *s == status);
+    // Pending stages left over once nothing runs or can start wait on a stage that failed.
+    let run_status = if all(StageStatus::Succeeded) {
+        Some(PipelineRunStatus::Succeeded)
+    } else if any(StageStatus::Failed) && !any(StageStatus::Running) && ready.is_empty() {
+        Some(PipelineRunStatus::Failed)
+    } else {
+        None
+    };
+    if let Some(run_status) = run_status {
+        log::info!("pipeline run {} is {:?}", pipeline_run_id, run_status);
+        sqlx::query!(
+            r#"update pipeline_run set run_status = $1 where pipeline_run_id = $2"#,
+            run_status as i32,
+            pipeline_run_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    if ready.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(ReadyStages {
+        workspace_id: run.workspace_id,
+        user_id: run.user_id,
+        stages: ready,
+    }))
+}
+
+/// Lock a run that is still running, and say whether `stage_key` still waits to start.
+async fn lock_pending_stage(
+    tx: &mut Transaction<'_, Postgres>,
+    pipeline_run_id: Uuid,
+    stage_key: &str,
+) -> Result<bool> {
+    let run = sqlx::query_scalar!(
+        r#"select pipeline_run_id from pipeline_run
+        where pipeline_run_id = $1 and run_status = $2
+        for update"#,
+        pipeline_run_id,
+        PipelineRunStatus::Running as i32,
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+    if run.is_none() {
+        return Ok(false);
+    }
+    let status = sqlx::query_scalar!(
+        r#"select stage_status "stage_status: StageStatus" from pipeline_stage
+        where pipeline_run_id = $1 and stage_key = $2"#,
+        pipeline_run_id,
+        stage_key,
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    Ok(status == Some(StageStatus::Pending))
+}
+
+/// What became of a running stage's job, if it's done.
+async fn job_outcome(
+    ctx: &ApiContext,
+    job_id: Uuid,
+) -> Result<Option<(StageStatus, Option<String>)>> {
+    let job = sqlx::query!(
+        r#"select
+            job_status "job_status: JobStatusV2",
+            target_count,
+            completed_count,
+            failed_count
+        from job_v2 where job_id = $1"#,
+        job_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?;
+    let Some(job) = job else {
+        return Ok(Some((
+            StageStatus::Failed,
+            Some("the stage's job is gone".to_string()),
+        )));
+    };
+
+    let outcome = if job.job_status == JobStatusV2::Cancelled {
+        Some((
+            StageStatus::Failed,
+            Some("the stage's job was cancelled".to_string()),
+        ))
+    } else if job.completed_count + job.failed_count < job.target_count {
+        None
+    } else if job.failed_count > 0 {
+        Some((
+            StageStatus::Failed,
+            Some(format!(
+                "{} of {} inputs failed",
+                job.failed_count, job.target_count
+            )),
+        ))
+    } else {
+        Some((StageStatus::Succeeded, None))
+    };
+
+    Ok(outcome)
+}
+
+/// Start a stage whose stages before it all succeeded.
+///
+/// The module's shared preprocess steps and extracting its files can take long, so they run
+/// without the run's lock. The stage's job is then created under the lock, if the stage still
+/// waits to start, and its messages are only published once that is committed. A stage with no
+/// inputs succeeds straight away, and one that can't start fails.
+async fn start_stage(
+    ctx: &ApiContext,
+    pipeline_run_id: Uuid,
+    workspace_id: Uuid,
+    user_id: Uuid,
+    stage: &StageConfig,
+) -> Result<()> {
+    let prepared = prepare_stage(ctx, pipeline_run_id, workspace_id, user_id, stage).await;
+
+    let mut tx = ctx.db.begin().await?;
+    // Another replica started it while this one was preparing, or the run was cancelled.
+    if !lock_pending_stage(&mut tx, pipeline_run_id, &stage.key).await? {
+        return Ok(());
+    }
+    let (run, batches) = match prepared {
+        Ok(Some(prepared)) => prepared,
+        Ok(None) => {
+            log::info!(
+                "pipeline run {}: stage {} has no inputs",
+                pipeline_run_id,
+                stage.key
+            );
+            set_stage(
+                &mut *tx,
+                pipeline_run_id,
+                &stage.key,
+                StageStatus::Succeeded,
+                None,
+            )
+            .await?;
+            tx.commit().await?;
+            return Ok(());
+        }
+        Err(e) => {
+            log::info!(
+                "pipeline run {}: stage {} failed to start: {}",
+                pipeline_run_id,
+                stage.key,
+                e
+            );
+            set_stage(
+                &mut *tx,
+                pipeline_run_id,
+                &stage.key,
+                StageStatus::Failed,
+                Some(e.to_string()),
+            )
+            .await?;
+            tx.commit().await?;
+            return Ok(());
+        }
+    };
+    let target_count = batches.iter().map(|batch| batch.inputs.len()).sum();
+    let (job_id, priority) = run
+        .create_job(&mut *tx, &ctx.config, None, target_count)
+        .await?;
+    sqlx::query!(
+        r#"update pipeline_stage set job_id = $3, stage_status = $4, error = null
+        where pipeline_run_id = $1 and stage_key = $2"#,
+        pipeline_run_id,
+        stage.key,
+        job_id,
+        StageStatus::Running as i32,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    log::info!(
+        "pipeline run {}: stage {} is Running",
+        pipeline_run_id,
+        stage.key
+    );
+
+    for batch in batches {
+        if let Err(e) = run.queue_job(ctx, job_id, priority, batch).await {
+            // The stage keeps its job, which resuming the run cancels before starting over.
+            let mut tx = ctx.db.begin().await?;
+            sqlx::query!(
+                r#"select pipeline_run_id from pipeline_run where pipeline_run_id = $1 for update"#,
+                pipeline_run_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            set_stage(
+                &mut *tx,
+                pipeline_run_id,
+                &stage.key,
+                StageStatus::Failed,
+                Some(e.to_string()),
+            )
+            .await?;
+            tx.commit().await?;
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the module of a stage up to where its job can be created: its shared preprocess steps,
+/// and gathering its inputs. `None` if it has no inputs.
+async fn prepare_stage(
+    ctx: &ApiContext,
+    pipeline_run_id: Uuid,
+    workspace_id: Uuid,
+    user_id: Uuid,
+    stage: &StageConfig,
+) -> Result<Option<(ModuleRun, Vec<RunBatch>)>> {
+    let module = sqlx::query!(
+        r#"select config_data, module_category from module_v2 where module_id = $1"#,
+        stage.module_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+    let run = ModuleRun::prepare(
+        ctx,
+        stage.module_id,
+        workspace_id,
+        user_id,
+        &module.module_category,
+        module.config_data,
+    )
+    .await?;
+
+    let batches = if stage.after.is_empty() {
+        run_batches(ctx, stage.module_id, &run.module_config, Inputs::All).await?
+    } else {
+        vec![RunBatch {
+            file_id: None,
+            inputs: stage_inputs(ctx, pipeline_run_id, stage).await?,
+        }]
+    };
+    if batches.iter().all(|batch| batch.inputs.is_empty()) {
+        return Ok(None);
+    }
+
+    Ok(Some((run, batches)))
+}
+
+/// The candidates of the stages before `stage` that pass its filter, as its inputs.
+async fn stage_inputs(
+    ctx: &ApiContext,
+    pipeline_run_id: Uuid,
+    stage: &StageConfig,
+) -> Result<Vec<RunInput>> {
+    let candidates = sqlx::query!(
+        r#"select c.content, c.extra_data
+        from candidate_v2 c
+        join pipeline_stage s on s.job_id = c.job_id
+        where s.pipeline_run_id = $1 and s.stage_key = any($2)
+        order by c.created_at"#,
+        pipeline_run_id,
+        &stage.after
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    let min_rating = stage.filter.as_ref().and_then(|filter| filter.min_rating);
+    let inputs = candidates
+        .into_iter()
+        .filter(|candidate| match min_rating {
+            Some(min_rating) => candidate
+                .extra_data
+                .as_ref()
+                .and_then(|extra_data| rating(&extra_data["rating"]))
+                .is_some_and(|rating| rating >= min_rating),
+            None => true,
+        })
+        .map(|candidate| RunInput {
+            input: candidate.content,
+            reference: candidate
+                .extra_data
+                .as_ref()
+                .and_then(|extra_data| extra_data["text"].as_str())
+                .unwrap_or_default()
+                .to_string(),
+        })
+        .collect();
+
+    Ok(inputs)
+}
+
+/// A rating as evaluators write it, a number or a number in a string.
+fn rating(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Keep advancing running pipelines for as long as the process runs.
+pub(crate) fn spawn_pipeline_driver(ctx: ApiContext, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            let runs = sqlx::query_scalar!(
+                r#"select pipeline_run_id from pipeline_run where run_status = $1 order by created_at"#,
+                PipelineRunStatus::Running as i32,
+            )
+            .fetch_all(&ctx.db)
+            .await;
+            let runs = match runs {
+                Ok(runs) => runs,
+                Err(e) => {
+                    log::error!("failed to list running pipelines: {:?}", e);
+                    continue;
+                }
+            };
+            for pipeline_run_id in runs {
+                if let Err(e) = advance_run(&ctx, pipeline_run_id).await {
+                    log::error!(
+                        "failed to advance pipeline run {}: {:?}",
+                        pipeline_run_id,
+                        e
+                    );
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,70 @@
+use std::convert::Infallible;
+use std::future::Future;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream, StreamExt};
+use serde_json::json;
+
+use crate::http::Result;
+use crate::openai::ChatStream;
+
+/// Relay a streamed answer to the client as server-sent events.
+///
+/// Every piece of the answer is sent as a `delta` event with `{"content": ...}`. Once the
+/// answer is complete, `finish` is called with the full output, e.g. to save it, and
+/// whatever it returns is sent as a final `done` event. If the model or `finish` fails, an
+/// `error` event with `{"message": ...}` is sent instead and the stream ends.
+///
+/// If the client goes away mid-answer we stop reading from the model, and `finish` is not
+/// called.
+pub(crate) fn relay<F, Fut>(
+    mut deltas: ChatStream,
+    finish: F,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>>
+where
+    F: FnOnce(String) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<serde_json::Value>> + Send,
+{
+    let (mut tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut output = String::new();
+        while let Some(delta) = deltas.next().await {
+            let event = match delta {
+                Ok(delta) => {
+                    let event = Event::default()
+                        .event("delta")
+                        .data(json!({ "content": &delta }).to_string());
+                    output.push_str(&delta);
+                    event
+                }
+                Err(e) => {
+                    log::error!("streamed answer failed: {}", e);
+                    let _ = tx.send(error_event(&e.to_string())).await;
+                    return;
+                }
+            };
+            if tx.send(event).await.is_err() {
+                log::debug!("client went away, dropping the rest of the streamed answer");
+                return;
+            }
+        }
+
+        let event = match finish(output).await {
+            Ok(data) => Event::default().event("done").data(data.to_string()),
+            Err(e) => {
+                log::error!("failed to finish streamed answer: {:?}", e);
+                error_event(&e.to_string())
+            }
+        };
+        let _ = tx.send(event).await;
+    });
+
+    Sse::new(rx.map(Ok)).keep_alive(KeepAlive::default())
+}
+
+fn error_event(message: &str) -> Event {
+    Event::default()
+        .event("error")
+        .data(json!({ "message": message }).to_string())
+}
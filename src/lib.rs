@@ -1,4 +1,8 @@
 pub mod config;
+pub mod graph;
 pub mod http;
 pub mod openai;
+pub mod preprocess;
 pub mod queue;
+pub mod retrieval;
+pub mod template;
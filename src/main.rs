@@ -1,10 +1,18 @@
 use anyhow::Context;
 use clap::Parser;
+use futures::channel::mpsc;
+use futures::StreamExt;
 use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
-use claymore_backend::config::Config;
+use claymore_backend::config::{Config, Mode};
 use claymore_backend::http;
+use claymore_backend::openai;
+use claymore_backend::preprocess;
 use claymore_backend::queue;
+use claymore_backend::retrieval;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -37,11 +45,160 @@ async fn main() -> anyhow::Result<()> {
     // is migrated correctly on startup
     sqlx::migrate!("./migrations").run(&db).await?;
 
-    let mq = queue::make_channel(&config.rabbitmq_url).await;
-    queue::start_consumer(db.clone(), mq).await;
+    // Every model call, from the API or the queue workers, goes through this one handle.
+    let llm = openai::Llm::from_config(db.clone(), &config)?;
 
-    // Finally, we spin up our API.
-    http::serve(config, db).await?;
+    // Keys leased by a worker that crashed are handed back once their lease runs out.
+    openai::spawn_lease_sweeper(db.clone(), Duration::from_secs(30));
+
+    // Cached preprocess answers are only reused for so long; drop them once they're not.
+    preprocess::spawn_cache_sweeper(
+        db.clone(),
+        config.preprocess_cache_secs,
+        Duration::from_secs(600),
+    );
+
+    // Stops the API and the workers on SIGTERM or ctrl-c.
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            log::info!("shutting down");
+            shutdown.cancel();
+        }
+    });
+
+    match config.mode {
+        Mode::Api => {
+            let served = serve_api(config, db.clone(), llm, shutdown).await;
+            // Requests still running were dropped with the server, so nothing needs the keys
+            // anymore. With the workers alongside, `stop_worker` does this once they're done too.
+            release_leases(&db).await;
+            served
+        }
+        Mode::Worker => run_worker(config, db, llm, shutdown).await,
+        Mode::All => {
+            let grace = Duration::from_secs(config.shutdown_timeout_secs);
+            let connection = queue::make_connection(&config.rabbitmq_url).await?;
+            let consumers =
+                queue::start_consumer(db.clone(), &connection, llm.clone(), &config).await?;
+            // Drain the HTTP connections and the queues side by side, each within the grace.
+            let (served, ()) = tokio::join!(
+                serve_api(config, db.clone(), llm, shutdown.clone()),
+                async {
+                    shutdown.cancelled().await;
+                    consumers.shutdown(grace).await;
+                }
+            );
+            stop_worker(&db, &connection).await?;
+            served
+        }
+    }
+}
+
+/// Wait for SIGTERM, or ctrl-c when running by hand.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+async fn serve_api(
+    config: Config,
+    db: PgPool,
+    llm: openai::Llm,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    // Search indexes follow the database through the outbox, with a periodic full comparison
+    // to catch anything that slipped through.
+    let retriever = retrieval::Retriever::new(&config.es_url, llm.clone())?;
+    retrieval::spawn_index_sync(
+        db.clone(),
+        retriever.clone(),
+        Duration::from_secs(config.search_sync_secs),
+    );
+    retrieval::spawn_reconciler(
+        db.clone(),
+        retriever,
+        Duration::from_secs(config.search_reconcile_secs),
+    );
+
+    // Finally, we spin up our API. Once told to stop, open requests get the grace period to
+    // finish; long-lived ones like event streams are cut off when it's up.
+    let grace = Duration::from_secs(config.shutdown_timeout_secs);
+    let server = http::serve(config, db, llm, shutdown.clone().cancelled_owned());
+    tokio::select! {
+        result = server => result?,
+        _ = async {
+            shutdown.cancelled().await;
+            tokio::time::sleep(grace).await;
+        } => log::warn!("dropping HTTP connections still open after {:?}", grace),
+    }
 
     Ok(())
 }
+
+/// Work on the queues until told to stop, or until the connection to RabbitMQ is lost.
+///
+/// A lost connection is an error, so that whatever supervises the worker restarts it.
+async fn run_worker(
+    config: Config,
+    db: PgPool,
+    llm: openai::Llm,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let connection = queue::make_connection(&config.rabbitmq_url).await?;
+    let (error_tx, mut error_rx) = mpsc::unbounded();
+    connection.on_error(move |e| {
+        let _ = error_tx.unbounded_send(e);
+    });
+    let consumers = queue::start_consumer(db.clone(), &connection, llm, &config).await?;
+    log::info!("worker started");
+
+    tokio::select! {
+        _ = shutdown.cancelled() => {
+            log::info!("worker stopping");
+            consumers
+                .shutdown(Duration::from_secs(config.shutdown_timeout_secs))
+                .await;
+            stop_worker(&db, &connection).await
+        }
+        Some(e) = error_rx.next() => Err(e).context("lost connection to rabbitmq"),
+    }
+}
+
+/// Hand back the keys still leased and close the connection, once the consumers have stopped.
+async fn stop_worker(db: &PgPool, connection: &lapin::Connection) -> anyhow::Result<()> {
+    release_leases(db).await;
+    connection.close(200, "worker stopping").await?;
+
+    Ok(())
+}
+
+/// Hand back the keys this process still holds, once nothing is left that uses them.
+async fn release_leases(db: &PgPool) {
+    match openai::release_held_leases(db).await {
+        Ok(0) => {}
+        Ok(released) => log::info!("released {} key leases", released),
+        // The sweeper reclaims them once they expire.
+        Err(e) => log::error!("failed to release key leases: {}", e),
+    }
+}
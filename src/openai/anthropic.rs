@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_json::json;
+
+use crate::openai::provider::{no_redirects, LlmProvider};
+use crate::openai::{ChatRequest, Error, PinnedHost};
+
+const DEFAULT_API_BASE: &str = "https://api.anthropic.com";
+const API_VERSION: &str = "2023-06-01";
+
+/// Anthropic's Messages API.
+///
+/// `async_openai` can't talk to it, so this is a small hand-rolled client on top of `reqwest`.
+pub struct AnthropicProvider {
+    client: reqwest::Client,
+    api_base: String,
+    api_key: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_base: Option<&str>, api_key: &str, pinned: Option<&PinnedHost>) -> Self {
+        Self {
+            client: no_redirects(pinned),
+            api_base: api_base
+                .unwrap_or(DEFAULT_API_BASE)
+                .trim_end_matches('/')
+                .to_string(),
+            api_key: api_key.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<String, Error> {
+        let mut messages = Vec::new();
+        for h in request.history.unwrap_or_default() {
+            messages.push(json!({ "role": "user", "content": h.user_input }));
+            messages.push(json!({ "role": "assistant", "content": h.ai_output }));
+        }
+        messages.push(json!({ "role": "user", "content": request.input }));
+
+        // Anthropic has no seed; the catalog keeps modules from asking for one.
+        let mut body = json!({
+            "model": request.model,
+            "max_tokens": request.max_tokens.unwrap_or(2048),
+            "temperature": request.temperature.unwrap_or(0.1),
+            "messages": messages,
+        });
+        if let Some(system) = request.system {
+            body["system"] = json!(system);
+        }
+        if let Some(top_p) = request.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(stop) = request.stop {
+            body["stop_sequences"] = json!(stop);
+        }
+
+        let response = self
+            .client
+            .post(format!("{}/v1/messages", self.api_base))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let body = response.json::<serde_json::Value>().await?;
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(Error::RateLimited {
+                provider: self.name(),
+                retry_after,
+                message: body["error"]["message"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+        if !status.is_success() {
+            return Err(Error::Api {
+                provider: self.name(),
+                message: body["error"]["message"]
+                    .as_str()
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| status.to_string()),
+            });
+        }
+
+        // The response is a list of content blocks; we only ever ask for text.
+        let output = body["content"]
+            .as_array()
+            .map(|blocks| {
+                blocks
+                    .iter()
+                    .filter(|b| b["type"] == "text")
+                    .filter_map(|b| b["text"].as_str())
+                    .collect::<Vec<&str>>()
+                    .join("")
+            })
+            .unwrap_or_default();
+
+        Ok(output)
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use async_openai::error::OpenAIError;
+
+/// The error type returned by every `LlmProvider`.
+///
+/// Provider specific failures are folded into this so that callers (the queue executor and
+/// the HTTP handlers) only have to deal with a single error type no matter which backend
+/// served the request.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// An error from an OpenAI-shaped API (OpenAI, Azure OpenAI, or a compatible server).
+    #[error("openai error: {0}")]
+    OpenAI(#[from] OpenAIError),
+
+    /// A transport error from a provider we talk to with plain `reqwest`.
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    /// The provider answered, but with an error status or a body we can't use.
+    #[error("provider {provider} returned an error: {message}")]
+    Api {
+        provider: &'static str,
+        message: String,
+    },
+
+    /// The provider turned the call away because a rate limit was hit.
+    ///
+    /// `retry_after` is how long the provider asked us to wait, if it said.
+    #[error("provider {provider} rate limited the request: {message}")]
+    RateLimited {
+        provider: &'static str,
+        retry_after: Option<Duration>,
+        message: String,
+    },
+
+    /// Every key in the `openai` table that could serve the request is leased, cooling down
+    /// or over its per-minute limits.
+    #[error("no openai key is available")]
+    NoKeyAvailable,
+
+    /// The provider can't do what was asked of it, e.g. Anthropic has no embeddings API.
+    #[error("provider {provider} does not support {operation}")]
+    Unsupported {
+        provider: &'static str,
+        operation: &'static str,
+    },
+
+    /// The provider section of a module's `config_data` is missing something we need.
+    #[error("invalid provider config: {0}")]
+    InvalidConfig(String),
+
+    /// Leasing or releasing a key from the `openai` table failed.
+    #[error("an error occurred with the database: {0}")]
+    Sqlx(#[from] sqlx::Error),
+}
@@ -0,0 +1,282 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenAIKey {
+    pub openai_id: Uuid,
+    pub openai_key: String,
+}
+
+/// The leases this process holds, as `(openai_id, lease_id)`, so they can be handed back on
+/// shutdown rather than waiting for them to expire.
+static HELD_LEASES: Mutex<Vec<(Uuid, Uuid)>> = Mutex::new(Vec::new());
+
+fn forget_lease(lease_id: Uuid) {
+    HELD_LEASES
+        .lock()
+        .unwrap()
+        .retain(|&(_, held)| held != lease_id);
+}
+
+/// A key from the `openai` table that is reserved for us until `leased_until`.
+///
+/// Call `release` when done with it. If the lease is dropped without being released, e.g.
+/// because the holder returned early with `?` or panicked, a release is spawned onto the
+/// runtime instead; and if even that never happens (the process died), the sweeper started by
+/// `spawn_lease_sweeper` reclaims the key once the lease expires.
+pub struct KeyLease {
+    db: PgPool,
+    lease_id: Uuid,
+    key: OpenAIKey,
+    released: bool,
+}
+
+impl KeyLease {
+    pub fn key(&self) -> &OpenAIKey {
+        &self.key
+    }
+
+    pub async fn release(mut self) -> Result<(), sqlx::Error> {
+        self.released = true;
+        forget_lease(self.lease_id);
+        release(&self.db, self.key.openai_id, self.lease_id).await
+    }
+
+    /// Release the key, but keep it out of rotation for `duration`.
+    ///
+    /// Used when the provider rate limits the key.
+    pub async fn cool_down(mut self, duration: Duration) -> Result<(), sqlx::Error> {
+        self.released = true;
+        forget_lease(self.lease_id);
+        sqlx::query!(
+            r#"update openai set
+                openai_status = 0,
+                lease_id = null,
+                leased_until = null,
+                cooldown_until = now() + make_interval(secs => $3)
+            where openai_id = $1 and lease_id = $2"#,
+            self.key.openai_id,
+            self.lease_id,
+            duration.as_secs_f64()
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for KeyLease {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        forget_lease(self.lease_id);
+
+        let db = self.db.clone();
+        let openai_id = self.key.openai_id;
+        let lease_id = self.lease_id;
+        // Without a runtime (e.g. during shutdown) there is nothing we can do here;
+        // the sweeper picks the key up when the lease runs out.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                if let Err(e) = release(&db, openai_id, lease_id).await {
+                    log::error!("failed to release dropped key lease {}: {}", lease_id, e);
+                }
+            });
+        }
+    }
+}
+
+/// What a call needs from the key that serves it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyRequirements {
+    /// Only keys flagged `is_plus` may serve gpt-4 class models.
+    pub needs_plus: bool,
+    /// Counted against the key's tokens-per-minute budget when it is leased.
+    pub estimated_tokens: i32,
+}
+
+impl KeyRequirements {
+    pub fn for_model(model: &str, estimated_tokens: i32) -> Self {
+        Self {
+            needs_plus: model.starts_with("gpt-4"),
+            estimated_tokens,
+        }
+    }
+}
+
+/// Lease a free key for `duration`, or `None` if no key can take the call right now.
+///
+/// Keys whose lease has already expired count as free, so a crashed holder can never lock a
+/// key for longer than one lease. Keys that are cooling down after a 429, or that would go
+/// over their requests- or tokens-per-minute limit, are skipped. Among the rest, the key that
+/// has done the least work this minute wins, so bursts get spread across the whole pool; and
+/// plus keys are kept for the calls that need them.
+pub async fn lease_key(
+    db: &PgPool,
+    duration: Duration,
+    requirements: KeyRequirements,
+) -> Result<Option<KeyLease>, sqlx::Error> {
+    let lease_id = Uuid::new_v4();
+    let key = sqlx::query!(
+        r#"update openai set
+            openai_status = 1,
+            lease_id = $1,
+            leased_until = now() + make_interval(secs => $2),
+            window_started_at = case
+                when window_started_at is null or window_started_at < now() - interval '1 minute'
+                then now() else window_started_at end,
+            window_requests = case
+                when window_started_at is null or window_started_at < now() - interval '1 minute'
+                then 1 else window_requests + 1 end,
+            window_tokens = case
+                when window_started_at is null or window_started_at < now() - interval '1 minute'
+                then $4 else window_tokens + $4 end
+        where openai_id = (
+            select openai_id from openai
+            where (openai_status = 0 or leased_until < now())
+                and (cooldown_until is null or cooldown_until < now())
+                and (is_plus or not $3)
+                and (
+                    window_started_at is null
+                    or window_started_at < now() - interval '1 minute'
+                    or (
+                        (rpm_limit is null or window_requests < rpm_limit)
+                        and (tpm_limit is null or window_tokens + $4 <= tpm_limit)
+                    )
+                )
+            order by
+                is_plus and not $3,
+                case
+                    when window_started_at is null or window_started_at < now() - interval '1 minute'
+                    then 0 else window_tokens end
+            limit 1
+            for update skip locked
+        )
+        returning openai_id, openai_key"#,
+        lease_id,
+        duration.as_secs_f64(),
+        requirements.needs_plus,
+        requirements.estimated_tokens
+    )
+    .fetch_optional(db)
+    .await?;
+    if let Some(key) = &key {
+        HELD_LEASES.lock().unwrap().push((key.openai_id, lease_id));
+    }
+
+    Ok(key.map(|key| KeyLease {
+        db: db.clone(),
+        lease_id,
+        key: OpenAIKey {
+            openai_id: key.openai_id,
+            openai_key: key.openai_key,
+        },
+        released: false,
+    }))
+}
+
+/// Hand back every key this process still holds, e.g. when shutting down.
+///
+/// Calls still running with one of these keys carry on, but the key may be leased to someone
+/// else in the meantime; so only call this once nothing is left that needs them.
+pub async fn release_held_leases(db: &PgPool) -> Result<usize, sqlx::Error> {
+    let held = std::mem::take(&mut *HELD_LEASES.lock().unwrap());
+    for &(openai_id, lease_id) in &held {
+        release(db, openai_id, lease_id).await?;
+    }
+
+    Ok(held.len())
+}
+
+async fn release(db: &PgPool, openai_id: Uuid, lease_id: Uuid) -> Result<(), sqlx::Error> {
+    // Only release the key if it's still ours; if the lease expired, someone else may hold it now.
+    sqlx::query!(
+        r#"update openai set openai_status = 0, lease_id = null, leased_until = null
+        where openai_id = $1 and lease_id = $2"#,
+        openai_id,
+        lease_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Count a successful call and the tokens it used against the key that served it.
+pub async fn record_key_success(
+    db: &PgPool,
+    openai_id: Uuid,
+    tokens: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"update openai set
+            token_count = token_count + $2,
+            request_count = request_count + 1,
+            consecutive_error_count = 0,
+            last_used_at = now()
+        where openai_id = $1"#,
+        openai_id,
+        tokens
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Count a failed call against the key that served it and remember why it failed.
+pub async fn record_key_failure(
+    db: &PgPool,
+    openai_id: Uuid,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"update openai set
+            request_count = request_count + 1,
+            error_count = error_count + 1,
+            consecutive_error_count = consecutive_error_count + 1,
+            last_error = $2,
+            last_error_at = now(),
+            last_used_at = now()
+        where openai_id = $1"#,
+        openai_id,
+        reason
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Hand back every key whose lease has run out. Returns how many were reclaimed.
+pub async fn reclaim_expired_leases(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"update openai set openai_status = 0, lease_id = null, leased_until = null
+        where openai_status = 1 and leased_until < now()"#
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically reclaim expired leases for as long as the process runs.
+pub fn spawn_lease_sweeper(db: PgPool, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match reclaim_expired_leases(&db).await {
+                Ok(0) => {}
+                Ok(count) => log::warn!("reclaimed {} expired openai key leases", count),
+                Err(e) => log::error!("failed to reclaim expired openai key leases: {}", e),
+            }
+        }
+    });
+}
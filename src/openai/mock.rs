@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::openai::provider::LlmProvider;
+use crate::openai::{count_prompt_tokens, count_tokens, model_info, ChatRequest, Error};
+
+/// How the mock decides what to answer.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MockMode {
+    /// Answer with the prompt itself.
+    #[default]
+    Echo,
+    /// Answer with `responses`, in order, wrapping around at the end.
+    Scripted,
+}
+
+/// The JSON file pointed to by `--llm-mock`.
+///
+/// ```json
+/// { "mode": "scripted", "responses": ["first", "second"], "latencyMs": 200, "failEvery": 3 }
+/// ```
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MockConfig {
+    pub mode: MockMode,
+    pub responses: Vec<String>,
+    /// Sleep this long before answering, to simulate a slow model.
+    pub latency_ms: u64,
+    /// Fail every n-th call (1-based), to exercise the retry paths. `0` never fails.
+    pub fail_every: usize,
+}
+
+impl MockConfig {
+    /// Parse the value of `--llm-mock`: either the literal `echo` or a path to a JSON file.
+    pub fn load(value: &str) -> anyhow::Result<Self> {
+        if value == "echo" {
+            return Ok(Self::default());
+        }
+        let data = std::fs::read_to_string(value)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+}
+
+/// Token and call counters collected by a `MockProvider`.
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MockUsage {
+    pub calls: usize,
+    pub failures: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// The same, per model asked for.
+    pub models: BTreeMap<String, MockModelUsage>,
+    /// What the calls would have cost in US dollars, at the catalog's prices. Models that
+    /// aren't in the catalog count for nothing.
+    pub spend: f64,
+}
+
+/// The calls and tokens a `MockProvider` served for one model.
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MockModelUsage {
+    pub calls: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// What they would have cost in US dollars, or `None` if the model isn't in the catalog.
+    pub spend: Option<f64>,
+}
+
+/// A deterministic, offline `LlmProvider` for tests and local development.
+///
+/// Answers are a pure function of the config and the number of calls made so far, so a test
+/// that publishes a known set of messages always sees the same candidates come out.
+pub struct MockProvider {
+    config: MockConfig,
+    calls: AtomicUsize,
+    failures: AtomicUsize,
+    prompt_tokens: AtomicUsize,
+    completion_tokens: AtomicUsize,
+    models: Mutex<BTreeMap<String, MockModelUsage>>,
+}
+
+impl MockProvider {
+    pub fn new(config: MockConfig) -> Self {
+        Self {
+            config,
+            calls: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            prompt_tokens: AtomicUsize::new(0),
+            completion_tokens: AtomicUsize::new(0),
+            models: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn usage(&self) -> MockUsage {
+        let mut models = self.models.lock().unwrap().clone();
+        for (name, usage) in &mut models {
+            usage.spend = model_info(name)
+                .map(|model| model.cost(usage.prompt_tokens, usage.completion_tokens));
+        }
+        MockUsage {
+            calls: self.calls.load(Ordering::SeqCst),
+            failures: self.failures.load(Ordering::SeqCst),
+            prompt_tokens: self.prompt_tokens.load(Ordering::SeqCst),
+            completion_tokens: self.completion_tokens.load(Ordering::SeqCst),
+            spend: models.values().filter_map(|usage| usage.spend).sum(),
+            models,
+        }
+    }
+
+    /// Count a call to `model`, or just its tokens if `call` is false.
+    fn count(&self, model: &str, call: bool, prompt_tokens: usize, completion_tokens: usize) {
+        let mut models = self.models.lock().unwrap();
+        let usage = models.entry(model.to_string()).or_default();
+        usage.calls += call as usize;
+        usage.prompt_tokens += prompt_tokens;
+        usage.completion_tokens += completion_tokens;
+    }
+}
+
+#[async_trait]
+impl LlmProvider for MockProvider {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<String, Error> {
+        // 1-based, so that `failEvery: 1` fails every call.
+        let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let prompt_tokens = count_prompt_tokens(&request);
+        self.prompt_tokens
+            .fetch_add(prompt_tokens, Ordering::SeqCst);
+        self.count(&request.model, true, prompt_tokens, 0);
+
+        if self.config.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(self.config.latency_ms)).await;
+        }
+
+        if self.config.fail_every > 0 && call.is_multiple_of(self.config.fail_every) {
+            self.failures.fetch_add(1, Ordering::SeqCst);
+            return Err(Error::Api {
+                provider: self.name(),
+                message: format!("injected failure on call {}", call),
+            });
+        }
+
+        let output = match self.config.mode {
+            MockMode::Echo => request.input,
+            MockMode::Scripted if self.config.responses.is_empty() => String::new(),
+            MockMode::Scripted => {
+                self.config.responses[(call - 1) % self.config.responses.len()].clone()
+            }
+        };
+        let completion_tokens = count_tokens(&output);
+        self.completion_tokens
+            .fetch_add(completion_tokens, Ordering::SeqCst);
+        self.count(&request.model, false, 0, completion_tokens);
+
+        Ok(output)
+    }
+
+    async fn embed(&self, model: &str, inputs: Vec<String>) -> Result<Vec<Vec<f32>>, Error> {
+        let prompt_tokens = inputs.iter().map(|input| count_tokens(input)).sum();
+        self.prompt_tokens
+            .fetch_add(prompt_tokens, Ordering::SeqCst);
+        self.count(model, true, prompt_tokens, 0);
+
+        Ok(inputs.iter().map(|input| mock_embedding(input)).collect())
+    }
+}
+
+/// How many dimensions mock embeddings have.
+const MOCK_EMBEDDING_DIMS: usize = 64;
+
+/// A bag of character bigrams hashed into `MOCK_EMBEDDING_DIMS` buckets and normalized, so
+/// texts sharing many bigrams end up close to each other, and the same text always embeds the
+/// same way.
+fn mock_embedding(input: &str) -> Vec<f32> {
+    let mut embedding = vec![0.0; MOCK_EMBEDDING_DIMS];
+    let chars = input.chars().collect::<Vec<char>>();
+    for pair in chars.windows(2) {
+        // FNV-1a, since `DefaultHasher` isn't guaranteed to be stable across releases.
+        let mut hash: u32 = 0x811c9dc5;
+        for c in pair {
+            hash ^= *c as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+        embedding[hash as usize % MOCK_EMBEDDING_DIMS] += 1.0;
+    }
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        // Elasticsearch refuses zero vectors for cosine similarity.
+        embedding[0] = 1.0;
+    } else {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+
+    embedding
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(input: &str) -> ChatRequest {
+        ChatRequest {
+            model: "gpt-3.5-turbo".to_string(),
+            input: input.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn usage_counts_calls_failures_and_tokens() {
+        let mock = MockProvider::new(MockConfig {
+            mode: MockMode::Scripted,
+            responses: vec!["one two".to_string()],
+            fail_every: 2,
+            ..Default::default()
+        });
+
+        assert_eq!(mock.chat(request("hello")).await.unwrap(), "one two");
+        assert!(mock.chat(request("hello")).await.is_err());
+        assert_eq!(mock.chat(request("hello")).await.unwrap(), "one two");
+
+        let prompt_tokens = count_prompt_tokens(&request("hello"));
+        let completion_tokens = count_tokens("one two");
+        let spend = model_info("gpt-3.5-turbo")
+            .unwrap()
+            .cost(3 * prompt_tokens, 2 * completion_tokens);
+        assert_eq!(
+            mock.usage(),
+            MockUsage {
+                calls: 3,
+                failures: 1,
+                prompt_tokens: 3 * prompt_tokens,
+                completion_tokens: 2 * completion_tokens,
+                models: BTreeMap::from([(
+                    "gpt-3.5-turbo".to_string(),
+                    MockModelUsage {
+                        calls: 3,
+                        prompt_tokens: 3 * prompt_tokens,
+                        completion_tokens: 2 * completion_tokens,
+                        spend: Some(spend),
+                    }
+                )]),
+                spend,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn models_outside_the_catalog_cost_nothing() {
+        let mock = MockProvider::new(MockConfig::default());
+        let request = ChatRequest {
+            model: "my-finetune".to_string(),
+            ..request("hello")
+        };
+        mock.chat(request).await.unwrap();
+
+        let usage = mock.usage();
+        assert_eq!(usage.models["my-finetune"].calls, 1);
+        assert_eq!(usage.models["my-finetune"].spend, None);
+        assert_eq!(usage.spend, 0.0);
+    }
+
+    #[tokio::test]
+    async fn echo_answers_with_the_prompt() {
+        let mock = MockProvider::new(MockConfig::default());
+        assert_eq!(mock.chat(request("same")).await.unwrap(), "same");
+        assert_eq!(mock.usage().completion_tokens, count_tokens("same"));
+    }
+}
@@ -1,21 +1,58 @@
-use async_openai::{
-    config::OpenAIConfig,
-    types::{
-        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
-        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, Role,
-    },
-    Client,
-};
+use anyhow::Context;
+use futures::future::BoxFuture;
+use futures::stream;
+use futures::{FutureExt, StreamExt};
 use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tiktoken_rs::cl100k_base_singleton;
 use uuid::Uuid;
 
-#[derive(serde::Deserialize, serde::Serialize)]
+use crate::config::Config;
+
+mod anthropic;
+mod error;
+mod keys;
+mod mock;
+mod models;
+mod provider;
+mod secrets;
+
+pub use error::Error;
+pub use keys::{
+    lease_key, reclaim_expired_leases, record_key_failure, record_key_success, release_held_leases,
+    spawn_lease_sweeper, KeyLease, KeyRequirements, OpenAIKey,
+};
+pub use mock::{MockConfig, MockMode, MockModelUsage, MockProvider, MockUsage};
+pub use models::{model_info, ModelInfo, ModelParams, ParamsError, MODELS};
+pub use provider::{ChatStream, LlmProvider, OpenAIProvider, ProviderConfig};
+pub use secrets::{check_api_base, store_provider_secrets, PinnedHost};
+
+/// How long a key is leased for when the config doesn't say otherwise.
+///
+/// This has to comfortably outlast the slowest completion we expect, otherwise the sweeper
+/// hands the key to someone else while it's still in use.
+pub const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(300);
+
+/// How long a rate limited key sits out when the provider didn't say how long to wait.
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// How many different keys a rate limited call is tried with before giving up.
+const MAX_KEY_ATTEMPTS: usize = 3;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ChatRequest {
     pub model: String,
     pub input: String,
     pub max_tokens: Option<u16>,
     pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Sequences the answer stops at.
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<i64>,
+    /// The system message, sent ahead of the history.
+    pub system: Option<String>,
     pub history: Option<Vec<History>>,
 }
 
@@ -26,117 +63,431 @@ impl Default for ChatRequest {
             input: "".to_string(),
             max_tokens: None,
             temperature: None,
+            top_p: None,
+            stop: None,
+            seed: None,
+            system: None,
             history: None,
         }
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct History {
     pub user_input: String,
     pub ai_output: String,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct OpenAIKey {
-    pub openai_id: Uuid,
-    pub openai_key: String,
+/// The handle through which the API and the queue workers talk to language models.
+///
+/// Requests are normally routed to whatever `ProviderConfig` the caller passes in. When the
+/// process is started with `--llm-mock`, or the handle is built with `with_provider`, every
+/// request is answered by that one shared provider instead, which is how the module run and
+/// chat flows are exercised without network access.
+#[derive(Clone)]
+pub struct Llm {
+    db: PgPool,
+    lease_duration: Duration,
+    override_provider: Option<Arc<dyn LlmProvider>>,
+    /// The override, if it's the `--llm-mock` provider, so its usage can be read back.
+    mock: Option<Arc<MockProvider>>,
+    /// Hosts a provider's `apiBase` may point at even if they aren't public; see
+    /// `check_api_base`.
+    allowed_hosts: Arc<[String]>,
 }
 
-pub async fn chat(
-    request: ChatRequest,
-    api_key: &String,
-) -> Result<String, async_openai::error::OpenAIError> {
-    // log::info!(
-    //     "chat with: model: {}, input_chars: {}",
-    //     &request.model,
-    //     &request.input.chars().count(),
-    // );
-    let mut messages;
-    if let Some(chat_history) = request.history {
-        messages = chat_history
+impl Llm {
+    pub fn new(db: PgPool) -> Self {
+        Self {
+            db,
+            lease_duration: DEFAULT_LEASE_DURATION,
+            override_provider: None,
+            mock: None,
+            allowed_hosts: Arc::new([]),
+        }
+    }
+
+    pub fn from_config(db: PgPool, config: &Config) -> anyhow::Result<Self> {
+        let Some(mock) = &config.llm_mock else {
+            return Ok(Self {
+                lease_duration: Duration::from_secs(config.openai_lease_secs),
+                allowed_hosts: config.provider_allowed_hosts.clone().into(),
+                ..Self::new(db)
+            });
+        };
+        let mock = MockConfig::load(mock).context("could not load llm_mock")?;
+        log::warn!(
+            "serving every model call from the mock provider: {:?}",
+            mock
+        );
+        let mock = Arc::new(MockProvider::new(mock));
+        Ok(Self {
+            mock: Some(mock.clone()),
+            ..Self::with_provider(db, mock)
+        })
+    }
+
+    pub fn with_provider(db: PgPool, provider: Arc<dyn LlmProvider>) -> Self {
+        Self {
+            override_provider: Some(provider),
+            ..Self::new(db)
+        }
+    }
+
+    /// What the `--llm-mock` provider was asked so far, if calls are served by it.
+    pub fn mock_usage(&self) -> Option<MockUsage> {
+        self.mock.as_ref().map(|mock| mock.usage())
+    }
+
+    /// Chat with whichever provider `provider` describes.
+    ///
+    /// If the provider needs a key from the `openai` table, one is leased for the duration of
+    /// the call and released afterwards, whether the call succeeded or not. Should this future
+    /// be dropped mid-call, the lease releases itself; see `KeyLease`.
+    pub async fn chat(
+        &self,
+        provider: &ProviderConfig,
+        request: ChatRequest,
+    ) -> Result<String, Error> {
+        Ok(self.chat_with_usage(provider, request).await?.output)
+    }
+
+    /// Like `chat`, but also say which key served the call so the caller can attribute the
+    /// tokens it records in `metric_v2`.
+    ///
+    /// Every call served from the pool is also counted against its key, successful or not;
+    /// see `record_key_success` and `record_key_failure`.
+    pub async fn chat_with_usage(
+        &self,
+        provider: &ProviderConfig,
+        request: ChatRequest,
+    ) -> Result<ChatOutput, Error> {
+        if let Some(override_provider) = &self.override_provider {
+            let output = override_provider.chat(request).await?;
+            return Ok(ChatOutput {
+                output,
+                openai_id: None,
+            });
+        }
+
+        if !provider.uses_key_pool() {
+            let output = self.own_key(provider).await?.chat(request).await?;
+            return Ok(ChatOutput {
+                output,
+                openai_id: None,
+            });
+        }
+
+        let prompt_tokens = count_prompt_tokens(&request);
+        let requirements = KeyRequirements::for_model(
+            &request.model,
+            (prompt_tokens + request.max_tokens.unwrap_or(2048) as usize) as i32,
+        );
+        let (output, lease) = self
+            .with_pooled_key(requirements, |key| {
+                let provider = provider.build(Some(key), None);
+                let request = request.clone();
+                async move { provider?.chat(request).await }.boxed()
+            })
+            .await?;
+        let tokens = prompt_tokens + count_tokens(&output);
+        let openai_id = self.settle(lease, tokens).await;
+
+        Ok(ChatOutput {
+            output,
+            openai_id: Some(openai_id),
+        })
+    }
+
+    /// Like `chat_with_usage`, but stream the answer as it's produced.
+    ///
+    /// A pooled key stays leased until the stream ends or is dropped. Rate limits are only
+    /// retried with another key if they show up before the first piece of the answer; once
+    /// output has been handed out, an error ends the stream.
+    pub async fn chat_stream(
+        &self,
+        provider: &ProviderConfig,
+        request: ChatRequest,
+    ) -> Result<ChatStreamOutput, Error> {
+        if let Some(override_provider) = &self.override_provider {
+            return Ok(ChatStreamOutput {
+                deltas: override_provider.chat_stream(request).await?,
+                openai_id: None,
+            });
+        }
+
+        if !provider.uses_key_pool() {
+            return Ok(ChatStreamOutput {
+                deltas: self.own_key(provider).await?.chat_stream(request).await?,
+                openai_id: None,
+            });
+        }
+
+        let prompt_tokens = count_prompt_tokens(&request);
+        let requirements = KeyRequirements::for_model(
+            &request.model,
+            (prompt_tokens + request.max_tokens.unwrap_or(2048) as usize) as i32,
+        );
+        let (deltas, lease) = self
+            .with_pooled_key(requirements, |key| {
+                let provider = provider.build(Some(key), None);
+                let request = request.clone();
+                async move {
+                    let mut deltas = provider?.chat_stream(request).await?;
+                    // OpenAI reports a 429 as the first item of the stream rather than from
+                    // `create_stream`, so peek at it before committing to this key.
+                    let first = match deltas.next().await {
+                        Some(Err(e)) => return Err(e),
+                        first => first,
+                    };
+                    Ok(stream::iter(first).chain(deltas).boxed())
+                }
+                .boxed()
+            })
+            .await?;
+        let openai_id = lease.key().openai_id;
+
+        let state = MeteredStream {
+            db: self.db.clone(),
+            deltas,
+            lease: Some(lease),
+            prompt_tokens,
+            output: String::new(),
+        };
+        Ok(ChatStreamOutput {
+            deltas: stream::unfold(state, MeteredStream::next).boxed(),
+            openai_id: Some(openai_id),
+        })
+    }
+
+    /// Embed each of `inputs` with `model`, in order, with whichever provider `provider`
+    /// describes.
+    pub async fn embed(
+        &self,
+        provider: &ProviderConfig,
+        model: &str,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Error> {
+        if let Some(override_provider) = &self.override_provider {
+            return override_provider.embed(model, inputs).await;
+        }
+
+        if !provider.uses_key_pool() {
+            return self.own_key(provider).await?.embed(model, inputs).await;
+        }
+
+        let tokens = inputs
             .iter()
-            .map(|h| {
-                vec![
-                    ChatCompletionRequestUserMessageArgs::default()
-                        .content(h.user_input.clone())
-                        .build()
-                        .unwrap()
-                        .into(),
-                    ChatCompletionRequestAssistantMessageArgs::default()
-                        .content(h.ai_output.clone())
-                        .build()
-                        .unwrap()
-                        .into(),
-                ]
+            .map(|input| count_tokens(input))
+            .sum::<usize>();
+        let requirements = KeyRequirements::for_model(model, tokens as i32);
+        let (embeddings, lease) = self
+            .with_pooled_key(requirements, |key| {
+                let provider = provider.build(Some(key), None);
+                let model = model.to_string();
+                let inputs = inputs.clone();
+                async move { provider?.embed(&model, inputs).await }.boxed()
             })
-            .flatten()
-            .collect::<Vec<ChatCompletionRequestMessage>>();
-    } else {
-        messages = Vec::<ChatCompletionRequestMessage>::new()
-    };
-    messages.push(
-        ChatCompletionRequestUserMessageArgs::default()
-            .content(request.input)
-            .build()?
-            .into(),
-    );
-
-    let config = OpenAIConfig::new().with_api_key(api_key);
-    let client = Client::with_config(config);
-    let chat_request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(request.max_tokens.unwrap_or(2048))
-        .model(request.model)
-        .temperature(request.temperature.unwrap_or(0.1))
-        .messages(messages)
-        .build()?;
-    let gpt_response = client.chat().create(chat_request).await?;
-    let output = &gpt_response
-        .choices
-        .iter()
-        .filter(|x| x.message.role == Role::Assistant)
-        .next()
-        .unwrap()
-        .message
-        .content
-        .clone()
-        .unwrap_or_default();
-
-    Ok(output.to_string())
+            .await?;
+        self.settle(lease, tokens).await;
+
+        Ok(embeddings)
+    }
+
+    /// Build a provider that doesn't take its key from the pool, with the key it refers to.
+    ///
+    /// Its `apiBase` is checked on every call rather than only when the module is saved, since
+    /// what a host name resolves to can change in between, and the provider connects to the
+    /// addresses that were checked.
+    async fn own_key(&self, provider: &ProviderConfig) -> Result<Box<dyn LlmProvider>, Error> {
+        let pinned = match provider.api_base() {
+            Some(api_base) => check_api_base(api_base, &self.allowed_hosts).await?,
+            None => None,
+        };
+        let Some(secret_id) = provider.api_key_id() else {
+            return provider.build(None, pinned.as_ref());
+        };
+        let mut provider = provider.clone();
+        provider.set_api_key(secrets::load_provider_secret(&self.db, secret_id).await?);
+
+        provider.build(None, pinned.as_ref())
+    }
+
+    /// Run `call` with a key leased from the pool, moving on to another key each time one is
+    /// rate limited.
+    ///
+    /// On success the lease is handed back along with the output, for the caller to `settle`
+    /// once it knows how many tokens the call used.
+    async fn with_pooled_key<T, F>(
+        &self,
+        requirements: KeyRequirements,
+        mut call: F,
+    ) -> Result<(T, KeyLease), Error>
+    where
+        F: FnMut(&str) -> BoxFuture<'static, Result<T, Error>>,
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let lease = lease_key(&self.db, self.lease_duration, requirements)
+                .await?
+                .ok_or(Error::NoKeyAvailable)?;
+            match call(&lease.key().openai_key).await {
+                Ok(output) => return Ok((output, lease)),
+                Err(e) => self.key_failed(lease, e, attempts).await?,
+            }
+        }
+    }
+
+    /// Count a successful call against its key and release the key.
+    ///
+    /// The call was answered, and paid for, either way: failing here would have it made again.
+    async fn settle(&self, lease: KeyLease, tokens: usize) -> Uuid {
+        let openai_id = lease.key().openai_id;
+        if let Err(e) = record_key_success(&self.db, openai_id, tokens as i64).await {
+            log::error!("failed to record usage of key {}: {}", openai_id, e);
+        }
+        if let Err(e) = lease.release().await {
+            log::error!("failed to release key {}: {}", openai_id, e);
+        }
+
+        openai_id
+    }
+
+    /// Deal with a call that failed on a pooled key.
+    ///
+    /// Rate limited keys are cooled down and `Ok` is returned to have the caller try again
+    /// with another key, unless it already tried `MAX_KEY_ATTEMPTS` times. Any other error
+    /// releases the key and is handed back.
+    async fn key_failed(
+        &self,
+        lease: KeyLease,
+        error: Error,
+        attempts: usize,
+    ) -> Result<(), Error> {
+        let openai_id = lease.key().openai_id;
+        record_key_failure(&self.db, openai_id, &error.to_string()).await?;
+        let Error::RateLimited { retry_after, .. } = error else {
+            lease.release().await?;
+            return Err(error);
+        };
+        log::warn!("openai key {} was rate limited, cooling it down", openai_id);
+        lease
+            .cool_down(retry_after.unwrap_or(DEFAULT_COOLDOWN))
+            .await?;
+        if attempts >= MAX_KEY_ATTEMPTS {
+            return Err(error);
+        }
+
+        Ok(())
+    }
+}
+
+/// What `Llm::chat_with_usage` hands back.
+#[derive(Debug)]
+pub struct ChatOutput {
+    pub output: String,
+    /// The pooled key that served the call, if it was served from the `openai` table.
+    pub openai_id: Option<Uuid>,
+}
+
+/// What `Llm::chat_stream` hands back.
+pub struct ChatStreamOutput {
+    pub deltas: ChatStream,
+    /// The pooled key serving the call, if it is served from the `openai` table.
+    pub openai_id: Option<Uuid>,
+}
+
+/// Holds on to the lease while a pooled stream is consumed, and settles the key's accounting
+/// once the stream ends.
+///
+/// A stream dropped before it ended, e.g. because the client went away, is settled on drop
+/// with the tokens streamed so far, since those were paid for all the same.
+struct MeteredStream {
+    db: PgPool,
+    deltas: ChatStream,
+    /// Taken once the stream has ended, so that it isn't polled again.
+    lease: Option<KeyLease>,
+    prompt_tokens: usize,
+    output: String,
+}
+
+impl MeteredStream {
+    async fn next(mut self) -> Option<(Result<String, Error>, Self)> {
+        let openai_id = self.lease.as_ref()?.key().openai_id;
+        match self.deltas.next().await {
+            Some(Ok(delta)) => {
+                self.output.push_str(&delta);
+                Some((Ok(delta), self))
+            }
+            Some(Err(e)) => {
+                let lease = self.lease.take()?;
+                if let Err(db_error) = record_key_failure(&self.db, openai_id, &e.to_string()).await
+                {
+                    log::error!(
+                        "failed to record failure of key {}: {}",
+                        openai_id,
+                        db_error
+                    );
+                }
+                if let Err(db_error) = lease.release().await {
+                    log::error!("failed to release key {}: {}", openai_id, db_error);
+                }
+                Some((Err(e), self))
+            }
+            None => {
+                let lease = self.lease.take()?;
+                settle_stream(&self.db, lease, self.prompt_tokens, &self.output).await;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for MeteredStream {
+    fn drop(&mut self) {
+        let Some(lease) = self.lease.take() else {
+            return;
+        };
+        // Without a runtime the lease releases itself as best it can; see `KeyLease`.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let db = self.db.clone();
+            let prompt_tokens = self.prompt_tokens;
+            let output = std::mem::take(&mut self.output);
+            handle.spawn(async move { settle_stream(&db, lease, prompt_tokens, &output).await });
+        }
+    }
 }
 
-pub async fn get_available_key(db: &PgPool) -> Result<OpenAIKey, sqlx::Error> {
-    let mut tx = db.begin().await?;
-    let key = sqlx::query!(
-        r#"select openai_id, openai_key from openai where openai_status = 0 limit 1 for update"#,
-    )
-    .fetch_one(tx.as_mut())
-    .await?;
-
-    sqlx::query!(
-        r#"update openai set openai_status = 1 where openai_id = $1"#,
-        key.openai_id
-    )
-    .execute(tx.as_mut())
-    .await?;
-
-    tx.commit().await?;
-
-    Ok(OpenAIKey {
-        openai_id: key.openai_id,
-        openai_key: key.openai_key,
-    })
+/// Count a stream's tokens against its key and release the key.
+async fn settle_stream(db: &PgPool, lease: KeyLease, prompt_tokens: usize, output: &str) {
+    let openai_id = lease.key().openai_id;
+    let tokens = prompt_tokens + count_tokens(output);
+    if let Err(e) = record_key_success(db, openai_id, tokens as i64).await {
+        log::error!("failed to record usage of key {}: {}", openai_id, e);
+    }
+    if let Err(e) = lease.release().await {
+        log::error!("failed to release key {}: {}", openai_id, e);
+    }
 }
 
-pub async fn release_key(db: &PgPool, key: OpenAIKey) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"update openai set openai_status = 0 where openai_id = $1"#,
-        key.openai_id
-    )
-    .execute(db)
-    .await?;
+/// Tokens in everything we send: the system message, the history and the input.
+pub(crate) fn count_prompt_tokens(request: &ChatRequest) -> usize {
+    request.system.as_deref().map_or(0, count_tokens)
+        + request
+            .history
+            .iter()
+            .flatten()
+            .map(|h| count_tokens(&h.user_input) + count_tokens(&h.ai_output))
+            .sum::<usize>()
+        + count_tokens(&request.input)
+}
 
-    Ok(())
+/// Count tokens the way the rest of the app does, with `cl100k_base`.
+pub(crate) fn count_tokens(text: &str) -> usize {
+    cl100k_base_singleton()
+        .lock()
+        .encode_with_special_tokens(text)
+        .len()
 }
@@ -0,0 +1,299 @@
+use serde_json::Value;
+
+use crate::openai::{ChatRequest, ProviderConfig};
+
+/// What we know about a model we send calls to.
+#[derive(serde::Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    pub name: &'static str,
+    /// Who makes it: `openai` models are served by OpenAI and Azure, `anthropic` ones by
+    /// Anthropic.
+    pub provider: &'static str,
+    /// How many tokens the prompt and the answer may take together.
+    pub context_window: usize,
+    /// How many tokens the answer may take.
+    pub max_output_tokens: usize,
+    pub max_temperature: f32,
+    /// How many stop sequences a call may have.
+    pub max_stop: usize,
+    /// Whether a seed makes its answers (mostly) reproducible.
+    pub supports_seed: bool,
+    /// US dollars per 1000 prompt tokens.
+    pub input_price: f64,
+    /// US dollars per 1000 answer tokens.
+    pub output_price: f64,
+}
+
+impl ModelInfo {
+    /// What `input_tokens` prompt tokens and `output_tokens` answer tokens cost, in US dollars.
+    pub fn cost(&self, input_tokens: usize, output_tokens: usize) -> f64 {
+        (input_tokens as f64 * self.input_price + output_tokens as f64 * self.output_price) / 1000.0
+    }
+}
+
+/// Every model we know, with its limits and list prices.
+///
+/// Prices are only used for estimates, so they don't need to track the providers' to the cent.
+pub const MODELS: &[ModelInfo] = &[
+    ModelInfo {
+        name: "gpt-3.5-turbo",
+        provider: "openai",
+        context_window: 4_096,
+        max_output_tokens: 4_096,
+        max_temperature: 2.0,
+        max_stop: 4,
+        supports_seed: false,
+        input_price: 0.0015,
+        output_price: 0.002,
+    },
+    ModelInfo {
+        name: "gpt-3.5-turbo-16k",
+        provider: "openai",
+        context_window: 16_385,
+        max_output_tokens: 4_096,
+        max_temperature: 2.0,
+        max_stop: 4,
+        supports_seed: false,
+        input_price: 0.003,
+        output_price: 0.004,
+    },
+    ModelInfo {
+        name: "gpt-3.5-turbo-1106",
+        provider: "openai",
+        context_window: 16_385,
+        max_output_tokens: 4_096,
+        max_temperature: 2.0,
+        max_stop: 4,
+        supports_seed: true,
+        input_price: 0.001,
+        output_price: 0.002,
+    },
+    ModelInfo {
+        name: "gpt-4",
+        provider: "openai",
+        context_window: 8_192,
+        max_output_tokens: 8_192,
+        max_temperature: 2.0,
+        max_stop: 4,
+        supports_seed: false,
+        input_price: 0.03,
+        output_price: 0.06,
+    },
+    ModelInfo {
+        name: "gpt-4-32k",
+        provider: "openai",
+        context_window: 32_768,
+        max_output_tokens: 32_768,
+        max_temperature: 2.0,
+        max_stop: 4,
+        supports_seed: false,
+        input_price: 0.06,
+        output_price: 0.12,
+    },
+    ModelInfo {
+        name: "gpt-4-1106-preview",
+        provider: "openai",
+        context_window: 128_000,
+        max_output_tokens: 4_096,
+        max_temperature: 2.0,
+        max_stop: 4,
+        supports_seed: true,
+        input_price: 0.01,
+        output_price: 0.03,
+    },
+    ModelInfo {
+        name: "claude-instant-1.2",
+        provider: "anthropic",
+        context_window: 100_000,
+        max_output_tokens: 4_096,
+        max_temperature: 1.0,
+        max_stop: 16,
+        supports_seed: false,
+        input_price: 0.0008,
+        output_price: 0.0024,
+    },
+    ModelInfo {
+        name: "claude-2.1",
+        provider: "anthropic",
+        context_window: 200_000,
+        max_output_tokens: 4_096,
+        max_temperature: 1.0,
+        max_stop: 16,
+        supports_seed: false,
+        input_price: 0.008,
+        output_price: 0.024,
+    },
+];
+
+pub fn model_info(name: &str) -> Option<&'static ModelInfo> {
+    MODELS.iter().find(|model| model.name == name)
+}
+
+/// Who makes the models `provider` serves, or `None` if it may serve any model.
+fn catalog_provider(provider: &ProviderConfig) -> Option<&'static str> {
+    match provider {
+        ProviderConfig::OpenAI | ProviderConfig::Azure { .. } => Some("openai"),
+        ProviderConfig::Anthropic { .. } => Some("anthropic"),
+        ProviderConfig::Compatible { .. } => None,
+    }
+}
+
+/// A model parameter of a module that its model doesn't accept.
+#[derive(thiserror::Error, Debug)]
+#[error("`{param}` {reason}")]
+pub struct ParamsError {
+    pub param: &'static str,
+    pub reason: String,
+}
+
+impl ParamsError {
+    fn new(param: &'static str, reason: impl Into<String>) -> Self {
+        Self {
+            param,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// How a module calls its model, as kept in the `modelParams` of its `config_data`:
+///
+/// ```json
+/// { "model": "gpt-4-1106-preview", "temperature": 0.7, "stop": ["###"], "seed": 42 }
+/// ```
+///
+/// Everything is optional; a module without a model calls the one for its category.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ModelParams {
+    pub model: String,
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// How many tokens the answer may take.
+    pub max_tokens: u16,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// Sent ahead of the prompt as the system message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+}
+
+impl Default for ModelParams {
+    fn default() -> Self {
+        Self {
+            model: String::new(),
+            temperature: 0.1,
+            top_p: None,
+            max_tokens: 2048,
+            stop: Vec::new(),
+            seed: None,
+            system_prompt: None,
+        }
+    }
+}
+
+impl ModelParams {
+    /// The parameters in a module's config, with `default_model` if it doesn't name one,
+    /// checked against what the model accepts from `provider`.
+    pub fn from_module_config(
+        config_data: &Value,
+        provider: &ProviderConfig,
+        default_model: &str,
+    ) -> Result<Self, ParamsError> {
+        let mut params = match config_data.get("modelParams") {
+            None | Some(Value::Null) => Self::default(),
+            Some(params) => serde_json::from_value::<Self>(params.clone())
+                .map_err(|e| ParamsError::new("modelParams", e.to_string()))?,
+        };
+        if params.model.is_empty() {
+            params.model = default_model.to_string();
+        }
+        params.check(provider)?;
+
+        Ok(params)
+    }
+
+    /// Check the parameters against the catalog entry of the model.
+    ///
+    /// Servers speaking the OpenAI API may serve models we don't know; those get OpenAI's
+    /// limits.
+    pub fn check(&self, provider: &ProviderConfig) -> Result<(), ParamsError> {
+        let info = super::model_info(&self.model);
+        if let Some(expected) = catalog_provider(provider) {
+            match info {
+                None => return Err(ParamsError::new("model", "is not a model we know")),
+                Some(info) if info.provider != expected => {
+                    return Err(ParamsError::new(
+                        "model",
+                        format!("is not served by the {} provider", expected),
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+
+        let max_temperature = info.map_or(2.0, |info| info.max_temperature);
+        if !(0.0..=max_temperature).contains(&self.temperature) {
+            return Err(ParamsError::new(
+                "temperature",
+                format!("must be between 0 and {}", max_temperature),
+            ));
+        }
+        if self
+            .top_p
+            .is_some_and(|top_p| !(0.0..=1.0).contains(&top_p))
+        {
+            return Err(ParamsError::new("topP", "must be between 0 and 1"));
+        }
+        if self.max_tokens == 0 {
+            return Err(ParamsError::new("maxTokens", "must be at least 1"));
+        }
+        if let Some(info) = info {
+            if self.max_tokens as usize > info.max_output_tokens {
+                return Err(ParamsError::new(
+                    "maxTokens",
+                    format!(
+                        "must be at most {} for {}",
+                        info.max_output_tokens, info.name
+                    ),
+                ));
+            }
+        }
+        let max_stop = info.map_or(4, |info| info.max_stop);
+        if self.stop.len() > max_stop {
+            return Err(ParamsError::new(
+                "stop",
+                format!("may have at most {} sequences", max_stop),
+            ));
+        }
+        if self.stop.iter().any(|stop| stop.is_empty()) {
+            return Err(ParamsError::new("stop", "may not have empty sequences"));
+        }
+        if self.seed.is_some() && info.is_some_and(|info| !info.supports_seed) {
+            return Err(ParamsError::new(
+                "seed",
+                format!("is not supported by {}", self.model),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// A call with these parameters, sending `input`.
+    pub fn request(&self, input: String) -> ChatRequest {
+        ChatRequest {
+            model: self.model.clone(),
+            input,
+            max_tokens: Some(self.max_tokens),
+            temperature: Some(self.temperature),
+            top_p: self.top_p,
+            stop: (!self.stop.is_empty()).then(|| self.stop.clone()),
+            seed: self.seed,
+            system: self.system_prompt.clone(),
+            history: None,
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::time::Duration;
+
+use async_openai::{
+    config::{AzureConfig, Config, OpenAIConfig},
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateEmbeddingRequestArgs,
+        Role, Stop,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use backoff::ExponentialBackoff;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use uuid::Uuid;
+
+use crate::openai::anthropic::AnthropicProvider;
+use crate::openai::{ChatRequest, Error, PinnedHost};
+
+/// A backend that can answer a `ChatRequest`.
+///
+/// Everything that talks to a language model goes through this trait, so switching a module
+/// from OpenAI to Azure, a self-hosted OpenAI-compatible server or Anthropic is only a matter
+/// of changing the `provider` section of its `config_data`.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// A short name used in logs and error messages.
+    fn name(&self) -> &'static str;
+
+    async fn chat(&self, request: ChatRequest) -> Result<String, Error>;
+
+    /// Like `chat`, but hand out the answer piece by piece as the model produces it.
+    ///
+    /// Providers that can't stream answer with the whole output as a single piece.
+    async fn chat_stream(&self, request: ChatRequest) -> Result<ChatStream, Error> {
+        let output = self.chat(request).await?;
+        Ok(stream::once(async { Ok(output) }).boxed())
+    }
+
+    /// Embed each of `inputs` with `model`, in order.
+    async fn embed(&self, _model: &str, _inputs: Vec<String>) -> Result<Vec<Vec<f32>>, Error> {
+        Err(Error::Unsupported {
+            provider: self.name(),
+            operation: "embeddings",
+        })
+    }
+}
+
+/// The pieces of a streamed answer, in order. Concatenated, they make up the full output.
+pub type ChatStream = BoxStream<'static, Result<String, Error>>;
+
+/// Which provider a module talks to, read from `config_data["provider"]`.
+///
+/// ```json
+/// { "type": "azure", "apiBase": "https://foo.openai.azure.com", "apiVersion": "2023-12-01-preview", "deploymentId": "gpt-4", "apiKeyId": "..." }
+/// { "type": "compatible", "apiBase": "http://vllm:8000/v1" }
+/// { "type": "anthropic", "apiKeyId": "..." }
+/// ```
+///
+/// Keys are kept in `provider_secret_v2` and only referred to by `apiKeyId`, so that they
+/// aren't sent back to everyone who can read the module. An `apiKey` saved with a module is
+/// moved there by `store_provider_secrets`, and `Llm` looks it up again for each call.
+///
+/// Modules without a `provider` section keep using OpenAI with keys from the `openai` table.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ProviderConfig {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// Azure keys belong to one resource, so they can't come from the pool.
+    #[serde(rename_all = "camelCase")]
+    Azure {
+        api_base: String,
+        api_version: String,
+        deployment_id: String,
+        /// Only filled in by `Llm` for the call, from `api_key_id`; never serialized.
+        #[serde(default, skip_serializing)]
+        api_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        api_key_id: Option<Uuid>,
+    },
+    /// Any server speaking the OpenAI chat completions API, e.g. vLLM, Ollama or LM Studio.
+    #[serde(rename_all = "camelCase")]
+    Compatible {
+        api_base: String,
+        #[serde(default, skip_serializing)]
+        api_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        api_key_id: Option<Uuid>,
+    },
+    /// The pool only holds OpenAI keys, so Anthropic needs a key of its own.
+    #[serde(rename_all = "camelCase")]
+    Anthropic {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        api_base: Option<String>,
+        #[serde(default, skip_serializing)]
+        api_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        api_key_id: Option<Uuid>,
+    },
+}
+
+impl ProviderConfig {
+    /// Read the provider from a module's `config_data`, falling back to OpenAI.
+    pub fn from_module_config(config_data: &serde_json::Value) -> Result<Self, Error> {
+        match config_data.get("provider") {
+            None | Some(serde_json::Value::Null) => Ok(Self::default()),
+            Some(provider) => serde_json::from_value(provider.clone())
+                .map_err(|e| Error::InvalidConfig(e.to_string())),
+        }
+    }
+
+    /// Whether requests should be served with a key leased from the `openai` table.
+    ///
+    /// The pool holds OpenAI keys, so only OpenAI itself is served from it; every other
+    /// provider carries its own `apiKey`, or doesn't need one at all.
+    pub fn uses_key_pool(&self) -> bool {
+        matches!(self, Self::OpenAI)
+    }
+
+    /// The server the provider is called at, if the config chooses it.
+    pub fn api_base(&self) -> Option<&str> {
+        match self {
+            Self::OpenAI => None,
+            Self::Azure { api_base, .. } | Self::Compatible { api_base, .. } => Some(api_base),
+            Self::Anthropic { api_base, .. } => api_base.as_deref(),
+        }
+    }
+
+    /// The stored key the provider is called with, if any.
+    pub fn api_key_id(&self) -> Option<Uuid> {
+        match self {
+            Self::OpenAI => None,
+            Self::Azure { api_key_id, .. }
+            | Self::Compatible { api_key_id, .. }
+            | Self::Anthropic { api_key_id, .. } => *api_key_id,
+        }
+    }
+
+    /// Fill in the key that `api_key_id` refers to.
+    pub(crate) fn set_api_key(&mut self, key: String) {
+        match self {
+            Self::OpenAI => {}
+            Self::Azure { api_key, .. }
+            | Self::Compatible { api_key, .. }
+            | Self::Anthropic { api_key, .. } => *api_key = Some(key),
+        }
+    }
+
+    /// Check that the config names a key wherever the provider can't do without one.
+    pub fn check_api_key(&self) -> Result<(), Error> {
+        match self {
+            Self::Azure {
+                api_key_id: None, ..
+            }
+            | Self::Anthropic {
+                api_key_id: None, ..
+            } => Err(Error::InvalidConfig("an apiKey is required".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Build the provider, using `pooled_key` when the config doesn't carry its own key, and
+    /// connecting to the `pinned` addresses of its `apiBase`, if it was looked up.
+    pub fn build(
+        &self,
+        pooled_key: Option<&str>,
+        pinned: Option<&PinnedHost>,
+    ) -> Result<Box<dyn LlmProvider>, Error> {
+        let missing_key = || Error::InvalidConfig("an apiKey is required".to_string());
+        Ok(match self {
+            Self::OpenAI => match pooled_key {
+                Some(key) => Box::new(OpenAIProvider::new(key)),
+                None => Box::new(OpenAIProvider::from_env()),
+            },
+            Self::Azure {
+                api_base,
+                api_version,
+                deployment_id,
+                api_key,
+                ..
+            } => Box::new(OpenAIProvider::azure(
+                api_base,
+                api_version,
+                deployment_id,
+                api_key.as_deref().ok_or_else(missing_key)?,
+                pinned,
+            )),
+            Self::Compatible {
+                api_base, api_key, ..
+            } => Box::new(OpenAIProvider::compatible(
+                api_base,
+                api_key.as_deref().or(pooled_key),
+                pinned,
+            )),
+            Self::Anthropic {
+                api_base, api_key, ..
+            } => Box::new(AnthropicProvider::new(
+                api_base.as_deref(),
+                api_key.as_deref().ok_or_else(missing_key)?,
+                pinned,
+            )),
+        })
+    }
+}
+
+/// OpenAI and everything that speaks its API, backed by `async_openai`.
+pub struct OpenAIProvider<C: Config = OpenAIConfig> {
+    name: &'static str,
+    client: Client<C>,
+}
+
+impl OpenAIProvider<OpenAIConfig> {
+    pub fn new(api_key: &str) -> Self {
+        Self {
+            name: "openai",
+            client: Client::with_config(OpenAIConfig::new().with_api_key(api_key))
+                .with_backoff(no_backoff()),
+        }
+    }
+
+    /// Use the key from the `OPENAI_API_KEY` environment variable.
+    ///
+    /// This is what the v1 endpoints and the older queues have always done.
+    pub fn from_env() -> Self {
+        Self {
+            name: "openai",
+            client: Client::new(),
+        }
+    }
+
+    pub fn compatible(api_base: &str, api_key: Option<&str>, pinned: Option<&PinnedHost>) -> Self {
+        // Most self-hosted servers ignore the key, but `async_openai` always sends the header.
+        let config = OpenAIConfig::new()
+            .with_api_base(api_base)
+            .with_api_key(api_key.unwrap_or("none"));
+        Self {
+            name: "compatible",
+            client: Client::with_config(config)
+                .with_http_client(no_redirects(pinned))
+                .with_backoff(no_backoff()),
+        }
+    }
+}
+
+impl OpenAIProvider<AzureConfig> {
+    pub fn azure(
+        api_base: &str,
+        api_version: &str,
+        deployment_id: &str,
+        api_key: &str,
+        pinned: Option<&PinnedHost>,
+    ) -> Self {
+        let config = AzureConfig::new()
+            .with_api_base(api_base)
+            .with_api_version(api_version)
+            .with_deployment_id(deployment_id)
+            .with_api_key(api_key);
+        Self {
+            name: "azure",
+            client: Client::with_config(config)
+                .with_http_client(no_redirects(pinned))
+                .with_backoff(no_backoff()),
+        }
+    }
+}
+
+#[async_trait]
+impl<C> LlmProvider for OpenAIProvider<C>
+where
+    C: Config + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<String, Error> {
+        let chat_request = build_request(request)?;
+        let gpt_response = self
+            .client
+            .chat()
+            .create(chat_request)
+            .await
+            .map_err(|e| map_openai_error(self.name, e))?;
+        let output = gpt_response
+            .choices
+            .into_iter()
+            .find(|x| x.message.role == Role::Assistant)
+            .and_then(|x| x.message.content)
+            .unwrap_or_default();
+
+        Ok(output)
+    }
+
+    async fn chat_stream(&self, request: ChatRequest) -> Result<ChatStream, Error> {
+        let name = self.name;
+        let chat_request = build_request(request)?;
+        let chunks = self
+            .client
+            .chat()
+            .create_stream(chat_request)
+            .await
+            .map_err(|e| map_openai_error(name, e))?;
+        // The first chunk only carries the role, and the last one only the finish reason.
+        let deltas = chunks.filter_map(move |chunk| async move {
+            match chunk {
+                Ok(chunk) => chunk
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.delta.content)
+                    .map(Ok),
+                Err(e) => Some(Err(map_openai_error(name, e))),
+            }
+        });
+
+        Ok(deltas.boxed())
+    }
+
+    async fn embed(&self, model: &str, inputs: Vec<String>) -> Result<Vec<Vec<f32>>, Error> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(model)
+            .input(inputs)
+            .build()?;
+        let response = self
+            .client
+            .embeddings()
+            .create(request)
+            .await
+            .map_err(|e| map_openai_error(self.name, e))?;
+        let mut data = response.data;
+        // The API doesn't promise to keep the order of the inputs.
+        data.sort_by_key(|embedding| embedding.index);
+
+        Ok(data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+}
+
+/// `async_openai` retries rate limited calls on the same key for up to 15 minutes by default.
+/// We'd rather hear about the 429 straight away so the key can cool down and the call can be
+/// retried with a different one; see `Llm::chat`.
+fn no_backoff() -> ExponentialBackoff {
+    ExponentialBackoff {
+        max_elapsed_time: Some(Duration::ZERO),
+        ..Default::default()
+    }
+}
+
+/// A client for servers a module chose, which mustn't be able to bounce us to addresses that
+/// `check_api_base` would have turned away, nor have their name resolve elsewhere after it
+/// checked: the client only connects to the `pinned` addresses.
+pub(crate) fn no_redirects(pinned: Option<&PinnedHost>) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    if let Some(pinned) = pinned {
+        builder = builder.resolve_to_addrs(&pinned.host, &pinned.addresses);
+    }
+    builder.build().unwrap_or_default()
+}
+
+fn map_openai_error(provider: &'static str, error: OpenAIError) -> Error {
+    match error {
+        OpenAIError::ApiError(api_error)
+            if api_error.code == Some(serde_json::json!("rate_limit_exceeded")) =>
+        {
+            Error::RateLimited {
+                provider,
+                retry_after: parse_retry_after(&api_error.message),
+                message: api_error.message,
+            }
+        }
+        // Streams don't get a parsed error body, only the status line.
+        OpenAIError::StreamError(message) if message.contains("429") => Error::RateLimited {
+            provider,
+            retry_after: None,
+            message,
+        },
+        error => Error::OpenAI(error),
+    }
+}
+
+/// OpenAI doesn't hand us the `retry-after` header through `async_openai`, but it repeats it in
+/// the message: "... Please try again in 1m6.5s." or "... try again in 20ms."
+fn parse_retry_after(message: &str) -> Option<Duration> {
+    static RETRY_AFTER: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"try again in ((?:\d+(?:\.\d+)?(?:ms|s|m|h))+)").unwrap());
+    static PARTS: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\d+(?:\.\d+)?)(ms|s|m|h)").unwrap());
+    let captures = RETRY_AFTER.captures(message)?;
+    let mut seconds = 0.0;
+    for part in PARTS.captures_iter(&captures[1]) {
+        let value = part[1].parse::<f64>().ok()?;
+        seconds += match &part[2] {
+            "ms" => value / 1000.0,
+            "s" => value,
+            "m" => value * 60.0,
+            _ => value * 3600.0,
+        };
+    }
+    Some(Duration::from_secs_f64(seconds))
+}
+
+fn build_request(request: ChatRequest) -> Result<CreateChatCompletionRequest, Error> {
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.max_tokens(request.max_tokens.unwrap_or(2048))
+        .model(&request.model)
+        .temperature(request.temperature.unwrap_or(0.1));
+    if let Some(top_p) = request.top_p {
+        args.top_p(top_p);
+    }
+    if let Some(stop) = request.stop.clone() {
+        args.stop(Stop::StringArray(stop));
+    }
+    if let Some(seed) = request.seed {
+        args.seed(seed);
+    }
+
+    Ok(args.messages(build_messages(request)?).build()?)
+}
+
+fn build_messages(request: ChatRequest) -> Result<Vec<ChatCompletionRequestMessage>, Error> {
+    let mut messages = Vec::<ChatCompletionRequestMessage>::new();
+    if let Some(system) = request.system {
+        messages.push(
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(system)
+                .build()?
+                .into(),
+        );
+    }
+    for h in request.history.unwrap_or_default() {
+        messages.push(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(h.user_input)
+                .build()?
+                .into(),
+        );
+        messages.push(
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content(h.ai_output)
+                .build()?
+                .into(),
+        );
+    }
+    messages.push(
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(request.input)
+            .build()?
+            .into(),
+    );
+
+    Ok(messages)
+}
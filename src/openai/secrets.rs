@@ -0,0 +1,245 @@
+use std::net::{IpAddr, SocketAddr};
+
+use reqwest::Url;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::openai::{Error, ProviderConfig};
+
+/// Where providers sit in a module's `config_data`: the one it chats with, and the one its
+/// retrieval embeds with.
+const PROVIDER_PATHS: [&[&str]; 2] = [&["provider"], &["retrieval", "provider"]];
+
+/// Move the `apiKey`s of a module config's providers into `provider_secret_v2`, leaving an
+/// `apiKeyId` in their place, and check the providers can be called.
+///
+/// An `apiKeyId` that was already there, because the config was read back and saved again,
+/// has to belong to `workspace_id`.
+pub async fn store_provider_secrets(
+    db: &PgPool,
+    workspace_id: Uuid,
+    allowed_hosts: &[String],
+    config_data: &mut serde_json::Value,
+) -> Result<(), Error> {
+    for path in PROVIDER_PATHS {
+        let Some(section) = path
+            .iter()
+            .try_fold(&mut *config_data, |value, key| value.get_mut(*key))
+            .and_then(|section| section.as_object_mut())
+        else {
+            continue;
+        };
+
+        match section.remove("apiKey") {
+            Some(serde_json::Value::String(api_key)) => {
+                let secret_id = sqlx::query_scalar!(
+                    r#"insert into provider_secret_v2 (workspace_id, api_key) values ($1, $2) returning secret_id"#,
+                    workspace_id,
+                    api_key
+                )
+                .fetch_one(db)
+                .await?;
+                section.insert("apiKeyId".to_string(), secret_id.to_string().into());
+            }
+            Some(serde_json::Value::Null) | None => {}
+            Some(_) => return Err(Error::InvalidConfig("apiKey must be a string".to_string())),
+        }
+
+        let provider = serde_json::from_value::<ProviderConfig>(section.clone().into())
+            .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+        provider.check_api_key()?;
+        if let Some(secret_id) = provider.api_key_id() {
+            sqlx::query_scalar!(
+                r#"select secret_id from provider_secret_v2 where secret_id = $1 and workspace_id = $2"#,
+                secret_id,
+                workspace_id
+            )
+            .fetch_optional(db)
+            .await?
+            .ok_or_else(|| Error::InvalidConfig(format!("unknown apiKeyId {}", secret_id)))?;
+        }
+        if let Some(api_base) = provider.api_base() {
+            check_api_base(api_base, allowed_hosts).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Look up the key a provider refers to.
+pub(crate) async fn load_provider_secret(db: &PgPool, secret_id: Uuid) -> Result<String, Error> {
+    sqlx::query_scalar!(
+        r#"select api_key from provider_secret_v2 where secret_id = $1"#,
+        secret_id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| Error::InvalidConfig(format!("unknown apiKeyId {}", secret_id)))
+}
+
+/// The addresses `check_api_base` found a host name at.
+///
+/// What a name resolves to can change between the check and the call, so the client calling
+/// the provider connects to these, rather than looking the name up again; see
+/// `provider::no_redirects`.
+#[derive(Clone, Debug)]
+pub struct PinnedHost {
+    pub host: String,
+    pub addresses: Vec<SocketAddr>,
+}
+
+/// Check that `api_base` is somewhere the server may send requests to.
+///
+/// Any workspace member can point a module at a server, so unless the host is one of
+/// `allowed_hosts` it has to be on the public internet: loopback, private and link-local
+/// addresses would let members reach services that are only meant for us.
+///
+/// A host name is resolved to check its addresses, which are handed back to be connected to.
+/// Allowed hosts and IP addresses need no lookup, and give `None`.
+pub async fn check_api_base(
+    api_base: &str,
+    allowed_hosts: &[String],
+) -> Result<Option<PinnedHost>, Error> {
+    let invalid = |reason: &str| Error::InvalidConfig(format!("apiBase {}: {}", api_base, reason));
+    let url = Url::parse(api_base).map_err(|e| invalid(&e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("only http and https are supported"));
+    }
+    let host = url.host_str().ok_or_else(|| invalid("no host"))?;
+    if allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Ok(None);
+    }
+
+    // IPv6 hosts come in brackets.
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        if !is_public(ip) {
+            return Err(invalid("the host isn't a public address"));
+        }
+        return Ok(None);
+    }
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addresses = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| invalid(&e.to_string()))?
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        return Err(invalid("the host doesn't resolve"));
+    }
+    if addresses.iter().any(|address| !is_public(address.ip())) {
+        return Err(invalid("the host isn't a public address"));
+    }
+
+    Ok(Some(PinnedHost {
+        host: host.to_string(),
+        addresses,
+    }))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // "This network" and carrier-grade NAT.
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local and link-local.
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn private_and_loopback_addresses_are_turned_away() {
+        for api_base in [
+            "http://127.0.0.1:8000/v1",
+            "http://10.0.0.8/v1",
+            "http://169.254.169.254/latest",
+            "http://[::1]:8000",
+            "http://[::ffff:192.168.1.1]",
+            "http://[fd00::1]",
+            "http://localhost:8000/v1",
+        ] {
+            assert!(
+                check_api_base(api_base, &[]).await.is_err(),
+                "{} was allowed",
+                api_base
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn public_and_allowed_hosts_are_accepted() {
+        // Neither is looked up, so there's nothing to pin.
+        assert!(check_api_base("https://1.1.1.1/v1", &[])
+            .await
+            .unwrap()
+            .is_none());
+        assert!(check_api_base("http://vllm:8000/v1", &["vllm".to_string()])
+            .await
+            .unwrap()
+            .is_none());
+        check_api_base("http://VLLM/v1", &["vllm".to_string()])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn pinned_hosts_are_not_looked_up_again() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        // `.invalid` never resolves, so the answer can only come from the pinned address.
+        let pinned = PinnedHost {
+            host: "provider.invalid".to_string(),
+            addresses: vec![address],
+        };
+        let response = crate::openai::provider::no_redirects(Some(&pinned))
+            .get(format!("http://provider.invalid:{}/v1", address.port()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn only_http_is_spoken() {
+        assert!(check_api_base("file:///etc/passwd", &[]).await.is_err());
+        assert!(check_api_base("not a url", &[]).await.is_err());
+    }
+}
@@ -0,0 +1,574 @@
+//! A module's preprocess steps: prompts whose answers become keys of the module's prompt.
+//!
+//! Steps read keys and write one key each, so they form a graph, and run in an order where
+//! every step comes after the steps whose answers it reads. Steps that read the input of a
+//! run, directly or through other steps, run once per input on the workers; the rest run
+//! once, before the run's messages are queued.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::graph;
+use crate::openai::{ChatRequest, Llm, ModelParams, ProviderConfig};
+use crate::template::{self, Context, Template};
+
+/// Keys that only exist per input of a run.
+const INPUT_KEYS: &[&str] = &["input", "reference"];
+
+/// A step, as kept in the `preprocess` of a module's `config_data`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StepConfig {
+    /// The key the step's answer is written to, for the prompt and later steps to read.
+    pub output_key: String,
+    pub prompt: String,
+    /// The keys the step reads. Taken from the prompt if not given; if given, the prompt may
+    /// not read any others.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_keys: Option<Vec<String>>,
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: u16,
+    /// Reuse the answer to an earlier call with the same prompt and parameters.
+    #[serde(default = "default_cache")]
+    pub cache: bool,
+}
+
+fn default_model() -> String {
+    "gpt-3.5-turbo-1106".to_string()
+}
+
+fn default_temperature() -> f32 {
+    0.1
+}
+
+fn default_max_tokens() -> u16 {
+    2048
+}
+
+fn default_cache() -> bool {
+    true
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("invalid preprocess steps: {0}")]
+    Config(String),
+
+    #[error("step `{step}` {reason}")]
+    InvalidStep { step: String, reason: String },
+
+    #[error("steps {0} wait on each other")]
+    Cycle(String),
+
+    #[error("step `{step}`: {source}")]
+    Template {
+        step: String,
+        source: crate::template::Error,
+    },
+
+    #[error(transparent)]
+    Llm(#[from] crate::openai::Error),
+
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+}
+
+/// Which steps to run.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Steps {
+    All,
+    /// Those that don't read the input, once per run.
+    Shared,
+    /// Those that read the input, once per input.
+    PerInput,
+}
+
+#[derive(Clone, Debug)]
+struct Step {
+    config: StepConfig,
+    template: Template,
+    per_input: bool,
+}
+
+/// A module's preprocess steps, in the order they run in.
+#[derive(Clone, Debug, Default)]
+pub struct Preprocess {
+    steps: Vec<Step>,
+}
+
+/// What steps call the model with.
+pub struct Runner<'a> {
+    pub db: &'a PgPool,
+    pub llm: &'a Llm,
+    pub provider: &'a ProviderConfig,
+    /// How long, in seconds, a cached answer is reused; 0 turns caching off.
+    pub cache_secs: u64,
+}
+
+impl Preprocess {
+    /// The steps in the `preprocess` of a module's config, which may be missing. Their
+    /// prompts are in the syntax of the module's.
+    pub fn from_config(config: &Map<String, Value>) -> Result<Preprocess, Error> {
+        let steps = match config.get("preprocess") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(steps) => serde_json::from_value::<Vec<StepConfig>>(steps.clone())
+                .map_err(|e| Error::Config(e.to_string()))?,
+        };
+        Preprocess::new(steps, template::prompt_version(config))
+    }
+
+    /// Order `steps`, with prompts in syntax `version`, so that each comes after the steps
+    /// whose output it reads.
+    ///
+    /// Fails on steps writing the same key, or a key that's only known per input, and on
+    /// steps that read each other's output.
+    ///
+    /// Steps that don't declare their input keys may have been saved before steps could, when
+    /// they ran in the order they're listed, and read the module's value of the keys that the
+    /// step itself or later steps write. Rather than failing, a step like that reading its own
+    /// output still reads the module's value, and if steps like that read each other's
+    /// output, they run in the order they're listed.
+    pub fn new(steps: Vec<StepConfig>, version: u32) -> Result<Preprocess, Error> {
+        let invalid = |step: &StepConfig, reason: String| Error::InvalidStep {
+            step: step.output_key.clone(),
+            reason,
+        };
+
+        let mut writers = HashMap::new();
+        for (i, step) in steps.iter().enumerate() {
+            if step.output_key.is_empty() {
+                return Err(Error::Config("every step needs an output key".to_string()));
+            }
+            if INPUT_KEYS.contains(&step.output_key.as_str()) {
+                return Err(invalid(step, "can't write a key of the input".to_string()));
+            }
+            if writers.insert(step.output_key.as_str(), i).is_some() {
+                return Err(invalid(step, "is defined twice".to_string()));
+            }
+        }
+
+        let mut parsed = Vec::with_capacity(steps.len());
+        for step in &steps {
+            let template = Template::parse_version(&step.prompt, version).map_err(|source| {
+                Error::Template {
+                    step: step.output_key.clone(),
+                    source,
+                }
+            })?;
+            let used = template.used_keys();
+            let inputs = match &step.input_keys {
+                Some(input_keys) => {
+                    let is_input = |key: &str| input_keys.iter().any(|input| input == key);
+                    if let Some(key) = used
+                        .iter()
+                        .find(|key| template::resolve_key(key, is_input).is_none())
+                    {
+                        return Err(invalid(
+                            step,
+                            format!("reads `{}`, which isn't one of its input keys", key),
+                        ));
+                    }
+                    input_keys.clone()
+                }
+                // Keys that aren't an input or another step's output come from the module.
+                None => used
+                    .iter()
+                    .map(|key| {
+                        let is_known =
+                            |key: &str| INPUT_KEYS.contains(&key) || writers.contains_key(key);
+                        template::resolve_key(key, is_known)
+                            .unwrap_or(key)
+                            .to_string()
+                    })
+                    .collect(),
+            };
+            parsed.push((template, inputs));
+        }
+
+        let mut edges = Vec::new();
+        // Reads of the output of a later step, by steps that don't declare their input keys.
+        let mut backward = Vec::new();
+        for (i, (_, inputs)) in parsed.iter().enumerate() {
+            let declared = steps[i].input_keys.is_some();
+            for input in inputs {
+                if let Some(&j) = writers.get(input.as_str()) {
+                    if i == j {
+                        if declared {
+                            return Err(invalid(&steps[i], "reads its own output".to_string()));
+                        }
+                        continue;
+                    }
+                    if !declared && j > i {
+                        backward.push(edges.len());
+                    }
+                    edges.push((j, i));
+                }
+            }
+        }
+        let order = match graph::toposort(steps.len(), edges.iter().copied()) {
+            Err(_) if !backward.is_empty() => {
+                log::warn!("preprocess steps wait on each other, running them as listed");
+                for i in backward {
+                    let (from, to) = edges[i];
+                    edges[i] = (to, from);
+                }
+                graph::toposort(steps.len(), edges)
+            }
+            order => order,
+        };
+        let order = order.map_err(|waiting| {
+            let cycle = waiting
+                .into_iter()
+                .map(|i| format!("`{}`", steps[i].output_key))
+                .collect::<Vec<_>>()
+                .join(", ");
+            Error::Cycle(cycle)
+        })?;
+
+        // A step is per input if it reads the input, or the output of a step that is.
+        let mut per_input = HashMap::<&str, bool>::new();
+        let mut ordered = Vec::with_capacity(steps.len());
+        for i in order {
+            let (template, inputs) = parsed[i].clone();
+            let step_per_input = inputs.iter().any(|key| {
+                INPUT_KEYS.contains(&key.as_str())
+                    || per_input.get(key.as_str()).copied().unwrap_or(false)
+            });
+            per_input.insert(steps[i].output_key.as_str(), step_per_input);
+            ordered.push(Step {
+                config: steps[i].clone(),
+                template,
+                per_input: step_per_input,
+            });
+        }
+
+        Ok(Preprocess { steps: ordered })
+    }
+
+    fn selected(&self, which: Steps) -> impl Iterator<Item = &Step> {
+        self.steps.iter().filter(move |step| match which {
+            Steps::All => true,
+            Steps::Shared => !step.per_input,
+            Steps::PerInput => step.per_input,
+        })
+    }
+
+    /// The configs of the selected steps, in the order they run in.
+    pub fn configs(&self, which: Steps) -> Vec<StepConfig> {
+        self.selected(which)
+            .map(|step| step.config.clone())
+            .collect()
+    }
+
+    /// Check the model of every step, and its parameters, against the catalog, as served by
+    /// the module's `provider`.
+    pub fn check_models(&self, provider: &ProviderConfig) -> Result<(), Error> {
+        for step in &self.steps {
+            let params = ModelParams {
+                model: step.config.model.clone(),
+                temperature: step.config.temperature,
+                max_tokens: step.config.max_tokens,
+                ..Default::default()
+            };
+            params.check(provider).map_err(|e| Error::InvalidStep {
+                step: step.config.output_key.clone(),
+                reason: e.to_string(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// The keys the selected steps write.
+    pub fn output_keys(&self, which: Steps) -> Vec<String> {
+        self.selected(which)
+            .map(|step| step.config.output_key.clone())
+            .collect()
+    }
+
+    /// Run the selected steps, writing each answer into `keys` under its output key.
+    ///
+    /// `keys` must hold the input and reference for per-input steps.
+    pub async fn run(
+        &self,
+        runner: &Runner<'_>,
+        keys: &mut Map<String, Value>,
+        which: Steps,
+    ) -> Result<(), Error> {
+        for step in self.selected(which) {
+            let prompt = step
+                .template
+                .render(&Context::new().with_keys(keys.clone()))
+                .map_err(|source| Error::Template {
+                    step: step.config.output_key.clone(),
+                    source,
+                })?;
+            log::info!("preprocessing: key: {}", &step.config.output_key);
+            let output = runner.answer(&step.config, prompt).await?;
+            log::info!("preprocessing: key: {}, finish", &step.config.output_key);
+            keys.insert(step.config.output_key.clone(), Value::String(output));
+        }
+
+        Ok(())
+    }
+}
+
+impl Runner<'_> {
+    async fn answer(&self, step: &StepConfig, prompt: String) -> Result<String, Error> {
+        let cache = step.cache && self.cache_secs > 0;
+        let cache_key = cache_key(self.provider, step, &prompt);
+        if cache {
+            let cached = sqlx::query_scalar!(
+                r#"select output from preprocess_cache
+                where cache_key = $1 and created_at > now() - make_interval(secs => $2)"#,
+                cache_key,
+                self.cache_secs as f64
+            )
+            .fetch_optional(self.db)
+            .await?;
+            if let Some(output) = cached {
+                return Ok(output);
+            }
+        }
+
+        let output = self
+            .llm
+            .chat(
+                self.provider,
+                ChatRequest {
+                    model: step.model.clone(),
+                    input: prompt,
+                    max_tokens: Some(step.max_tokens),
+                    temperature: Some(step.temperature),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        if cache {
+            sqlx::query!(
+                r#"insert into preprocess_cache (cache_key, output) values ($1, $2)
+                on conflict (cache_key) do update set output = excluded.output, created_at = now()"#,
+                cache_key,
+                output
+            )
+            .execute(self.db)
+            .await?;
+        }
+
+        Ok(output)
+    }
+}
+
+/// Drop the cached answers older than `cache_secs`, which are no longer reused. Returns how
+/// many were dropped.
+pub async fn expire_cache(db: &PgPool, cache_secs: u64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"delete from preprocess_cache where created_at < now() - make_interval(secs => $1)"#,
+        cache_secs as f64
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically drop expired cached answers for as long as the process runs.
+pub fn spawn_cache_sweeper(db: PgPool, cache_secs: u64, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = expire_cache(&db, cache_secs).await {
+                log::error!("failed to expire preprocess cache: {}", e);
+            }
+        }
+    });
+}
+
+/// What a step's answer is cached under: everything that goes into the call.
+fn cache_key(provider: &ProviderConfig, step: &StepConfig, prompt: &str) -> String {
+    let call = json!({
+        "provider": provider,
+        "model": step.model,
+        "temperature": step.temperature,
+        "maxTokens": step.max_tokens,
+        "prompt": prompt,
+    });
+    Sha256::digest(call.to_string().as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::PROMPT_VERSION;
+
+    fn step(output_key: &str, prompt: &str) -> StepConfig {
+        serde_json::from_value(json!({ "outputKey": output_key, "prompt": prompt })).unwrap()
+    }
+
+    fn with_input_keys(mut step: StepConfig, input_keys: &[&str]) -> StepConfig {
+        step.input_keys = Some(input_keys.iter().map(|key| key.to_string()).collect());
+        step
+    }
+
+    fn invalid_step(result: Result<Preprocess, Error>) -> (String, String) {
+        match result {
+            Err(Error::InvalidStep { step, reason }) => (step, reason),
+            other => panic!("expected an invalid step, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn steps_run_after_the_steps_they_read() {
+        let preprocess = Preprocess::new(
+            vec![
+                step("summary", "Summarize @{key/outline}"),
+                step("outline", "Outline @{key/topic} using @{key/facts}"),
+                step("facts", "List facts about @{key/topic}"),
+            ],
+            PROMPT_VERSION,
+        )
+        .unwrap();
+        assert_eq!(
+            preprocess.output_keys(Steps::All),
+            ["facts", "outline", "summary"]
+        );
+    }
+
+    #[test]
+    fn reading_the_input_spreads_to_dependent_steps() {
+        let preprocess = Preprocess::new(
+            vec![
+                step("answer", "Answer @{key/question} with @{key/glossary}"),
+                step("question", "Rephrase @{key/input}"),
+                step("glossary", "Define the terms of @{key/topic}"),
+                step("intro", "Introduce @{key/glossary}"),
+                step("graded", "Grade @{key/answer} against @{key/reference}"),
+            ],
+            PROMPT_VERSION,
+        )
+        .unwrap();
+        assert_eq!(preprocess.output_keys(Steps::Shared), ["glossary", "intro"]);
+        assert_eq!(
+            preprocess.output_keys(Steps::PerInput),
+            ["question", "answer", "graded"]
+        );
+    }
+
+    #[test]
+    fn declared_input_keys_decide_the_order() {
+        let preprocess = Preprocess::new(
+            vec![
+                with_input_keys(step("b", "@{key/a | default: \"none\"}"), &["a"]),
+                with_input_keys(step("a", "Read @{key/input}"), &["input"]),
+            ],
+            PROMPT_VERSION,
+        )
+        .unwrap();
+        assert_eq!(preprocess.output_keys(Steps::PerInput), ["a", "b"]);
+        assert!(preprocess.output_keys(Steps::Shared).is_empty());
+    }
+
+    #[test]
+    fn steps_may_only_read_their_declared_input_keys() {
+        let (step, reason) = invalid_step(Preprocess::new(
+            vec![with_input_keys(
+                step("a", "@{key/topic} and @{key/other}"),
+                &["topic"],
+            )],
+            PROMPT_VERSION,
+        ));
+        assert_eq!(step, "a");
+        assert!(reason.contains("`other`"), "{}", reason);
+    }
+
+    #[test]
+    fn steps_that_wait_on_each_other_are_a_cycle() {
+        let error = Preprocess::new(
+            vec![
+                with_input_keys(step("a", "@{key/c}"), &["c"]),
+                with_input_keys(step("b", "@{key/a}"), &["a"]),
+                with_input_keys(step("c", "@{key/b}"), &["b"]),
+                step("d", "@{key/topic}"),
+            ],
+            PROMPT_VERSION,
+        )
+        .unwrap_err();
+        let Error::Cycle(cycle) = error else {
+            panic!("expected a cycle, got {:?}", error);
+        };
+        for key in ["`a`", "`b`", "`c`"] {
+            assert!(cycle.contains(key), "{}", cycle);
+        }
+        assert!(!cycle.contains("`d`"), "{}", cycle);
+    }
+
+    #[test]
+    fn steps_may_not_read_their_own_output() {
+        let (step, reason) = invalid_step(Preprocess::new(
+            vec![with_input_keys(step("a", "More @{key/a}"), &["a"])],
+            PROMPT_VERSION,
+        ));
+        assert_eq!(step, "a");
+        assert_eq!(reason, "reads its own output");
+    }
+
+    #[test]
+    fn steps_saved_before_input_keys_still_load() {
+        // As saved when steps ran in the order they're listed, in syntax version 1: `title`
+        // reads the module's `outline`, which `outline` then rewrites from it and `title`.
+        let config = json!({
+            "promptVersion": 1,
+            "preprocess": [
+                {
+                    "model": "gpt-3.5-turbo-1106",
+                    "outputKey": "title",
+                    "prompt": "A title for @key/outline, {% as is %}",
+                },
+                {
+                    "model": "gpt-3.5-turbo-1106",
+                    "outputKey": "outline",
+                    "prompt": "Improve @key/outline to fit @key/title",
+                },
+            ],
+        });
+        let preprocess = Preprocess::from_config(config.as_object().unwrap()).unwrap();
+        assert_eq!(preprocess.output_keys(Steps::All), ["title", "outline"]);
+        assert!(preprocess.output_keys(Steps::PerInput).is_empty());
+    }
+
+    #[test]
+    fn output_keys_are_written_once() {
+        let (step, reason) = invalid_step(Preprocess::new(
+            vec![step("a", "one"), step("b", "two"), step("a", "three")],
+            PROMPT_VERSION,
+        ));
+        assert_eq!(step, "a");
+        assert_eq!(reason, "is defined twice");
+    }
+
+    #[test]
+    fn steps_may_not_write_input_keys() {
+        for key in INPUT_KEYS {
+            let (step, _) =
+                invalid_step(Preprocess::new(vec![step(key, "anything")], PROMPT_VERSION));
+            assert_eq!(step, *key);
+        }
+        assert!(matches!(
+            Preprocess::new(vec![step("", "anything")], PROMPT_VERSION),
+            Err(Error::Config(_))
+        ));
+    }
+}
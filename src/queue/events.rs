@@ -0,0 +1,210 @@
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// The Postgres channel job events are sent on, so that events from the queue workers reach
+/// the API no matter which process either runs in.
+const JOB_EVENT_CHANNEL: &str = "job_events";
+
+/// How many events a slow subscriber may fall behind before it starts missing some.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Notifications are limited to 8000 bytes, so long texts are cut to this many characters.
+const MAX_TEXT_CHARS: usize = 1000;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum JobEventKind {
+    /// The job's messages were queued.
+    Started,
+    /// A message of the job was answered and its candidates saved.
+    Progress,
+    /// A message of the job was given up on and dead-lettered.
+    Failed,
+    /// Every message of the job was either answered or given up on.
+    Finished,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LatestCandidate {
+    pub candidate_id: Uuid,
+    pub content: String,
+}
+
+/// Where a `job_v2` stands, sent to the workspace's subscribers whenever it moves.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JobEvent {
+    pub kind: JobEventKind,
+    pub job_id: Uuid,
+    pub workspace_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module_id: Option<Uuid>,
+    pub target_count: i64,
+    /// How many messages of the job were answered.
+    pub completed_count: i64,
+    /// How many messages of the job were given up on, and not re-driven since.
+    pub failed_count: i64,
+    /// A guess at how many seconds are left, from the pace of the job so far.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest_candidate: Option<LatestCandidate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl JobEvent {
+    /// The current state of `job_id`, as an event of `kind`. `None` if the job is gone.
+    ///
+    /// The counts are the ones the workers keep on the job, so this is cheap enough to do for
+    /// every message.
+    pub async fn load(
+        db: &PgPool,
+        kind: JobEventKind,
+        job_id: Uuid,
+    ) -> anyhow::Result<Option<JobEvent>> {
+        let job = sqlx::query!(
+            r#"select
+                workspace_id,
+                module_id,
+                target_count,
+                extract(epoch from now() - created_at)::float8 "elapsed_secs!",
+                completed_count,
+                failed_count
+            from job_v2 where job_id = $1"#,
+            job_id
+        )
+        .fetch_optional(db)
+        .await?;
+        let Some(job) = job else {
+            return Ok(None);
+        };
+
+        let target_count = job.target_count as i64;
+        let (completed_count, failed_count) = (job.completed_count as i64, job.failed_count as i64);
+        let done = completed_count + failed_count;
+        let remaining = (target_count - done).max(0);
+        let eta_secs = if done > 0 && remaining > 0 {
+            Some((job.elapsed_secs / done as f64 * remaining as f64).round() as i64)
+        } else {
+            None
+        };
+
+        Ok(Some(JobEvent {
+            kind,
+            job_id,
+            workspace_id: job.workspace_id,
+            module_id: job.module_id,
+            target_count,
+            completed_count,
+            failed_count,
+            eta_secs,
+            latest_candidate: None,
+            error: None,
+        }))
+    }
+
+    fn is_finished(&self) -> bool {
+        self.completed_count + self.failed_count >= self.target_count
+    }
+}
+
+/// Tell the job's workspace that it moved, followed by a `Finished` event if that was the
+/// last of its messages.
+///
+/// Events are only for showing progress, so failing to send one is logged rather than
+/// failing the job.
+pub async fn emit_job_event(
+    db: &PgPool,
+    kind: JobEventKind,
+    job_id: Uuid,
+    latest_candidate: Option<LatestCandidate>,
+    error: Option<String>,
+) {
+    let result = async {
+        let Some(mut event) = JobEvent::load(db, kind, job_id).await? else {
+            return Ok(());
+        };
+        event.latest_candidate = latest_candidate.map(|candidate| LatestCandidate {
+            content: truncate(&candidate.content),
+            ..candidate
+        });
+        event.error = error.as_deref().map(truncate);
+        notify(db, &event).await?;
+
+        if kind != JobEventKind::Started && event.is_finished() {
+            event.kind = JobEventKind::Finished;
+            event.latest_candidate = None;
+            event.error = None;
+            notify(db, &event).await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!("failed to emit {:?} event of job {}: {:?}", kind, job_id, e);
+    }
+}
+
+async fn notify(db: &PgPool, event: &JobEvent) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"select pg_notify($1, $2)"#,
+        JOB_EVENT_CHANNEL,
+        serde_json::to_string(event)?
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+fn truncate(text: &str) -> String {
+    text.chars().take(MAX_TEXT_CHARS).collect()
+}
+
+/// Relays job events from Postgres to whoever in this process subscribed.
+#[derive(Clone)]
+pub struct JobEvents {
+    sender: broadcast::Sender<JobEvent>,
+}
+
+impl JobEvents {
+    /// Start listening for job events for as long as the process runs.
+    pub async fn listen(db: &PgPool) -> anyhow::Result<Self> {
+        let mut listener = PgListener::connect_with(db).await?;
+        listener.listen(JOB_EVENT_CHANNEL).await?;
+
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        let events = JobEvents {
+            sender: sender.clone(),
+        };
+        tokio::spawn(async move {
+            loop {
+                // The listener reconnects on its own; events sent meanwhile are lost, which
+                // subscribers make up for with the next one.
+                match listener.recv().await {
+                    Ok(notification) => {
+                        match serde_json::from_str::<JobEvent>(notification.payload()) {
+                            // Nobody listening is fine.
+                            Ok(event) => drop(sender.send(event)),
+                            Err(e) => log::error!("malformed job event: {:?}", e),
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("failed to receive job events: {:?}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(events)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+        self.sender.subscribe()
+    }
+}
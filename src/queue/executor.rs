@@ -1,13 +1,13 @@
-use crate::openai;
-use crate::openai::ChatRequest;
-use async_openai::{
-    types::{ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, Role},
-    Client,
-};
+use crate::openai::{ChatRequest, Llm, ProviderConfig};
+use crate::preprocess::{self, Preprocess, Runner, Steps};
+use crate::queue::messages::{parse_message, EvaluateMessage, GeneratorMessage, ModuleMessage};
+use crate::queue::slots::acquire_slot;
+use crate::queue::{delivery_attempts, emit_job_event, JobEventKind, LatestCandidate};
+use crate::template::{self, Context, Template};
+use anyhow::Context as _;
 use lapin::message::Delivery;
 use log::info;
-use regex::Regex;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use sqlx::PgPool;
 use std::str;
 use tiktoken_rs::{cl100k_base, model};
@@ -22,6 +22,42 @@ pub enum JobStatus {
     Paused,
 }
 
+/// The status of a `job_v2`, which the workers check before and after every model call.
+#[derive(serde::Serialize, serde::Deserialize, sqlx::Type, PartialEq, Clone, Copy, Debug)]
+#[repr(i32)]
+pub enum JobStatusV2 {
+    /// The job's messages are being worked on.
+    Active = 0,
+    /// The job was cancelled, or its module was reset or run again. Its messages are dropped.
+    Cancelled = 1,
+    /// The job's messages are set aside until it is resumed.
+    Paused = 2,
+}
+
+/// Which lane the messages of a `job_v2` are queued on.
+///
+/// Each lane has its own queue and its own share of every worker, so a run of a few inputs
+/// doesn't wait behind a file of thousands.
+#[derive(serde::Serialize, serde::Deserialize, sqlx::Type, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+#[repr(i32)]
+pub enum JobPriority {
+    /// Large jobs, which take turns with other workspaces' large jobs.
+    Batch = 0,
+    /// Small jobs someone is waiting on.
+    Interactive = 1,
+}
+
+impl JobPriority {
+    /// The queue messages of this priority are published to.
+    pub fn queue(self) -> &'static str {
+        match self {
+            JobPriority::Batch => "claymore_evo_queue",
+            JobPriority::Interactive => "claymore_evo_interactive_queue",
+        }
+    }
+}
+
 pub enum ExecuteResult {
     Overflow,
     Success,
@@ -29,33 +65,62 @@ pub enum ExecuteResult {
 
 pub enum ExecuteResultV2 {
     Success,
-    Failed(i32),
+    /// The attempt failed; carries the attempts made so far and why this one failed.
+    Failed(i32, String),
+    /// The message can't be worked on yet, e.g. because its job is paused or it's newer than
+    /// this worker understands, so it should come back later.
+    Deferred,
+    /// The job was cancelled, so the message should be dropped.
+    Cancelled,
+    /// The message's workspace already has as many calls in flight as it may, so it should
+    /// wait its turn.
+    Throttled,
 }
 
-pub async fn execute_job(db: PgPool, delivery: &Delivery) -> Result<ExecuteResult, anyhow::Error> {
-    let message = str::from_utf8(&delivery.data).unwrap();
-    let message: Value = serde_json::from_str(message).unwrap();
+/// The status of `job_id`, or `Cancelled` if the job is gone.
+async fn job_status_v2(db: &PgPool, job_id: Uuid) -> Result<JobStatusV2, anyhow::Error> {
+    let status = sqlx::query!(
+        r#"select job_status "job_status: JobStatusV2" from job_v2 where job_id = $1"#,
+        job_id
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|job| job.job_status)
+    .unwrap_or(JobStatusV2::Cancelled);
+
+    Ok(status)
+}
+
+/// Write one datadrop of a v1 job.
+///
+/// A message that doesn't parse, or whose job or generator is gone, is an error, and so is a
+/// failed model call; the consumer drops the message either way.
+pub async fn execute_job(
+    db: PgPool,
+    llm: Llm,
+    delivery: &Delivery,
+) -> Result<ExecuteResult, anyhow::Error> {
+    let message: Value = serde_json::from_slice(&delivery.data)?;
     info!("Start execute job: {}", message);
-    let job_id = message["job_id"].as_str().unwrap();
-    let job_id = Uuid::parse_str(job_id).unwrap();
+    let job_id = message["job_id"]
+        .as_str()
+        .context("message has no job_id")?;
+    let job_id = Uuid::parse_str(job_id)?;
     let finished_count = sqlx::query!(
         r#"select count(*) as finished_count from datadrop where job_id = $1"#,
         job_id
     )
     .fetch_one(&db)
-    .await
-    .unwrap()
+    .await?
     .finished_count
     .unwrap_or(0);
 
     let target_count = sqlx::query!(r#"select target_count from job where job_id = $1"#, job_id)
         .fetch_one(&db)
-        .await
-        .unwrap()
+        .await?
         .target_count;
     let target_count = target_count as i64;
 
-    let client = Client::new();
     info!(
         "Job {}: finished count: {}, target_count: {}",
         job_id, finished_count, target_count
@@ -67,8 +132,7 @@ pub async fn execute_job(db: PgPool, delivery: &Delivery) -> Result<ExecuteResul
             job_id
         )
         .execute(&db)
-        .await
-        .unwrap();
+        .await?;
         return Ok(ExecuteResult::Overflow);
     }
 
@@ -77,34 +141,45 @@ pub async fn execute_job(db: PgPool, delivery: &Delivery) -> Result<ExecuteResul
     let temperature;
     let prompt_chain;
     let prompts;
+    let prompt_version;
 
     let generator_id = message["generator_id"].as_str();
-    if generator_id.is_some() {
-        let generator_id = Uuid::parse_str(generator_id.unwrap()).unwrap();
+    if let Some(generator_id) = generator_id {
+        let generator_id = Uuid::parse_str(generator_id)?;
         let generator = sqlx::query!(
             r#"select model_name, word_count, prompt_chain, temperature from generator where generator_id = $1"#,
             generator_id
         )
         .fetch_one(&db)
-        .await
-        .unwrap();
+        .await?;
         model_name = generator.model_name;
         word_count = generator.word_count as u16;
         temperature = generator.temperature as f32;
         prompt_chain = generator.prompt_chain;
-        prompts = prompt_chain["prompts"].as_array().unwrap();
+        prompts = prompt_chain["prompts"]
+            .as_array()
+            .context("generator has no prompts")?;
+        prompt_version = template::prompt_version_of(&prompt_chain);
     } else {
-        model_name = message["model_name"].as_str().unwrap().to_string();
-        word_count = message["word_count"].as_i64().unwrap() as u16;
-        temperature = message["temperature"].as_f64().unwrap() as f32;
-        let prompt_chain = message["prompt_chain"].as_object().unwrap();
-        prompts = prompt_chain["prompts"].as_array().unwrap();
+        model_name = message["model_name"]
+            .as_str()
+            .context("message has no model_name")?
+            .to_string();
+        word_count = message["word_count"]
+            .as_i64()
+            .context("message has no word_count")? as u16;
+        temperature = message["temperature"]
+            .as_f64()
+            .context("message has no temperature")? as f32;
+        prompts = message["prompt_chain"]["prompts"]
+            .as_array()
+            .context("message has no prompts")?;
+        prompt_version = template::prompt_version_of(&message["prompt_chain"]);
     }
 
     let project_id = sqlx::query!(r#"select project_id from job where job_id = $1"#, job_id)
         .fetch_one(&db)
-        .await
-        .unwrap()
+        .await?
         .project_id;
     // Remove loop here, but nack message outside
     // for _ in finished_count..target_count {
@@ -113,8 +188,7 @@ pub async fn execute_job(db: PgPool, delivery: &Delivery) -> Result<ExecuteResul
         job_id
     )
     .fetch_one(&db)
-    .await
-    .unwrap()
+    .await?
     .job_status;
     if job_status == JobStatus::Paused {
         return Ok(ExecuteResult::Overflow);
@@ -126,175 +200,41 @@ pub async fn execute_job(db: PgPool, delivery: &Delivery) -> Result<ExecuteResul
             job_id
         )
         .execute(&db)
-        .await
-        .unwrap();
+        .await?;
     }
-    let mut response = String::new();
     let mut prompt_responses: Vec<String> = Vec::new();
-    for (current_idx, prompt) in prompts.iter().enumerate() {
+    for prompt in prompts.iter() {
         info!(
             "Job {}: prompt: {} model_name: {}",
             job_id, prompt, &model_name
         );
-        let prompt = prompt.as_str().unwrap();
-        let mut prompt = prompt.replace("^^", &response);
-        let regex = Regex::new(r"@(ref|prompt)/([\w+/]+)").unwrap();
-        let mut patterns = Vec::new();
-        for cap in regex.captures_iter(&prompt) {
-            let mut pattern = Vec::new();
-            pattern.push(cap[1].to_string());
-            pattern.extend(cap[2].split('/').map(|s| s.to_string()));
-            patterns.push(pattern);
-        }
-        for pattern in patterns {
-            let pattern_type = &pattern[0];
-            if pattern_type == "ref" {
-                let name = &pattern[1];
-                let character = sqlx::query!(
-                    r#"select settings from character where character_name = $1 and project_id = $2"#,
-                    name,
-                    project_id
-                )
-                .fetch_one(&db)
-                .await
-                .unwrap()
-                .settings;
-                let character = character["kv"].as_array().unwrap();
-                let result;
-                if pattern.len() == 2 {
-                    // whole
-                    result = character
-                        .iter()
-                        .map(|x| {
-                            let key = x["key"].as_str().unwrap();
-                            let value_type = x["type"].as_str().unwrap();
-                            let value;
-                            if value_type == "array" {
-                                let values = x["value"].as_array().unwrap();
-                                value = values
-                                    .iter()
-                                    .map(|x| x.as_str().unwrap())
-                                    .collect::<Vec<&str>>()
-                                    .join("; ");
-                            } else {
-                                value = x["value"].as_str().unwrap().to_string();
-                            }
-                            format!("{}: {}", key, value)
-                        })
-                        .collect::<Vec<String>>()
-                        .join(", ");
-                } else if pattern.len() == 3 {
-                    if pattern[2] == "random" {
-                        // random from `name`
-                        let idx = rand::random::<usize>() % character.len();
-                        let x = &character[idx];
-                        let value_type = x["type"].as_str().unwrap();
-                        let value;
-                        if value_type == "array" {
-                            let values = x["value"].as_array().unwrap();
-                            value = values
-                                .iter()
-                                .map(|x| x.as_str().unwrap())
-                                .collect::<Vec<&str>>()
-                                .join("; ");
-                        } else {
-                            value = x["value"].as_str().unwrap().to_string();
-                        }
-                        result = format!("{}: {}", x["key"].as_str().unwrap(), value);
-                    } else {
-                        let keys = pattern[2].split('+').collect::<Vec<&str>>();
-                        let mut temp = Vec::new();
-                        for key in keys {
-                            let x = character
-                                .iter()
-                                .find(|x| x["key"].as_str().unwrap() == key)
-                                .unwrap();
-                            let value_type = x["type"].as_str().unwrap();
-                            let value;
-                            if value_type == "array" {
-                                let values = x["value"].as_array().unwrap();
-                                value = values
-                                    .iter()
-                                    .map(|x| x.as_str().unwrap())
-                                    .collect::<Vec<&str>>()
-                                    .join("; ");
-                            } else {
-                                value = x["value"].as_str().unwrap().to_string();
-                            }
-                            temp.push(format!("{}: {}", key, value));
-                        }
-                        result = temp.join(", ");
-                    }
-                } else if pattern.len() == 4 {
-                    let key = &pattern[2];
-                    if pattern[3] == "random" {
-                        let value_type = character
-                            .iter()
-                            .find(|x| x["key"].as_str().unwrap() == key)
-                            .unwrap()["type"]
-                            .as_str()
-                            .unwrap();
-                        if value_type != "array" {
-                            result = "".to_string();
-                        } else {
-                            // random from `name` with `key`
-                            let value = character
-                                .iter()
-                                .find(|x| x["key"].as_str().unwrap() == key)
-                                .unwrap()["value"]
-                                .as_array();
-                            if let Some(value) = value {
-                                let idx = rand::random::<usize>() % value.len();
-                                result = value[idx].as_str().unwrap().to_string();
-                            } else {
-                                result = "".to_string();
-                            }
-                        }
-                    } else {
-                        result = "".to_string();
-                    }
-                } else {
-                    result = "".to_string();
-                }
-                prompt = prompt.replacen(&format!("@{}", pattern.join("/")), &result, 1);
-            } else if pattern_type == "prompt" {
-                let prompt_idx = pattern[1].parse::<usize>().unwrap();
-                if prompt_idx > current_idx {
-                    continue;
-                }
-                let prompt_response = prompt_responses[prompt_idx - 1].clone();
-                prompt = prompt.replace(&format!("@prompt/{}", prompt_idx), &prompt_response);
-            }
-        }
+        let prompt = prompt.as_str().context("prompts must be text")?;
+        let template = Template::parse_chain_version(prompt, prompt_version)?;
+        let refs = template::character_refs(&db, project_id, &template).await?;
+        let prompt = template.render(
+            &Context::new()
+                .with_answers(&prompt_responses)
+                .with_refs(refs),
+        )?;
         info!(
             "Job {}: processed prompt: {} model_name: {}",
             job_id, prompt, &model_name
         );
-        let chat_request = CreateChatCompletionRequestArgs::default()
-            .max_tokens(word_count)
-            .model(&model_name)
-            .temperature(temperature)
-            .messages([ChatCompletionRequestUserMessageArgs::default()
-                .content(format!(r#"{}"#, prompt))
-                .build()
-                .unwrap()
-                .into()])
-            .build()
-            .unwrap();
-        let gpt_response = client.chat().create(chat_request).await.unwrap();
-        let output = &gpt_response
-            .choices
-            .iter()
-            .filter(|x| x.message.role == Role::Assistant)
-            .next()
-            .unwrap()
-            .message
-            .content
-            .clone()
-            .unwrap_or_default();
-        prompt_responses.push(output.to_string());
-        response = output.to_string();
+        let output = llm
+            .chat(
+                &ProviderConfig::OpenAI,
+                ChatRequest {
+                    model: model_name.clone(),
+                    input: prompt,
+                    max_tokens: Some(word_count),
+                    temperature: Some(temperature),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        prompt_responses.push(output);
     }
+    let response = prompt_responses.last().cloned().unwrap_or_default();
     let _result = sqlx::query!(
             r#"insert into datadrop (job_id, datadrop_name, datadrop_content, project_id) values ($1, $2, $3, $4)"#,
             job_id,
@@ -303,59 +243,72 @@ pub async fn execute_job(db: PgPool, delivery: &Delivery) -> Result<ExecuteResul
             project_id
         )
         .execute(&db)
-        .await
-        .unwrap();
+        .await?;
     Ok(ExecuteResult::Success)
     // }
 }
 
+/// The prompt of a message, with its keys and input filled in.
+///
+/// Before version 2, messages came with the keys already filled in, and only `@key/input` and
+/// `@key/reference` left to replace; since, the prompt is a template in syntax
+/// `prompt_version`. A prompt that doesn't render is an error, so the message is dead-lettered
+/// rather than retried.
+fn message_prompt(
+    version: u32,
+    prompt: &str,
+    prompt_version: u32,
+    keys: Map<String, Value>,
+    input: &str,
+    reference: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    if version < 2 {
+        let mut prompt = prompt.replace("@key/input", input);
+        if let Some(reference) = reference.filter(|reference| !reference.is_empty()) {
+            prompt = prompt.replace("@key/reference", reference);
+        }
+        return Ok(prompt);
+    }
+
+    let mut context = Context::new().with_keys(keys).with_key("input", input);
+    if let Some(reference) = reference {
+        context = context.with_key("reference", reference);
+    }
+    Ok(template::render_version(prompt, prompt_version, &context)?)
+}
+
 pub async fn execute_job_v2(
     db: PgPool,
+    llm: Llm,
     delivery: &Delivery,
 ) -> Result<ExecuteResultV2, anyhow::Error> {
-    let message = str::from_utf8(&delivery.data).unwrap();
-    let message: Value = serde_json::from_str(message).unwrap();
-    let mut attempts = 0;
-    if let Some(headers) = delivery.properties.headers() {
-        for (key, value) in headers.into_iter() {
-            if key.as_str() == "x-attempts" {
-                if let lapin::types::AMQPValue::LongLongInt(val) = value {
-                    attempts = *val as i32;
-                    break;
-                }
-            }
-        }
-    }
-    let generator_id = message["generator_id"].as_str().unwrap();
-    let generator_id = Uuid::parse_str(generator_id).unwrap();
-    let project_id = message["project_id"].as_str().unwrap();
-    let project_id = Uuid::parse_str(project_id).unwrap();
-    let file_id = message["file_id"].as_str().unwrap();
-    let file_id = Uuid::parse_str(file_id).unwrap();
-    let input = message["input"].as_str().unwrap();
-    let input = input.to_string();
-    let prompt = message["prompt"].as_str().unwrap();
-    let prompt = prompt.to_string();
-    let prompt = prompt.replace("@key/input", &input);
-    let team_id = message["team_id"].as_str().unwrap();
-    let team_id = Uuid::parse_str(team_id).unwrap();
-    let user_id = message["user_id"].as_str().unwrap();
-    let user_id = Uuid::parse_str(user_id).unwrap();
-    let separator = message["separator"].as_str().unwrap_or("\n\n");
-    let separator = separator.to_string();
+    let Some(message) = parse_message::<GeneratorMessage>(&delivery.data)? else {
+        return Ok(ExecuteResultV2::Deferred);
+    };
+    let attempts = delivery_attempts(delivery);
+    let GeneratorMessage {
+        version,
+        prompt_version,
+        keys,
+        generator_id,
+        project_id,
+        file_id,
+        input,
+        prompt,
+        team_id,
+        user_id,
+        separator,
+        ..
+    } = message;
+    let prompt = message_prompt(version, &prompt, prompt_version, keys, &input, None)?;
     let bpe = cl100k_base().unwrap();
-    let client = Client::new();
-    let chat_request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(2048u16)
-        .model("gpt-3.5-turbo")
-        .temperature(0.1)
-        .messages([ChatCompletionRequestUserMessageArgs::default()
-            .content(format!(r#"{}"#, prompt))
-            .build()
-            .unwrap()
-            .into()])
-        .build()
-        .unwrap();
+    let chat_request = ChatRequest {
+        model: "gpt-3.5-turbo".to_string(),
+        input: prompt.clone(),
+        max_tokens: Some(2048),
+        temperature: Some(0.1),
+        ..Default::default()
+    };
     let tokens = bpe.encode_with_special_tokens(&prompt);
     sqlx::query!(
         r#"insert into usage_v2 (team_id, project_id, generator_id, user_id, token_count) values ($1, $2, $3, $4, $5)"#,
@@ -366,26 +319,14 @@ pub async fn execute_job_v2(
         tokens.len() as i32
     )
     .execute(&db)
-    .await
-    .unwrap();
+    .await?;
 
-    let gpt_response = client.chat().create(chat_request).await;
-    if gpt_response.is_err() {
-        let error = gpt_response.unwrap_err();
+    let output = llm.chat(&ProviderConfig::OpenAI, chat_request).await;
+    if let Err(error) = output {
         log::error!("attempt: {}, error: {}", attempts, error);
-        return Ok(ExecuteResultV2::Failed(attempts + 1));
+        return Ok(ExecuteResultV2::Failed(attempts + 1, error.to_string()));
     }
-    let gpt_response = gpt_response.unwrap();
-    let output = &gpt_response
-        .choices
-        .iter()
-        .filter(|x| x.message.role == Role::Assistant)
-        .next()
-        .unwrap()
-        .message
-        .content
-        .clone()
-        .unwrap_or_default();
+    let output = &output.unwrap();
     let tokens = bpe.encode_with_special_tokens(&output);
     sqlx::query!(
         r#"insert into usage_v2 (team_id, project_id, generator_id, user_id, token_count) values ($1, $2, $3, $4, $5)"#,
@@ -396,8 +337,7 @@ pub async fn execute_job_v2(
         tokens.len() as i32
     )
     .execute(&db)
-    .await
-    .unwrap();
+    .await?;
 
     let results = output.split(&separator).collect::<Vec<&str>>();
     for result in results {
@@ -412,8 +352,7 @@ pub async fn execute_job_v2(
             })
         )
         .execute(&db)
-        .await
-        .unwrap();
+        .await?;
     }
 
     sqlx::query!(
@@ -423,60 +362,50 @@ pub async fn execute_job_v2(
         generator_id
     )
     .execute(&db)
-    .await
-    .unwrap();
+    .await?;
 
     Ok(ExecuteResultV2::Success)
 }
 
 pub async fn execute_job_v2_evaluate(
     db: PgPool,
+    llm: Llm,
     delivery: &Delivery,
 ) -> Result<ExecuteResultV2, anyhow::Error> {
-    let message = str::from_utf8(&delivery.data).unwrap();
-    let message: Value = serde_json::from_str(message).unwrap();
-    let mut attempts = 0;
-    if let Some(headers) = delivery.properties.headers() {
-        for (key, value) in headers.into_iter() {
-            if key.as_str() == "x-attempts" {
-                if let lapin::types::AMQPValue::LongLongInt(val) = value {
-                    attempts = *val as i32;
-                    break;
-                }
-            }
-        }
-    }
-    let generator_id = message["generator_id"].as_str().unwrap();
-    let generator_id = Uuid::parse_str(generator_id).unwrap();
-    let datadrop_id = message["datadrop_id"].as_str().unwrap();
-    let datadrop_id = Uuid::parse_str(datadrop_id).unwrap();
-    let project_id = message["project_id"].as_str().unwrap();
-    let project_id = Uuid::parse_str(project_id).unwrap();
-    let input = message["input"].as_str().unwrap();
-    let input = input.to_string();
-    let prompt = message["prompt"].as_str().unwrap();
-    let prompt = prompt.to_string();
-    let prompt = prompt.replace("@key/input", &input);
-    let team_id = message["team_id"].as_str().unwrap();
-    let team_id = Uuid::parse_str(team_id).unwrap();
-    let user_id = message["user_id"].as_str().unwrap();
-    let user_id = Uuid::parse_str(user_id).unwrap();
-    let reference = message["reference"].as_str().unwrap();
-    let reference = reference.to_string();
-    let prompt = prompt.replace("@key/reference", &reference);
+    let Some(message) = parse_message::<EvaluateMessage>(&delivery.data)? else {
+        return Ok(ExecuteResultV2::Deferred);
+    };
+    let attempts = delivery_attempts(delivery);
+    let EvaluateMessage {
+        version,
+        prompt_version,
+        keys,
+        generator_id,
+        datadrop_id,
+        project_id,
+        input,
+        prompt,
+        team_id,
+        user_id,
+        reference,
+        ..
+    } = message;
+    let prompt = message_prompt(
+        version,
+        &prompt,
+        prompt_version,
+        keys,
+        &input,
+        Some(&reference),
+    )?;
     let bpe = cl100k_base().unwrap();
-    let client = Client::new();
-    let chat_request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(2048u16)
-        .model("gpt-4")
-        .temperature(0.1)
-        .messages([ChatCompletionRequestUserMessageArgs::default()
-            .content(format!(r#"{}"#, prompt))
-            .build()
-            .unwrap()
-            .into()])
-        .build()
-        .unwrap();
+    let chat_request = ChatRequest {
+        model: "gpt-4".to_string(),
+        input: prompt.clone(),
+        max_tokens: Some(2048),
+        temperature: Some(0.1),
+        ..Default::default()
+    };
     let tokens = bpe.encode_with_special_tokens(&prompt);
     sqlx::query!(
         r#"insert into usage_v2 (team_id, project_id, generator_id, user_id, token_count) values ($1, $2, $3, $4, $5)"#,
@@ -487,24 +416,13 @@ pub async fn execute_job_v2_evaluate(
         tokens.len() as i32
     )
     .execute(&db)
-    .await
-    .unwrap();
+    .await?;
 
-    let gpt_response = client.chat().create(chat_request).await;
-    if gpt_response.is_err() {
-        return Ok(ExecuteResultV2::Failed(attempts + 1));
+    let output = llm.chat(&ProviderConfig::OpenAI, chat_request).await;
+    if let Err(error) = output {
+        return Ok(ExecuteResultV2::Failed(attempts + 1, error.to_string()));
     }
-    let gpt_response = gpt_response.unwrap();
-    let output = &gpt_response
-        .choices
-        .iter()
-        .filter(|x| x.message.role == Role::Assistant)
-        .next()
-        .unwrap()
-        .message
-        .content
-        .clone()
-        .unwrap_or_default();
+    let output = &output.unwrap();
     let tokens = bpe.encode_with_special_tokens(&output);
     sqlx::query!(
         r#"insert into usage_v2 (team_id, project_id, generator_id, user_id, token_count) values ($1, $2, $3, $4, $5)"#,
@@ -515,8 +433,7 @@ pub async fn execute_job_v2_evaluate(
         tokens.len() as i32
     )
     .execute(&db)
-    .await
-    .unwrap();
+    .await?;
 
     let _result = sqlx::query!(
         r#"update datadrop_v2 set extra_data['evaluate'] = to_jsonb($1::text) where datadrop_id = $2"#,
@@ -524,99 +441,168 @@ pub async fn execute_job_v2_evaluate(
         datadrop_id
     )
     .execute(&db)
-    .await
-    .unwrap();
+    .await?;
 
     Ok(ExecuteResultV2::Success)
 }
 
+/// Answer one input of a module job.
+///
+/// With a `workspace_concurrency`, the call waits its turn while the workspace already has
+/// that many calls in flight across all workers.
 pub async fn execute_job_evo(
     db: PgPool,
+    llm: Llm,
     delivery: &Delivery,
+    workspace_concurrency: Option<i64>,
+    preprocess_cache_secs: u64,
 ) -> Result<ExecuteResultV2, anyhow::Error> {
-    let message = str::from_utf8(&delivery.data).unwrap();
-    let message: Value = serde_json::from_str(message).unwrap();
-    let mut attempts = 0;
-    if let Some(headers) = delivery.properties.headers() {
-        for (key, value) in headers.into_iter() {
-            if key.as_str() == "x-attempts" {
-                if let lapin::types::AMQPValue::LongLongInt(val) = value {
-                    attempts = *val as i32;
-                    break;
-                }
-            }
+    let Some(message) = parse_message::<ModuleMessage>(&delivery.data)? else {
+        return Ok(ExecuteResultV2::Deferred);
+    };
+    execute_module_message(
+        db,
+        llm,
+        message,
+        delivery_attempts(delivery),
+        workspace_concurrency,
+        preprocess_cache_secs,
+    )
+    .await
+}
+
+/// Answer a module message that was delivered `attempts` times before.
+async fn execute_module_message(
+    db: PgPool,
+    llm: Llm,
+    message: ModuleMessage,
+    attempts: i32,
+    workspace_concurrency: Option<i64>,
+    preprocess_cache_secs: u64,
+) -> Result<ExecuteResultV2, anyhow::Error> {
+    let ModuleMessage {
+        version,
+        prompt_version,
+        keys,
+        preprocess,
+        module_id,
+        job_id,
+        work_item_id,
+        workspace_id,
+        file_id,
+        input,
+        prompt,
+        user_id,
+        separator,
+        reference,
+        model_name,
+        mut params,
+        provider,
+        ..
+    } = message;
+
+    match job_status_v2(&db, job_id).await? {
+        JobStatusV2::Active => {}
+        JobStatusV2::Paused => return Ok(ExecuteResultV2::Deferred),
+        JobStatusV2::Cancelled => return Ok(ExecuteResultV2::Cancelled),
+    }
+
+    // A message delivered again after its candidates were saved, e.g. because the worker died
+    // before acking it, has nothing left to do.
+    if let Some(work_item_id) = work_item_id {
+        let done = sqlx::query!(
+            r#"select exists(select 1 from work_item where work_item_id = $1) "done!""#,
+            work_item_id
+        )
+        .fetch_one(&db)
+        .await?
+        .done;
+        if done {
+            info!(
+                "skipping work item {} of job {}, already done",
+                work_item_id, job_id
+            );
+            return Ok(ExecuteResultV2::Success);
         }
     }
-    let module_id = message["module_id"].as_str().unwrap();
-    let module_id = Uuid::parse_str(module_id).unwrap();
-    let job_id = message["job_id"].as_str().unwrap();
-    let job_id = Uuid::parse_str(job_id).unwrap();
-    let workspace_id = message["workspace_id"].as_str().unwrap();
-    let workspace_id = Uuid::parse_str(workspace_id).unwrap();
-    let file_id = message["file_id"].as_str().unwrap_or_default();
-    let input = message["input"].as_str().unwrap();
-    let input = input.to_string();
-    let prompt = message["prompt"].as_str().unwrap();
-    let prompt = prompt.to_string();
-    let mut prompt = prompt.replace("@key/input", &input);
-    let user_id = message["user_id"].as_str().unwrap();
-    let user_id = Uuid::parse_str(user_id).unwrap();
-    let separator = message["separator"].as_str().unwrap_or_default();
-    let separator = separator.to_string();
-    let reference = message["reference"].as_str().unwrap_or_default();
-    let reference = reference.to_string();
-    let model_name = message["model_name"]
-        .as_str()
-        .unwrap_or("gpt-3.5-turbo-1106");
-    if reference != "" {
-        prompt = prompt.replace("@key/reference", &reference);
+
+    let slot = match workspace_concurrency {
+        Some(limit) => match acquire_slot(&db, workspace_id, job_id, limit).await? {
+            Some(slot) => Some(slot),
+            None => return Ok(ExecuteResultV2::Throttled),
+        },
+        None => None,
+    };
+
+    let mut keys = keys;
+    if !preprocess.is_empty() {
+        keys.insert("input".to_string(), Value::String(input.clone()));
+        keys.insert("reference".to_string(), Value::String(reference.clone()));
+        let runner = Runner {
+            db: &db,
+            llm: &llm,
+            provider: &provider,
+            cache_secs: preprocess_cache_secs,
+        };
+        let steps = Preprocess::new(preprocess, prompt_version)?;
+        if let Err(error) = steps.run(&runner, &mut keys, Steps::PerInput).await {
+            if let Some(slot) = slot {
+                slot.release().await?;
+            }
+            return match error {
+                preprocess::Error::Llm(error) => {
+                    log::error!("attempt: {}, preprocess error: {}", attempts, error);
+                    Ok(ExecuteResultV2::Failed(attempts + 1, error.to_string()))
+                }
+                error => Err(error.into()),
+            };
+        }
     }
+    let prompt = message_prompt(
+        version,
+        &prompt,
+        prompt_version,
+        keys,
+        &input,
+        Some(&reference),
+    )?;
+    // Messages before version 4 only name the model.
+    params.model = model_name;
 
     let bpe = cl100k_base().unwrap();
-    let tokens = bpe.encode_with_special_tokens(&prompt);
-    sqlx::query!(
-        r#"insert into metric_v2 (workspace_id, user_id, module_id, token_count, word_count) values ($1, $2, $3, $4, $5)"#,
-        workspace_id,
-        user_id,
-        module_id,
-        tokens.len() as i32,
-        prompt.chars().count() as i32
-    )
-    .execute(&db)
-    .await.unwrap();
-
-    let api_key = openai::get_available_key(&db).await.unwrap();
-    let output = openai::chat(
-        ChatRequest {
-            max_tokens: Some(2048),
-            input: prompt,
-            model: model_name.to_string(),
-            temperature: Some(0.1),
-            history: None,
-        },
-        &api_key.openai_key,
-    )
-    .await;
-    openai::release_key(&db, api_key).await.unwrap();
+    let prompt_tokens = bpe.encode_with_special_tokens(&prompt).len();
+    let prompt_words = prompt.chars().count();
+
+    let output = llm.chat_with_usage(&provider, params.request(prompt)).await;
+    if let Some(slot) = slot {
+        slot.release().await?;
+    }
 
     if output.is_err() {
         let error = output.unwrap_err();
         log::error!("attempt: {}, error: {}", attempts, error);
-        return Ok(ExecuteResultV2::Failed(attempts + 1));
+        return Ok(ExecuteResultV2::Failed(attempts + 1, error.to_string()));
     }
-    let output = output.unwrap();
+    let chat_output = output.unwrap();
+    let output = chat_output.output;
 
     let tokens = bpe.encode_with_special_tokens(&output);
     sqlx::query!(
-        r#"insert into metric_v2 (workspace_id, user_id, module_id, token_count, word_count) values ($1, $2, $3, $4, $5)"#,
+        r#"insert into metric_v2
+            (workspace_id, user_id, module_id, openai_id, model, token_count, word_count, is_output)
+        values ($1, $2, $3, $4, $5, $6, $7, false), ($1, $2, $3, $4, $5, $8, $9, true)"#,
         workspace_id,
         user_id,
         module_id,
+        chat_output.openai_id,
+        params.model,
+        prompt_tokens as i32,
+        prompt_words as i32,
         tokens.len() as i32,
         output.chars().count() as i32
     )
     .execute(&db)
-    .await.unwrap();
+    .await?;
 
     struct Result {
         content: String,
@@ -657,33 +643,302 @@ pub async fn execute_job_evo(
             extra_data,
         }];
     }
-    let job_status_group_id = Uuid::new_v4();
+    // The candidates of one message are saved together with its work item, so a redelivered
+    // message that got this far anyway, e.g. while the first delivery was still running,
+    // saves nothing.
+    let job_status_group_id = work_item_id.unwrap_or_else(Uuid::new_v4);
+    let mut tx = db.begin().await?;
+    // The job may have been cancelled while the model was answering, e.g. by resetting the
+    // module, in which case the answer would land in a module that no longer wants it. The
+    // row stays locked until the candidates are saved, so a cancel either lands before this
+    // and is seen here, or waits for the candidates and then clears them out.
+    let job_status = sqlx::query_scalar!(
+        r#"select job_status "job_status: JobStatusV2" from job_v2 where job_id = $1 for share"#,
+        job_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if matches!(job_status, None | Some(JobStatusV2::Cancelled)) {
+        return Ok(ExecuteResultV2::Cancelled);
+    }
+    if let Some(work_item_id) = work_item_id {
+        let claimed = sqlx::query!(
+            r#"insert into work_item (work_item_id, job_id) values ($1, $2)
+            on conflict do nothing returning work_item_id"#,
+            work_item_id,
+            job_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if claimed.is_none() {
+            info!(
+                "skipping work item {} of job {}, already done",
+                work_item_id, job_id
+            );
+            return Ok(ExecuteResultV2::Success);
+        }
+    }
+    let mut latest_candidate = None;
     for result in results {
-        let _result = sqlx::query!(
-            r#"insert into candidate_v2 (content, module_id, job_id, job_status_group_id, extra_data) values ($1, $2, $3, $4, $5)"#,
+        let candidate = sqlx::query!(
+            r#"insert into candidate_v2 (content, module_id, job_id, job_status_group_id, extra_data) values ($1, $2, $3, $4, $5) returning candidate_id"#,
             result.content,
             module_id,
             job_id,
             job_status_group_id,
             result.extra_data
         )
-        .execute(&db)
-        .await
-        .unwrap();
+        .fetch_one(&mut *tx)
+        .await?;
+        latest_candidate = Some(LatestCandidate {
+            candidate_id: candidate.candidate_id,
+            content: result.content,
+        });
     }
 
-    if file_id != "" {
-        let file_id = Uuid::parse_str(file_id).unwrap();
+    sqlx::query!(
+        r#"update job_v2 set completed_count = completed_count + 1 where job_id = $1"#,
+        job_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(file_id) = file_id {
         sqlx::query!(
             r#"update file_module set finish_process = $1 where file_id = $2 and module_id = $3"#,
             true,
             file_id,
             module_id
         )
-        .execute(&db)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    emit_job_event(&db, JobEventKind::Progress, job_id, latest_candidate, None).await;
+
+    Ok(ExecuteResultV2::Success)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::openai::{count_tokens, MockConfig, MockMode, MockProvider};
+    use crate::queue::MESSAGE_VERSION;
+
+    struct Fixture {
+        workspace_id: Uuid,
+        user_id: Uuid,
+        module_id: Uuid,
+        job_id: Uuid,
+    }
+
+    async fn fixture(db: &PgPool) -> Fixture {
+        let user_id = sqlx::query_scalar!(
+            r#"insert into "user" (user_name, email, password_hash)
+            values ('runner', 'runner@example.com', '') returning user_id"#
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+        let workspace_id = sqlx::query_scalar!(
+            r#"insert into workspace_v2 (workspace_name, owner_id, config_data)
+            values ('runs', $1, '{}') returning workspace_id"#,
+            user_id
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+        let module_id = sqlx::query_scalar!(
+            r#"insert into module_v2 (module_name, config_data, workspace_id, module_category)
+            values ('summaries', '{}', $1, 'generator') returning module_id"#,
+            workspace_id
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+        let job_id = sqlx::query_scalar!(
+            r#"insert into job_v2 (module_id, config_data, workspace_id, target_count)
+            values ($1, '{}', $2, 1) returning job_id"#,
+            module_id,
+            workspace_id
+        )
+        .fetch_one(db)
         .await
         .unwrap();
+
+        Fixture {
+            workspace_id,
+            user_id,
+            module_id,
+            job_id,
+        }
     }
 
-    Ok(ExecuteResultV2::Success)
+    fn message(fixture: &Fixture, work_item_id: Uuid) -> ModuleMessage {
+        ModuleMessage {
+            version: MESSAGE_VERSION,
+            module_id: fixture.module_id,
+            job_id: fixture.job_id,
+            work_item_id: Some(work_item_id),
+            workspace_id: fixture.workspace_id,
+            file_id: None,
+            input: "the cat sat on the mat".to_string(),
+            prompt: "Summarize: @{key/input}".to_string(),
+            prompt_version: template::PROMPT_VERSION,
+            keys: Map::new(),
+            preprocess: Vec::new(),
+            user_id: fixture.user_id,
+            separator: "\n\n".to_string(),
+            reference: String::new(),
+            model_name: "gpt-3.5-turbo-1106".to_string(),
+            params: Default::default(),
+            provider: Default::default(),
+        }
+    }
+
+    #[sqlx::test]
+    async fn module_messages_write_candidates_and_metrics(db: PgPool) {
+        let fixture = fixture(&db).await;
+        let mock = Arc::new(MockProvider::new(MockConfig {
+            mode: MockMode::Scripted,
+            responses: vec!["a cat\n\na mat".to_string()],
+            ..Default::default()
+        }));
+        let llm = Llm::with_provider(db.clone(), mock.clone());
+        let work_item_id = Uuid::new_v4();
+
+        let result = execute_module_message(
+            db.clone(),
+            llm.clone(),
+            message(&fixture, work_item_id),
+            0,
+            None,
+            0,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, ExecuteResultV2::Success));
+
+        let candidates = sqlx::query!(
+            r#"select content, job_status_group_id, extra_data from candidate_v2
+            where job_id = $1 order by content"#,
+            fixture.job_id
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            candidates
+                .iter()
+                .map(|candidate| candidate.content.as_str())
+                .collect::<Vec<_>>(),
+            ["a cat", "a mat"]
+        );
+        for candidate in &candidates {
+            assert_eq!(candidate.job_status_group_id, work_item_id);
+            assert_eq!(
+                candidate.extra_data.as_ref().unwrap()["text"],
+                "the cat sat on the mat"
+            );
+        }
+        let completed_count = sqlx::query_scalar!(
+            r#"select completed_count from job_v2 where job_id = $1"#,
+            fixture.job_id
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(completed_count, 1);
+
+        // One row for the prompt, one for the answer.
+        let metrics = sqlx::query!(
+            r#"select workspace_id, user_id, module_id, model, token_count, word_count, is_output
+            from metric_v2 order by is_output"#
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(metrics.len(), 2);
+        for metric in &metrics {
+            assert_eq!(metric.workspace_id, Some(fixture.workspace_id));
+            assert_eq!(metric.user_id, Some(fixture.user_id));
+            assert_eq!(metric.module_id, Some(fixture.module_id));
+            assert_eq!(metric.model.as_deref(), Some("gpt-3.5-turbo-1106"));
+        }
+        let counts = metrics
+            .iter()
+            .map(|metric| (metric.is_output, metric.token_count, metric.word_count))
+            .collect::<Vec<_>>();
+        let (prompt, output) = ("Summarize: the cat sat on the mat", "a cat\n\na mat");
+        assert_eq!(
+            counts,
+            [
+                (
+                    Some(false),
+                    count_tokens(prompt) as i32,
+                    prompt.chars().count() as i32
+                ),
+                (
+                    Some(true),
+                    count_tokens(output) as i32,
+                    output.chars().count() as i32
+                ),
+            ]
+        );
+
+        // A redelivery of the same message doesn't ask the model again or save anything twice.
+        let result =
+            execute_module_message(db.clone(), llm, message(&fixture, work_item_id), 1, None, 0)
+                .await
+                .unwrap();
+        assert!(matches!(result, ExecuteResultV2::Success));
+        assert_eq!(mock.usage().calls, 1);
+        let candidate_count = sqlx::query_scalar!(
+            r#"select count(*) "count!" from candidate_v2 where job_id = $1"#,
+            fixture.job_id
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(candidate_count, 2);
+    }
+
+    #[sqlx::test]
+    async fn cancelled_jobs_save_nothing(db: PgPool) {
+        let fixture = fixture(&db).await;
+        sqlx::query!(
+            r#"update job_v2 set job_status = $1 where job_id = $2"#,
+            JobStatusV2::Cancelled as i32,
+            fixture.job_id
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let mock = Arc::new(MockProvider::new(MockConfig::default()));
+        let llm = Llm::with_provider(db.clone(), mock.clone());
+
+        let result = execute_module_message(
+            db.clone(),
+            llm,
+            message(&fixture, Uuid::new_v4()),
+            0,
+            None,
+            0,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, ExecuteResultV2::Cancelled));
+        assert_eq!(mock.usage().calls, 0);
+        let candidate_count = sqlx::query_scalar!(
+            r#"select count(*) "count!" from candidate_v2 where job_id = $1"#,
+            fixture.job_id
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(candidate_count, 0);
+    }
 }
@@ -0,0 +1,132 @@
+use lapin::{
+    message::Delivery,
+    types::{AMQPValue, FieldTable},
+    BasicProperties,
+};
+use serde_json::Value;
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::queue::{
+    delivery_attempts, emit_job_event, JobEventKind, Publisher, DEAD_LETTER_EXCHANGE,
+};
+
+/// Hand a message the worker gave up on to the dead-letter exchange, along with why.
+///
+/// The message keeps its original routing key, which is the name of the queue it came from,
+/// so it can be sent back there by `redrive`. This only returns once the broker has confirmed
+/// it, so the message can be acked after.
+pub(crate) async fn dead_letter(
+    publisher: &Publisher,
+    delivery: &Delivery,
+    error: &str,
+    attempts: i32,
+) -> anyhow::Result<()> {
+    log::error!(
+        "giving up on message from {} after {} attempts: {}",
+        delivery.routing_key,
+        attempts,
+        error
+    );
+    let mut headers = FieldTable::default();
+    headers.insert("x-attempts".into(), AMQPValue::LongLongInt(attempts as i64));
+    headers.insert("x-error".into(), AMQPValue::LongString(error.into()));
+    publisher
+        .publish_to(
+            DEAD_LETTER_EXCHANGE,
+            delivery.routing_key.as_str(),
+            delivery.data.as_slice(),
+            BasicProperties::default().with_headers(headers),
+        )
+        .await
+}
+
+/// Record a message from the dead-letter queue in `job_failure`.
+pub(crate) async fn record_failure(db: &PgPool, delivery: &Delivery) -> anyhow::Result<()> {
+    let payload = String::from_utf8_lossy(&delivery.data).to_string();
+    // Whatever ids the message carries, so failures can be listed per workspace and job.
+    let message = serde_json::from_str::<Value>(&payload).unwrap_or_default();
+    let id = |key: &str| message[key].as_str().and_then(|s| Uuid::parse_str(s).ok());
+
+    let mut error = String::new();
+    if let Some(headers) = delivery.properties.headers() {
+        if let Some(AMQPValue::LongString(value)) = headers.inner().get("x-error") {
+            error = value.to_string();
+        }
+    }
+
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        r#"insert into job_failure (queue_name, job_id, module_id, workspace_id, error, attempts, payload)
+        values ($1, $2, $3, $4, $5, $6, $7)"#,
+        delivery.routing_key.as_str(),
+        id("job_id"),
+        id("module_id"),
+        id("workspace_id"),
+        error,
+        delivery_attempts(delivery),
+        payload
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"update job_v2 set failed_count = failed_count + 1 where job_id = $1"#,
+        id("job_id")
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    // Only messages of a module job have a job to report on; generator messages don't.
+    if let (Some(job_id), Some(_)) = (id("job_id"), id("module_id")) {
+        emit_job_event(db, JobEventKind::Failed, job_id, None, Some(error)).await;
+    }
+
+    Ok(())
+}
+
+/// Mark a failure as re-driven, so it no longer counts against its job.
+///
+/// Call this once `redrive` succeeded, so a failed publish leaves the failure to be retried.
+pub async fn mark_redriven(db: impl PgExecutor<'_>, failure_id: Uuid) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"with redriven as (
+            update job_failure set redriven_at = now()
+            where failure_id = $1 and redriven_at is null
+            returning job_id
+        )
+        update job_v2 set failed_count = failed_count - 1
+        where job_id = (select job_id from redriven)"#,
+        failure_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Count a failure against its job again, after `mark_redriven` was called for it ahead of a
+/// `redrive` that then failed.
+pub async fn reopen_failure(db: impl PgExecutor<'_>, failure_id: Uuid) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"with reopened as (
+            update job_failure set redriven_at = null
+            where failure_id = $1 and redriven_at is not null
+            returning job_id
+        )
+        update job_v2 set failed_count = failed_count + 1
+        where job_id = (select job_id from reopened)"#,
+        failure_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Publish a failed message back to the queue it came from, with its attempts reset.
+pub async fn redrive(publisher: &Publisher, queue_name: &str, payload: &str) -> anyhow::Result<()> {
+    publisher
+        .publish(queue_name, payload.as_bytes().to_vec())
+        .await
+}
@@ -0,0 +1,160 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::openai::{ModelParams, ProviderConfig};
+use crate::preprocess::StepConfig;
+use crate::template::PROMPT_VERSION;
+
+/// The newest payload version this build publishes and understands.
+///
+/// Adding a field that has a default is backwards compatible and doesn't change it: older
+/// workers ignore fields they don't know, and newer workers fill in the default for messages
+/// that lack it. Anything else bumps the version, and a worker that gets a message newer than
+/// it understands hands it back for a newer worker, so old and new workers can run side by side
+/// during a rollout.
+///
+/// Field names are the ones the queues have always used, so messages published before payloads
+/// were versioned still parse, as version 0.
+///
+/// From version 2 on, `prompt` is a template that the worker renders with `keys` and the input;
+/// before, it came with its keys already filled in. From version 3 on, a module message's
+/// `preprocess` holds steps to run on the input before rendering, whose answers the prompt
+/// needs, so a worker that ignored them would fail every message. From version 4 on, a module
+/// message's `params` hold the module's model parameters, which a worker that ignored them
+/// would quietly call the model without. From version 5 on, `prompt_version` says which syntax
+/// `prompt` is written in, and a worker that ignored it would misread version 1 prompts.
+pub const MESSAGE_VERSION: u32 = 5;
+
+/// A chunk of a file to run through a generator, on `claymore_v2_queue`.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct GeneratorMessage {
+    #[serde(default)]
+    pub version: u32,
+    pub generator_id: Uuid,
+    pub file_id: Uuid,
+    pub project_id: Uuid,
+    pub input: String,
+    pub prompt: String,
+    /// The syntax `prompt` is written in; see `PROMPT_VERSION`.
+    #[serde(default = "default_prompt_version")]
+    pub prompt_version: u32,
+    /// The values `prompt` is rendered with, besides the input.
+    #[serde(default)]
+    pub keys: Map<String, Value>,
+    pub team_id: Uuid,
+    pub user_id: Uuid,
+    #[serde(default = "default_separator")]
+    pub separator: String,
+}
+
+/// A datadrop to evaluate, on `claymore_v2_evaluate_queue`.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct EvaluateMessage {
+    #[serde(default)]
+    pub version: u32,
+    pub generator_id: Uuid,
+    pub datadrop_id: Uuid,
+    pub project_id: Uuid,
+    pub input: String,
+    pub prompt: String,
+    /// The syntax `prompt` is written in; see `PROMPT_VERSION`.
+    #[serde(default = "default_prompt_version")]
+    pub prompt_version: u32,
+    /// The values `prompt` is rendered with, besides the input.
+    #[serde(default)]
+    pub keys: Map<String, Value>,
+    pub team_id: Uuid,
+    pub user_id: Uuid,
+    pub reference: String,
+}
+
+/// One input of a module job, on `claymore_evo_queue`.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ModuleMessage {
+    #[serde(default)]
+    pub version: u32,
+    pub module_id: Uuid,
+    pub job_id: Uuid,
+    /// Stays the same however often the message is delivered, so its candidates are saved
+    /// once. Messages published before it existed don't have one.
+    #[serde(default)]
+    pub work_item_id: Option<Uuid>,
+    pub workspace_id: Uuid,
+    /// The file the input was extracted from, if any. Version 0 sent `""` for none.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub file_id: Option<Uuid>,
+    pub input: String,
+    pub prompt: String,
+    /// The syntax `prompt` is written in; see `PROMPT_VERSION`.
+    #[serde(default = "default_prompt_version")]
+    pub prompt_version: u32,
+    /// The values `prompt` is rendered with, besides the input.
+    #[serde(default)]
+    pub keys: Map<String, Value>,
+    /// Preprocess steps that read the input, which add their answers to `keys`.
+    #[serde(default)]
+    pub preprocess: Vec<StepConfig>,
+    pub user_id: Uuid,
+    /// Splits the answer into several candidates; the whole answer is one candidate if empty.
+    #[serde(default)]
+    pub separator: String,
+    #[serde(default)]
+    pub reference: String,
+    #[serde(default = "default_model_name")]
+    pub model_name: String,
+    /// How to call `model_name`. Messages before version 4 don't have them, and get the
+    /// parameters every module used then.
+    #[serde(default)]
+    pub params: ModelParams,
+    #[serde(default)]
+    pub provider: ProviderConfig,
+}
+
+fn default_prompt_version() -> u32 {
+    PROMPT_VERSION
+}
+
+fn default_separator() -> String {
+    "\n\n".to_string()
+}
+
+fn default_model_name() -> String {
+    "gpt-3.5-turbo-1106".to_string()
+}
+
+fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<Uuid>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    match value.as_deref() {
+        None | Some("") => Ok(None),
+        Some(s) => Uuid::parse_str(s)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// Parse a message off a queue.
+///
+/// Returns `None` if the message is newer than this worker understands. A message that
+/// doesn't parse is an error, so that it gets dead-lettered rather than retried.
+pub(crate) fn parse_message<T: DeserializeOwned>(data: &[u8]) -> anyhow::Result<Option<T>> {
+    #[derive(serde::Deserialize)]
+    struct Envelope {
+        #[serde(default)]
+        version: u32,
+    }
+
+    let envelope = serde_json::from_slice::<Envelope>(data)
+        .map_err(|e| anyhow::anyhow!("malformed message: {}", e))?;
+    if envelope.version > MESSAGE_VERSION {
+        return Ok(None);
+    }
+    let message = serde_json::from_slice::<T>(data)
+        .map_err(|e| anyhow::anyhow!("malformed version {} message: {}", envelope.version, e))?;
+
+    Ok(Some(message))
+}
@@ -1,78 +1,199 @@
+use anyhow::Context;
 use lapin::{
-    message::DeliveryResult,
+    message::{Delivery, DeliveryResult},
     options::{
-        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
-        BasicQosOptions, QueueDeclareOptions,
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions,
+        ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
     },
-    publisher_confirm::Confirmation,
     types::FieldTable,
-    BasicProperties, Channel, Connection, ConnectionProperties,
+    Connection, ConnectionProperties, ExchangeKind,
 };
 use serde_json::Value;
 use sqlx::PgPool;
+use std::time::Duration;
 
+use crate::config::Config;
+use crate::openai::Llm;
+
+mod events;
 mod executor;
+mod failures;
+mod messages;
+mod publisher;
+mod retry;
+mod shutdown;
+mod slots;
+
+pub use events::{emit_job_event, JobEvent, JobEventKind, JobEvents, LatestCandidate};
+pub use executor::{JobPriority, JobStatusV2};
+pub use failures::{mark_redriven, redrive, reopen_failure};
+pub use messages::{EvaluateMessage, GeneratorMessage, ModuleMessage, MESSAGE_VERSION};
+pub use publisher::Publisher;
+pub use retry::RetryPolicy;
+pub use shutdown::Consumers;
+
+/// Where messages go once a worker has given up on them; see `failures::dead_letter`.
+const DEAD_LETTER_EXCHANGE: &str = "claymore_dead_letter";
+/// Collects everything sent to `DEAD_LETTER_EXCHANGE`, to be recorded in `job_failure`.
+const DEAD_LETTER_QUEUE: &str = "claymore_dead_letter_queue";
+
+/// How many times the message has been tried before, as counted in its `x-attempts` header.
+pub(crate) fn delivery_attempts(delivery: &Delivery) -> i32 {
+    if let Some(headers) = delivery.properties.headers() {
+        if let Some(lapin::types::AMQPValue::LongLongInt(val)) = headers.inner().get("x-attempts") {
+            return *val as i32;
+        }
+    }
+
+    0
+}
 
-pub async fn make_channel(url: &String) -> Channel {
-    let uri = url;
+pub async fn make_connection(url: &str) -> anyhow::Result<Connection> {
     let options = ConnectionProperties::default()
         // Use tokio executor and reactor.
         // At the moment the reactor is only available for unix.
         .with_executor(tokio_executor_trait::Tokio::current())
         .with_reactor(tokio_reactor_trait::Tokio);
-    let connection = Connection::connect(uri, options).await.unwrap();
-    let channel = connection.create_channel().await.unwrap();
-    channel
+    let connection = Connection::connect(url, options)
+        .await
+        .context("could not connect to rabbitmq_url")?;
+
+    Ok(connection)
+}
+
+/// How each of the v2 queues retries failed messages, as configured.
+struct RetryPolicies {
+    v2: RetryPolicy,
+    v2_evaluate: RetryPolicy,
+    evo: RetryPolicy,
 }
 
-pub async fn start_consumer(db: PgPool, mq: Channel) {
+impl RetryPolicies {
+    fn from_config(config: &Config) -> Self {
+        let policy = |max_attempts| RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(config.queue_retry_base_ms),
+            max_delay: Duration::from_millis(config.queue_retry_max_ms),
+        };
+        RetryPolicies {
+            v2: policy(config.v2_queue_max_attempts),
+            v2_evaluate: policy(config.v2_evaluate_queue_max_attempts),
+            evo: policy(config.evo_queue_max_attempts),
+        }
+    }
+}
+
+/// Start consuming every queue on `connection`.
+///
+/// The v2 generator and evaluate queues share one channel, and the two module queues have one
+/// each, each with its own prefetch, so that how many messages a worker holds at once can be
+/// tuned per queue. In particular a worker always has room for interactive module messages,
+/// however many batch ones are waiting. Call `Consumers::shutdown` on the result to stop consuming again.
+pub async fn start_consumer(
+    db: PgPool,
+    connection: &Connection,
+    llm: Llm,
+    config: &Config,
+) -> anyhow::Result<Consumers> {
+    let policies = RetryPolicies::from_config(config);
+    // Retries and dead letters are confirmed by the broker before the message they replace is
+    // acked, which the consumer channels can't do.
+    let publisher = Publisher::new(&config.rabbitmq_url, config.rabbitmq_publish_channels);
+    let in_flight = shutdown::InFlight::default();
     let db = db.clone();
     let db2 = db.clone();
     let db_eval = db.clone();
     let db_evo = db.clone();
-    let channel = mq;
-    let channel2 = channel.clone();
-    let channel_evo = channel.clone();
+    let db_interactive = db.clone();
+    let db_dead_letter = db.clone();
+    let channel = connection.create_channel().await?;
+    let channel_evo = connection.create_channel().await?;
+    let channel_interactive = connection.create_channel().await?;
     channel
-        .basic_qos(1, BasicQosOptions::default())
-        .await
-        .unwrap();
+        .basic_qos(config.queue_prefetch, BasicQosOptions::default())
+        .await?;
     channel_evo
-        .basic_qos(2, BasicQosOptions::default())
-        .await
-        .unwrap();
+        .basic_qos(config.evo_queue_prefetch, BasicQosOptions::default())
+        .await?;
+    channel_interactive
+        .basic_qos(
+            config.evo_interactive_queue_prefetch,
+            BasicQosOptions::default(),
+        )
+        .await?;
     let _queue = channel
         .queue_declare(
             "claymore_job_queue",
             QueueDeclareOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .unwrap();
+        .await?;
     let _queue_v2 = channel
         .queue_declare(
             "claymore_v2_queue",
             QueueDeclareOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .unwrap();
+        .await?;
     let _queue_v2_evaluate = channel
         .queue_declare(
             "claymore_v2_evaluate_queue",
             QueueDeclareOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .unwrap();
+        .await?;
     let _queue_evo = channel_evo
         .queue_declare(
-            "claymore_evo_queue",
+            JobPriority::Batch.queue(),
             QueueDeclareOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .unwrap();
+        .await?;
+    let _queue_interactive = channel_interactive
+        .queue_declare(
+            JobPriority::Interactive.queue(),
+            QueueDeclareOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    retry::declare_retry_queues(&channel, "claymore_v2_queue", &policies.v2).await?;
+    retry::declare_retry_queues(
+        &channel,
+        "claymore_v2_evaluate_queue",
+        &policies.v2_evaluate,
+    )
+    .await?;
+    retry::declare_retry_queues(&channel_evo, JobPriority::Batch.queue(), &policies.evo).await?;
+    retry::declare_retry_queues(
+        &channel_interactive,
+        JobPriority::Interactive.queue(),
+        &policies.evo,
+    )
+    .await?;
+    channel
+        .exchange_declare(
+            DEAD_LETTER_EXCHANGE,
+            ExchangeKind::Fanout,
+            ExchangeDeclareOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .queue_declare(
+            DEAD_LETTER_QUEUE,
+            QueueDeclareOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .queue_bind(
+            DEAD_LETTER_QUEUE,
+            DEAD_LETTER_EXCHANGE,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
     let consumer = channel
         .basic_consume(
             "claymore_job_queue",
@@ -80,8 +201,7 @@ pub async fn start_consumer(db: PgPool, mq: Channel) {
             BasicConsumeOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .unwrap();
+        .await?;
     let consumer_v2 = channel
         .basic_consume(
             "claymore_v2_queue",
@@ -89,8 +209,7 @@ pub async fn start_consumer(db: PgPool, mq: Channel) {
             BasicConsumeOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .unwrap();
+        .await?;
     let consumer_v2_evaluate = channel
         .basic_consume(
             "claymore_v2_evaluate_queue",
@@ -98,21 +217,72 @@ pub async fn start_consumer(db: PgPool, mq: Channel) {
             BasicConsumeOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .unwrap();
+        .await?;
     let consumer_evo = channel_evo
         .basic_consume(
-            "claymore_evo_queue",
+            JobPriority::Batch.queue(),
             "tag_evo_consumer",
             BasicConsumeOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .unwrap();
+        .await?;
+    let consumer_interactive = channel_interactive
+        .basic_consume(
+            JobPriority::Interactive.queue(),
+            "tag_evo_interactive_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    let consumer_dead_letter = channel
+        .basic_consume(
+            DEAD_LETTER_QUEUE,
+            "tag_dead_letter_consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    let consumers = Consumers {
+        channels: vec![
+            channel.clone(),
+            channel_evo.clone(),
+            channel_interactive.clone(),
+        ],
+        tags: vec![
+            (channel.clone(), "tag_job_consumer"),
+            (channel.clone(), "tag_v2_consumer"),
+            (channel.clone(), "tag_v2_evaluate_consumer"),
+            (channel_evo.clone(), "tag_evo_consumer"),
+            (channel_interactive.clone(), "tag_evo_interactive_consumer"),
+            (channel.clone(), "tag_dead_letter_consumer"),
+        ],
+        in_flight: in_flight.clone(),
+    };
+    let in_flight_v2 = in_flight.clone();
+    let in_flight_eval = in_flight.clone();
+    let in_flight_evo = in_flight.clone();
+    let in_flight_interactive = in_flight.clone();
+    let llm_interactive = llm.clone();
+    let llm_v1 = llm.clone();
+    let llm_v2 = llm.clone();
+    let llm_eval = llm.clone();
+    let policy_interactive = policies.evo.clone();
+    // Batch messages take turns per workspace; interactive ones have their own lane, which
+    // the prefetch already keeps small.
+    let workspace_concurrency =
+        (config.workspace_concurrency > 0).then_some(config.workspace_concurrency);
+    let preprocess_cache_secs = config.preprocess_cache_secs;
+    let in_flight_dead_letter = in_flight.clone();
+    let publisher_v2 = publisher.clone();
+    let publisher_eval = publisher.clone();
+    let publisher_evo = publisher.clone();
 
     consumer.set_delegate(move |delivery: DeliveryResult| {
         let db = db.clone();
+        let llm = llm_v1.clone();
+        let in_flight = in_flight.enter();
         async move {
+            let _in_flight = in_flight;
             let delivery = match delivery {
                 // Carries the delivery alongside its channel
                 Ok(Some(delivery)) => delivery,
@@ -125,42 +295,40 @@ pub async fn start_consumer(db: PgPool, mq: Channel) {
                 }
             };
 
-            let result = match executor::execute_job(db, &delivery).await {
+            let result = match executor::execute_job(db, llm, &delivery).await {
                 Ok(result) => result,
                 Err(error) => {
                     log::error!("Execute job error: {}", error);
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("Failed to ack message");
+                    if let Err(error) = delivery.ack(BasicAckOptions::default()).await {
+                        log::error!("Ack message error: {}", error);
+                    }
                     return;
                 }
             };
 
             match result {
                 executor::ExecuteResult::Overflow => {
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("Failed to ack message");
+                    if let Err(error) = delivery.ack(BasicAckOptions::default()).await {
+                        log::error!("Ack message error: {}", error);
+                    }
                 }
                 executor::ExecuteResult::Success => {
-                    delivery
-                        .nack(BasicNackOptions {
-                            multiple: false,
-                            requeue: true,
-                        })
-                        .await
-                        .expect("Failed to requeue message");
+                    if let Err(error) = delivery.nack(REQUEUE).await {
+                        log::error!("Requeue message error: {}", error);
+                    }
                 }
             }
         }
     });
 
     consumer_v2.set_delegate(move |delivery: DeliveryResult| {
-        let channel = channel.clone();
         let db = db2.clone();
+        let llm = llm_v2.clone();
+        let policy = policies.v2.clone();
+        let publisher = publisher_v2.clone();
+        let in_flight = in_flight_v2.enter();
         async move {
+            let _in_flight = in_flight;
             let delivery = match delivery {
                 // Carries the delivery alongside its channel
                 Ok(Some(delivery)) => delivery,
@@ -173,64 +341,21 @@ pub async fn start_consumer(db: PgPool, mq: Channel) {
                 }
             };
 
-            let result = match executor::execute_job_v2(db, &delivery).await {
-                Ok(result) => result,
-                Err(error) => {
-                    log::error!("Execute job error: {}", error);
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("Failed to ack message");
-                    return;
-                }
-            };
-
-            match result {
-                executor::ExecuteResultV2::Success => {
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("Failed to ack message");
-                }
-                executor::ExecuteResultV2::Failed(attempts) => {
-                    if attempts > 3 {
-                        delivery
-                            .ack(BasicAckOptions::default())
-                            .await
-                            .expect("Failed to ack message");
-                    } else {
-                        let mut headers = FieldTable::default();
-                        headers.insert(
-                            "x-attempts".into(),
-                            lapin::types::AMQPValue::LongLongInt(attempts as i64),
-                        );
-                        let props = BasicProperties::default().with_headers(headers);
-                        let result = channel
-                            .basic_publish(
-                                "",
-                                &delivery.routing_key.as_str(),
-                                BasicPublishOptions::default(),
-                                delivery.data.as_slice(),
-                                props,
-                            )
-                            .await
-                            .unwrap()
-                            .await
-                            .unwrap();
-                        delivery
-                            .ack(BasicAckOptions::default())
-                            .await
-                            .expect("Failed to ack message");
-                    }
-                }
+            let result = executor::execute_job_v2(db, llm, &delivery).await;
+            if let Err(error) = retry::settle(&publisher, &delivery, result, &policy).await {
+                requeue(&delivery, error).await;
             }
         }
     });
 
     consumer_v2_evaluate.set_delegate(move |delivery: DeliveryResult| {
-        let channel = channel2.clone();
         let db = db_eval.clone();
+        let llm = llm_eval.clone();
+        let policy = policies.v2_evaluate.clone();
+        let publisher = publisher_eval.clone();
+        let in_flight = in_flight_eval.enter();
         async move {
+            let _in_flight = in_flight;
             let delivery = match delivery {
                 // Carries the delivery alongside its channel
                 Ok(Some(delivery)) => delivery,
@@ -243,64 +368,55 @@ pub async fn start_consumer(db: PgPool, mq: Channel) {
                 }
             };
 
-            let result = match executor::execute_job_v2_evaluate(db, &delivery).await {
-                Ok(result) => result,
+            let result = executor::execute_job_v2_evaluate(db, llm, &delivery).await;
+            if let Err(error) = retry::settle(&publisher, &delivery, result, &policy).await {
+                requeue(&delivery, error).await;
+            }
+        }
+    });
+
+    consumer_evo.set_delegate(move |delivery: DeliveryResult| {
+        let db = db_evo.clone();
+        let llm = llm.clone();
+        let policy = policies.evo.clone();
+        let publisher = publisher_evo.clone();
+        let in_flight = in_flight_evo.enter();
+        async move {
+            let _in_flight = in_flight;
+            let delivery = match delivery {
+                // Carries the delivery alongside its channel
+                Ok(Some(delivery)) => delivery,
+                // The consumer got canceled
+                Ok(None) => return,
+                // Carries the error and is always followed by Ok(None)
                 Err(error) => {
-                    log::error!("Execute job error: {}", error);
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("Failed to ack message");
+                    log::error!("Consumer error: {}", error);
                     return;
                 }
             };
 
-            match result {
-                executor::ExecuteResultV2::Success => {
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("Failed to ack message");
-                }
-                executor::ExecuteResultV2::Failed(attempts) => {
-                    if attempts > 3 {
-                        delivery
-                            .ack(BasicAckOptions::default())
-                            .await
-                            .expect("Failed to ack message");
-                    } else {
-                        let mut headers = FieldTable::default();
-                        headers.insert(
-                            "x-attempts".into(),
-                            lapin::types::AMQPValue::LongLongInt(attempts as i64),
-                        );
-                        let props = BasicProperties::default().with_headers(headers);
-                        let result = channel
-                            .basic_publish(
-                                "",
-                                &delivery.routing_key.as_str(),
-                                BasicPublishOptions::default(),
-                                delivery.data.as_slice(),
-                                props,
-                            )
-                            .await
-                            .unwrap()
-                            .await
-                            .unwrap();
-                        delivery
-                            .ack(BasicAckOptions::default())
-                            .await
-                            .expect("Failed to ack message");
-                    }
-                }
+            let result = executor::execute_job_evo(
+                db,
+                llm,
+                &delivery,
+                workspace_concurrency,
+                preprocess_cache_secs,
+            )
+            .await;
+            if let Err(error) = retry::settle(&publisher, &delivery, result, &policy).await {
+                requeue(&delivery, error).await;
             }
         }
     });
 
-    consumer_evo.set_delegate(move |delivery: DeliveryResult| {
-        let channel = channel_evo.clone();
-        let db = db_evo.clone();
+    consumer_interactive.set_delegate(move |delivery: DeliveryResult| {
+        let db = db_interactive.clone();
+        let llm = llm_interactive.clone();
+        let policy = policy_interactive.clone();
+        let publisher = publisher.clone();
+        let in_flight = in_flight_interactive.enter();
         async move {
+            let _in_flight = in_flight;
             let delivery = match delivery {
                 // Carries the delivery alongside its channel
                 Ok(Some(delivery)) => delivery,
@@ -313,121 +429,113 @@ pub async fn start_consumer(db: PgPool, mq: Channel) {
                 }
             };
 
-            let result = match executor::execute_job_evo(db, &delivery).await {
-                Ok(result) => result,
+            let result =
+                executor::execute_job_evo(db, llm, &delivery, None, preprocess_cache_secs).await;
+            if let Err(error) = retry::settle(&publisher, &delivery, result, &policy).await {
+                requeue(&delivery, error).await;
+            }
+        }
+    });
+
+    consumer_dead_letter.set_delegate(move |delivery: DeliveryResult| {
+        let db = db_dead_letter.clone();
+        let in_flight = in_flight_dead_letter.enter();
+        async move {
+            let _in_flight = in_flight;
+            let delivery = match delivery {
+                // Carries the delivery alongside its channel
+                Ok(Some(delivery)) => delivery,
+                // The consumer got canceled
+                Ok(None) => return,
+                // Carries the error and is always followed by Ok(None)
                 Err(error) => {
-                    log::error!("Execute job error: {}", error);
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("Failed to ack message");
+                    log::error!("Consumer error: {}", error);
                     return;
                 }
             };
 
-            match result {
-                executor::ExecuteResultV2::Success => {
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("Failed to ack message");
+            match failures::record_failure(&db, &delivery).await {
+                Ok(()) => {
+                    if let Err(error) = delivery.ack(BasicAckOptions::default()).await {
+                        log::error!("Ack message error: {}", error);
+                    }
                 }
-                executor::ExecuteResultV2::Failed(attempts) => {
-                    if attempts > 3 {
-                        delivery
-                            .ack(BasicAckOptions::default())
-                            .await
-                            .expect("Failed to ack message");
-                    } else {
-                        let mut headers = FieldTable::default();
-                        headers.insert(
-                            "x-attempts".into(),
-                            lapin::types::AMQPValue::LongLongInt(attempts as i64),
-                        );
-                        let props = BasicProperties::default().with_headers(headers);
-                        let result = channel
-                            .basic_publish(
-                                "",
-                                &delivery.routing_key.as_str(),
-                                BasicPublishOptions::default(),
-                                delivery.data.as_slice(),
-                                props,
-                            )
-                            .await
-                            .unwrap()
-                            .await
-                            .unwrap();
-                        delivery
-                            .ack(BasicAckOptions::default())
-                            .await
-                            .expect("Failed to ack message");
+                Err(error) => {
+                    // Leave it on the dead-letter queue rather than lose it.
+                    log::error!("Record job failure error: {:?}", error);
+                    if let Err(error) = delivery.nack(REQUEUE).await {
+                        log::error!("Requeue message error: {}", error);
                     }
                 }
             }
         }
     });
+
+    Ok(consumers)
 }
 
-pub async fn publish_message(channel: &Channel, message: Value) -> Confirmation {
-    let result = channel
-        .basic_publish(
-            "",
-            "claymore_job_queue",
-            BasicPublishOptions::default(),
-            message.to_string().as_bytes(),
-            BasicProperties::default(),
-        )
-        .await
-        .unwrap()
-        .await
-        .unwrap();
-    result
+/// Nack a message so the broker delivers it again.
+const REQUEUE: BasicNackOptions = BasicNackOptions {
+    multiple: false,
+    requeue: true,
+};
+
+/// Put a message back on its queue after it couldn't be settled, e.g. because the broker
+/// didn't confirm its retry, so it's tried again rather than lost.
+async fn requeue(delivery: &Delivery, error: anyhow::Error) {
+    log::error!(
+        "Settle message from {} error, requeueing: {:?}",
+        delivery.routing_key,
+        error
+    );
+    if let Err(error) = delivery.nack(REQUEUE).await {
+        // The channel is gone, so the broker delivers the message again anyway.
+        log::error!("Requeue message error: {}", error);
+    }
 }
 
-pub async fn publish_message_v2(channel: &Channel, message: Value) -> Confirmation {
-    let result = channel
-        .basic_publish(
-            "",
-            "claymore_v2_queue",
-            BasicPublishOptions::default(),
-            message.to_string().as_bytes(),
-            BasicProperties::default(),
-        )
-        .await
-        .unwrap()
+pub async fn publish_message(publisher: &Publisher, message: Value) -> anyhow::Result<()> {
+    publisher
+        .publish("claymore_job_queue", message.to_string().into_bytes())
         .await
-        .unwrap();
-    result
 }
 
-pub async fn publish_message_v2_evaluate(channel: &Channel, message: Value) -> Confirmation {
-    let result = channel
-        .basic_publish(
-            "",
-            "claymore_v2_evaluate_queue",
-            BasicPublishOptions::default(),
-            message.to_string().as_bytes(),
-            BasicProperties::default(),
-        )
-        .await
-        .unwrap()
+/// Publish every message of a job to `claymore_v2_queue` in one batch.
+pub async fn publish_messages_v2(
+    publisher: &Publisher,
+    messages: &[GeneratorMessage],
+) -> anyhow::Result<()> {
+    publisher
+        .publish_batch("claymore_v2_queue", &payloads(messages)?)
         .await
-        .unwrap();
-    result
 }
 
-pub async fn publish_message_evo(channel: &Channel, message: Value) -> Confirmation {
-    let result = channel
-        .basic_publish(
-            "",
-            "claymore_evo_queue",
-            BasicPublishOptions::default(),
-            message.to_string().as_bytes(),
-            BasicProperties::default(),
-        )
+/// Publish every message of a job to `claymore_v2_evaluate_queue` in one batch.
+pub async fn publish_messages_v2_evaluate(
+    publisher: &Publisher,
+    messages: &[EvaluateMessage],
+) -> anyhow::Result<()> {
+    publisher
+        .publish_batch("claymore_v2_evaluate_queue", &payloads(messages)?)
         .await
-        .unwrap()
+}
+
+/// Publish every message of a job to the queue for its `priority` in one batch.
+pub async fn publish_messages_evo(
+    publisher: &Publisher,
+    priority: JobPriority,
+    messages: &[ModuleMessage],
+) -> anyhow::Result<()> {
+    publisher
+        .publish_batch(priority.queue(), &payloads(messages)?)
         .await
-        .unwrap();
-    result
+}
+
+fn payloads<T: serde::Serialize>(messages: &[T]) -> anyhow::Result<Vec<Vec<u8>>> {
+    let payloads = messages
+        .iter()
+        .map(serde_json::to_vec)
+        .collect::<Result<_, _>>()?;
+
+    Ok(payloads)
 }
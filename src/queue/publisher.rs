@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context;
+use futures::future::try_join_all;
+use futures::lock::Mutex;
+use lapin::{
+    options::{BasicPublishOptions, ConfirmSelectOptions},
+    publisher_confirm::Confirmation,
+    BasicProperties, Channel, Connection, ConnectionProperties,
+};
+
+/// How many messages are published before waiting for the broker to confirm them.
+///
+/// This bounds how many unconfirmed messages are held in memory while a large file is queued.
+const CONFIRM_WINDOW: usize = 500;
+
+/// A long-lived connection to RabbitMQ shared by every API handler that publishes jobs, and by
+/// the workers for the retries and dead letters they publish.
+///
+/// Publishes are spread over a few channels of the one connection. The connection and the
+/// channels are opened on first use, and opened again if they were closed, e.g. because the
+/// broker restarted. Every channel is in confirm mode, so a publish only succeeds once the
+/// broker has taken the message.
+#[derive(Clone)]
+pub struct Publisher {
+    inner: Arc<PublisherInner>,
+}
+
+struct PublisherInner {
+    url: String,
+    connection: Mutex<Option<Connection>>,
+    channels: Vec<Mutex<Option<Channel>>>,
+    next: AtomicUsize,
+}
+
+impl Publisher {
+    /// A publisher for the broker at `url`, spreading publishes over `channels` channels.
+    ///
+    /// Nothing is opened until the first publish.
+    pub fn new(url: &str, channels: usize) -> Self {
+        Publisher {
+            inner: Arc::new(PublisherInner {
+                url: url.to_string(),
+                connection: Mutex::new(None),
+                channels: (0..channels.max(1)).map(|_| Mutex::new(None)).collect(),
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Publish `payloads` to `queue` and wait for the broker to confirm all of them.
+    ///
+    /// If the channel fails halfway, the unconfirmed part is published again once on a fresh
+    /// channel, so a message may occasionally be delivered twice.
+    pub async fn publish_batch(&self, queue: &str, payloads: &[Vec<u8>]) -> anyhow::Result<()> {
+        self.publish_all("", queue, payloads, BasicProperties::default())
+            .await
+    }
+
+    /// Publish a single payload to `queue`; see `publish_batch`.
+    pub async fn publish(&self, queue: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+        self.publish_batch(queue, &[payload]).await
+    }
+
+    /// Publish a single payload to `exchange` with `properties`, e.g. headers, and wait for the
+    /// broker to confirm it; see `publish_batch`.
+    pub async fn publish_to(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> anyhow::Result<()> {
+        self.publish_all(exchange, routing_key, &[payload.to_vec()], properties)
+            .await
+    }
+
+    async fn publish_all(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payloads: &[Vec<u8>],
+        properties: BasicProperties,
+    ) -> anyhow::Result<()> {
+        let slot = self.inner.next.fetch_add(1, Ordering::Relaxed) % self.inner.channels.len();
+        let mut channel = self.inner.channels[slot].lock().await;
+
+        for window in payloads.chunks(CONFIRM_WINDOW) {
+            let result = async {
+                let channel = self.open_channel(&mut channel).await?;
+                publish_window(channel, exchange, routing_key, window, &properties).await
+            }
+            .await;
+            if let Err(e) = result {
+                log::warn!("publishing to {} failed, retrying: {:?}", routing_key, e);
+                *channel = None;
+                let channel = self.open_channel(&mut channel).await?;
+                publish_window(channel, exchange, routing_key, window, &properties).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The channel in `slot`, opened first if it isn't open.
+    async fn open_channel<'a>(&self, slot: &'a mut Option<Channel>) -> anyhow::Result<&'a Channel> {
+        if !slot.as_ref().is_some_and(|c| c.status().connected()) {
+            let channel = self.create_channel().await?;
+            channel
+                .confirm_select(ConfirmSelectOptions::default())
+                .await?;
+            *slot = Some(channel);
+        }
+
+        Ok(slot.as_ref().unwrap())
+    }
+
+    async fn create_channel(&self) -> anyhow::Result<Channel> {
+        let mut connection = self.inner.connection.lock().await;
+        if let Some(conn) = connection.as_ref() {
+            if conn.status().connected() {
+                return Ok(conn.create_channel().await?);
+            }
+        }
+
+        log::info!("connecting to RabbitMQ for publishing");
+        let options = ConnectionProperties::default()
+            .with_executor(tokio_executor_trait::Tokio::current())
+            .with_reactor(tokio_reactor_trait::Tokio);
+        let conn = Connection::connect(&self.inner.url, options)
+            .await
+            .context("could not connect to rabbitmq_url")?;
+        let channel = conn.create_channel().await?;
+        *connection = Some(conn);
+
+        Ok(channel)
+    }
+}
+
+async fn publish_window(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    window: &[Vec<u8>],
+    properties: &BasicProperties,
+) -> anyhow::Result<()> {
+    let mut confirms = Vec::with_capacity(window.len());
+    for payload in window {
+        let confirm = channel
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                payload,
+                properties.clone(),
+            )
+            .await?;
+        confirms.push(confirm);
+    }
+
+    for confirmation in try_join_all(confirms).await? {
+        if let Confirmation::Nack(_) = confirmation {
+            anyhow::bail!("broker refused a message published to {}", routing_key);
+        }
+    }
+
+    Ok(())
+}
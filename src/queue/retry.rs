@@ -0,0 +1,230 @@
+use std::time::Duration;
+
+use lapin::{
+    message::Delivery,
+    options::{BasicAckOptions, QueueDeclareOptions},
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel,
+};
+use rand::Rng;
+
+use crate::queue::executor::ExecuteResultV2;
+use crate::queue::{failures, Publisher};
+
+/// How a queue retries the messages its worker failed on.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How many times a message is tried in all, counting the first, before it is dead-lettered.
+    pub max_attempts: i32,
+    /// How long to wait before the first retry. Every further retry waits twice as long.
+    pub base_delay: Duration,
+    /// The longest to wait before any retry.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait before trying again, after `attempts` failed attempts.
+    ///
+    /// Half the delay is random, so that messages which failed together, e.g. because a key
+    /// was rate limited, don't all come back at the same moment.
+    pub fn delay(&self, attempts: i32) -> Duration {
+        let exponent = attempts.saturating_sub(1).clamp(0, 31) as u32;
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// How long a deferred message, e.g. of a paused job, first waits before it is tried again.
+const PAUSED_RECHECK: Duration = Duration::from_secs(30);
+
+/// How long a throttled message first waits before it is tried again.
+///
+/// Short, since the workspace gets a slot back as soon as any of its calls finishes.
+const THROTTLED_RECHECK: Duration = Duration::from_secs(5);
+
+/// How many times the wait of a message that keeps being deferred or throttled doubles.
+///
+/// A job that stays paused is then looked at every 4 minutes rather than every 30 seconds, and
+/// a workspace that stays busy every 40 seconds rather than every 5.
+const HOLD_LEVELS: u32 = 4;
+
+/// The queue a message waits in before its next attempt.
+///
+/// There's one per attempt, so messages in the same retry queue wait about as long as each
+/// other and a long wait at the head never holds up a short one behind it.
+fn retry_queue(queue: &str, attempts: i32) -> String {
+    format!("{}.retry.{}", queue, attempts)
+}
+
+/// The queue deferred messages wait in, for `PAUSED_RECHECK` times `2^level`.
+fn paused_queue(queue: &str, level: u32) -> String {
+    format!("{}.paused.{}", queue, level)
+}
+
+/// The queue throttled messages wait in, for `THROTTLED_RECHECK` times `2^level`.
+fn throttled_queue(queue: &str, level: u32) -> String {
+    format!("{}.throttled.{}", queue, level)
+}
+
+/// The level of the paused or throttled queue a message goes to after it was already held
+/// back `holds` times in a row.
+fn hold_level(holds: i32) -> u32 {
+    (holds.max(0) as u32).min(HOLD_LEVELS - 1)
+}
+
+/// How many times in a row a message was deferred or throttled.
+fn delivery_holds(delivery: &Delivery) -> i32 {
+    if let Some(headers) = delivery.properties.headers() {
+        if let Some(AMQPValue::LongLongInt(val)) = headers.inner().get("x-holds") {
+            return *val as i32;
+        }
+    }
+
+    0
+}
+
+/// Declare the retry queues of `queue`, and the queues for deferred and throttled messages at
+/// every level of hold back.
+///
+/// Messages expire out of these queues once their delay is up, and are dead-lettered straight
+/// back into `queue`.
+pub(crate) async fn declare_retry_queues(
+    channel: &Channel,
+    queue: &str,
+    policy: &RetryPolicy,
+) -> lapin::Result<()> {
+    let arguments = || {
+        let mut arguments = FieldTable::default();
+        arguments.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString("".into()),
+        );
+        arguments.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(queue.into()),
+        );
+        arguments
+    };
+    for attempts in 1..policy.max_attempts {
+        channel
+            .queue_declare(
+                &retry_queue(queue, attempts),
+                QueueDeclareOptions::default(),
+                arguments(),
+            )
+            .await?;
+    }
+    let held = (0..HOLD_LEVELS).flat_map(|level| {
+        [
+            (paused_queue(queue, level), PAUSED_RECHECK * 2u32.pow(level)),
+            (
+                throttled_queue(queue, level),
+                THROTTLED_RECHECK * 2u32.pow(level),
+            ),
+        ]
+    });
+    for (name, ttl) in held {
+        let mut arguments = arguments();
+        arguments.insert(
+            "x-message-ttl".into(),
+            AMQPValue::LongLongInt(ttl.as_millis() as i64),
+        );
+        channel
+            .queue_declare(&name, QueueDeclareOptions::default(), arguments)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Ack a message once its worker is done with it, after scheduling whatever comes next.
+///
+/// A failed attempt is retried after `policy.delay`, until `policy.max_attempts` is reached and
+/// the message is dead-lettered. An error from the worker itself isn't a failed call to the
+/// model but something wrong with the message or the database, so it's dead-lettered straight
+/// away. That includes messages that don't parse. Deferred messages are set aside and tried
+/// again later, without counting as an attempt, and so are throttled ones, only sooner. Each
+/// time a message is held back again in a row, it waits twice as long, up to `HOLD_LEVELS`.
+/// Messages of a cancelled job are dropped.
+pub(crate) async fn settle(
+    publisher: &Publisher,
+    delivery: &Delivery,
+    result: anyhow::Result<ExecuteResultV2>,
+    policy: &RetryPolicy,
+) -> anyhow::Result<()> {
+    match result {
+        Ok(ExecuteResultV2::Success) => {}
+        Ok(ExecuteResultV2::Cancelled) => {
+            log::info!(
+                "dropping message from {} of a cancelled job",
+                delivery.routing_key
+            );
+        }
+        Ok(ExecuteResultV2::Deferred) => {
+            let attempts = super::delivery_attempts(delivery);
+            let holds = delivery_holds(delivery);
+            let queue = paused_queue(delivery.routing_key.as_str(), hold_level(holds));
+            publish_delayed(publisher, delivery, &queue, attempts, holds + 1, None).await?;
+        }
+        Ok(ExecuteResultV2::Throttled) => {
+            let attempts = super::delivery_attempts(delivery);
+            let holds = delivery_holds(delivery);
+            let queue = throttled_queue(delivery.routing_key.as_str(), hold_level(holds));
+            publish_delayed(publisher, delivery, &queue, attempts, holds + 1, None).await?;
+        }
+        Ok(ExecuteResultV2::Failed(attempts, error)) => {
+            if attempts >= policy.max_attempts {
+                failures::dead_letter(publisher, delivery, &error, attempts).await?;
+            } else {
+                let delay = policy.delay(attempts);
+                log::warn!(
+                    "retrying message from {} in {:?} after {} attempts: {}",
+                    delivery.routing_key,
+                    delay,
+                    attempts,
+                    error
+                );
+                let queue = retry_queue(delivery.routing_key.as_str(), attempts);
+                publish_delayed(publisher, delivery, &queue, attempts, 0, Some(delay)).await?;
+            }
+        }
+        Err(error) => {
+            let attempts = super::delivery_attempts(delivery) + 1;
+            failures::dead_letter(publisher, delivery, &format!("{:#}", error), attempts).await?;
+        }
+    }
+    delivery.ack(BasicAckOptions::default()).await?;
+
+    Ok(())
+}
+
+/// Publish the message to one of the queues that hold it back from `delivery.routing_key`, and
+/// wait for the broker to confirm it.
+///
+/// Without a `delay`, the message waits as long as the queue's own TTL. `holds` is how many
+/// times in a row it has now been held back without being tried.
+async fn publish_delayed(
+    publisher: &Publisher,
+    delivery: &Delivery,
+    queue: &str,
+    attempts: i32,
+    holds: i32,
+    delay: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut headers = FieldTable::default();
+    headers.insert("x-attempts".into(), AMQPValue::LongLongInt(attempts as i64));
+    if holds > 0 {
+        headers.insert("x-holds".into(), AMQPValue::LongLongInt(holds as i64));
+    }
+    let mut props = BasicProperties::default().with_headers(headers);
+    if let Some(delay) = delay {
+        props = props.with_expiration(delay.as_millis().to_string().into());
+    }
+    publisher
+        .publish_to("", queue, delivery.data.as_slice(), props)
+        .await
+}
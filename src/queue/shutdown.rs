@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use lapin::{options::BasicCancelOptions, Channel};
+use tokio::sync::Notify;
+
+/// Counts the deliveries the consumers of this process are still working on.
+#[derive(Clone, Default)]
+pub(crate) struct InFlight {
+    inner: Arc<InFlightInner>,
+}
+
+#[derive(Default)]
+struct InFlightInner {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlight {
+    /// Count a delivery as in flight until the returned guard is dropped.
+    pub(crate) fn enter(&self) -> InFlightGuard {
+        self.inner.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Wait until no delivery is in flight.
+    async fn idle(&self) {
+        loop {
+            // Register before checking, so a guard dropped in between still wakes us.
+            let notified = self.inner.idle.notified();
+            if self.inner.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.inner.count.load(Ordering::SeqCst)
+    }
+}
+
+pub(crate) struct InFlightGuard {
+    inner: Arc<InFlightInner>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.inner.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/// The consumers started by `start_consumer`, so they can be stopped again.
+pub struct Consumers {
+    pub(crate) channels: Vec<Channel>,
+    /// Every consumer tag, with the channel it consumes on.
+    pub(crate) tags: Vec<(Channel, &'static str)>,
+    pub(crate) in_flight: InFlight,
+}
+
+impl Consumers {
+    /// Stop taking new messages and give the ones in flight up to `grace` to finish.
+    ///
+    /// Whatever hasn't been acked by then is left unacked when the channels close, so the
+    /// broker puts it back on its queue for another worker. Its attempt isn't counted, since
+    /// the worker never got to settle it.
+    pub async fn shutdown(self, grace: Duration) {
+        for (channel, tag) in &self.tags {
+            if let Err(e) = channel
+                .basic_cancel(tag, BasicCancelOptions::default())
+                .await
+            {
+                log::warn!("failed to cancel consumer {}: {}", tag, e);
+            }
+        }
+
+        log::info!(
+            "waiting up to {:?} for {} messages in flight",
+            grace,
+            self.in_flight.count()
+        );
+        if tokio::time::timeout(grace, self.in_flight.idle())
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "requeueing {} messages still in flight after {:?}",
+                self.in_flight.count(),
+                grace
+            );
+        }
+
+        for channel in &self.channels {
+            if let Err(e) = channel.close(200, "worker stopping").await {
+                log::warn!("failed to close channel: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// How long a slot is held before it counts as abandoned by a worker that died.
+///
+/// This has to outlast the slowest model call, like the key lease does.
+const SLOT_LEASE: Duration = Duration::from_secs(600);
+
+/// One of the calls a workspace may have in flight at once, held for the length of a call.
+///
+/// Like `KeyLease`, the slot is given back on drop if it wasn't released, and a slot that is
+/// never given back stops counting once its lease runs out.
+pub(crate) struct WorkspaceSlot {
+    db: PgPool,
+    slot_id: Uuid,
+    released: bool,
+}
+
+impl WorkspaceSlot {
+    pub(crate) async fn release(mut self) -> Result<(), sqlx::Error> {
+        self.released = true;
+        release(&self.db, self.slot_id).await
+    }
+}
+
+impl Drop for WorkspaceSlot {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+
+        let db = self.db.clone();
+        let slot_id = self.slot_id;
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                if let Err(e) = release(&db, slot_id).await {
+                    log::error!(
+                        "failed to release dropped workspace slot {}: {}",
+                        slot_id,
+                        e
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// Take one of the `limit` slots of `workspace_id`, or `None` if they're all taken.
+///
+/// Workers take slots for a workspace one at a time, by locking its row, so the limit holds
+/// across every worker.
+pub(crate) async fn acquire_slot(
+    db: &PgPool,
+    workspace_id: Uuid,
+    job_id: Uuid,
+    limit: i64,
+) -> Result<Option<WorkspaceSlot>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        r#"select workspace_id from workspace_v2 where workspace_id = $1 for update"#,
+        workspace_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from workspace_slot where workspace_id = $1 and leased_until < now()"#,
+        workspace_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    let slot = sqlx::query!(
+        r#"insert into workspace_slot (workspace_id, job_id, leased_until)
+        select $1, $2, now() + make_interval(secs => $4)
+        where (select count(*) from workspace_slot where workspace_id = $1) < $3
+        returning slot_id"#,
+        workspace_id,
+        job_id,
+        limit,
+        SLOT_LEASE.as_secs_f64()
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(slot.map(|slot| WorkspaceSlot {
+        db: db.clone(),
+        slot_id: slot.slot_id,
+        released: false,
+    }))
+}
+
+async fn release(db: &PgPool, slot_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"delete from workspace_slot where slot_id = $1"#, slot_id)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
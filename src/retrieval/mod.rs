@@ -0,0 +1,685 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::http::response::Response;
+use elasticsearch::http::transport::Transport;
+use elasticsearch::indices::{
+    IndicesCreateParts, IndicesDeleteParts, IndicesExistsParts, IndicesGetMappingParts,
+};
+use elasticsearch::params::Refresh;
+use elasticsearch::{BulkParts, Elasticsearch, SearchParts};
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::openai::{Llm, ProviderConfig};
+
+mod sync;
+
+pub use sync::{
+    build_requested_indexes, drain_outbox, reconcile_all, request_index, spawn_index_sync,
+    spawn_reconciler,
+};
+
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/// Bumped whenever the layout of module indexes changes, so that old ones get rebuilt.
+///
+/// Indexes without a version predate this module and are keyed by a counter instead of the
+/// document id.
+const INDEX_VERSION: u64 = 2;
+
+/// How many documents are embedded and indexed per request.
+const BATCH_SIZE: usize = 64;
+
+/// Reconciliation compares the whole index with the database in one go; larger indexes are
+/// simply rebuilt.
+const MAX_RECONCILED_DOCUMENTS: i64 = 10000;
+
+/// In hybrid mode keyword and vector scores are simply added up. BM25 scores are unbounded
+/// while cosine similarity stays within 0..1, so keywords are damped to keep them from
+/// drowning out the vectors.
+const HYBRID_KEYWORD_BOOST: f32 = 0.1;
+const HYBRID_VECTOR_BOOST: f32 = 1.0;
+
+/// How candidates are matched against a question.
+///
+/// Modules search by keyword unless they opt into the other modes, which embed every
+/// candidate and so cost a call to the embedding provider per document.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SearchMode {
+    /// BM25 on the candidate text. Breaks down as soon as the question is paraphrased.
+    #[default]
+    Keyword,
+    /// kNN over candidate embeddings.
+    Dense,
+    /// Both, with the scores added up.
+    Hybrid,
+}
+
+impl SearchMode {
+    fn uses_embeddings(self) -> bool {
+        self != Self::Keyword
+    }
+}
+
+/// How a module retrieves references for chat, read from `config_data["retrieval"]`.
+///
+/// ```json
+/// { "mode": "dense", "embeddingModel": "text-embedding-3-small", "size": 8 }
+/// ```
+///
+/// Embeddings are computed by `provider`, which defaults to OpenAI with keys from the pool,
+/// independently of the provider the module chats with, since not every chat provider offers
+/// embeddings.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RetrievalConfig {
+    pub mode: SearchMode,
+    pub embedding_model: String,
+    pub provider: ProviderConfig,
+    /// How many candidates to retrieve.
+    pub size: i64,
+}
+
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        Self {
+            mode: SearchMode::default(),
+            embedding_model: DEFAULT_EMBEDDING_MODEL.to_string(),
+            provider: ProviderConfig::default(),
+            size: 5,
+        }
+    }
+}
+
+impl RetrievalConfig {
+    pub fn from_module_config(config_data: &serde_json::Value) -> anyhow::Result<Self> {
+        match config_data.get("retrieval") {
+            None | Some(serde_json::Value::Null) => Ok(Self::default()),
+            Some(retrieval) => {
+                serde_json::from_value(retrieval.clone()).context("invalid retrieval config")
+            }
+        }
+    }
+
+    /// Load the config of a module, falling back to the default if the module is gone.
+    pub async fn load(db: &PgPool, module_id: Uuid) -> anyhow::Result<Self> {
+        let module = sqlx::query!(
+            r#"select config_data from module_v2 where module_id = $1"#,
+            module_id
+        )
+        .fetch_optional(db)
+        .await?;
+
+        match module {
+            Some(module) => Self::from_module_config(&module.config_data),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// What a document in a module index was made from.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DocumentKind {
+    /// A row of `candidate_v2`.
+    Candidate,
+    /// A row of `data_v2`.
+    Data,
+}
+
+/// A document found for a question.
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Hit {
+    /// The `candidate_id` or `data_id` of the row the document was made from.
+    pub id: Uuid,
+    pub kind: DocumentKind,
+    pub content: String,
+    pub score: f64,
+}
+
+/// A searchable row, as stored in the database.
+struct Document {
+    id: Uuid,
+    kind: String,
+    content: String,
+    /// When the row last changed, in microseconds since the epoch.
+    version: i64,
+}
+
+/// Searches the Elasticsearch index kept for each module.
+///
+/// Every module has its own index, named after the module id, holding one document per row of
+/// `candidate_v2` and `data_v2` that belongs to the module, keyed by the row's id. Documents
+/// carry the row's `content`, its `kind`, the `version` of the row they were made from and,
+/// unless the module only uses keyword search, the `embedding` of the content.
+///
+/// Indexes are built in the background once a module is first searched, see `request_index`
+/// (chat uses `search_unindexed` until then), and kept up to date from then on by the
+/// triggers feeding `search_outbox`; see `spawn_index_sync` and `spawn_reconciler`.
+#[derive(Clone)]
+pub struct Retriever {
+    es: Elasticsearch,
+    llm: Llm,
+}
+
+impl Retriever {
+    pub fn new(es_url: &str, llm: Llm) -> anyhow::Result<Self> {
+        let transport = Transport::single_node(es_url)?;
+        Ok(Self {
+            es: Elasticsearch::new(transport),
+            llm,
+        })
+    }
+
+    /// Whether the module has an index that can serve `config`.
+    pub async fn index_ready(
+        &self,
+        module_id: Uuid,
+        config: &RetrievalConfig,
+    ) -> anyhow::Result<bool> {
+        let index = module_id.to_string();
+        Ok(self.index_exists(&index).await? && self.is_current(&index, config).await?)
+    }
+
+    /// Make sure the module has an index that can serve `config`, building it if not.
+    ///
+    /// Indexes from an older `INDEX_VERSION`, or without vectors when the module now needs
+    /// them, are rebuilt.
+    pub async fn ensure_index(
+        &self,
+        db: &PgPool,
+        module_id: Uuid,
+        config: &RetrievalConfig,
+    ) -> anyhow::Result<()> {
+        if self.index_ready(module_id, config).await? {
+            return Ok(());
+        }
+        log::info!("building index {}", module_id);
+        self.rebuild_index(db, module_id, config).await?;
+
+        Ok(())
+    }
+
+    /// Throw away the module's index and build it again from the database.
+    ///
+    /// Returns how many documents were indexed.
+    pub async fn rebuild_index(
+        &self,
+        db: &PgPool,
+        module_id: Uuid,
+        config: &RetrievalConfig,
+    ) -> anyhow::Result<usize> {
+        let index = module_id.to_string();
+        if self.index_exists(&index).await? {
+            let response = self
+                .es
+                .indices()
+                .delete(IndicesDeleteParts::Index(&[&index]))
+                .send()
+                .await?;
+            check(response).await?;
+        }
+
+        let mut properties = json!({
+            "content": { "type": "text" },
+            "kind": { "type": "keyword" },
+            "version": { "type": "long" },
+        });
+        if config.mode.uses_embeddings() {
+            let dims = self
+                .embed(config, vec!["probe".to_string()])
+                .await?
+                .first()
+                .map(|embedding| embedding.len())
+                .context("no embedding returned for the probe")?;
+            properties["embedding"] = json!({
+                "type": "dense_vector",
+                "dims": dims,
+                "index": true,
+                "similarity": "cosine",
+            });
+        }
+        let response = self
+            .es
+            .indices()
+            .create(IndicesCreateParts::Index(&index))
+            .body(json!({
+                "mappings": {
+                    "_meta": { "version": INDEX_VERSION },
+                    "properties": properties,
+                }
+            }))
+            .send()
+            .await?;
+        check(response).await?;
+
+        let documents = module_documents(db, module_id).await?;
+        self.index_documents(&index, config, &documents).await?;
+
+        Ok(documents.len())
+    }
+
+    /// Bring the documents for `ids` in line with the database: index the rows that still
+    /// belong to the module, and drop the rest.
+    ///
+    /// Modules that don't have an index yet are left alone; they get one, with everything in
+    /// it, once chat asks for it.
+    pub async fn sync_documents(
+        &self,
+        db: &PgPool,
+        module_id: Uuid,
+        config: &RetrievalConfig,
+        ids: &[Uuid],
+    ) -> anyhow::Result<()> {
+        let index = module_id.to_string();
+        if !self.index_exists(&index).await? {
+            return Ok(());
+        }
+
+        let documents = sqlx::query_as!(
+            Document,
+            // language=PostgreSQL
+            r#"select
+                candidate_id "id!",
+                'candidate' "kind!",
+                content "content!",
+                (extract(epoch from coalesce(updated_at, created_at)) * 1000000)::bigint "version!"
+            from candidate_v2 where module_id = $1 and candidate_id = any($2)
+            union all
+            select
+                data_id,
+                'data',
+                data_content,
+                (extract(epoch from coalesce(updated_at, created_at)) * 1000000)::bigint
+            from data_v2 where module_id = $1 and data_id = any($2)"#,
+            module_id,
+            ids
+        )
+        .fetch_all(db)
+        .await?;
+        let stale = ids
+            .iter()
+            .filter(|id| !documents.iter().any(|d| d.id == **id))
+            .copied()
+            .collect::<Vec<Uuid>>();
+
+        self.index_documents(&index, config, &documents).await?;
+        self.delete_documents(&index, &stale).await?;
+
+        Ok(())
+    }
+
+    /// Compare the module's index with the database and fix whatever differs.
+    ///
+    /// Returns how many documents were indexed or dropped.
+    pub async fn reconcile(
+        &self,
+        db: &PgPool,
+        module_id: Uuid,
+        config: &RetrievalConfig,
+    ) -> anyhow::Result<usize> {
+        let index = module_id.to_string();
+        if !self.index_exists(&index).await? {
+            return Ok(0);
+        }
+        if !self.is_current(&index, config).await? {
+            return self.rebuild_index(db, module_id, config).await;
+        }
+
+        let response = self
+            .es
+            .search(SearchParts::Index(&[&index]))
+            .size(MAX_RECONCILED_DOCUMENTS)
+            ._source(&["version"])
+            .body(json!({
+                "query": { "match_all": {} },
+                "track_total_hits": true,
+            }))
+            .send()
+            .await?;
+        let response = check(response).await?;
+        if response["hits"]["total"]["value"]
+            .as_i64()
+            .unwrap_or_default()
+            > MAX_RECONCILED_DOCUMENTS
+        {
+            return self.rebuild_index(db, module_id, config).await;
+        }
+        let mut indexed = response["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|hit| {
+                        Some((
+                            hit["_id"].as_str()?.parse::<Uuid>().ok()?,
+                            hit["_source"]["version"].as_i64(),
+                        ))
+                    })
+                    .collect::<HashMap<Uuid, Option<i64>>>()
+            })
+            .unwrap_or_default();
+
+        let mut outdated = Vec::new();
+        for document in module_documents(db, module_id).await? {
+            if indexed.remove(&document.id) != Some(Some(document.version)) {
+                outdated.push(document);
+            }
+        }
+        let stale = indexed.into_keys().collect::<Vec<Uuid>>();
+
+        self.index_documents(&index, config, &outdated).await?;
+        self.delete_documents(&index, &stale).await?;
+
+        Ok(outdated.len() + stale.len())
+    }
+
+    /// Find the documents of the module that best answer `query`.
+    pub async fn search(
+        &self,
+        module_id: Uuid,
+        config: &RetrievalConfig,
+        query: &str,
+    ) -> anyhow::Result<Vec<Hit>> {
+        let index = module_id.to_string();
+        let keyword = |boost: f32| {
+            json!({
+                "match": {
+                    "content": { "query": query, "boost": boost },
+                }
+            })
+        };
+        let body = if config.mode.uses_embeddings() {
+            let query_vector = self
+                .embed(config, vec![query.to_string()])
+                .await?
+                .pop()
+                .context("no embedding returned for the query")?;
+            let knn = |boost: f32| {
+                json!({
+                    "field": "embedding",
+                    "query_vector": query_vector,
+                    "k": config.size,
+                    "num_candidates": (config.size * 10).max(50),
+                    "boost": boost,
+                })
+            };
+            match config.mode {
+                SearchMode::Dense => json!({ "knn": knn(1.0) }),
+                _ => json!({
+                    "query": keyword(HYBRID_KEYWORD_BOOST),
+                    "knn": knn(HYBRID_VECTOR_BOOST),
+                }),
+            }
+        } else {
+            json!({ "query": keyword(1.0) })
+        };
+
+        let response = self
+            .es
+            .search(SearchParts::Index(&[&index]))
+            .size(config.size)
+            ._source(&["content", "kind"])
+            .body(body)
+            .send()
+            .await?;
+        let response = check(response).await?;
+        let hits = response["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|hit| {
+                        Some(Hit {
+                            id: hit["_id"].as_str()?.parse().ok()?,
+                            kind: serde_json::from_value(hit["_source"]["kind"].clone()).ok()?,
+                            content: hit["_source"]["content"].as_str()?.to_string(),
+                            score: hit["_score"].as_f64().unwrap_or_default(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(hits)
+    }
+
+    async fn index_exists(&self, index: &str) -> anyhow::Result<bool> {
+        let response = self
+            .es
+            .indices()
+            .exists(IndicesExistsParts::Index(&[index]))
+            .send()
+            .await?;
+
+        Ok(response.status_code().is_success())
+    }
+
+    async fn is_current(&self, index: &str, config: &RetrievalConfig) -> anyhow::Result<bool> {
+        let response = self
+            .es
+            .indices()
+            .get_mapping(IndicesGetMappingParts::Index(&[index]))
+            .send()
+            .await?;
+        let mapping = check(response).await?;
+        let mappings = &mapping[index]["mappings"];
+
+        Ok(mappings["_meta"]["version"] == INDEX_VERSION
+            && (!config.mode.uses_embeddings() || !mappings["properties"]["embedding"].is_null()))
+    }
+
+    async fn index_documents(
+        &self,
+        index: &str,
+        config: &RetrievalConfig,
+        documents: &[Document],
+    ) -> anyhow::Result<()> {
+        for batch in documents.chunks(BATCH_SIZE) {
+            let embeddings = if config.mode.uses_embeddings() {
+                let inputs = batch.iter().map(|d| d.content.clone()).collect();
+                self.embed(config, inputs).await?
+            } else {
+                Vec::new()
+            };
+
+            let mut body = Vec::<JsonBody<serde_json::Value>>::with_capacity(batch.len() * 2);
+            for (i, document) in batch.iter().enumerate() {
+                let mut source = json!({
+                    "content": document.content,
+                    "kind": document.kind,
+                    "version": document.version,
+                });
+                if let Some(embedding) = embeddings.get(i) {
+                    source["embedding"] = json!(embedding);
+                }
+                body.push(json!({ "index": { "_id": document.id } }).into());
+                body.push(source.into());
+            }
+            self.bulk(index, body).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_documents(&self, index: &str, ids: &[Uuid]) -> anyhow::Result<()> {
+        for batch in ids.chunks(BATCH_SIZE) {
+            let body = batch
+                .iter()
+                .map(|id| json!({ "delete": { "_id": id } }).into())
+                .collect::<Vec<JsonBody<serde_json::Value>>>();
+            self.bulk(index, body).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn bulk(
+        &self,
+        index: &str,
+        body: Vec<JsonBody<serde_json::Value>>,
+    ) -> anyhow::Result<()> {
+        // Wait for the changes to become searchable, so a chat right after a change sees it.
+        let response = self
+            .es
+            .bulk(BulkParts::Index(index))
+            .refresh(Refresh::WaitFor)
+            .body(body)
+            .send()
+            .await?;
+        let response = check(response).await?;
+        // Deleting a document that isn't there doesn't count as an error.
+        if response["errors"].as_bool().unwrap_or_default() {
+            bail!(
+                "bulk request to index {} failed: {}",
+                index,
+                response["items"]
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn embed(
+        &self,
+        config: &RetrievalConfig,
+        inputs: Vec<String>,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.llm
+            .embed(&config.provider, &config.embedding_model, inputs)
+            .await
+            .context("failed to embed documents")
+    }
+}
+
+/// Find the module's documents for `query` without its index, for chat to fall back on while
+/// the index is being built.
+///
+/// Documents are ranked by how many of the pairs of adjacent characters of the query they
+/// contain, which works about as well for text without spaces between words as with. The
+/// score is that number. Documents that share nothing with the query are left out.
+pub async fn search_unindexed(
+    db: &PgPool,
+    module_id: Uuid,
+    config: &RetrievalConfig,
+    query: &str,
+) -> anyhow::Result<Vec<Hit>> {
+    let documents = module_documents(db, module_id).await?;
+    Ok(rank_unindexed(documents, config.size as usize, query))
+}
+
+fn rank_unindexed(documents: Vec<Document>, size: usize, query: &str) -> Vec<Hit> {
+    let bigrams = |text: &str| {
+        let chars = text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .flat_map(char::to_lowercase)
+            .collect::<Vec<_>>();
+        chars
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect::<std::collections::HashSet<_>>()
+    };
+    let wanted = bigrams(query);
+
+    let mut hits = documents
+        .into_iter()
+        .filter_map(|document| {
+            let found = bigrams(&document.content);
+            let score = wanted.iter().filter(|pair| found.contains(pair)).count();
+            let kind = match document.kind.as_str() {
+                "candidate" => DocumentKind::Candidate,
+                _ => DocumentKind::Data,
+            };
+            (score > 0).then_some(Hit {
+                id: document.id,
+                kind,
+                content: document.content,
+                score: score as f64,
+            })
+        })
+        .collect::<Vec<_>>();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(size);
+
+    hits
+}
+
+/// Every searchable row of a module.
+async fn module_documents(db: &PgPool, module_id: Uuid) -> anyhow::Result<Vec<Document>> {
+    let documents = sqlx::query_as!(
+        Document,
+        // language=PostgreSQL
+        r#"select
+            candidate_id "id!",
+            'candidate' "kind!",
+            content "content!",
+            (extract(epoch from coalesce(updated_at, created_at)) * 1000000)::bigint "version!"
+        from candidate_v2 where module_id = $1
+        union all
+        select
+            data_id,
+            'data',
+            data_content,
+            (extract(epoch from coalesce(updated_at, created_at)) * 1000000)::bigint
+        from data_v2 where module_id = $1"#,
+        module_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(documents)
+}
+
+/// Turn an error status into an error, and parse the body of anything else.
+async fn check(response: Response) -> anyhow::Result<serde_json::Value> {
+    let status = response.status_code();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        bail!("elasticsearch returned {}: {}", status, body);
+    }
+
+    Ok(response.json().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(kind: &str, content: &str) -> Document {
+        Document {
+            id: Uuid::new_v4(),
+            kind: kind.to_string(),
+            content: content.to_string(),
+            version: 0,
+        }
+    }
+
+    #[test]
+    fn unindexed_search_ranks_by_shared_characters() {
+        let documents = vec![
+            document("candidate", "猫喜欢吃鱼"),
+            document("data", "Cats like fish, and catnip"),
+            document("candidate", "狗喜欢啃骨头"),
+            document("data", "nothing in common"),
+        ];
+        let hits = rank_unindexed(documents, 5, "猫喜欢什么");
+        let contents = hits
+            .iter()
+            .map(|hit| hit.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["猫喜欢吃鱼", "狗喜欢啃骨头"]);
+        assert_eq!(hits[0].kind, DocumentKind::Candidate);
+
+        let documents = vec![
+            document("candidate", "dogs"),
+            document("data", "Cats like fish, and catnip"),
+        ];
+        let hits = rank_unindexed(documents, 1, "what do CATS like");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].kind, DocumentKind::Data);
+    }
+}
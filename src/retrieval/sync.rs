@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::retrieval::{RetrievalConfig, Retriever};
+
+/// How many outbox entries are applied per round.
+const OUTBOX_BATCH_SIZE: i64 = 500;
+
+/// How long claimed entries are kept from other replicas. A replica that dies while applying
+/// them leaves them to be claimed again after this.
+const OUTBOX_LEASE: Duration = Duration::from_secs(600);
+
+/// How many requested indexes are built per round.
+const INDEX_BUILD_BATCH_SIZE: i64 = 10;
+
+/// How long a claimed index request is kept from other replicas, i.e. how long building an
+/// index may take.
+const INDEX_BUILD_LEASE: Duration = Duration::from_secs(3600);
+
+/// How long entries of a module whose index couldn't be updated wait before they are tried
+/// again, doubling with every attempt up to `OUTBOX_MAX_BACKOFF`.
+const OUTBOX_BASE_BACKOFF: Duration = Duration::from_secs(10);
+const OUTBOX_MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Apply a batch of changes recorded in `search_outbox` to the module indexes.
+///
+/// Entries are claimed with a short lease first and applied after, so no transaction stays
+/// open while Elasticsearch and the embedding provider are called, and several API replicas
+/// can drain the outbox side by side. Entries of a module whose index couldn't be updated
+/// stay in the outbox and back off before they are tried again, so a module that keeps
+/// failing doesn't keep the others waiting. Returns how many entries were applied.
+pub async fn drain_outbox(db: &PgPool, retriever: &Retriever) -> anyhow::Result<usize> {
+    let entries = sqlx::query!(
+        r#"update search_outbox set available_at = now() + make_interval(secs => $2)
+        where outbox_id in (
+            select outbox_id from search_outbox
+            where available_at <= now()
+            order by outbox_id
+            limit $1
+            for update skip locked
+        )
+        returning outbox_id, module_id, document_id, attempts"#,
+        OUTBOX_BATCH_SIZE,
+        OUTBOX_LEASE.as_secs_f64(),
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut modules = HashMap::<Uuid, (Vec<i64>, Vec<Uuid>, i32)>::new();
+    for entry in entries {
+        let (outbox_ids, document_ids, attempts) = modules.entry(entry.module_id).or_default();
+        outbox_ids.push(entry.outbox_id);
+        if !document_ids.contains(&entry.document_id) {
+            document_ids.push(entry.document_id);
+        }
+        *attempts = (*attempts).max(entry.attempts);
+    }
+
+    let mut applied = 0;
+    for (module_id, (outbox_ids, document_ids, attempts)) in modules {
+        let result = async {
+            let config = RetrievalConfig::load(db, module_id).await?;
+            retriever
+                .sync_documents(db, module_id, &config, &document_ids)
+                .await
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                sqlx::query!(
+                    r#"delete from search_outbox where outbox_id = any($1)"#,
+                    &outbox_ids
+                )
+                .execute(db)
+                .await?;
+                applied += outbox_ids.len();
+            }
+            Err(e) => {
+                let backoff = outbox_backoff(attempts);
+                log::error!(
+                    "failed to sync index of module {} (attempt {}), retrying in {:?}: {:?}",
+                    module_id,
+                    attempts + 1,
+                    backoff,
+                    e
+                );
+                sqlx::query!(
+                    r#"update search_outbox
+                    set attempts = attempts + 1, available_at = now() + make_interval(secs => $2)
+                    where outbox_id = any($1)"#,
+                    &outbox_ids,
+                    backoff.as_secs_f64(),
+                )
+                .execute(db)
+                .await?;
+            }
+        }
+    }
+
+    Ok(applied)
+}
+
+/// Ask for the module's index to be built, or rebuilt for its current config, by the index
+/// sync task.
+///
+/// Asking again while a build is pending or running does nothing.
+pub async fn request_index(db: &PgPool, module_id: Uuid) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"insert into search_index_request (module_id) values ($1) on conflict do nothing"#,
+        module_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Build the indexes asked for with `request_index`.
+///
+/// Requests are claimed with a lease like outbox entries, so only one replica builds a given
+/// index at a time and no transaction stays open while it does. Builds that fail back off
+/// before they are tried again. Returns how many indexes were built.
+pub async fn build_requested_indexes(db: &PgPool, retriever: &Retriever) -> anyhow::Result<usize> {
+    let requests = sqlx::query!(
+        r#"update search_index_request set available_at = now() + make_interval(secs => $2)
+        where module_id in (
+            select module_id from search_index_request
+            where available_at <= now()
+            order by available_at
+            limit $1
+            for update skip locked
+        )
+        returning module_id, attempts"#,
+        INDEX_BUILD_BATCH_SIZE,
+        INDEX_BUILD_LEASE.as_secs_f64(),
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut built = 0;
+    for request in requests {
+        let result = async {
+            let config = RetrievalConfig::load(db, request.module_id).await?;
+            retriever.ensure_index(db, request.module_id, &config).await
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                sqlx::query!(
+                    r#"delete from search_index_request where module_id = $1"#,
+                    request.module_id
+                )
+                .execute(db)
+                .await?;
+                built += 1;
+            }
+            Err(e) => {
+                let backoff = outbox_backoff(request.attempts);
+                log::error!(
+                    "failed to build index of module {} (attempt {}), retrying in {:?}: {:?}",
+                    request.module_id,
+                    request.attempts + 1,
+                    backoff,
+                    e
+                );
+                sqlx::query!(
+                    r#"update search_index_request
+                    set attempts = attempts + 1, available_at = now() + make_interval(secs => $2)
+                    where module_id = $1"#,
+                    request.module_id,
+                    backoff.as_secs_f64(),
+                )
+                .execute(db)
+                .await?;
+            }
+        }
+    }
+
+    Ok(built)
+}
+
+/// How long entries that failed `attempts` times before wait after failing once more.
+fn outbox_backoff(attempts: i32) -> Duration {
+    OUTBOX_BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts.clamp(0, 16) as u32))
+        .min(OUTBOX_MAX_BACKOFF)
+}
+
+/// Compare every module index with the database and fix whatever differs.
+///
+/// This catches anything the outbox missed, e.g. changes made while an index was being built.
+/// Returns how many documents were fixed.
+pub async fn reconcile_all(db: &PgPool, retriever: &Retriever) -> anyhow::Result<usize> {
+    let modules = sqlx::query!(r#"select module_id, config_data from module_v2"#)
+        .fetch_all(db)
+        .await?;
+
+    let mut fixed = 0;
+    for module in modules {
+        let result = async {
+            let config = RetrievalConfig::from_module_config(&module.config_data)?;
+            retriever.reconcile(db, module.module_id, &config).await
+        }
+        .await;
+        match result {
+            Ok(count) => fixed += count,
+            Err(e) => log::error!(
+                "failed to reconcile index of module {}: {:?}",
+                module.module_id,
+                e
+            ),
+        }
+    }
+
+    Ok(fixed)
+}
+
+/// Keep building the indexes chat asks for and draining the outbox for as long as the
+/// process runs.
+pub fn spawn_index_sync(db: PgPool, retriever: Retriever, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = build_requested_indexes(&db, &retriever).await {
+                log::error!("failed to build requested search indexes: {:?}", e);
+            }
+            // Keep going while there's a backlog, rather than waiting a full tick per batch.
+            loop {
+                match drain_outbox(&db, &retriever).await {
+                    Ok(count) if count as i64 == OUTBOX_BATCH_SIZE => continue,
+                    Ok(_) => break,
+                    Err(e) => {
+                        log::error!("failed to drain search outbox: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Periodically reconcile every module index for as long as the process runs.
+///
+/// The first round runs one period after startup rather than straight away, so that a fleet
+/// of restarting replicas doesn't all walk every index at once.
+pub fn spawn_reconciler(db: PgPool, retriever: Retriever, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+        loop {
+            interval.tick().await;
+            match reconcile_all(&db, &retriever).await {
+                Ok(0) => {}
+                Ok(count) => log::warn!("reconciliation fixed {} indexed documents", count),
+                Err(e) => log::error!("failed to reconcile search indexes: {:?}", e),
+            }
+        }
+    });
+}
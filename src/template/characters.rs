@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::template::Template;
+
+/// Look up the `@ref/` paths of `template` among the characters of `project_id`.
+///
+/// Paths naming a character or key that doesn't exist are left out, so rendering reports
+/// them. A path that's used twice gets the same random pick both times.
+pub async fn character_refs(
+    db: &PgPool,
+    project_id: Uuid,
+    template: &Template,
+) -> Result<HashMap<String, String>, sqlx::Error> {
+    let mut characters = HashMap::<String, Option<Vec<Value>>>::new();
+    let mut refs = HashMap::new();
+    for path in template.refs() {
+        let segments = path.split('/').collect::<Vec<_>>();
+        let name = segments[0];
+        if !characters.contains_key(name) {
+            let settings = sqlx::query!(
+                r#"select settings from character where character_name = $1 and project_id = $2"#,
+                name,
+                project_id
+            )
+            .fetch_optional(db)
+            .await?
+            .map(|character| character.settings);
+            let kv = settings.and_then(|settings| settings["kv"].as_array().cloned());
+            characters.insert(name.to_string(), kv);
+        }
+        let Some(Some(kv)) = characters.get(name) else {
+            continue;
+        };
+        if let Some(value) = resolve(kv, &segments[1..]) {
+            refs.insert(path, value);
+        }
+    }
+
+    Ok(refs)
+}
+
+fn resolve(kv: &[Value], path: &[&str]) -> Option<String> {
+    let find = |key: &str| kv.iter().find(|x| x["key"].as_str() == Some(key));
+    match path {
+        // The whole character.
+        [] => Some(kv.iter().map(entry).collect::<Vec<_>>().join(", ")),
+        // A random entry.
+        ["random"] => {
+            if kv.is_empty() {
+                return None;
+            }
+            Some(entry(&kv[rand::random::<usize>() % kv.len()]))
+        }
+        // Some entries.
+        [keys] => keys
+            .split('+')
+            .map(|key| find(key).map(entry))
+            .collect::<Option<Vec<_>>>()
+            .map(|entries| entries.join(", ")),
+        // A random value of a list entry.
+        [key, "random"] => {
+            let x = find(key)?;
+            match x["value"].as_array() {
+                Some(values) if x["type"].as_str() == Some("array") && !values.is_empty() => {
+                    let value = &values[rand::random::<usize>() % values.len()];
+                    Some(value.as_str().unwrap_or_default().to_string())
+                }
+                _ => Some(String::new()),
+            }
+        }
+        _ => Some(String::new()),
+    }
+}
+
+/// An entry as `key: value`, with list values joined by `; `.
+fn entry(x: &Value) -> String {
+    let value = if x["type"].as_str() == Some("array") {
+        x["value"]
+            .as_array()
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            })
+            .unwrap_or_default()
+    } else {
+        x["value"].as_str().unwrap_or_default().to_string()
+    };
+    format!("{}: {}", x["key"].as_str().unwrap_or_default(), value)
+}
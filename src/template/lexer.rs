@@ -0,0 +1,288 @@
+use crate::template::{Error, ErrorKind, Pos, PROMPT_VERSION};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TokenKind {
+    /// Literal text, with escapes already resolved.
+    Text(String),
+    /// `@key/<name>`, where the name runs up to the next space, so it may take in text that
+    /// follows it; see `resolve_key`.
+    Key(String),
+    /// `@prompt/<n>`
+    Prompt(String),
+    /// `^^`, in the prompts of a prompt chain.
+    Previous,
+    /// `@ref/<path>`
+    Ref(String),
+    /// What's between `@{` and `}`.
+    Expr(String),
+    /// What's between `{%` and `%}`.
+    Tag(String),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    pub pos: Pos,
+}
+
+/// Split a template into text and the expansions in it.
+///
+/// An `@key/`, `@prompt/` or `@ref/` that isn't followed by a name is left as text, as is an
+/// `@` before anything else, so e-mail addresses and the like need no escaping. `^^` is only
+/// the previous answer in a prompt `chain`; anywhere else it's text.
+pub(crate) fn tokenize(source: &str, chain: bool) -> Result<Vec<Token>, Error> {
+    tokenize_version(source, chain, PROMPT_VERSION)
+}
+
+/// Like `tokenize`, for a prompt written in syntax `version`. Before version 2 there were no
+/// `@{`, `{%` or escapes, so they are text.
+pub(crate) fn tokenize_version(
+    source: &str,
+    chain: bool,
+    version: u32,
+) -> Result<Vec<Token>, Error> {
+    let expressions = version >= 2;
+    let chars = source.chars().collect::<Vec<_>>();
+    let positions = positions(&chars);
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut text_pos = positions[0];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let rest = &chars[i..];
+        let pos = positions[i];
+        let token = if expressions
+            && rest.starts_with(&['\\'])
+            && matches!(rest.get(1), Some('@' | '^' | '{'))
+        {
+            if text.is_empty() {
+                text_pos = pos;
+            }
+            text.push(rest[1]);
+            i += 2;
+            continue;
+        } else if chain && rest.starts_with(&['^', '^']) {
+            i += 2;
+            TokenKind::Previous
+        } else if expressions && rest.starts_with(&['@', '{']) {
+            let end = find_close(rest, 2, &['}']).ok_or_else(|| Error {
+                kind: ErrorKind::Syntax("`@{` is never closed with `}`".to_string()),
+                pos,
+            })?;
+            i += end + 1;
+            TokenKind::Expr(rest[2..end].iter().collect())
+        } else if expressions && rest.starts_with(&['{', '%']) {
+            let end = find_close(rest, 2, &['%', '}']).ok_or_else(|| Error {
+                kind: ErrorKind::Syntax("`{%` is never closed with `%}`".to_string()),
+                pos,
+            })?;
+            i += end + 2;
+            TokenKind::Tag(rest[2..end].iter().collect::<String>().trim().to_string())
+        } else if let Some((kind, len)) = bare(rest) {
+            i += len;
+            kind
+        } else {
+            if text.is_empty() {
+                text_pos = pos;
+            }
+            text.push(rest[0]);
+            i += 1;
+            continue;
+        };
+
+        if !text.is_empty() {
+            tokens.push(Token {
+                kind: TokenKind::Text(std::mem::take(&mut text)),
+                pos: text_pos,
+            });
+        }
+        tokens.push(Token { kind: token, pos });
+    }
+    if !text.is_empty() {
+        tokens.push(Token {
+            kind: TokenKind::Text(text),
+            pos: text_pos,
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// The line and column of every character, and of the end of the source.
+fn positions(chars: &[char]) -> Vec<Pos> {
+    let mut positions = Vec::with_capacity(chars.len() + 1);
+    let mut pos = Pos { line: 1, column: 1 };
+    for &c in chars {
+        positions.push(pos);
+        if c == '\n' {
+            pos = Pos {
+                line: pos.line + 1,
+                column: 1,
+            };
+        } else {
+            pos.column += 1;
+        }
+    }
+    positions.push(pos);
+    positions
+}
+
+/// The index of `close` in `chars`, from `start` on, skipping over quoted strings.
+fn find_close(chars: &[char], start: usize, close: &[char]) -> Option<usize> {
+    let mut quoted = false;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if quoted => i += 1,
+            '"' => quoted = !quoted,
+            _ if !quoted && chars[i..].starts_with(close) => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// An `@key/`, `@prompt/` or `@ref/` expansion at the start of `chars`, and its length.
+fn bare(chars: &[char]) -> Option<(TokenKind, usize)> {
+    let name = |prefix: &str, allowed: fn(char) -> bool| {
+        let prefix = prefix.chars().collect::<Vec<_>>();
+        if !chars.starts_with(&prefix) {
+            return None;
+        }
+        let name = chars[prefix.len()..]
+            .iter()
+            .take_while(|&&c| allowed(c))
+            .collect::<String>();
+        // `@ref/bob/` ends a sentence, not a path.
+        let name = name.trim_end_matches('/').to_string();
+        (!name.is_empty()).then(|| {
+            let len = prefix.len() + name.chars().count();
+            (name, len)
+        })
+    };
+
+    // Key names have always been free text, so only what ends a word or starts another
+    // expansion ends them.
+    if let Some((name, len)) = name("@key/", |c| {
+        !c.is_whitespace() && !matches!(c, '@' | '\\' | '{' | '}' | '^')
+    }) {
+        return Some((TokenKind::Key(name), len));
+    }
+    if let Some((name, len)) = name("@prompt/", |c| c.is_ascii_digit()) {
+        return Some((TokenKind::Prompt(name), len));
+    }
+    if let Some((name, len)) = name("@ref/", |c| {
+        c.is_alphanumeric() || c == '_' || c == '+' || c == '/'
+    }) {
+        return Some((TokenKind::Ref(name), len));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str, chain: bool) -> Vec<TokenKind> {
+        tokenize(source, chain)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    fn text(text: &str) -> TokenKind {
+        TokenKind::Text(text.to_string())
+    }
+
+    fn key(name: &str) -> TokenKind {
+        TokenKind::Key(name.to_string())
+    }
+
+    #[test]
+    fn key_names_run_up_to_a_space() {
+        assert_eq!(
+            kinds("@key/my-key.v2 and @key/topic请回答", false),
+            [key("my-key.v2"), text(" and "), key("topic请回答")]
+        );
+    }
+
+    #[test]
+    fn key_names_end_at_another_expansion() {
+        assert_eq!(kinds("@key/a@key/b", false), [key("a"), key("b")]);
+        assert_eq!(
+            kinds("@key/a{% if key/b %}", false),
+            [key("a"), TokenKind::Tag("if key/b".to_string())]
+        );
+    }
+
+    #[test]
+    fn previous_answer_only_in_chains() {
+        assert_eq!(kinds("a ^^ b", false), [text("a ^^ b")]);
+        assert_eq!(
+            kinds("a ^^ b", true),
+            [text("a "), TokenKind::Previous, text(" b")]
+        );
+    }
+
+    #[test]
+    fn escapes_write_text() {
+        assert_eq!(kinds(r"\@key/a \{% x", false), [text("@key/a {% x")]);
+        assert_eq!(kinds(r"\^^", true), [text("^^")]);
+    }
+
+    #[test]
+    fn version_1_has_no_expressions_or_escapes() {
+        let kinds = |source| {
+            tokenize_version(source, true, 1)
+                .unwrap()
+                .into_iter()
+                .map(|token| token.kind)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kinds(r"{% raw %} @{x} \@key/a ^^"),
+            [
+                text(r"{% raw %} @{x} \"),
+                key("a"),
+                text(" "),
+                TokenKind::Previous
+            ]
+        );
+    }
+
+    #[test]
+    fn lone_at_is_text() {
+        assert_eq!(
+            kinds("mail me@example.com or @key/", false),
+            [text("mail me@example.com or @key/")]
+        );
+    }
+
+    #[test]
+    fn refs_drop_a_trailing_slash() {
+        assert_eq!(
+            kinds("@ref/bob/age+job/", false),
+            [TokenKind::Ref("bob/age+job".to_string()), text("/")]
+        );
+    }
+
+    #[test]
+    fn positions_count_lines_and_columns() {
+        let tokens = tokenize("ab\n  @key/x", false).unwrap();
+        assert_eq!(tokens[0].pos, Pos { line: 1, column: 1 });
+        assert_eq!(tokens[1].pos, Pos { line: 2, column: 3 });
+    }
+
+    #[test]
+    fn unclosed_expansions_are_errors_where_they_start() {
+        let error = tokenize("ok\nthen @{key/x", false).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::Syntax(_)));
+        assert_eq!(error.pos, Pos { line: 2, column: 6 });
+
+        let error = tokenize("{% if key/x", false).unwrap_err();
+        assert_eq!(error.pos, Pos { line: 1, column: 1 });
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+mod characters;
+mod lexer;
+mod parser;
+mod render;
+
+pub use characters::character_refs;
+
+/// The syntax version of prompts written now, kept as `promptVersion` next to the prompt of a
+/// config. Version 1 prompts were written before `@{...}`, `{% ... %}` and `\` escapes, and
+/// read them as text.
+pub const PROMPT_VERSION: u32 = 2;
+
+/// Where in a template something is, counting from 1.
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct Pos {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Why a template couldn't be parsed or rendered, and where.
+#[derive(thiserror::Error, serde::Serialize, Debug)]
+#[error("line {}, column {}: {kind}", pos.line, pos.column)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub pos: Pos,
+}
+
+#[derive(thiserror::Error, serde::Serialize, Debug)]
+#[serde(tag = "type", content = "detail", rename_all = "camelCase")]
+pub enum ErrorKind {
+    #[error("{0}")]
+    Syntax(String),
+
+    /// A key, prompt or reference that the template uses but nothing provides, and that has
+    /// no `default`.
+    #[error("unknown key `{0}`")]
+    UnknownKey(String),
+
+    #[error("unknown filter `{0}`")]
+    UnknownFilter(String),
+
+    #[error("filter `{filter}` {reason}")]
+    InvalidFilterArgs { filter: String, reason: String },
+
+    #[error("unknown tag `{{% {0} %}}`")]
+    UnknownTag(String),
+
+    #[error("`{{% {0} %}}` is never closed")]
+    Unclosed(String),
+
+    #[error("unexpected `{{% {0} %}}`")]
+    UnexpectedTag(String),
+
+    #[error("`{0}` is not a list")]
+    NotIterable(String),
+}
+
+/// A parsed prompt template.
+///
+/// Templates are plain text with these expansions:
+///
+/// - `@key/name`, a key of the module or generator, e.g. `@key/input` or `@key/reference`.
+///   The name runs up to the next space, and stands for its longest prefix that's a key,
+///   followed by the rest as text, so `@key/topic,` and `@key/topic请回答` need no space
+/// - `^^`, the previous answer of a prompt chain, and `@prompt/N`, the answer to its Nth prompt.
+///   `^^` is only special in templates parsed with `parse_chain`
+/// - `@ref/name`, `@ref/name/key+key`, `@ref/name/random` and `@ref/name/key/random`, a
+///   character of the project, looked up with `character_refs` before rendering
+/// - `@{key/name | filter | filter: arg}`, any of the above run through filters: `upper`,
+///   `lower`, `truncate: N`, `json` and `default: "value"`, which also stands in for a key
+///   that's missing or empty
+/// - `{% if key/name %}...{% elif key/name == "value" %}...{% else %}...{% endif %}`, where a
+///   condition holds if its value isn't empty, and may be negated with `not`
+/// - `{% for item in key/name %}...@{item}...{% endfor %}`, over a list, or over the lines of
+///   a text; `loop/index`, `loop/first` and `loop/last` say where the loop is
+///
+/// A `\` before `@`, `^` or `{` writes it as is. Anything used but not provided is an error,
+/// rather than being left in the prompt.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<parser::Node>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, Error> {
+        Self::parse_tokens(lexer::tokenize(source, false)?)
+    }
+
+    /// Parse a prompt written in syntax `version`; see `PROMPT_VERSION`.
+    pub fn parse_version(source: &str, version: u32) -> Result<Template, Error> {
+        Self::parse_tokens(lexer::tokenize_version(source, false, version)?)
+    }
+
+    /// Parse a prompt of a generator's prompt chain, where `^^` is the previous answer.
+    pub fn parse_chain(source: &str) -> Result<Template, Error> {
+        Self::parse_tokens(lexer::tokenize(source, true)?)
+    }
+
+    /// Parse a prompt of a prompt chain written in syntax `version`.
+    pub fn parse_chain_version(source: &str, version: u32) -> Result<Template, Error> {
+        Self::parse_tokens(lexer::tokenize_version(source, true, version)?)
+    }
+
+    fn parse_tokens(tokens: Vec<lexer::Token>) -> Result<Template, Error> {
+        Ok(Template {
+            nodes: parser::parse(tokens)?,
+        })
+    }
+
+    pub fn render(&self, context: &Context) -> Result<String, Error> {
+        let mut out = String::new();
+        render::Renderer::new(context).render(&self.nodes, &mut out)?;
+        Ok(out)
+    }
+
+    /// The `@ref/` paths the template uses, without the `ref/`, e.g. `bob/age+job`.
+    pub fn refs(&self) -> Vec<String> {
+        let mut refs = Vec::new();
+        for expr in self.exprs() {
+            if let Some((first, path)) = expr.path.split_first() {
+                let path = path.join("/");
+                if first == "ref" && !refs.contains(&path) {
+                    refs.push(path);
+                }
+            }
+        }
+        refs
+    }
+
+    /// The `@key/` names the template needs, leaving out those with a `default`.
+    ///
+    /// Bare names are as written, so they may run on into the text after them; match them
+    /// against the keys there are with `resolve_key`.
+    pub fn keys(&self) -> Vec<String> {
+        self.key_names(false)
+    }
+
+    /// Every `@key/` name the template reads, including those with a `default`, written as
+    /// `keys` writes them.
+    pub fn used_keys(&self) -> Vec<String> {
+        self.key_names(true)
+    }
+
+    fn key_names(&self, with_defaults: bool) -> Vec<String> {
+        let mut keys = Vec::new();
+        for expr in self.exprs() {
+            let has_default = expr
+                .filters
+                .iter()
+                .any(|filter| matches!(filter, parser::Filter::Default(_)));
+            if let [first, name, ..] = expr.path.as_slice() {
+                if first == "key" && (with_defaults || !has_default) && !keys.contains(name) {
+                    keys.push(name.clone());
+                }
+            }
+        }
+        keys
+    }
+
+    /// Every expression in the template, in order.
+    fn exprs(&self) -> Vec<&parser::Expr> {
+        fn collect<'a>(nodes: &'a [parser::Node], exprs: &mut Vec<&'a parser::Expr>) {
+            for node in nodes {
+                match node {
+                    parser::Node::Text(_) => {}
+                    parser::Node::Output(expr) => exprs.push(expr),
+                    parser::Node::If {
+                        branches,
+                        otherwise,
+                    } => {
+                        for (condition, body) in branches {
+                            exprs.push(&condition.expr);
+                            collect(body, exprs);
+                        }
+                        collect(otherwise, exprs);
+                    }
+                    parser::Node::For { iterable, body, .. } => {
+                        exprs.push(iterable);
+                        collect(body, exprs);
+                    }
+                }
+            }
+        }
+
+        let mut exprs = Vec::new();
+        collect(&self.nodes, &mut exprs);
+        exprs
+    }
+}
+
+/// What a template is rendered with.
+#[derive(Default, Clone, Debug)]
+pub struct Context {
+    values: Map<String, Value>,
+    refs: HashMap<String, String>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `@key/` values, e.g. from `config_keys`.
+    pub fn with_keys(mut self, keys: Map<String, Value>) -> Self {
+        for (name, value) in keys {
+            self = self.with_key(&name, value);
+        }
+        self
+    }
+
+    pub fn with_key(mut self, name: &str, value: impl Into<Value>) -> Self {
+        let keys = self
+            .values
+            .entry("key")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Object(keys) = keys {
+            keys.insert(name.to_string(), value.into());
+        }
+        self
+    }
+
+    /// The answers of a prompt chain so far, for `^^` and `@prompt/N`.
+    pub fn with_answers(mut self, answers: &[String]) -> Self {
+        let previous = answers.last().cloned().unwrap_or_default();
+        self.values
+            .insert("previous".to_string(), Value::String(previous));
+        let prompts = answers
+            .iter()
+            .enumerate()
+            .map(|(i, answer)| ((i + 1).to_string(), Value::String(answer.clone())))
+            .collect();
+        self.values
+            .insert("prompt".to_string(), Value::Object(prompts));
+        self
+    }
+
+    /// The `@ref/` values, from `character_refs`.
+    pub fn with_refs(mut self, refs: HashMap<String, String>) -> Self {
+        self.refs.extend(refs);
+        self
+    }
+}
+
+/// The key a bare `@key/name` stands for: `name` itself if it's a key, or else the longest
+/// of its prefixes that is, since the name runs on into any text right after it.
+pub fn resolve_key(name: &str, is_key: impl Fn(&str) -> bool) -> Option<&str> {
+    let mut ends = name
+        .char_indices()
+        .skip(1)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    ends.push(name.len());
+    ends.into_iter()
+        .rev()
+        .map(|end| &name[..end])
+        .find(|&prefix| is_key(prefix))
+}
+
+/// Parse and render `source` in one go.
+pub fn render(source: &str, context: &Context) -> Result<String, Error> {
+    Template::parse(source)?.render(context)
+}
+
+/// Parse and render `source`, written in syntax `version`, in one go.
+pub fn render_version(source: &str, version: u32, context: &Context) -> Result<String, Error> {
+    Template::parse_version(source, version)?.render(context)
+}
+
+/// The syntax version of the `prompt` of a module, generator or evaluator config. Configs
+/// without a `promptVersion` are written in the current one.
+pub fn prompt_version(config: &Map<String, Value>) -> u32 {
+    config
+        .get("promptVersion")
+        .and_then(Value::as_u64)
+        .map_or(PROMPT_VERSION, |version| version as u32)
+}
+
+/// Keep the `promptVersion` of a `stored` config in `config`, which replaces it. Clients that
+/// don't know about versions save configs without one, which would read a version 1 prompt in
+/// the current syntax; a prompt only changes version when the client sends a new one.
+pub fn keep_prompt_version(config: &mut Value, stored: &Value) {
+    if let (Some(config), Some(version)) = (config.as_object_mut(), stored.get("promptVersion")) {
+        config
+            .entry("promptVersion")
+            .or_insert_with(|| version.clone());
+    }
+}
+
+/// The syntax version of prompts kept elsewhere than in a config, next to a `promptVersion`
+/// like in configs: the `prompts` of a generator's prompt chain, or the prompts of a template.
+pub fn prompt_version_of(value: &Value) -> u32 {
+    value.as_object().map_or(PROMPT_VERSION, prompt_version)
+}
+
+/// The values of the `keyConfigs` of a module, generator or evaluator config.
+pub fn config_keys(config: &Map<String, Value>) -> Map<String, Value> {
+    config
+        .get("keyConfigs")
+        .and_then(Value::as_object)
+        .map(|key_configs| {
+            key_configs
+                .iter()
+                .filter_map(|(name, key_config)| {
+                    let value = key_config.get("value")?;
+                    Some((name.clone(), value.clone()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn saving_keeps_the_stored_prompt_version() {
+        let stored = json!({ "prompt": "@{old}", "promptVersion": 1 });
+        let mut config = json!({ "prompt": "@{edited}" });
+        keep_prompt_version(&mut config, &stored);
+        assert_eq!(prompt_version(config.as_object().unwrap()), 1);
+
+        let mut upgraded = json!({ "prompt": "@{edited}", "promptVersion": 2 });
+        keep_prompt_version(&mut upgraded, &stored);
+        assert_eq!(prompt_version(upgraded.as_object().unwrap()), 2);
+
+        let mut config = json!({ "prompt": "@{new}" });
+        keep_prompt_version(&mut config, &json!({ "prompt": "" }));
+        assert_eq!(prompt_version(config.as_object().unwrap()), PROMPT_VERSION);
+    }
+}
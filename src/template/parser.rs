@@ -0,0 +1,480 @@
+use serde_json::Value;
+
+use crate::template::lexer::{Token, TokenKind};
+use crate::template::{Error, ErrorKind, Pos};
+
+#[derive(Debug, Clone)]
+pub(crate) enum Node {
+    Text(String),
+    Output(Expr),
+    If {
+        /// Each condition with what to render if it's the first that holds.
+        branches: Vec<(Condition, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        var: String,
+        iterable: Expr,
+        body: Vec<Node>,
+    },
+}
+
+/// A value looked up by its path, run through filters.
+#[derive(Debug, Clone)]
+pub(crate) struct Expr {
+    pub path: Vec<String>,
+    pub filters: Vec<Filter>,
+    pub pos: Pos,
+    /// A bare `@key/name`, whose name may take in text after it; see `resolve_key`.
+    pub bare_key: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Filter {
+    Upper,
+    Lower,
+    Truncate(usize),
+    Json,
+    Default(Value),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Condition {
+    pub negated: bool,
+    pub expr: Expr,
+    /// Compare the value's text with a literal, rather than testing whether it's empty.
+    pub compare: Option<(bool, String)>,
+}
+
+/// A `{% ... %}` tag.
+enum Tag {
+    If(Condition),
+    Elif(Condition),
+    Else,
+    EndIf,
+    For { var: String, iterable: Expr },
+    EndFor,
+}
+
+impl Tag {
+    fn name(&self) -> &'static str {
+        match self {
+            Tag::If(_) => "if",
+            Tag::Elif(_) => "elif",
+            Tag::Else => "else",
+            Tag::EndIf => "endif",
+            Tag::For { .. } => "for",
+            Tag::EndFor => "endfor",
+        }
+    }
+}
+
+pub(crate) fn parse(tokens: Vec<Token>) -> Result<Vec<Node>, Error> {
+    let mut tokens = tokens.into_iter();
+    let (nodes, _) = parse_block(&mut tokens, &[])?;
+    Ok(nodes)
+}
+
+/// The tag that ended a block, and where.
+type Closer = Option<(Tag, Pos)>;
+
+/// Parse nodes up to the first tag in `closers`, which is returned along with them.
+///
+/// Returns no closer if the tokens ran out first, which the caller reports.
+fn parse_block(
+    tokens: &mut impl Iterator<Item = Token>,
+    closers: &[&str],
+) -> Result<(Vec<Node>, Closer), Error> {
+    let mut nodes = Vec::new();
+    while let Some(Token { kind, pos }) = tokens.next() {
+        let node = match kind {
+            TokenKind::Text(text) => Node::Text(text),
+            TokenKind::Key(name) => Node::Output(Expr {
+                bare_key: true,
+                ..bare_expr(vec!["key".to_string(), name], pos)
+            }),
+            TokenKind::Prompt(n) => Node::Output(bare_expr(vec!["prompt".to_string(), n], pos)),
+            TokenKind::Previous => Node::Output(bare_expr(vec!["previous".to_string()], pos)),
+            TokenKind::Ref(path) => {
+                let mut segments = vec!["ref".to_string()];
+                segments.extend(path.split('/').map(str::to_string));
+                Node::Output(bare_expr(segments, pos))
+            }
+            TokenKind::Expr(source) => {
+                let mut words = Words::new(&source, pos)?;
+                let expr = words.expr()?;
+                words.end()?;
+                Node::Output(expr)
+            }
+            TokenKind::Tag(source) => match parse_tag(&source, pos)? {
+                Tag::If(condition) => parse_if(tokens, condition, pos)?,
+                Tag::For { var, iterable } => {
+                    let (body, closer) = parse_block(tokens, &["endfor"])?;
+                    if closer.is_none() {
+                        return Err(Error {
+                            kind: ErrorKind::Unclosed("for".to_string()),
+                            pos,
+                        });
+                    }
+                    Node::For {
+                        var,
+                        iterable,
+                        body,
+                    }
+                }
+                tag if closers.contains(&tag.name()) => return Ok((nodes, Some((tag, pos)))),
+                tag => {
+                    return Err(Error {
+                        kind: ErrorKind::UnexpectedTag(tag.name().to_string()),
+                        pos,
+                    })
+                }
+            },
+        };
+        nodes.push(node);
+    }
+
+    Ok((nodes, None))
+}
+
+fn parse_if(
+    tokens: &mut impl Iterator<Item = Token>,
+    condition: Condition,
+    pos: Pos,
+) -> Result<Node, Error> {
+    let unclosed = || Error {
+        kind: ErrorKind::Unclosed("if".to_string()),
+        pos,
+    };
+    let mut branches = Vec::new();
+    let mut condition = condition;
+    loop {
+        let (body, closer) = parse_block(tokens, &["elif", "else", "endif"])?;
+        branches.push((condition, body));
+        match closer.ok_or_else(unclosed)?.0 {
+            Tag::Elif(next) => condition = next,
+            Tag::Else => {
+                let (otherwise, closer) = parse_block(tokens, &["endif"])?;
+                closer.ok_or_else(unclosed)?;
+                return Ok(Node::If {
+                    branches,
+                    otherwise,
+                });
+            }
+            _ => {
+                return Ok(Node::If {
+                    branches,
+                    otherwise: Vec::new(),
+                })
+            }
+        }
+    }
+}
+
+fn bare_expr(path: Vec<String>, pos: Pos) -> Expr {
+    Expr {
+        path,
+        filters: Vec::new(),
+        pos,
+        bare_key: false,
+    }
+}
+
+fn parse_tag(source: &str, pos: Pos) -> Result<Tag, Error> {
+    let mut words = Words::new(source, pos)?;
+    let tag = match words.ident()?.as_str() {
+        "if" => Tag::If(words.condition()?),
+        "elif" => Tag::Elif(words.condition()?),
+        "else" => Tag::Else,
+        "endif" => Tag::EndIf,
+        "for" => {
+            let var = words.ident()?;
+            if words.ident()? != "in" {
+                return Err(words.error("expected `in` after the loop variable"));
+            }
+            Tag::For {
+                var,
+                iterable: words.expr()?,
+            }
+        }
+        "endfor" => Tag::EndFor,
+        other => {
+            return Err(Error {
+                kind: ErrorKind::UnknownTag(other.to_string()),
+                pos,
+            })
+        }
+    };
+    words.end()?;
+    Ok(tag)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Word {
+    /// A name or a path, like `upper` or `key/topic`.
+    Ident(String),
+    Str(String),
+    Int(usize),
+    Symbol(&'static str),
+}
+
+/// The words of an expression or tag.
+struct Words {
+    words: std::vec::IntoIter<Word>,
+    pos: Pos,
+}
+
+impl Words {
+    fn new(source: &str, pos: Pos) -> Result<Self, Error> {
+        let error = |message: &str| Error {
+            kind: ErrorKind::Syntax(message.to_string()),
+            pos,
+        };
+        let mut words = Vec::new();
+        let mut chars = source.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == '"' {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err(error("string is never closed")),
+                    }
+                }
+                words.push(Word::Str(text));
+            } else if c.is_ascii_digit() {
+                let mut digits = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    digits.push(c);
+                    chars.next();
+                }
+                words.push(Word::Int(
+                    digits.parse().map_err(|_| error("number is too large"))?,
+                ));
+            } else if is_ident_char(c) {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek().filter(|&&c| is_ident_char(c)) {
+                    ident.push(c);
+                    chars.next();
+                }
+                words.push(Word::Ident(ident));
+            } else {
+                chars.next();
+                let symbol = match (c, chars.peek()) {
+                    ('=', Some('=')) => "==",
+                    ('!', Some('=')) => "!=",
+                    ('|', _) => "|",
+                    (':', _) => ":",
+                    (',', _) => ",",
+                    _ => return Err(error(&format!("unexpected `{}`", c))),
+                };
+                if symbol.len() == 2 {
+                    chars.next();
+                }
+                words.push(Word::Symbol(symbol));
+            }
+        }
+
+        Ok(Words {
+            words: words.into_iter(),
+            pos,
+        })
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error {
+            kind: ErrorKind::Syntax(message.to_string()),
+            pos: self.pos,
+        }
+    }
+
+    fn peek(&self) -> Option<&Word> {
+        self.words.as_slice().first()
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.words.next() {
+            Some(Word::Ident(ident)) => Ok(ident),
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value, Error> {
+        match self.words.next() {
+            Some(Word::Str(text)) => Ok(Value::String(text)),
+            Some(Word::Int(n)) => Ok(Value::from(n)),
+            _ => Err(self.error("expected a string or a number")),
+        }
+    }
+
+    /// `path | filter | filter: arg, arg`
+    fn expr(&mut self) -> Result<Expr, Error> {
+        let path = self
+            .ident()?
+            .split('/')
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if path.iter().any(String::is_empty) {
+            return Err(self.error("empty segment in path"));
+        }
+        let mut filters = Vec::new();
+        while self.peek() == Some(&Word::Symbol("|")) {
+            self.words.next();
+            let name = self.ident()?;
+            let mut args = Vec::new();
+            if self.peek() == Some(&Word::Symbol(":")) {
+                self.words.next();
+                args.push(self.literal()?);
+                while self.peek() == Some(&Word::Symbol(",")) {
+                    self.words.next();
+                    args.push(self.literal()?);
+                }
+            }
+            filters.push(self.filter(name, args)?);
+        }
+
+        Ok(Expr {
+            path,
+            filters,
+            pos: self.pos,
+            bare_key: false,
+        })
+    }
+
+    fn filter(&self, name: String, args: Vec<Value>) -> Result<Filter, Error> {
+        let invalid = |reason: &str| Error {
+            kind: ErrorKind::InvalidFilterArgs {
+                filter: name.clone(),
+                reason: reason.to_string(),
+            },
+            pos: self.pos,
+        };
+        let filter = match (name.as_str(), args.as_slice()) {
+            ("upper", []) => Filter::Upper,
+            ("lower", []) => Filter::Lower,
+            ("json", []) => Filter::Json,
+            ("truncate", [Value::Number(n)]) => {
+                Filter::Truncate(n.as_u64().ok_or_else(|| invalid("takes a length"))? as usize)
+            }
+            ("truncate", _) => return Err(invalid("takes a length")),
+            ("default", [value]) => Filter::Default(value.clone()),
+            ("default", _) => return Err(invalid("takes one value")),
+            ("upper" | "lower" | "json", _) => return Err(invalid("takes no arguments")),
+            _ => {
+                return Err(Error {
+                    kind: ErrorKind::UnknownFilter(name),
+                    pos: self.pos,
+                })
+            }
+        };
+        Ok(filter)
+    }
+
+    /// `[not] expr [== literal | != literal]`
+    fn condition(&mut self) -> Result<Condition, Error> {
+        let negated = self.peek() == Some(&Word::Ident("not".to_string()));
+        if negated {
+            self.words.next();
+        }
+        let expr = self.expr()?;
+        let compare = match self.peek() {
+            Some(Word::Symbol(op @ ("==" | "!="))) => {
+                let equal = *op == "==";
+                self.words.next();
+                let literal = match self.literal()? {
+                    Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                Some((equal, literal))
+            }
+            _ => None,
+        };
+
+        Ok(Condition {
+            negated,
+            expr,
+            compare,
+        })
+    }
+
+    fn end(&mut self) -> Result<(), Error> {
+        match self.words.next() {
+            None => Ok(()),
+            Some(_) => Err(self.error("unexpected words at the end")),
+        }
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '+' | '.' | '/')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::template::lexer::tokenize;
+    use crate::template::{ErrorKind, Pos};
+
+    fn parse_error(source: &str) -> crate::template::Error {
+        super::parse(tokenize(source, false).unwrap()).unwrap_err()
+    }
+
+    #[test]
+    fn parses_filters_and_tags() {
+        let source = r#"{% if not key/a %}@{key/b | upper | truncate: 3}{% elif key/c == "x" %}{% else %}{% for i in key/d %}@{i}{% endfor %}{% endif %}"#;
+        let nodes = super::parse(tokenize(source, false).unwrap()).unwrap();
+        assert_eq!(nodes.len(), 1);
+    }
+
+    #[test]
+    fn unclosed_blocks_point_at_their_tag() {
+        let error = parse_error("a\n{% if key/a %}b");
+        assert!(matches!(error.kind, ErrorKind::Unclosed(ref tag) if tag == "if"));
+        assert_eq!(error.pos, Pos { line: 2, column: 1 });
+
+        let error = parse_error("{% for x in key/a %}");
+        assert!(matches!(error.kind, ErrorKind::Unclosed(ref tag) if tag == "for"));
+    }
+
+    #[test]
+    fn stray_closers_are_unexpected() {
+        let error = parse_error("a {% endif %}");
+        assert!(matches!(error.kind, ErrorKind::UnexpectedTag(ref tag) if tag == "endif"));
+        assert_eq!(error.pos, Pos { line: 1, column: 3 });
+    }
+
+    #[test]
+    fn unknown_tags_and_filters() {
+        assert!(matches!(
+            parse_error("{% while key/a %}").kind,
+            ErrorKind::UnknownTag(ref tag) if tag == "while"
+        ));
+        assert!(matches!(
+            parse_error("@{key/a | shout}").kind,
+            ErrorKind::UnknownFilter(ref filter) if filter == "shout"
+        ));
+        assert!(matches!(
+            parse_error("@{key/a | truncate}").kind,
+            ErrorKind::InvalidFilterArgs { .. }
+        ));
+    }
+
+    #[test]
+    fn syntax_errors_in_expressions() {
+        let error = parse_error("x @{key//a}");
+        assert!(matches!(error.kind, ErrorKind::Syntax(_)));
+        assert_eq!(error.pos, Pos { line: 1, column: 3 });
+        assert!(matches!(
+            parse_error("@{key/a | upper later}").kind,
+            ErrorKind::Syntax(_)
+        ));
+        assert!(matches!(
+            parse_error("{% for x key/a %}{% endfor %}").kind,
+            ErrorKind::Syntax(_)
+        ));
+    }
+}
@@ -0,0 +1,268 @@
+use serde_json::{json, Value};
+
+use crate::template::parser::{Condition, Expr, Filter, Node};
+use crate::template::{resolve_key, Context, Error, ErrorKind};
+
+pub(crate) struct Renderer<'a> {
+    context: &'a Context,
+    /// Loop variables, innermost last.
+    scopes: Vec<(String, Value)>,
+}
+
+impl<'a> Renderer<'a> {
+    pub(crate) fn new(context: &'a Context) -> Self {
+        Renderer {
+            context,
+            scopes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn render(&mut self, nodes: &[Node], out: &mut String) -> Result<(), Error> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Output(expr) if expr.bare_key => self.render_bare_key(expr, out)?,
+                Node::Output(expr) => out.push_str(&text(&self.eval(expr)?)),
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let mut taken = None;
+                    for (condition, body) in branches {
+                        if self.holds(condition)? {
+                            taken = Some(body);
+                            break;
+                        }
+                    }
+                    self.render(taken.unwrap_or(otherwise), out)?;
+                }
+                Node::For {
+                    var,
+                    iterable,
+                    body,
+                } => {
+                    let items = match self.eval(iterable)? {
+                        Value::Array(items) => items,
+                        // A list typed into a key, one item per line.
+                        Value::String(text) => text
+                            .lines()
+                            .filter(|line| !line.trim().is_empty())
+                            .map(|line| Value::String(line.to_string()))
+                            .collect(),
+                        _ => {
+                            return Err(Error {
+                                kind: ErrorKind::NotIterable(iterable.path.join("/")),
+                                pos: iterable.pos,
+                            })
+                        }
+                    };
+                    let count = items.len();
+                    for (index, item) in items.into_iter().enumerate() {
+                        self.scopes.push((
+                            "loop".to_string(),
+                            json!({
+                                "index": index + 1,
+                                "first": index == 0,
+                                "last": index + 1 == count,
+                            }),
+                        ));
+                        self.scopes.push((var.clone(), item));
+                        let result = self.render(body, out);
+                        self.scopes.truncate(self.scopes.len() - 2);
+                        result?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The key a bare `@key/name` stands for, and the rest of the name as text.
+    fn render_bare_key(&self, expr: &Expr, out: &mut String) -> Result<(), Error> {
+        let name = &expr.path[1];
+        let key_path = |key: &str| vec!["key".to_string(), key.to_string()];
+        let key =
+            resolve_key(name, |key| self.lookup(&key_path(key)).is_some()).ok_or_else(|| {
+                Error {
+                    kind: ErrorKind::UnknownKey(expr.path.join("/")),
+                    pos: expr.pos,
+                }
+            })?;
+        out.push_str(&text(&self.lookup(&key_path(key)).unwrap_or_default()));
+        out.push_str(&name[key.len()..]);
+        Ok(())
+    }
+
+    fn holds(&self, condition: &Condition) -> Result<bool, Error> {
+        let value = self.eval(&condition.expr)?;
+        let holds = match &condition.compare {
+            Some((equal, literal)) => (text(&value) == *literal) == *equal,
+            None => truthy(&value),
+        };
+        Ok(holds != condition.negated)
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Value, Error> {
+        let mut value = self.lookup(&expr.path);
+        for filter in &expr.filters {
+            value = match (filter, value) {
+                (Filter::Default(default), None) => Some(default.clone()),
+                (Filter::Default(default), Some(value)) if !truthy(&value) => Some(default.clone()),
+                (_, None) => None,
+                (Filter::Default(_), Some(value)) => Some(value),
+                (Filter::Upper, Some(value)) => Some(Value::String(text(&value).to_uppercase())),
+                (Filter::Lower, Some(value)) => Some(Value::String(text(&value).to_lowercase())),
+                (Filter::Truncate(length), Some(value)) => {
+                    Some(Value::String(text(&value).chars().take(*length).collect()))
+                }
+                (Filter::Json, Some(value)) => Some(Value::String(value.to_string())),
+            };
+        }
+
+        value.ok_or_else(|| Error {
+            kind: ErrorKind::UnknownKey(expr.path.join("/")),
+            pos: expr.pos,
+        })
+    }
+
+    fn lookup(&self, path: &[String]) -> Option<Value> {
+        let (first, rest) = path.split_first()?;
+        if let Some((_, value)) = self.scopes.iter().rev().find(|(name, _)| name == first) {
+            return walk(value, rest).cloned();
+        }
+        if first == "ref" {
+            return self
+                .context
+                .refs
+                .get(&rest.join("/"))
+                .map(|text| Value::String(text.clone()));
+        }
+        walk(self.context.values.get(first)?, rest).cloned()
+    }
+}
+
+fn walk<'v>(mut value: &'v Value, path: &[String]) -> Option<&'v Value> {
+    for segment in path {
+        value = match value {
+            Value::Object(map) => map.get(segment)?,
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// How a value reads in a prompt. Lists and objects are written as JSON.
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(text) => !text.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::template::{render, Context, ErrorKind, Pos, Template};
+
+    fn context() -> Context {
+        Context::new()
+            .with_key("topic", "cats")
+            .with_key("my-key", "dashed")
+            .with_key("a", "short")
+            .with_key("a-b", "long")
+            .with_key("empty", "")
+            .with_key("list", json!(["x", "y"]))
+    }
+
+    #[test]
+    fn bare_keys_take_the_longest_key_prefix() {
+        let context = context();
+        assert_eq!(render("@key/topic", &context).unwrap(), "cats");
+        assert_eq!(render("@key/topic, then", &context).unwrap(), "cats, then");
+        assert_eq!(render("@key/topic请回答", &context).unwrap(), "cats请回答");
+        assert_eq!(render("@key/my-key.", &context).unwrap(), "dashed.");
+        assert_eq!(render("@key/a-bc", &context).unwrap(), "longc");
+        assert_eq!(render("@key/a.b", &context).unwrap(), "short.b");
+    }
+
+    #[test]
+    fn unknown_keys_are_errors_where_they_are_used() {
+        let error = render("ok\n  @key/nope", &context()).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::UnknownKey(ref key) if key == "key/nope"));
+        assert_eq!(error.pos, Pos { line: 2, column: 3 });
+
+        let error = render("@{key/nope | upper}", &context()).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::UnknownKey(_)));
+    }
+
+    #[test]
+    fn defaults_stand_in_for_missing_and_empty_keys() {
+        let context = context();
+        assert_eq!(
+            render(r#"@{key/nope | default: "x"}"#, &context).unwrap(),
+            "x"
+        );
+        assert_eq!(
+            render(r#"@{key/empty | default: "x"}"#, &context).unwrap(),
+            "x"
+        );
+    }
+
+    #[test]
+    fn escapes_render_as_written() {
+        assert_eq!(
+            render(r"\@key/topic \^^", &context()).unwrap(),
+            "@key/topic ^^"
+        );
+    }
+
+    #[test]
+    fn previous_answer_only_in_chains() {
+        let context = context().with_answers(&["one".to_string(), "two".to_string()]);
+        assert_eq!(render("a ^^", &context).unwrap(), "a ^^");
+        let chain = Template::parse_chain("a ^^ @prompt/1").unwrap();
+        assert_eq!(chain.render(&context).unwrap(), "a two one");
+        let chain = Template::parse_chain_version(r"^^ @{x} {% if \", 1).unwrap();
+        assert_eq!(chain.render(&context).unwrap(), r"two @{x} {% if \");
+    }
+
+    #[test]
+    fn filters_conditions_and_loops() {
+        let context = context();
+        assert_eq!(
+            render("@{key/topic | upper | truncate: 2}", &context).unwrap(),
+            "CA"
+        );
+        assert_eq!(
+            render(
+                r#"{% if key/empty %}no{% elif key/topic == "cats" %}yes{% endif %}"#,
+                &context
+            )
+            .unwrap(),
+            "yes"
+        );
+        assert_eq!(
+            render(
+                "{% for item in key/list %}@{loop/index}@{item}{% if not loop/last %},{% endif %}{% endfor %}",
+                &context
+            )
+            .unwrap(),
+            "1x,2y"
+        );
+    }
+}